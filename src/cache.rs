@@ -0,0 +1,78 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+// (file name, chunk offset) of a chunk
+type Key = (String, u64);
+
+// Decompressed chunks, least recently used evicted first once they add up
+// to more than the capacity in bytes
+pub(crate) struct ChunkCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    used: usize,
+    tick: u64,
+    chunks: HashMap<Key, (u64, Arc<[u8]>)>,
+    // Last use -> chunk, oldest first
+    lru: BTreeMap<u64, Key>,
+}
+
+impl ChunkCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ChunkCache {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn get(&self, name: &str, chunk_offset: u64) -> Option<Arc<[u8]>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut inner = self.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let key = (name.to_string(), chunk_offset);
+        let (last_use, data) = inner.chunks.get_mut(&key)?;
+        let old = std::mem::replace(last_use, tick);
+        let data = data.clone();
+        inner.lru.remove(&old);
+        inner.lru.insert(tick, key);
+        Some(data)
+    }
+
+    pub(crate) fn insert(&self, name: &str, chunk_offset: u64, data: Arc<[u8]>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        let mut inner = self.lock();
+        while inner.used + data.len() > self.capacity {
+            let Some((_, key)) = inner.lru.pop_first() else {
+                break;
+            };
+            if let Some((_, evicted)) = inner.chunks.remove(&key) {
+                inner.used -= evicted.len();
+            }
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        let key = (name.to_string(), chunk_offset);
+        inner.used += data.len();
+        inner.lru.insert(tick, key.clone());
+        if let Some((old, replaced)) = inner.chunks.insert(key, (tick, data)) {
+            // Two readers raced to fill the same chunk
+            inner.used -= replaced.len();
+            inner.lru.remove(&old);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // Nothing in a cache is worth failing a read over
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::{GzReader, TocEntry};
+
+/// How an entry of the layer and the file at the same path of a directory
+/// differ, see [`compare_with_dir`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// In the layer, not in the directory.
+    MissingOnDisk,
+    /// In the directory, not in the layer.
+    MissingInLayer,
+    /// Different kinds of entry, as TOC entry types (`reg`, `dir`...).
+    Type {
+        layer: String,
+        disk: String,
+    },
+    /// Permission bits, setuid/setgid/sticky included.
+    Mode {
+        layer: u32,
+        disk: u32,
+    },
+    Owner {
+        layer: (u32, u32),
+        disk: (u32, u32),
+    },
+    /// Modification time, in seconds since the epoch.
+    ModTime {
+        layer: i64,
+        disk: i64,
+    },
+    Size {
+        layer: u64,
+        disk: u64,
+    },
+    /// Same size, different bytes.
+    Content,
+    LinkTarget {
+        layer: String,
+        disk: String,
+    },
+    Device {
+        layer: (u64, u64),
+        disk: (u64, u64),
+    },
+}
+
+/// A path where the layer and the directory disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Drift {
+    /// Path relative to the layer root and to the directory.
+    pub path: String,
+    pub difference: Difference,
+}
+
+/// Compare every entry of `reader` with the file at the same path under
+/// `dir`, and report what differs: files missing on either side, then type,
+/// metadata and content of those present on both.
+///
+/// File content is compared by sha256, using the TOC digest when there is
+/// one and decompressing the file from the layer otherwise. Hardlinks are
+/// compared as the file they point to. Results are sorted by path, with at
+/// most one difference per path for type mismatches and missing files.
+pub fn compare_with_dir(reader: &GzReader, dir: &Path) -> Result<Vec<Drift>> {
+    let mut on_disk = BTreeMap::new();
+    walk(dir, "", &mut on_disk)?;
+
+    let mut drifts = Vec::new();
+    let mut names: Vec<&String> = reader.index().m.keys().filter(|n| !n.is_empty()).collect();
+    names.sort();
+    for name in names {
+        let mut diff = |difference| {
+            drifts.push(Drift {
+                path: name.clone(),
+                difference,
+            })
+        };
+        let Some(meta) = on_disk.remove(name.as_str()) else {
+            diff(Difference::MissingOnDisk);
+            continue;
+        };
+        let ent = reader.lookup(name)?;
+        let owner = Some((ent.uid, ent.gid));
+        compare_entry(
+            reader,
+            ent,
+            &dir.join(name),
+            &meta,
+            ent.mode & 0o7777,
+            owner,
+            diff,
+        )?;
+    }
+
+    for path in on_disk.into_keys() {
+        drifts.push(Drift {
+            path,
+            difference: Difference::MissingInLayer,
+        });
+    }
+    drifts.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(drifts)
+}
+
+// Compare `ent` with the file at `path`, which should have permission bits
+// `mode` and, unless None, owner `owner`
+pub(crate) fn compare_entry(
+    reader: &GzReader,
+    ent: &TocEntry,
+    path: &Path,
+    meta: &fs::Metadata,
+    mode: u32,
+    owner: Option<(u32, u32)>,
+    mut diff: impl FnMut(Difference),
+) -> Result<()> {
+    let disk_type = entry_type(meta);
+    if ent.entry_type != disk_type {
+        diff(Difference::Type {
+            layer: ent.entry_type.clone(),
+            disk: disk_type.to_string(),
+        });
+        return Ok(());
+    }
+
+    // Symlink permissions are meaningless, and so is their mode in TOCs
+    if ent.entry_type != "symlink" && mode != meta.mode() & 0o7777 {
+        diff(Difference::Mode {
+            layer: mode,
+            disk: meta.mode() & 0o7777,
+        });
+    }
+    if let Some(owner) = owner.filter(|&o| o != (meta.uid(), meta.gid())) {
+        diff(Difference::Owner {
+            layer: owner,
+            disk: (meta.uid(), meta.gid()),
+        });
+    }
+    if let Some(mod_time) = ent.mod_time() {
+        if ent.entry_type != "dir" && mod_time.timestamp() != meta.mtime() {
+            diff(Difference::ModTime {
+                layer: mod_time.timestamp(),
+                disk: meta.mtime(),
+            });
+        }
+    }
+    match ent.entry_type.as_str() {
+        "reg" if ent.size != meta.len() => diff(Difference::Size {
+            layer: ent.size,
+            disk: meta.len(),
+        }),
+        "reg" if layer_digest(reader, ent)? != file_digest(path)? => diff(Difference::Content),
+        "symlink" => {
+            let target = fs::read_link(path)?;
+            let target = target.to_string_lossy();
+            if target != ent.link_name {
+                diff(Difference::LinkTarget {
+                    layer: ent.link_name.clone(),
+                    disk: target.into_owned(),
+                });
+            }
+        }
+        "char" | "block" => {
+            let disk = (
+                libc::major(meta.rdev()) as u64,
+                libc::minor(meta.rdev()) as u64,
+            );
+            if (ent.dev_major, ent.dev_minor) != disk {
+                diff(Difference::Device {
+                    layer: (ent.dev_major, ent.dev_minor),
+                    disk,
+                });
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Collect the metadata of everything under `dir`, keyed by path relative to
+// the root of the walk
+fn walk(dir: &Path, prefix: &str, out: &mut BTreeMap<String, fs::Metadata>) -> Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            walk(&entry.path(), &format!("{name}/"), out)?;
+        }
+        out.insert(name, meta);
+    }
+    Ok(())
+}
+
+pub(crate) fn entry_type(meta: &fs::Metadata) -> &'static str {
+    let ft = meta.file_type();
+    if ft.is_dir() {
+        "dir"
+    } else if ft.is_symlink() {
+        "symlink"
+    } else if ft.is_char_device() {
+        "char"
+    } else if ft.is_block_device() {
+        "block"
+    } else if ft.is_fifo() {
+        "fifo"
+    } else {
+        "reg"
+    }
+}
+
+// sha256 of a file's content in the layer, hex only
+fn layer_digest(reader: &GzReader, ent: &TocEntry) -> Result<String> {
+    if let Some(digest) = ent.digest.strip_prefix("sha256:") {
+        return Ok(digest.to_string());
+    }
+    let mut hasher = Sha256::new();
+    reader.copy_file_to(&ent.name, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn file_digest(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
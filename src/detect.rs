@@ -0,0 +1,74 @@
+//! Telling stargz blobs apart from other layer formats.
+
+use std::io;
+
+use crate::{find_footer, FooterFormat, ReadAt};
+
+// Enough for the tar magic of an uncompressed layer
+const HEAD_SIZE: usize = 512;
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+// Last bytes of the zstd:chunked manifest footer
+const ZSTD_CHUNKED_MAGIC: &[u8] = b"GNUlInUx";
+
+/// What a blob looks like from its first and last bytes, see
+/// [`detect_format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobFormat {
+    /// Gzip ending with the original 47-byte stargz footer.
+    Stargz,
+    /// Gzip ending with the 51-byte eStargz footer.
+    Estargz,
+    /// Zstd ending with a zstd:chunked manifest footer.
+    ZstdChunked,
+    /// Gzip without a stargz footer, e.g. a plain tar.gz layer.
+    Gzip,
+    /// Zstd without a zstd:chunked footer.
+    Zstd,
+    /// An uncompressed tar.
+    Tar,
+    Unknown,
+}
+
+/// Find out what `blob` is by reading its first 512 and last 64 bytes,
+/// without decompressing or parsing anything else. Only fails on I/O
+/// errors; garbage is [`BlobFormat::Unknown`].
+pub fn detect_format(blob: &(impl ReadAt + ?Sized)) -> io::Result<BlobFormat> {
+    let size = blob.size()?;
+    let mut head = vec![0; size.min(HEAD_SIZE as u64) as usize];
+    blob.read_exact_at(&mut head, 0)?;
+    let tail_len = size.min(64) as usize;
+    let mut tail = vec![0; tail_len];
+    blob.read_exact_at(&mut tail, size - tail_len as u64)?;
+
+    let format = if head.starts_with(GZIP_MAGIC) {
+        match find_footer(&tail) {
+            Ok((toc_offset, format)) if toc_offset <= size - format.size() => match format {
+                FooterFormat::Estargz => BlobFormat::Estargz,
+                _ => BlobFormat::Stargz,
+            },
+            _ => BlobFormat::Gzip,
+        }
+    } else if head.starts_with(ZSTD_MAGIC) {
+        if tail.ends_with(ZSTD_CHUNKED_MAGIC) {
+            BlobFormat::ZstdChunked
+        } else {
+            BlobFormat::Zstd
+        }
+    } else if head.get(257..262) == Some(b"ustar") {
+        BlobFormat::Tar
+    } else {
+        BlobFormat::Unknown
+    };
+
+    Ok(format)
+}
+
+/// Whether `blob` ends with a stargz or eStargz footer, i.e. it has a TOC
+/// to be opened with. I/O errors count as no.
+pub fn is_stargz(blob: &(impl ReadAt + ?Sized)) -> bool {
+    matches!(
+        detect_format(blob),
+        Ok(BlobFormat::Stargz | BlobFormat::Estargz)
+    )
+}
@@ -0,0 +1,262 @@
+//! Reading a directory tree from disk to build a layer from, see
+//! [`crate::Writer::append_dir_all`].
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs, io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
+
+use crate::{clean_entry_name, filter::matches_any};
+
+/// How [`Writer::append_dir_all_with`](crate::Writer::append_dir_all_with)
+/// walks a directory: what to leave out of the layer.
+///
+/// Ignore rules work like those of a `.dockerignore` file. They are globs
+/// matched against paths relative to the root, `*` staying within a path
+/// component and `**` crossing them, and a rule matching a directory also
+/// matches everything below it. A rule starting with `!` brings back what
+/// earlier rules left out, the last rule matching a path deciding. An
+/// ignored directory is still walked when such an exception could apply
+/// below it, then only its own entry is left out.
+#[derive(Debug, Clone, Default)]
+pub struct DirOptions {
+    // Patterns in order, true for exceptions
+    ignore: Vec<(Pattern, bool)>,
+    symlinks: Symlinks,
+}
+
+/// What to do with the symlinks found walking a directory, see
+/// [`DirOptions::symlinks`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Add them as symlinks, whatever they point at.
+    #[default]
+    Keep,
+    /// Add what they point at in their place, walking into linked
+    /// directories. Links leading outside of the directory walked, back to
+    /// a directory they're in, or nowhere, fail the walk.
+    Follow,
+    /// Fail the walk on any symlink not ignored.
+    Forbid,
+}
+
+impl DirOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How to handle symlinks, kept as they are by default.
+    pub fn symlinks(mut self, symlinks: Symlinks) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Leave out what `pattern` matches, or bring it back with a leading
+    /// `!`.
+    pub fn ignore(mut self, pattern: &str) -> Result<Self> {
+        let (pattern, exception) = match pattern.trim().strip_prefix('!') {
+            Some(pattern) => (pattern.trim(), true),
+            None => (pattern.trim(), false),
+        };
+        let pattern = Pattern::new(&clean_entry_name(pattern))
+            .with_context(|| format!("invalid ignore pattern {pattern:?}"))?;
+        self.ignore.push((pattern, exception));
+        Ok(self)
+    }
+
+    /// Add the rules of the `.dockerignore` style file at `path`, one per
+    /// line, skipping blank lines and those starting with `#`.
+    pub fn ignore_file(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let rules =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        for rule in rules.lines().map(str::trim) {
+            if !rule.is_empty() && !rule.starts_with('#') {
+                self = self
+                    .ignore(rule)
+                    .with_context(|| format!("in {}", path.display()))?;
+            }
+        }
+        Ok(self)
+    }
+
+    fn ignored(&self, name: &str) -> bool {
+        self.ignore
+            .iter()
+            .rev()
+            .find(|(pattern, _)| matches_any(std::slice::from_ref(pattern), name))
+            .is_some_and(|&(_, exception)| !exception)
+    }
+
+    // Whether an exception could bring back something under the directory
+    // `dir`, going by the part of the pattern before any wildcard
+    fn may_bring_back(&self, dir: &str) -> bool {
+        let dir = format!("{dir}/");
+        self.ignore
+            .iter()
+            .filter(|(_, exception)| *exception)
+            .any(|(pattern, _)| {
+                let pattern = pattern.as_str();
+                let literal = &pattern[..pattern.find(['*', '?', '[']).unwrap_or(pattern.len())];
+                literal.starts_with(&dir) || dir.starts_with(literal)
+            })
+    }
+}
+
+/// A file found under the root of the walk.
+pub(crate) struct DiskEntry {
+    /// Path relative to the root, `/` separated.
+    pub name: String,
+    pub path: PathBuf,
+    pub meta: fs::Metadata,
+}
+
+/// Everything under `dir` not ignored by `opts`, the root itself excluded,
+/// parents before their children and siblings sorted by name so the same
+/// tree always gives the same order. Symlinks are handled as `opts` says,
+/// a followed one being listed with the path and metadata of its target.
+pub(crate) fn walk(dir: &Path, opts: &DirOptions) -> Result<Vec<DiskEntry>> {
+    let root = fs::canonicalize(dir).with_context(|| format!("reading {}", dir.display()))?;
+    let meta = fs::metadata(&root).with_context(|| format!("reading {}", dir.display()))?;
+    let mut walker = Walker {
+        opts,
+        root,
+        dirs: vec![(meta.dev(), meta.ino())],
+        out: Vec::new(),
+    };
+    walker.walk_into(dir, "")?;
+    Ok(walker.out)
+}
+
+struct Walker<'o> {
+    opts: &'o DirOptions,
+    // The root of the walk with symlinks resolved, and the directories the
+    // walk is in, to keep followed symlinks inside of it
+    root: PathBuf,
+    dirs: Vec<(u64, u64)>,
+    out: Vec<DiskEntry>,
+}
+
+impl Walker<'_> {
+    fn walk_into(&mut self, dir: &Path, prefix: &str) -> Result<()> {
+        let mut entries = fs::read_dir(dir)
+            .with_context(|| format!("reading {}", dir.display()))?
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+            let ignored = self.opts.ignored(&name);
+            if ignored && !self.opts.may_bring_back(&name) {
+                continue;
+            }
+            let path = entry.path();
+            let meta = fs::symlink_metadata(&path)
+                .with_context(|| format!("reading {}", path.display()))?;
+            let (path, meta) = match self.opts.symlinks {
+                _ if !meta.is_symlink() => (path, meta),
+                Symlinks::Keep => (path, meta),
+                Symlinks::Forbid => {
+                    return Err(anyhow!(
+                        "{} is a symlink, which aren't allowed",
+                        path.display()
+                    ))
+                }
+                Symlinks::Follow => self.follow(&path)?,
+            };
+            let is_dir = meta.is_dir();
+            if ignored && !is_dir {
+                continue;
+            }
+            let id = (meta.dev(), meta.ino());
+            if !ignored {
+                self.out.push(DiskEntry {
+                    name: name.clone(),
+                    path: path.clone(),
+                    meta,
+                });
+            }
+            if is_dir {
+                if self.dirs.contains(&id) {
+                    return Err(anyhow!(
+                        "{} leads back to a directory it's in",
+                        entry.path().display()
+                    ));
+                }
+                self.dirs.push(id);
+                self.walk_into(&path, &format!("{name}/"))?;
+                self.dirs.pop();
+            }
+        }
+        Ok(())
+    }
+
+    // The target of the symlink `path`, and its metadata
+    fn follow(&self, path: &Path) -> Result<(PathBuf, fs::Metadata)> {
+        let target =
+            fs::canonicalize(path).with_context(|| format!("following {}", path.display()))?;
+        if !target.starts_with(&self.root) {
+            return Err(anyhow!(
+                "{} leads to {}, outside of {}",
+                path.display(),
+                target.display(),
+                self.root.display()
+            ));
+        }
+        let meta =
+            fs::metadata(&target).with_context(|| format!("reading {}", target.display()))?;
+        Ok((target, meta))
+    }
+}
+
+/// The extended attributes of `path`, of the symlink itself for symlinks.
+/// Empty on filesystems without xattr support.
+pub(crate) fn xattrs(path: &Path) -> io::Result<HashMap<String, Vec<u8>>> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let mut out = HashMap::new();
+
+    let names = read_sized(|buf, len| unsafe { libc::llistxattr(cpath.as_ptr(), buf.cast(), len) });
+    let names = match names {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(out),
+        r => r?,
+    };
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let cname = CString::new(name)?;
+        let value = read_sized(|buf, len| unsafe {
+            libc::lgetxattr(cpath.as_ptr(), cname.as_ptr(), buf.cast(), len)
+        });
+        let value = match value {
+            // Removed since it was listed
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => continue,
+            r => r?,
+        };
+        out.insert(String::from_utf8_lossy(name).into_owned(), value);
+    }
+    Ok(out)
+}
+
+// Call `get` for the size of the value first, then again to read it,
+// retrying if it grew in between
+fn read_sized(get: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let len = get(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        let n = get(buf.as_mut_ptr(), buf.len());
+        if n >= 0 {
+            buf.truncate(n as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
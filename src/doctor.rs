@@ -0,0 +1,457 @@
+//! Diagnostics for blobs that fail to open or read back wrong.
+//!
+//! [`diagnose`] runs the same steps as [`crate::open`] one at a time and keeps
+//! going past the first problem where it can, so the report says which layer
+//! of the blob is broken rather than just that opening it failed.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::prelude::{FileExt, MetadataExt},
+};
+
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use tar::Archive;
+
+use crate::{
+    find_footer, read_footer_tail, Footer, FooterFormat, GzReader, JToc, MemberIter, ParseMode,
+    ReaderOptions, SectionReader, FOOTER_SIZE, NO_PREFETCH_LANDMARK, PREFETCH_LANDMARK,
+    TOCT_TAR_NAME,
+};
+
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 0x08];
+// Per check, the rest is only counted
+const MAX_REPORTED: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warn",
+            Severity::Error => "FAIL",
+        })
+    }
+}
+
+/// One observation about the blob.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    /// Which check produced it: `footer`, `toc`, `index`, `alignment`,
+    /// `landmarks` or `digests`.
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Everything [`diagnose`] found, in the order the checks ran.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnosis {
+    pub findings: Vec<Finding>,
+    /// Best guess at the tool that wrote the blob, from the footer flavour
+    /// and the TOC fields it uses.
+    pub producer: Option<String>,
+}
+
+impl Diagnosis {
+    /// Whether no check failed. Warnings don't count, the blob opens in
+    /// [`ParseMode::Permissive`].
+    pub fn is_healthy(&self) -> bool {
+        self.findings.iter().all(|f| f.severity != Severity::Error)
+    }
+
+    fn push(&mut self, check: &'static str, severity: Severity, message: impl Into<String>) {
+        self.findings.push(Finding {
+            check,
+            severity,
+            message: message.into(),
+        });
+    }
+
+    fn ok(&mut self, check: &'static str, message: impl Into<String>) {
+        self.push(check, Severity::Ok, message);
+    }
+
+    fn fail(&mut self, check: &'static str, message: impl Into<String>) {
+        self.push(check, Severity::Error, message);
+    }
+
+    // Report the first few of a list of problems and count the rest
+    fn push_all(&mut self, check: &'static str, severity: Severity, problems: Vec<String>) {
+        let total = problems.len();
+        for problem in problems.into_iter().take(MAX_REPORTED) {
+            self.push(check, severity, problem);
+        }
+        if total > MAX_REPORTED {
+            self.push(
+                check,
+                severity,
+                format!("... and {} more", total - MAX_REPORTED),
+            );
+        }
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in &self.findings {
+            writeln!(
+                f,
+                "[{:>4}] {}: {}",
+                finding.severity, finding.check, finding.message
+            )?;
+        }
+        if let Some(producer) = &self.producer {
+            writeln!(f, "likely producer: {producer}")?;
+        }
+        if self.is_healthy() {
+            write!(f, "blob looks healthy")
+        } else {
+            write!(f, "blob is broken")
+        }
+    }
+}
+
+/// Check `blob` layer by layer: footer, TOC member, TOC JSON, the index built
+/// from it, entry offsets, landmarks and, last, every regular file's content
+/// against its digest.
+///
+/// Problems with the blob end up in the returned [`Diagnosis`]; an `Err` only
+/// means the blob couldn't be read at all.
+pub fn diagnose(blob: &File) -> Result<Diagnosis> {
+    let mut diag = Diagnosis::default();
+    let size = blob.metadata()?.size();
+
+    // Footer
+    if size < u64::from(FOOTER_SIZE) {
+        diag.fail(
+            "footer",
+            format!("blob is {size} bytes, too small for a footer"),
+        );
+        return Ok(diag);
+    }
+    let tail = read_footer_tail(blob)?;
+    let (toc_offset, format) = match find_footer(&tail) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            diag.fail(
+                "footer",
+                format!("blob doesn't end with a stargz or eStargz footer: {e}"),
+            );
+            return Ok(diag);
+        }
+    };
+    let toc_end = size - format.size();
+    if toc_offset >= toc_end {
+        diag.fail(
+            "footer",
+            format!("TOC offset {toc_offset} is outside of the {size} byte blob"),
+        );
+        return Ok(diag);
+    }
+    let flavor = match format {
+        FooterFormat::Estargz => "eStargz",
+        _ => "legacy stargz",
+    };
+    diag.ok(
+        "footer",
+        format!("{flavor} footer, TOC at offset {toc_offset}"),
+    );
+
+    // TOC member
+    let mut magic = [0; 3];
+    blob.read_exact_at(&mut magic, toc_offset)?;
+    if magic != GZIP_MAGIC {
+        diag.fail(
+            "toc",
+            format!("no gzip member at TOC offset {toc_offset}, found {magic:02x?}"),
+        );
+        return Ok(diag);
+    }
+    let raw = match read_toc_json(blob, toc_offset, toc_end - toc_offset) {
+        Ok(raw) => raw,
+        Err(e) => {
+            diag.fail("toc", e.to_string());
+            return Ok(diag);
+        }
+    };
+    let toc: JToc = match serde_json::from_slice(&raw) {
+        Ok(toc) => toc,
+        Err(e) => {
+            diag.fail("toc", format!("{TOCT_TAR_NAME} isn't a valid TOC: {e}"));
+            return Ok(diag);
+        }
+    };
+    diag.ok(
+        "toc",
+        format!(
+            "version {}, {} entries, {} bytes compressed",
+            toc.version,
+            toc.entries.len(),
+            toc_end - toc_offset
+        ),
+    );
+
+    let has_landmark = toc
+        .entries
+        .iter()
+        .any(|e| e.name == PREFETCH_LANDMARK || e.name == NO_PREFETCH_LANDMARK);
+    diag.producer = Some(guess_producer(&raw, has_landmark).to_string());
+
+    // Index
+    let opts = ReaderOptions::new()
+        .parse_mode(ParseMode::Permissive)
+        .verify_crc(true);
+    let footer = Footer {
+        blob_size: size,
+        toc_offset,
+        format: FooterFormat::Stargz,
+    };
+    let reader = match GzReader::from_toc(Box::new(blob.try_clone()?), toc, footer, opts) {
+        Ok(reader) => reader,
+        Err(e) => {
+            diag.fail("index", format!("TOC can't be indexed: {e:#}"));
+            return Ok(diag);
+        }
+    };
+    if reader.warnings().is_empty() {
+        diag.ok("index", "TOC follows the spec");
+    } else {
+        let warnings = reader
+            .warnings()
+            .iter()
+            .map(|w| format!("{w} (rejected in strict mode)"))
+            .collect();
+        diag.push_all("index", Severity::Warning, warnings);
+    }
+
+    check_alignment(&mut diag, &reader, toc_offset)?;
+
+    if has_landmark {
+        diag.ok("landmarks", "prefetch landmark present");
+    } else {
+        diag.ok(
+            "landmarks",
+            "no prefetch landmark, nothing will be prefetched",
+        );
+    }
+
+    check_digests(&mut diag, &reader);
+
+    Ok(diag)
+}
+
+// The TOC JSON, kept raw so the producer can be guessed from its spelling
+fn read_toc_json(blob: &File, toc_offset: u64, toc_size: u64) -> Result<Vec<u8>> {
+    let section = SectionReader::new(blob, toc_offset, toc_size);
+    let mut archive = Archive::new(GzDecoder::new(section));
+    let mut entry = archive
+        .entries()?
+        .next()
+        .ok_or_else(|| anyhow!("TOC member holds an empty tar"))?
+        .map_err(|e| anyhow!("TOC member isn't a gzipped tar: {e}"))?;
+    let name = entry.path()?.to_string_lossy().into_owned();
+    if name != TOCT_TAR_NAME {
+        return Err(anyhow!(
+            "TOC member holds {name:?} instead of {TOCT_TAR_NAME}"
+        ));
+    }
+    let mut raw = Vec::new();
+    entry
+        .read_to_end(&mut raw)
+        .map_err(|e| anyhow!("TOC member is truncated: {e}"))?;
+    Ok(raw)
+}
+
+fn guess_producer(raw: &[u8], has_landmark: bool) -> &'static str {
+    let uses = |key: &str| raw.windows(key.len()).any(|w| w == key.as_bytes());
+    if uses("\"chunkDigest\"") || has_landmark {
+        "eStargz writer (containerd/stargz-snapshotter)"
+    } else if uses("\"uname\"") || uses("\"mod_time_3339\"") {
+        "stargz-rs writer, before the field names followed the spec"
+    } else {
+        "stargz writer (google/crfs)"
+    }
+}
+
+// Every data entry must start a gzip member of its own, before the TOC, in
+// TOC order
+fn check_alignment(diag: &mut Diagnosis, reader: &GzReader, toc_offset: u64) -> io::Result<()> {
+    let mut problems = Vec::new();
+    let mut checked = 0;
+    let mut last = 0;
+    for ent in reader.toc.entries.iter().filter(|e| e.offset != 0) {
+        checked += 1;
+        if ent.offset >= toc_offset {
+            problems.push(format!(
+                "{} starts at {}, past the TOC at {toc_offset}",
+                ent.name, ent.offset
+            ));
+            continue;
+        }
+        if ent.offset < last {
+            problems.push(format!(
+                "{} starts at {}, before the previous entry at {last}",
+                ent.name, ent.offset
+            ));
+        }
+        last = ent.offset;
+        let mut magic = [0; 3];
+        reader.sr.read_exact_at(&mut magic, ent.offset)?;
+        if magic != GZIP_MAGIC {
+            problems.push(format!(
+                "{} offset {} isn't the start of a gzip member",
+                ent.name, ent.offset
+            ));
+        }
+    }
+    if problems.is_empty() {
+        diag.ok(
+            "alignment",
+            format!("{checked} data offsets start gzip members"),
+        );
+    } else {
+        diag.push_all("alignment", Severity::Error, problems);
+    }
+    Ok(())
+}
+
+fn check_digests(diag: &mut Diagnosis, reader: &GzReader) {
+    let report = reader.verify();
+    let summary = format!(
+        "{} files match their digests, {} without any",
+        report.verified, report.skipped
+    );
+    if report.is_ok() {
+        diag.ok("digests", summary);
+    } else {
+        let failures = report.failures.iter().map(|c| c.to_string()).collect();
+        diag.push_all("digests", Severity::Error, failures);
+        diag.fail("digests", summary);
+    }
+}
+
+/// Print the raw footer fields of `blob`, every gzip member up to the footer
+/// and which TOC entries point at each, for comparing the layout against
+/// other writers'.
+pub fn dump(blob: &File, out: &mut impl Write) -> Result<()> {
+    let size = blob.metadata()?.size();
+    if size < u64::from(FOOTER_SIZE) {
+        return Err(anyhow!("blob is {size} bytes, smaller than a footer"));
+    }
+    let tail = read_footer_tail(blob)?;
+    let found = find_footer(&tail);
+    // Shown as the older flavor when it's neither
+    let footer_size = found
+        .as_ref()
+        .map_or(u64::from(FOOTER_SIZE), |(_, format)| format.size());
+    let toc_end = size - footer_size;
+    let footer = &tail[tail.len() - footer_size as usize..];
+
+    // Fixed gzip header fields, then the FEXTRA field the TOC offset lives in
+    writeln!(out, "footer at {toc_end}, {footer_size} bytes")?;
+    writeln!(
+        out,
+        "  magic {:02x}{:02x} method {} flags {:#04x} mtime {} xfl {} os {}",
+        footer[0],
+        footer[1],
+        footer[2],
+        footer[3],
+        u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]),
+        footer[8],
+        footer[9]
+    )?;
+    let xlen = usize::from(u16::from_le_bytes([footer[10], footer[11]]));
+    let extra = &footer[12..(12 + xlen).min(footer.len())];
+    writeln!(
+        out,
+        "  extra ({xlen} bytes): {:?}",
+        String::from_utf8_lossy(extra)
+    )?;
+    let toc_offset = match found {
+        Ok((offset, format)) => {
+            writeln!(out, "  {format:?} footer, TOC offset {offset}")?;
+            Some(offset)
+        }
+        Err(e) => {
+            writeln!(out, "  not a stargz footer: {e}")?;
+            None
+        }
+    };
+
+    // What the TOC says starts at each offset, if it can be read
+    let mut referenced: HashMap<u64, Vec<String>> = HashMap::new();
+    if let Some(toc_offset) = toc_offset.filter(|&o| o < toc_end) {
+        referenced
+            .entry(toc_offset)
+            .or_default()
+            .push(TOCT_TAR_NAME.to_string());
+        let toc = read_toc_json(blob, toc_offset, toc_end - toc_offset)
+            .and_then(|raw| Ok(serde_json::from_slice::<JToc>(&raw)?));
+        match toc {
+            Ok(toc) => {
+                for ent in toc.entries.iter().filter(|e| e.offset != 0) {
+                    let desc = match ent.entry_type.as_str() {
+                        "chunk" => format!("{} (chunk at {})", ent.name, ent.chunk_offset),
+                        _ => ent.name.clone(),
+                    };
+                    referenced.entry(ent.offset).or_default().push(desc);
+                }
+            }
+            Err(e) => writeln!(out, "TOC unreadable, offsets not mapped: {e:#}")?,
+        }
+    }
+
+    writeln!(out, "gzip members")?;
+    writeln!(
+        out,
+        "  {:>12} {:>12} {:>12}  TOC",
+        "offset", "compressed", "uncompressed"
+    )?;
+    let mut count = 0;
+    for m in MemberIter::new(blob, 0, toc_end) {
+        let m = match m {
+            Ok(m) => m,
+            Err(e) => {
+                writeln!(out, "member scan stopped: {e:#}")?;
+                return Ok(());
+            }
+        };
+        count += 1;
+        let names = referenced.remove(&m.offset).unwrap_or_default();
+        writeln!(
+            out,
+            "  {:>12} {:>12} {:>12}  {}",
+            m.offset,
+            m.compressed_size,
+            m.uncompressed_size,
+            if names.is_empty() {
+                "-".to_string()
+            } else {
+                names.join(", ")
+            }
+        )?;
+    }
+    writeln!(out, "{count} gzip members")?;
+
+    // TOC offsets that fall inside a member rather than at its start
+    let mut misaligned: Vec<_> = referenced.into_iter().collect();
+    misaligned.sort();
+    for (offset, names) in misaligned {
+        writeln!(
+            out,
+            "offset {offset} ({}) is not a member boundary",
+            names.join(", ")
+        )?;
+    }
+    Ok(())
+}
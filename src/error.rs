@@ -0,0 +1,103 @@
+use std::{fmt, io};
+
+/// Broad class of a failure, for callers that have to decide whether to retry
+/// and which errno to hand back (FUSE, daemons serving blobs remotely).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The operation may succeed if tried again: interrupted or timed out
+    /// I/O, a dropped connection.
+    Transient,
+    /// The blob, or an entry in it, doesn't exist.
+    NotFound,
+    /// Access to the blob was refused.
+    AuthFailed,
+    /// The blob is malformed: bad footer, unparsable TOC, data not where the
+    /// TOC says. Retrying won't help.
+    Corrupt,
+    /// The operation was abandoned on request.
+    Cancelled,
+}
+
+impl ErrorKind {
+    pub fn is_retryable(self) -> bool {
+        self == ErrorKind::Transient
+    }
+
+    /// The errno a filesystem layer should report for this kind.
+    pub fn errno(self) -> i32 {
+        match self {
+            ErrorKind::Transient => libc::EAGAIN,
+            ErrorKind::NotFound => libc::ENOENT,
+            ErrorKind::AuthFailed => libc::EACCES,
+            ErrorKind::Corrupt => libc::EIO,
+            ErrorKind::Cancelled => libc::EINTR,
+        }
+    }
+
+    /// Classify an error returned by this crate.
+    ///
+    /// Errors raised here carry an [`Error`] somewhere in their chain, plain
+    /// I/O errors are classified by their [`io::ErrorKind`]. Anything else
+    /// (e.g. invalid arguments) has no kind and shouldn't be retried.
+    pub fn of(err: &anyhow::Error) -> Option<ErrorKind> {
+        // Errors attached as context only downcast through anyhow itself
+        if let Some(e) = err.downcast_ref::<Error>() {
+            return Some(e.kind);
+        }
+        err.chain().find_map(|cause| {
+            if let Some(e) = cause.downcast_ref::<Error>() {
+                Some(e.kind)
+            } else {
+                cause.downcast_ref::<io::Error>().and_then(from_io)
+            }
+        })
+    }
+}
+
+fn from_io(err: &io::Error) -> Option<ErrorKind> {
+    match err.kind() {
+        io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe => Some(ErrorKind::Transient),
+        io::ErrorKind::NotFound => Some(ErrorKind::NotFound),
+        io::ErrorKind::PermissionDenied => Some(ErrorKind::AuthFailed),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => Some(ErrorKind::Corrupt),
+        _ => None,
+    }
+}
+
+/// An error tagged with its [`ErrorKind`], carried inside the
+/// [`anyhow::Error`]s the crate returns.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Error {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn corrupt(message: impl Into<String>) -> Self {
+        Error::new(ErrorKind::Corrupt, message)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,48 @@
+use crate::ReadAt;
+
+// 32-bit glibc has a 32-bit off_t, its 64-bit calls reach past 2 GiB
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+use libc::{off64_t as off_t, posix_fadvise64 as posix_fadvise};
+#[cfg(all(
+    any(target_os = "linux", target_os = "android", target_os = "freebsd"),
+    not(all(target_os = "linux", target_env = "gnu"))
+))]
+use libc::{off_t, posix_fadvise};
+
+/// Page-cache hints passed to `posix_fadvise(2)` for ranges of the blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// The range will be read soon, start readahead now.
+    WillNeed,
+    /// The range will be read front to back.
+    Sequential,
+    /// The range won't be read again, its pages can be dropped.
+    DontNeed,
+}
+
+/// Issue an advisory hint for `len` bytes at `offset` of `blob`. A `len` of
+/// zero means "until the end of the file", like the syscall itself.
+///
+/// Hints are best effort: failures are ignored since the kernel is free to
+/// disregard them anyway, and sources that aren't files as well as
+/// platforms without `posix_fadvise` are a no-op.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn advise<R: ReadAt + ?Sized>(blob: &R, offset: u64, len: u64, advice: Advice) {
+    let Some(fd) = blob.as_raw_fd() else {
+        return;
+    };
+    let advice = match advice {
+        Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    let (Ok(offset), Ok(len)) = (off_t::try_from(offset), off_t::try_from(len)) else {
+        return;
+    };
+    unsafe {
+        posix_fadvise(fd, offset, len, advice);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn advise<R: ReadAt + ?Sized>(_blob: &R, _offset: u64, _len: u64, _advice: Advice) {}
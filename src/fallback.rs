@@ -0,0 +1,50 @@
+//! Opening plain tar.gz layers, which have no TOC, by building one.
+
+use std::io::{self, BufReader, BufWriter};
+
+use anyhow::{Context, Result};
+use flate2::{read::MultiGzDecoder, Compression};
+use tar::Archive;
+
+use crate::{
+    gz_member_encoder, repair::toc_entry, spool_file, CountingWriter, DigestReader, Footer,
+    FooterFormat, GzReader, JToc, ReadAt, ReaderOptions, SectionReader,
+};
+
+// Read the gzipped tar in `input` front to back and index it like a stargz
+// blob. The data of every regular file is recompressed into a gzip member
+// of its own in a blob the TOC points into, so a read only decompresses the
+// file it's after. That blob is spooled to an unnamed temporary file, as
+// layers can be much larger than memory.
+pub(crate) fn open_tar_gz(input: &dyn ReadAt, opts: ReaderOptions) -> Result<GzReader> {
+    let size = input.size()?;
+    let spool = spool_file().context("creating a spool file")?;
+    let mut blob = CountingWriter::new(BufWriter::new(spool));
+    // A data offset of 0 means none in the TOC, start with an empty member
+    gz_member_encoder(&mut blob, Compression::fast()).finish()?;
+    let mut toc = JToc::new(1);
+    let mut archive = Archive::new(MultiGzDecoder::new(BufReader::new(SectionReader::new(
+        input, 0, size,
+    ))));
+    for entry in archive.entries().context("reading tar.gz")? {
+        let mut entry = entry.context("reading tar.gz")?;
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let mut ent = toc_entry(&mut entry, &name).with_context(|| format!("indexing {name}"))?;
+        if ent.entry_type == "reg" && ent.size > 0 {
+            ent.offset = blob.position();
+            let mut content = DigestReader::new(&mut entry);
+            let mut gz = gz_member_encoder(&mut blob, Compression::fast());
+            io::copy(&mut content, &mut gz).with_context(|| format!("reading {name}"))?;
+            gz.finish()?;
+            ent.digest = content.finish();
+        }
+        toc.entries.push(ent);
+    }
+
+    let footer = Footer {
+        blob_size: blob.position(),
+        toc_offset: blob.position(),
+        format: FooterFormat::Missing,
+    };
+    GzReader::from_toc(Box::new(blob.into_inner()?), toc, footer, opts)
+}
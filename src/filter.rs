@@ -0,0 +1,88 @@
+use anyhow::Result;
+use glob::{MatchOptions, Pattern};
+
+use crate::clean_entry_name;
+
+pub(crate) const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    // `*` stays within a path component, `**` crosses them
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+type EntryCallback<'f> = Box<dyn FnMut(&str, &tar::Header) -> bool + 'f>;
+
+/// Selects which entries of an input tar get appended, see
+/// [`Writer::append_tar_with_filter`](crate::Writer::append_tar_with_filter),
+/// or which entries of a layer get extracted, see
+/// [`GzReader::unpack_filtered`](crate::GzReader::unpack_filtered).
+///
+/// Patterns are globs matched against the cleaned entry path (no leading
+/// `/` or `./`). A pattern matching a directory also matches everything
+/// below it, so excluding `var/cache` drops the whole subtree like
+/// `tar --exclude` would. Excludes win over includes, and no includes means
+/// everything is included.
+///
+/// Paths that aren't valid UTF-8 are matched with the invalid bytes
+/// replaced by U+FFFD, the raw ones being in the header. Such entries fail
+/// the append unless they're dropped: the TOC can't hold their path.
+#[derive(Default)]
+pub struct EntryFilter<'f> {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    callback: Option<EntryCallback<'f>>,
+}
+
+impl<'f> EntryFilter<'f> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, pattern: &str) -> Result<Self> {
+        self.include.push(Pattern::new(pattern.trim_matches('/'))?);
+        Ok(self)
+    }
+
+    pub fn exclude(mut self, pattern: &str) -> Result<Self> {
+        self.exclude.push(Pattern::new(pattern.trim_matches('/'))?);
+        Ok(self)
+    }
+
+    /// Call `f` with the cleaned path and header of every entry passing the
+    /// patterns; entries it returns false for are dropped.
+    pub fn filter(mut self, f: impl FnMut(&str, &tar::Header) -> bool + 'f) -> Self {
+        self.callback = Some(Box::new(f));
+        self
+    }
+
+    pub(crate) fn accepts(&mut self, path: &str, header: &tar::Header) -> bool {
+        let path = clean_entry_name(path);
+        if !self.include.is_empty() && !matches_any(&self.include, &path) {
+            return false;
+        }
+        if matches_any(&self.exclude, &path) {
+            return false;
+        }
+        match self.callback.as_mut() {
+            Some(f) => f(&path, header),
+            None => true,
+        }
+    }
+}
+
+// Whether any pattern matches `path` or one of its parent directories
+pub(crate) fn matches_any(patterns: &[Pattern], path: &str) -> bool {
+    let mut prefix = path;
+    loop {
+        if patterns
+            .iter()
+            .any(|p| p.matches_with(prefix, MATCH_OPTIONS))
+        {
+            return true;
+        }
+        match prefix.rfind('/') {
+            Some(i) => prefix = &prefix[..i],
+            None => return false,
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Extension headers the [`crate::Writer`] puts in front of the header of an
+//! entry, for what the header itself can't hold.
+
+use anyhow::Result;
+use tar::{EntryType, Header};
+
+use crate::TocEntry;
+
+// PAX records describing fields the Writer sets in the header itself, so
+// copying them from the input could contradict it
+const HEADER_RECORDS: &[&str] = &["path", "linkpath", "size", "uid", "gid", "uname", "gname"];
+
+/// Whether the PAX record `key` of an input entry is carried over to the
+/// output as is.
+pub(crate) fn keeps_record(key: &str) -> bool {
+    // The sparse map is written in GNU sparse headers, xattrs from the
+    // possibly transformed ones
+    !HEADER_RECORDS.contains(&key)
+        && !key.starts_with("GNU.sparse.")
+        && !key.starts_with("SCHILY.xattr.")
+}
+
+/// A PAX extended header holding `records`, padded to whole blocks.
+pub(crate) fn pax_header(records: &[(String, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for (key, value) in records {
+        // The length counts its own digits
+        let rest = 1 + key.len() + 1 + value.len() + 1;
+        let mut len = rest + 1;
+        while len.to_string().len() + rest != len {
+            len = len.to_string().len() + rest;
+        }
+        data.extend_from_slice(format!("{len} {key}=").as_bytes());
+        data.extend_from_slice(value);
+        data.push(b'\n');
+    }
+
+    let mut h = Header::new_ustar();
+    h.set_path("././@PaxHeader")?;
+    h.set_size(data.len() as u64);
+    h.set_mode(0o644);
+    h.set_entry_type(EntryType::XHeader);
+    h.set_cksum();
+    let mut out = h.as_bytes().to_vec();
+    out.extend_from_slice(&data);
+    out.resize(out.len().next_multiple_of(512), 0);
+    Ok(out)
+}
+
+// Longest name or link target a header holds
+const NAME_FIELD_SIZE: usize = 100;
+
+/// Set the path of `h`, or when it's too long for the header a truncated
+/// one, returning the PAX record with the whole path to write before it.
+pub(crate) fn set_path(h: &mut Header, path: &str) -> Result<Option<(String, Vec<u8>)>> {
+    match h.set_path(path) {
+        Ok(()) => Ok(None),
+        Err(_) if path.len() > NAME_FIELD_SIZE => {
+            truncate_into(&mut h.as_old_mut().name, path);
+            Ok(Some(("path".to_string(), path.as_bytes().to_vec())))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Same as [`set_path`] for the link target.
+pub(crate) fn set_link_name(h: &mut Header, link: &str) -> Result<Option<(String, Vec<u8>)>> {
+    match h.set_link_name(link) {
+        Ok(()) => Ok(None),
+        Err(_) if link.len() > NAME_FIELD_SIZE => {
+            truncate_into(&mut h.as_old_mut().linkname, link);
+            Ok(Some(("linkpath".to_string(), link.as_bytes().to_vec())))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Readers that don't know PAX get the start of the name, cut at a character
+fn truncate_into(field: &mut [u8; NAME_FIELD_SIZE], name: &str) {
+    let mut end = NAME_FIELD_SIZE;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    field.fill(0);
+    field[..end].copy_from_slice(&name.as_bytes()[..end]);
+}
+
+/// The header of the entry `ent` stands for, as far as the TOC tells: what
+/// an [`EntryFilter`](crate::EntryFilter) callback gets when selecting
+/// entries of a layer rather than of an input tar. Names too long for the
+/// header are left out, filters get the path separately.
+pub(crate) fn toc_header(ent: &TocEntry) -> Header {
+    let mut h = Header::new_gnu();
+    h.set_entry_type(match ent.entry_type() {
+        "dir" => EntryType::Directory,
+        "symlink" => EntryType::Symlink,
+        "hardlink" => EntryType::Link,
+        "char" => EntryType::Char,
+        "block" => EntryType::Block,
+        "fifo" => EntryType::Fifo,
+        _ => EntryType::Regular,
+    });
+    let _ = h.set_path(ent.name());
+    let _ = h.set_link_name(ent.link_name());
+    h.set_size(ent.size());
+    h.set_mode(ent.mode());
+    h.set_uid(ent.uid().into());
+    h.set_gid(ent.gid().into());
+    let _ = h.set_username(ent.uname());
+    let _ = h.set_groupname(ent.gname());
+    h.set_mtime(ent.mod_time().map_or(0, |t| t.timestamp().max(0) as u64));
+    let _ = h.set_device_major(ent.dev_major() as u32);
+    let _ = h.set_device_minor(ent.dev_minor() as u32);
+    h.set_cksum();
+    h
+}
@@ -1,704 +1,4308 @@
+mod cache;
+mod compare;
+mod detect;
+mod dirtree;
+pub mod doctor;
+mod error;
+mod fadvise;
+mod fallback;
+mod filter;
+mod headers;
+mod members;
+mod plan;
+mod readat;
+pub mod repair;
 mod sectionreader;
-use anyhow::{anyhow, Ok, Result};
+mod sparse;
+mod stats;
+mod toccache;
+mod transform;
+mod unpack;
+mod verify;
+mod vfs;
+mod walk;
+mod watch;
+mod zstdchunked;
+use anyhow::{anyhow, Context, Ok, Result};
+use cache::ChunkCache;
 use chrono::{TimeZone, Utc};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use sectionreader::SectionReader;
-use serde::Deserialize;
+pub use compare::{compare_with_dir, Difference, Drift};
+pub use detect::{detect_format, is_stargz, BlobFormat};
+pub use dirtree::{DirOptions, Symlinks};
+pub use error::{Error, ErrorKind};
+use fadvise::Advice;
+pub use filter::EntryFilter;
+use flate2::{
+    read::{GzDecoder, MultiGzDecoder},
+    write::GzEncoder,
+    Compression, GzBuilder,
+};
+pub use members::{Member, MemberEntry, MemberIter};
+pub use plan::{ChunkInfo, FetchPlan, FetchRange};
+pub use readat::{ReadAt, SeekReader};
+pub use sectionreader::SectionReader;
+use serde::{Deserialize, Serialize};
+pub use stats::{DiskUsage, Ratio, SizeClass, WriterStats};
 use std::{
-    cell::RefCell,
-    collections::HashMap,
-    fs::{self, File},
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
     io::Read,
-    io::{self, BufReader, BufWriter, Write},
-    os::unix::prelude::{FileExt, MetadataExt, PermissionsExt},
+    io::{self, BufReader, BufWriter, Seek, Write},
+    os::unix::prelude::FileExt,
+    path::Path,
     rc::Rc,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
     vec,
 };
 use tar::Archive;
+pub use toccache::TocCache;
+pub use transform::{Chown, DropXattrs, EntryAttrs, RedactPaths, StripTimestamps, Transform};
+pub use unpack::{Ownership, UnpackOptions, UnpackReport, UnpackWarning, Warning};
+pub use verify::{Corruption, Problem, VerifyReport};
+pub use vfs::{Dir, Metadata, Vfs};
+pub use walk::Walk;
+pub use watch::DirWatcher;
+
+// Where a GzReader reads the blob from
+type Blob = Box<dyn ReadAt + Send + Sync>;
 
 static TOCT_TAR_NAME: &str = "stargz.index.json";
+// Entries eStargz writers put after the files to prefetch, or first when
+// there are none
+static PREFETCH_LANDMARK: &str = ".prefetch.landmark";
+static NO_PREFETCH_LANDMARK: &str = ".no.prefetch.landmark";
+// Name prefix of the OCI whiteout hiding a path of the lower layers, and
+// the name of the one hiding everything in its directory
+static WHITEOUT_PREFIX: &str = ".wh.";
+static OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+// The one byte of a landmark file
+const LANDMARK_CONTENTS: u8 = 0xf;
 const FOOTER_SIZE: u32 = 47;
+const ESTARGZ_FOOTER_SIZE: u32 = 51;
+// Subfield ID and length of the TOC offset in an eStargz footer extra field
+const ESTARGZ_SUBFIELD: &[u8] = b"SG\x16\x00";
+// Same limit as the kernel's MAXSYMLINKS
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// How strictly the TOC is checked against the eStargz spec.
+///
+/// Permissive is the default so that layers from producers known to deviate
+/// keep opening as they always have. It doesn't loosen what gets through:
+/// an absolute or `..` name is cleaned into a path inside the layer, never
+/// used as it is, whichever the mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Reject TOCs deviating from the spec.
+    Strict,
+    /// Tolerate the deviations some producers are known for (absolute or
+    /// `..` names, non RFC 3339 modtimes, unknown entry types, orphan chunks,
+    /// unknown TOC versions), normalizing them where possible and recording
+    /// a warning, see [`GzReader::warnings`].
+    #[default]
+    Permissive,
+}
+
+/// Footer flavor of a blob, see [`ArchiveInfo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FooterFormat {
+    /// The original stargz footer written by CRFS: an empty gzip member
+    /// whose extra field holds the TOC offset as 16 hex digits and `STARGZ`.
+    Stargz,
+    /// The eStargz footer: the same, in an `SG` subfield of the extra field.
+    Estargz,
+    /// No footer, or one that was ignored: the TOC came from elsewhere, see
+    /// [`open_with_external_toc`]. The data is taken to run to the end of
+    /// the blob.
+    External,
+    /// No footer: a plain tar.gz opened with
+    /// [`ReaderOptions::tar_gz_fallback`]. Offsets and sizes are those of
+    /// the temporary copy of its data.
+    Missing,
+}
+
+impl FooterFormat {
+    /// Size of the footer in bytes.
+    pub fn size(self) -> u64 {
+        match self {
+            FooterFormat::Stargz => FOOTER_SIZE.into(),
+            FooterFormat::Estargz => ESTARGZ_FOOTER_SIZE.into(),
+            FooterFormat::External | FooterFormat::Missing => 0,
+        }
+    }
+}
+
+// What the footer of a blob says about it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Footer {
+    pub(crate) blob_size: u64,
+    // Where the data members end
+    pub(crate) toc_offset: u64,
+    pub(crate) format: FooterFormat,
+}
+
+impl Footer {
+    // Compressed size of the TOC member, between the data and the footer
+    pub(crate) fn toc_size(&self) -> u64 {
+        self.blob_size - self.format.size() - self.toc_offset
+    }
+}
+
+/// Layout of an opened blob, see [`GzReader::info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveInfo {
+    pub footer: FooterFormat,
+    pub blob_size: u64,
+    /// Blob offset of the TOC's gzip member, where file data ends.
+    pub toc_offset: u64,
+    /// Compressed size of the TOC member.
+    pub toc_size: u64,
+    pub toc_version: u32,
+    /// Number of TOC entries, `chunk` entries included.
+    pub entries: usize,
+}
+
+/// A chunk read by a [`GzReader`], see [`ReaderOptions::on_read`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadEvent {
+    pub name: String,
+    /// Offset of the chunk in the file.
+    pub chunk_offset: u64,
+    pub chunk_size: u64,
+    /// Blob offset of the gzip member the chunk was read from.
+    pub blob_offset: u64,
+    /// Whether the chunk came from the chunk cache, without any blob read.
+    pub cached: bool,
+    pub elapsed: Duration,
+}
+
+type ReadHook = Arc<dyn Fn(&ReadEvent) + Send + Sync>;
+
+/// What [`GzReader::stat`] finds at a path.
+#[derive(Debug, Clone, Copy)]
+pub struct Stat<'a> {
+    /// The entry at the path, a hardlink when the path is one.
+    pub entry: &'a TocEntry,
+    /// The entry holding the content and attributes of the file: where the
+    /// hardlink leads, or `entry` itself for anything but a hardlink.
+    pub target: &'a TocEntry,
+}
+
+impl Stat<'_> {
+    /// Number of names of the file, hardlinks included, or 2 plus the
+    /// number of subdirectories for a directory, as `st_nlink`.
+    pub fn nlink(&self) -> u32 {
+        self.target.num_link
+    }
+
+    pub fn is_hardlink(&self) -> bool {
+        self.entry.entry_type == "hardlink"
+    }
+}
+
+/// Options controlling how a blob is opened, how paths are resolved and how
+/// file content is read. Also a builder: finish with
+/// [`ReaderOptions::open`] or one of its siblings.
+#[derive(Clone, Default)]
+pub struct ReaderOptions {
+    case_insensitive: bool,
+    parse_mode: ParseMode,
+    verify_crc: bool,
+    verify_digests: bool,
+    chunk_cache_size: usize,
+    on_read: Option<ReadHook>,
+    tar_gz_fallback: bool,
+    lazy_index: bool,
+}
+
+impl fmt::Debug for ReaderOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReaderOptions")
+            .field("case_insensitive", &self.case_insensitive)
+            .field("parse_mode", &self.parse_mode)
+            .field("verify_crc", &self.verify_crc)
+            .field("verify_digests", &self.verify_digests)
+            .field("chunk_cache_size", &self.chunk_cache_size)
+            .field("on_read", &self.on_read.is_some())
+            .field("tar_gz_fallback", &self.tar_gz_fallback)
+            .field("lazy_index", &self.lazy_index)
+            .finish()
+    }
+}
+
+impl ReaderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve paths ignoring case, for layers built on case-insensitive
+    /// filesystems. An exact match always wins; when several entries only
+    /// differ by case, the one sorting first byte-wise is picked and the
+    /// others are reported by [`GzReader::case_conflicts`].
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    pub fn parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.parse_mode = parse_mode;
+        self
+    }
+
+    /// Check the gzip CRC and ISIZE trailer of every member a chunk is read
+    /// from. Catches corruption in chunks the TOC has no digest for, at the
+    /// cost of decompressing each member to its end (the tar padding after
+    /// the chunk) on every read. Off by default.
+    pub fn verify_crc(mut self, verify_crc: bool) -> Self {
+        self.verify_crc = verify_crc;
+        self
+    }
+
+    /// Check every chunk read against its `chunkDigest`, or the file's
+    /// `digest` for unchunked files, failing the read with a corruption
+    /// error on mismatch. Off by default.
+    pub fn verify_digests(mut self, verify_digests: bool) -> Self {
+        self.verify_digests = verify_digests;
+        self
+    }
+
+    /// Keep up to `bytes` of decompressed chunks in memory, so reading the
+    /// same part of a file again doesn't decompress its gzip member again.
+    /// Least recently used chunks are evicted first. 0, the default,
+    /// disables the cache.
+    pub fn chunk_cache_size(mut self, bytes: usize) -> Self {
+        self.chunk_cache_size = bytes;
+        self
+    }
+
+    /// Call `f` after every chunk read, for metrics and tracing. It may be
+    /// called from several threads at once when the reader is shared.
+    pub fn on_read(mut self, f: impl Fn(&ReadEvent) + Send + Sync + 'static) -> Self {
+        self.on_read = Some(Arc::new(f));
+        self
+    }
+
+    /// Open blobs without a stargz footer as plain tar.gz layers: the whole
+    /// blob is read once, a TOC is built from its entries and their data is
+    /// recompressed one gzip member per file, so reads don't have to
+    /// decompress everything before them. That copy goes to an unnamed
+    /// temporary file, taking about as much disk space as the layer rather
+    /// than memory. Costly for large layers, but the rest of the API works
+    /// as on a stargz blob. Off by default.
+    pub fn tar_gz_fallback(mut self, tar_gz_fallback: bool) -> Self {
+        self.tar_gz_fallback = tar_gz_fallback;
+        self
+    }
+
+    /// Only parse and check the TOC when opening, and build the path map,
+    /// chunk lists and directory tree the first time they're needed. Opening
+    /// a layer with a huge TOC then returns sooner, which pays off when the
+    /// reader is dropped before any lookup, or its first lookup isn't on the
+    /// hot path. Off by default.
+    pub fn lazy_index(mut self, lazy_index: bool) -> Self {
+        self.lazy_index = lazy_index;
+        self
+    }
+
+    /// Open a blob stored in a file with these options, see
+    /// [`open_with_options`].
+    pub fn open(self, input: File) -> Result<GzReader> {
+        open_with_options(input, self)
+    }
+
+    /// See [`open_reader_with_options`].
+    pub fn open_reader<R: Read + Seek + Send + 'static>(self, r: R) -> Result<GzReader> {
+        open_reader_with_options(r, self)
+    }
+
+    /// See [`open_from_bytes_with_options`].
+    pub fn open_from_bytes(self, data: impl Into<Vec<u8>>) -> Result<GzReader> {
+        open_from_bytes_with_options(data, self)
+    }
+
+    /// See [`open_with_external_toc`].
+    pub fn open_with_external_toc(self, input: File, toc: impl Read) -> Result<GzReader> {
+        open_external_toc(Box::new(input), toc, self)
+    }
+}
 
 pub struct GzReader {
-    sr: File,
+    sr: Blob,
     toc: JToc,
-    m: HashMap<String, TocEntry>,
-    chunks: HashMap<String, Vec<TocEntry>>,
+    footer: Footer,
+    opts: ReaderOptions,
+    // Built when opening, or on first use by lazy readers
+    index: OnceLock<Index>,
+    warnings: Vec<String>,
+    cache: ChunkCache,
+}
+
+// Lookup structures over the normalized TOC entries
+#[derive(Default)]
+pub(crate) struct Index {
+    pub(crate) m: HashMap<String, TocEntry>,
+    pub(crate) chunks: HashMap<String, Vec<TocEntry>>,
+    // Case-folded path -> canonical path, only built for case-insensitive readers
+    folded: HashMap<String, String>,
+    case_conflicts: Vec<Vec<String>>,
+}
+
+impl Index {
+    // `entries` as normalized and checked by init_fields
+    fn build(entries: &[TocEntry], case_insensitive: bool) -> Self {
+        let mut index = Index {
+            m: HashMap::with_capacity(entries.len()),
+            ..Default::default()
+        };
+        for entry in entries {
+            match entry.entry_type.as_str() {
+                "chunk" => index
+                    .chunks
+                    .entry(entry.name.clone())
+                    .or_default()
+                    .push(entry.clone()),
+                entry_type => {
+                    // Chunks follow their file, so those collected under this
+                    // name belong to an earlier entry it shadows
+                    index.chunks.remove(&entry.name);
+                    if entry_type == "reg" && entry.chunk_size > 0 && entry.chunk_size < entry.size
+                    {
+                        // Only a capacity hint, don't trust it with a huge allocation
+                        let cap = usize::try_from(entry.size / entry.chunk_size + 1).unwrap_or(0);
+                        let mut chunks: Vec<TocEntry> = Vec::with_capacity(cap.min(1024));
+                        chunks.push(entry.clone());
+                        index.chunks.insert(entry.name.clone(), chunks);
+                    }
+                    index.m.insert(entry.name.clone(), entry.clone());
+                }
+            }
+        }
+        // Producers are free to list chunks in any order, lookups by file
+        // offset need them sorted
+        for chunks in index.chunks.values_mut() {
+            chunks.sort_by_key(|c| c.chunk_offset);
+        }
+
+        // Populate children, creating directories the TOC only implies
+        for entry in entries {
+            if entry.entry_type == "chunk" {
+                continue;
+            }
+            let name = &entry.name;
+            let parent = parent_dir(name);
+            if name == parent {
+                // The root directory itself ("./" or "/" in the tar), its
+                // own parent
+                if let Some(root) = index.m.get_mut(name) {
+                    root.num_link += 1;
+                }
+                continue;
+            }
+            index.get_or_create_dir(parent);
+            if index
+                .m
+                .get(parent)
+                .is_some_and(|dir| dir.children.contains_key(base_name(name)))
+            {
+                // A duplicate, the name was counted already
+                continue;
+            }
+            // At least this name references the entry
+            if let Some(e) = index.m.get_mut(name) {
+                e.num_link += 1;
+            }
+            if entry.entry_type == "hardlink" {
+                if let Some(original) = index
+                    .hardlink_target(entry)
+                    .and_then(|target| index.m.get_mut(&target))
+                {
+                    original.num_link += 1;
+                }
+            }
+            if let Some(dir) = index.m.get_mut(parent) {
+                dir.add_child(entry, base_name(name));
+            }
+        }
+        // Even an empty layer has a root
+        index.get_or_create_dir("");
+
+        if case_insensitive {
+            index.build_case_index();
+        }
+        index
+    }
+
+    // Name of the file at the end of a chain of hardlinks, None if the chain
+    // is broken or loops
+    fn hardlink_target(&self, link: &TocEntry) -> Option<String> {
+        let mut ent = link;
+        for _ in 0..MAX_SYMLINK_DEPTH {
+            let target = clean_entry_name(&ent.link_name);
+            ent = self.m.get(&target)?;
+            if ent.entry_type != "hardlink" {
+                return Some(target);
+            }
+        }
+        None
+    }
+
+    fn get_or_create_dir(&mut self, name: &str) {
+        if self.m.contains_key(name) {
+            return;
+        }
+        let dir = TocEntry {
+            name: name.to_string(),
+            entry_type: String::from("dir"),
+            mode: 0o755,
+            // The directory itself (.) and its parent's link to it
+            num_link: 2,
+            ..Default::default()
+        };
+        if !name.is_empty() {
+            let parent = parent_dir(name);
+            self.get_or_create_dir(parent);
+            if let Some(parent) = self.m.get_mut(parent) {
+                parent.add_child(&dir, base_name(name));
+            }
+        }
+        self.m.insert(name.to_string(), dir);
+    }
+
+    pub(crate) fn build_case_index(&mut self) {
+        let mut variants: HashMap<String, Vec<String>> = HashMap::new();
+        for name in self.m.keys() {
+            variants
+                .entry(name.to_lowercase())
+                .or_default()
+                .push(name.clone());
+        }
+
+        self.folded = HashMap::with_capacity(variants.len());
+        self.case_conflicts.clear();
+        for (key, mut names) in variants {
+            names.sort_unstable();
+            self.folded.insert(key, names[0].clone());
+            if names.len() > 1 {
+                self.case_conflicts.push(names);
+            }
+        }
+        self.case_conflicts.sort_unstable();
+    }
 }
 
 impl GzReader {
+    fn from_toc(sr: Blob, toc: JToc, footer: Footer, opts: ReaderOptions) -> Result<Self> {
+        let mut reader = GzReader {
+            sr,
+            toc,
+            footer,
+            index: OnceLock::new(),
+            warnings: Vec::new(),
+            cache: ChunkCache::new(opts.chunk_cache_size),
+            opts,
+        };
+
+        reader.init_fields()?;
+        if !reader.opts.lazy_index {
+            reader.index();
+        }
+
+        Ok(reader)
+    }
+
+    /// How the blob is laid out, as read from its footer and TOC.
+    pub fn info(&self) -> ArchiveInfo {
+        ArchiveInfo {
+            footer: self.footer.format,
+            blob_size: self.footer.blob_size,
+            toc_offset: self.footer.toc_offset,
+            toc_size: self.footer.toc_size(),
+            toc_version: self.toc.version,
+            entries: self.toc.entries.len(),
+        }
+    }
+
+    /// Spec deviations tolerated while parsing the TOC in permissive mode.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // Fail in strict mode, record a warning otherwise
+    fn deviation(&mut self, msg: String) -> Result<()> {
+        match self.opts.parse_mode {
+            ParseMode::Strict => Err(anyhow!(msg)),
+            ParseMode::Permissive => {
+                self.warnings.push(msg);
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn index(&self) -> &Index {
+        self.index
+            .get_or_init(|| Index::build(&self.toc.entries, self.opts.case_insensitive))
+    }
+
+    /// Groups of paths that only differ by case, sorted. The first path of
+    /// each group is the one case-insensitive lookups resolve to. Always
+    /// empty unless the reader was opened case-insensitive.
+    pub fn case_conflicts(&self) -> &[Vec<String>] {
+        &self.index().case_conflicts
+    }
+
+    fn get_entry(&self, path: &str) -> Option<&TocEntry> {
+        let index = self.index();
+        match index.m.get(path) {
+            Some(ent) => Some(ent),
+            None if self.opts.case_insensitive => index
+                .folded
+                .get(&path.to_lowercase())
+                .and_then(|canonical| index.m.get(canonical)),
+            None => None,
+        }
+    }
+
     fn init_fields(&mut self) -> Result<()> {
-        self.m = HashMap::with_capacity(self.toc.entries.len());
-        self.chunks = HashMap::new();
-        let mut last_reg_entry: Option<TocEntry> = None;
-        let mut last_path: &str = "";
+        let mut entries = std::mem::take(&mut self.toc.entries);
+        self.warnings.clear();
+        if self.toc.version != 1 {
+            self.deviation(format!("unsupported TOC version {}", self.toc.version))?;
+        }
+        let mut last_reg_size: Option<u64> = None;
+        let mut last_path = String::new();
         let mut uname = HashMap::<u32, String>::new();
         let mut gname = HashMap::<u32, String>::new();
-        for mut entry in &mut self.toc.entries.clone() {
-            entry.name = entry.name.trim_start_matches("./").to_owned();
+        let mut orphan_chunks = false;
+        for entry in entries.iter_mut() {
+            if entry.name.starts_with('/') || entry.name.split('/').any(|c| c == "..") {
+                self.deviation(format!("entry name {:?} isn't relative", entry.name))?;
+            }
+            entry.name = clean_entry_name(&entry.name);
             match entry.entry_type.as_str() {
-                "reg" => {
-                    last_reg_entry = Some(entry.clone());
-                }
                 "chunk" => {
-                    entry.name = last_path.to_owned();
-                    match self.chunks.get_mut(&entry.name) {
-                        Some(v) => {
-                            v.push(entry.clone());
-                        }
-                        None => {
-                            self.chunks
-                                .insert(entry.name.to_owned(), vec![entry.clone()]);
+                    if last_reg_size.is_none() {
+                        self.deviation(format!(
+                            "chunk at offset {} doesn't follow a regular file",
+                            entry.offset
+                        ))?;
+                        orphan_chunks = true;
+                        // Marks it for removal, no regular file can be the root
+                        entry.name.clear();
+                        continue;
+                    }
+                    entry.name = last_path.clone();
+                    if entry.chunk_size == 0 {
+                        if let Some(size) = last_reg_size {
+                            entry.chunk_size = size.saturating_sub(entry.chunk_offset);
                         }
-                    };
-                    if entry.chunk_size == 0 && last_reg_entry.is_some() {
-                        let last_ent_size = last_reg_entry.clone().unwrap().size;
-                        entry.chunk_size = last_ent_size - entry.chunk_offset;
                     }
                 }
-                _ => {
-                    last_path = &entry.name;
+                entry_type => {
+                    if entry_type == "reg" {
+                        last_reg_size = Some(entry.size);
+                    }
+                    last_path = entry.name.clone();
                     match entry.uname.as_str() {
-                        "" => {
-                            entry.uname = uname.get(&entry.uid).unwrap().to_string();
-                        }
+                        "" => entry.uname = uname.get(&entry.uid).cloned().unwrap_or_default(),
                         _ => {
                             uname.insert(entry.uid, entry.uname.clone());
                         }
                     }
                     match entry.gname.as_str() {
-                        "" => {
-                            entry.gname = gname.get(&entry.gid).unwrap().to_string();
-                        }
+                        "" => entry.gname = gname.get(&entry.gid).cloned().unwrap_or_default(),
                         _ => {
                             gname.insert(entry.gid, entry.gname.clone());
                         }
                     }
 
-                    if entry.mod_time_3339.is_some() {
-                        entry.mod_time = Some(
-                            chrono::DateTime::parse_from_rfc3339(
-                                &entry.mod_time_3339.as_ref().unwrap(),
-                            )?
-                            .into(),
-                        );
+                    if !TOC_ENTRY_TYPES.contains(&entry_type) {
+                        self.deviation(format!("{} has unknown type {entry_type:?}", entry.name))?;
+                    }
+                    if let Some(mod_time_3339) = &entry.mod_time_3339 {
+                        entry.mod_time = match chrono::DateTime::parse_from_rfc3339(mod_time_3339) {
+                            std::result::Result::Ok(t) => Some(t.into()),
+                            Err(e) => {
+                                let parsed = parse_lenient_time(mod_time_3339);
+                                let ignored = if parsed.is_some() { "" } else { ", ignored" };
+                                self.deviation(format!(
+                                    "{} has invalid modtime {mod_time_3339:?}: {e}{ignored}",
+                                    entry.name
+                                ))?;
+                                parsed
+                            }
+                        };
                     }
-                    if entry.entry_type == "dir" {
+                    if entry_type == "dir" {
+                        // The parent directory links to this one
                         entry.num_link += 1;
-                        self.m
-                            .insert(entry.name.trim_end_matches("/").to_owned(), entry.clone());
-                    } else {
-                        self.m.insert(entry.name.to_owned(), entry.clone());
                     }
                 }
             }
-
-            if entry.entry_type == "reg" && entry.chunk_size > 0 && entry.chunk_size < entry.size {
-                let cap = (entry.size / entry.chunk_size + 1) as usize;
-                let mut chunks: Vec<TocEntry> = Vec::with_capacity(cap);
-                chunks.push(entry.clone());
-                self.chunks.insert(entry.name.to_owned(), chunks);
-            }
-            if entry.chunk_size == 0 && entry.size != 0 {
+            // Nothing to read for a sparse file that is all hole
+            if entry.chunk_size == 0 && entry.physical_size() != 0 {
                 entry.chunk_size = entry.size;
             }
+        }
+        if orphan_chunks {
+            // Nothing to attach them to, they'd end up under the root
+            entries.retain(|e| e.entry_type != "chunk" || !e.name.is_empty());
+        }
 
-            for entry in &mut self.toc.entries.clone() {
-                if entry.entry_type == "chunk" {
-                    continue;
+        // The chunks of a file must cover it from the start, without gaps or
+        // overlaps, or some offsets would be read from no chunk or the wrong
+        // one. Sparse files have holes between theirs.
+        let mut i = 0;
+        while i < entries.len() {
+            let chunks = entries[i + 1..]
+                .iter()
+                .take_while(|e| e.entry_type == "chunk")
+                .count();
+            let ent = &entries[i];
+            if ent.entry_type == "reg" && chunks > 0 && !ent.is_sparse() {
+                let mut spans: Vec<(u64, u64)> = entries[i..=i + chunks]
+                    .iter()
+                    .map(|e| (e.chunk_offset, e.chunk_size))
+                    .collect();
+                spans.sort_unstable();
+                let mut end = 0;
+                let mut problem = None;
+                for (offset, size) in spans {
+                    if offset != end {
+                        problem = Some(format!("{} has no chunk at offset {end}", ent.name));
+                        break;
+                    }
+                    end = offset.saturating_add(size);
                 }
-                let mut name = entry.name.to_owned();
-                if entry.entry_type == "dir" {
-                    let bind = name.trim_end_matches("/").to_owned();
-                    name = bind;
-                }
-
-                let mut parent_dir = self.get_or_create_parent_dir(&name);
-                entry.num_link += 1;
-                if entry.entry_type == "hardlink" {
-                    let link_name = entry.link_name.clone();
-                    match self.m.get_mut(&link_name) {
-                        Some(original) => original.num_link += 1,
-                        None => {
-                            return Err(anyhow!(
-                                "{0} is a hardlink but the linkname {link_name} isn't found",
-                                entry.name
-                            ))
-                        }
-                    };
+                if problem.is_none() && end != ent.size {
+                    problem = Some(format!(
+                        "chunks of {} cover {end} of its {} bytes",
+                        ent.name, ent.size
+                    ));
+                }
+                if let Some(problem) = problem {
+                    self.deviation(problem)?;
                 }
-                parent_dir.add_child(entry.clone(), &name);
             }
+            i += chunks + 1;
+        }
 
-            let mut last_offset = self.sr.metadata().unwrap().size();
-            for i in (0..self.toc.entries.len()).rev() {
-                match self.toc.entries.get_mut(i) {
-                    Some(e) => {
-                        if e.is_data_type() {
-                            e.next_offset = last_offset;
-                        }
-                        if e.offset != 0 {
-                            last_offset = e.offset
-                        }
-                    }
-                    None => {}
+        // Each data entry's compressed bytes run until the next entry with
+        // another offset, or the TOC for the last one. Entries packed in a
+        // member share its offset.
+        let mut last_offset = self.footer.toc_offset;
+        let mut last_end = self.footer.toc_offset;
+        for e in entries.iter_mut().rev() {
+            if e.is_data_type() {
+                e.next_offset = if e.offset == last_offset {
+                    last_end
+                } else {
+                    last_offset
+                };
+            }
+            if e.offset != 0 && e.offset != last_offset {
+                last_end = last_offset;
+                last_offset = e.offset
+            }
+        }
+
+        // Hardlinks must point into the layer. Checked here rather than
+        // when indexing, which lazy readers only do on first use
+        if entries.iter().any(|e| e.entry_type == "hardlink") {
+            let names: HashSet<&str> = entries
+                .iter()
+                .filter(|e| e.entry_type != "chunk")
+                .map(|e| e.name.as_str())
+                .collect();
+            for entry in entries.iter().filter(|e| e.entry_type == "hardlink") {
+                let link_name = &entry.link_name;
+                if !names.contains(clean_entry_name(link_name).as_str()) {
+                    return Err(anyhow!(
+                        "{} is a hardlink but the linkname {link_name} isn't found",
+                        entry.name
+                    ));
                 }
             }
         }
+
+        self.toc.entries = entries;
         Ok(())
     }
 
-    fn get_or_create_parent_dir(&self, name: &str) -> TocEntry {
-        match self.m.get(&name.to_string()) {
-            Some(e) => e.to_owned(),
-            None => TocEntry {
-                name: name.to_string(),
-                entry_type: String::from("dir"),
-                size: 0,
-                mode: 0755,
-                mod_time_3339: None,
-                mod_time: None,
-                link_name: "".to_string(),
-                uid: 0,
-                gid: 0,
-                uname: "".to_string(),
-                gname: "".to_string(),
-                offset: 0,
-                next_offset: 0,
-                dev_major: 0,
-                dev_minor: 0,
-                num_link: 2,
-                xattrs: HashMap::new(),
-                digest: "".to_string(),
-                chunk_offset: 0,
-                chunk_size: 0,
-                children: HashMap::new(),
-            },
+    /// The TOC entries in archive order, `chunk` entries included, after
+    /// normalization. Implied directories and the root aren't part of it,
+    /// see [`GzReader::walk`] for the tree.
+    pub fn entries(&self) -> &[TocEntry] {
+        &self.toc.entries
+    }
+
+    /// The root directory of the layer, synthesized when the TOC has no
+    /// entry for it. Start here to walk the tree with
+    /// [`TocEntry::children`].
+    pub fn root(&self) -> &TocEntry {
+        // init_fields always creates it
+        &self.index().m[""]
+    }
+
+    /// Look up an entry by path, given as a string or a [`Path`]. The path
+    /// is cleaned first, so `/etc/passwd`, `./etc//passwd` and `etc/passwd/`
+    /// all resolve like `etc/passwd`.
+    ///
+    /// A missing path fails with [`ErrorKind::NotFound`], a hardlink whose
+    /// target isn't in the layer with [`ErrorKind::Corrupt`].
+    pub fn lookup(&self, path: impl AsRef<Path>) -> Result<&TocEntry> {
+        let name = path_entry_name(path.as_ref())?;
+        let ent = self
+            .get_entry(&name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{name} not found")))?;
+        self.hardlink_target(ent)
+    }
+
+    // The file a hardlink points to, following links to links; anything
+    // else is its own target
+    fn hardlink_target<'a>(&'a self, link: &'a TocEntry) -> Result<&'a TocEntry> {
+        let mut ent = link;
+        for _ in 0..MAX_SYMLINK_DEPTH {
+            if ent.entry_type != "hardlink" {
+                return Ok(ent);
+            }
+            let target = clean_entry_name(&ent.link_name);
+            ent = self.get_entry(&target).ok_or_else(|| {
+                Error::corrupt(format!("hardlink {} points to missing {target}", link.name))
+            })?;
+        }
+        Err(Error::corrupt(format!("hardlink {} is part of a loop", link.name)).into())
+    }
+
+    /// The entry at `path` and, when it's a hardlink, the file it stands
+    /// for, whose [`Stat::nlink`] counts every name of the file like
+    /// `st_nlink` would. Symlinks are followed like `stat(2)` does.
+    pub fn stat(&self, path: impl AsRef<Path>) -> Result<Stat<'_>> {
+        self.stat_resolved(path.as_ref(), true)
+    }
+
+    /// Like [`GzReader::stat`], but a symlink at `path` isn't followed,
+    /// like `lstat(2)`: what a filesystem frontend reports for the path.
+    pub fn lstat(&self, path: impl AsRef<Path>) -> Result<Stat<'_>> {
+        self.stat_resolved(path.as_ref(), false)
+    }
+
+    fn stat_resolved(&self, path: &Path, follow_last: bool) -> Result<Stat<'_>> {
+        let name = self.resolve_name(path, follow_last)?;
+        let entry = self
+            .get_entry(&name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{name} not found")))?;
+        Ok(Stat {
+            entry,
+            target: self.hardlink_target(entry)?,
+        })
+    }
+
+    /// Like [`GzReader::lookup`], but symlinks are followed wherever they
+    /// appear in the path, the way the kernel resolves paths: with `lib64`
+    /// a symlink to `lib`, `lib64/ld.so` resolves to `lib/ld.so`. Relative
+    /// targets are resolved against the link's directory, absolute ones
+    /// against the layer root.
+    ///
+    /// Layers are untrusted input, so symlink cycles are detected and at most
+    /// 40 links are followed before giving up, like ELOOP.
+    pub fn lookup_follow(&self, path: impl AsRef<Path>) -> Result<&TocEntry> {
+        self.resolve(path.as_ref(), true)
+    }
+
+    // Follow the symlinks along `path`, the last component's only if
+    // `follow_last` is set, like stat(2) vs lstat(2)
+    fn resolve(&self, path: &Path, follow_last: bool) -> Result<&TocEntry> {
+        self.lookup(self.resolve_name(path, follow_last)?)
+    }
+
+    // Name of the entry `path` leads to, see resolve
+    fn resolve_name(&self, path: &Path, follow_last: bool) -> Result<String> {
+        let mut pending = path_entry_name(path)?;
+        let mut resolved = String::new();
+        let mut seen = HashSet::new();
+        let mut links = 0;
+        while !pending.is_empty() {
+            let (part, rest) = pending.split_once('/').unwrap_or((&pending, ""));
+            let name = match resolved.as_str() {
+                "" => part.to_string(),
+                dir => format!("{dir}/{part}"),
+            };
+            let ent = self
+                .lookup(&name)
+                .with_context(|| format!("resolving {}", path.display()))?;
+            if ent.entry_type != "symlink" || (rest.is_empty() && !follow_last) {
+                resolved = name;
+                pending = rest.to_string();
+                continue;
+            }
+            links += 1;
+            if links > MAX_SYMLINK_DEPTH {
+                return Err(anyhow!(
+                    "too many levels of symbolic links resolving {}",
+                    path.display()
+                ));
+            }
+            let target = resolve_link_target(&ent.name, &ent.link_name);
+            pending = clean_entry_name(&format!("{target}/{rest}"));
+            resolved.clear();
+            // Back to a path already resolved from the root: a cycle
+            if !seen.insert(pending.clone()) {
+                return Err(anyhow!(
+                    "symlink loop at {} while resolving {}",
+                    ent.name,
+                    path.display()
+                ));
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// The entries of the directory at `path`, sorted by base name.
+    /// Symlinks leading to the directory are followed, the entries
+    /// themselves are returned as they are in the TOC.
+    pub fn readdir(&self, path: impl AsRef<Path>) -> Result<impl Iterator<Item = &TocEntry>> {
+        let path = path.as_ref();
+        let dir = self.lookup_follow(path)?;
+        if dir.entry_type != "dir" {
+            return Err(anyhow!("{} is not a directory", path.display()));
         }
+        Ok(dir
+            .children()
+            .filter_map(|(_, name)| self.index().m.get(name)))
     }
 
-    pub fn lookup(&self, path: &str) -> Result<&TocEntry> {
-        let mut ent = self.m.get(path).unwrap();
-        if ent.entry_type == "hardlink" {
-            let link_name = &ent.link_name;
-            ent = self.m.get(link_name).unwrap()
+    // The gzip members holding exactly one chunk, by chunk digest, as
+    // (blob offset, compressed size): those of every chunk of a file but
+    // its last, which is followed by the next entry's header
+    fn chunk_members(&self) -> HashMap<String, (u64, u64)> {
+        let mut members = HashMap::new();
+        for chunks in self.index().chunks.values() {
+            for pair in chunks.windows(2) {
+                let (chunk, next) = (&pair[0], &pair[1]);
+                if chunk.chunk_digest.is_empty()
+                    || chunk.inner_offset != 0
+                    || next.inner_offset != 0
+                    || next.offset <= chunk.offset
+                {
+                    continue;
+                }
+                members.insert(
+                    chunk.chunk_digest.clone(),
+                    (chunk.offset, next.offset - chunk.offset),
+                );
+            }
         }
-        return Ok(ent);
+        members
     }
 
     fn get_chunks(&self, entry: &TocEntry) -> Vec<TocEntry> {
-        match self.chunks.get(&entry.name) {
+        match self.index().chunks.get(&entry.name) {
             Some(entries) => entries.clone(),
             None => vec![entry.clone()],
         }
     }
 
-    pub fn open_file(&self, name: &str) -> Result<SectionReader<File>> {
-        let ent = self.lookup(name)?;
-        if ent.entry_type != "reg" {
-            return Err(anyhow!("Not a regular file"));
+    // Read a chunk (or an unchunked file) through the chunk cache, checking
+    // its digest if asked to
+    fn read_chunk(&self, chunk: &TocEntry) -> Result<Vec<u8>> {
+        let started = Instant::now();
+        let cached = self.cache.get(&chunk.name, chunk.chunk_offset);
+        let data = match &cached {
+            Some(data) => data.to_vec(),
+            None => {
+                let mut data = Vec::new();
+                self.copy_chunk(chunk, &mut data, self.opts.verify_digests)?;
+                self.cache
+                    .insert(&chunk.name, chunk.chunk_offset, data.as_slice().into());
+                data
+            }
+        };
+        self.report_read(chunk, cached.is_some(), started);
+
+        Ok(data)
+    }
+
+    fn report_read(&self, chunk: &TocEntry, cached: bool, started: Instant) {
+        if let Some(f) = &self.opts.on_read {
+            f(&ReadEvent {
+                name: chunk.name.clone(),
+                chunk_offset: chunk.chunk_offset,
+                chunk_size: chunk.chunk_size,
+                blob_offset: chunk.offset,
+                cached,
+                elapsed: started.elapsed(),
+            });
+        }
+    }
+
+    // Decompress a chunk (or an unchunked file) from its gzip member
+    fn decompress_chunk(&self, chunk: &TocEntry) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.copy_chunk(chunk, &mut data, false)?;
+        Ok(data)
+    }
+
+    // Stream a chunk's decompressed bytes into `w`, checking them against
+    // the TOC digest on the way if asked to
+    fn copy_chunk(&self, chunk: &TocEntry, w: &mut dyn Write, check_digest: bool) -> Result<()> {
+        let member = SectionReader::new(
+            &*self.sr,
+            chunk.offset,
+            chunk.next_offset().saturating_sub(chunk.offset),
+        );
+        let mut gz = flate2::bufread::GzDecoder::new(BufReader::new(member));
+        let skipped = io::copy(&mut gz.by_ref().take(chunk.inner_offset), &mut io::sink())?;
+        if skipped != chunk.inner_offset {
+            return Err(Error::corrupt(format!(
+                "gzip member of {} at {} ends before its inner offset {}",
+                chunk.name, chunk.offset, chunk.inner_offset
+            ))
+            .into());
+        }
+        // An unchunked file's digest covers exactly the one chunk
+        let expected = match chunk.chunk_digest.as_str() {
+            _ if !check_digest => "",
+            "" if chunk.entry_type == "reg" && chunk.chunk_size == chunk.size => {
+                chunk.digest.as_str()
+            }
+            digest => digest,
+        };
+        let mut content = DigestReader::new(gz.by_ref().take(chunk.chunk_size));
+        let copied = if expected.is_empty() {
+            io::copy(&mut content.inner, w)?
+        } else {
+            io::copy(&mut content, w)?
+        };
+        if copied != chunk.chunk_size {
+            return Err(Error::corrupt(format!(
+                "chunk of {} at {} holds {copied} of {} bytes",
+                chunk.name, chunk.chunk_offset, chunk.chunk_size
+            ))
+            .into());
+        }
+        if !expected.is_empty() {
+            let actual = content.finish();
+            if actual != expected {
+                return Err(Error::corrupt(format!(
+                    "chunk of {} at {} hashes to {actual}, TOC says {expected}",
+                    chunk.name, chunk.chunk_offset
+                ))
+                .into());
+            }
+        }
+        // The decoder only checks the trailer once it reaches it
+        if self.opts.verify_crc {
+            io::copy(&mut gz, &mut io::sink()).with_context(|| {
+                Error::corrupt(format!(
+                    "gzip member of {} at {} failed CRC validation",
+                    chunk.name, chunk.offset
+                ))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Open the regular file at `name` for reading, hardlinks resolved to
+    /// the file they point to.
+    pub fn open_file(&self, name: &str) -> Result<OpenedFile<'_>> {
+        let ent = self.lookup(name)?;
+        if ent.entry_type != "reg" {
+            return Err(anyhow!("Not a regular file"));
+        }
+        Ok(self.open_entry(ent))
+    }
+
+    /// The whole content of the regular file at `name`, for when streaming
+    /// it through [`GzReader::open_file`] isn't worth it.
+    pub fn read_file(&self, name: &str) -> Result<Vec<u8>> {
+        let ent = self.lookup(name)?;
+        if ent.entry_type != "reg" {
+            return Err(anyhow!("{name} is not a regular file"));
+        }
+        let mut data = Vec::new();
+        for chunk in self.get_chunks(ent).iter().filter(|c| c.chunk_size > 0) {
+            if ent.is_sparse() && (data.len() as u64) < chunk.chunk_offset {
+                data.resize(usize::try_from(chunk.chunk_offset)?, 0);
+            }
+            data.extend_from_slice(&self.read_chunk(chunk)?);
+        }
+        if ent.is_sparse() && (data.len() as u64) < ent.size {
+            data.resize(usize::try_from(ent.size)?, 0);
+        }
+        if data.len() as u64 != ent.size {
+            return Err(Error::corrupt(format!(
+                "{name} holds {} of {} bytes",
+                data.len(),
+                ent.size
+            ))
+            .into());
+        }
+
+        Ok(data)
+    }
+
+    /// Stream the content of the regular file at `name` into `w` one chunk
+    /// at a time, so memory use stays bounded by the chunk size however big
+    /// the file is. Returns the number of bytes written.
+    ///
+    /// Chunks are checked as they go by, so on a corrupt layer `w` may have
+    /// been given part of the file by the time the error comes back.
+    pub fn copy_file_to(&self, name: &str, w: &mut impl Write) -> Result<u64> {
+        let ent = self.lookup(name)?;
+        if ent.entry_type != "reg" {
+            return Err(anyhow!("{name} is not a regular file"));
+        }
+        let mut copied = 0;
+        for chunk in self.get_chunks(ent).iter().filter(|c| c.chunk_size > 0) {
+            if ent.is_sparse() {
+                copied += write_hole(w, copied, chunk.chunk_offset)?;
+            }
+            let started = Instant::now();
+            // Bypasses the cache on a miss, filling it would keep the file
+            // in memory after all
+            match self.cache.get(&chunk.name, chunk.chunk_offset) {
+                Some(data) => {
+                    w.write_all(&data)?;
+                    self.report_read(chunk, true, started);
+                }
+                None => {
+                    self.copy_chunk(chunk, w, self.opts.verify_digests)?;
+                    self.report_read(chunk, false, started);
+                }
+            }
+            copied += chunk.chunk_size;
+        }
+        if ent.is_sparse() {
+            copied += write_hole(w, copied, ent.size)?;
+        }
+        if copied != ent.size {
+            return Err(
+                Error::corrupt(format!("{name} holds {copied} of {} bytes", ent.size)).into(),
+            );
+        }
+
+        Ok(copied)
+    }
+
+    fn open_entry(&self, ent: &TocEntry) -> OpenedFile<'_> {
+        let file = OpenedFile::new(self, ent);
+
+        // Let the kernel start pulling the file's gzip members in while the
+        // caller sets up, they are going to be read front to back.
+        let (start, end) = file.blob_range();
+        if end > start {
+            fadvise::advise(&*self.sr, start, end - start, Advice::Sequential);
+            fadvise::advise(&*self.sr, start, end - start, Advice::WillNeed);
+        }
+
+        file
+    }
+
+    /// Tell the kernel the blob range backing `name` is no longer needed so
+    /// its pages can be evicted. Meant for one-shot consumers (e.g. copying a
+    /// file out of the layer) that won't read the same data again.
+    pub fn drop_file_cache(&self, name: &str) -> Result<()> {
+        let ent = self.lookup(name)?;
+        if ent.entry_type != "reg" {
+            return Ok(());
+        }
+        let (start, end) = OpenedFile::new(self, ent).blob_range();
+        if end > start {
+            fadvise::advise(&*self.sr, start, end - start, Advice::DontNeed);
+        }
+
+        Ok(())
+    }
+
+    /// Walk the gzip members holding the layer's tar stream, stopping before
+    /// the TOC. Unlike [`GzReader::members`] entries aren't attached.
+    pub fn iter_members(&self) -> MemberIter<'_> {
+        MemberIter::new(&*self.sr, 0, self.footer.toc_offset)
+    }
+
+    /// The gzip members holding the layer's tar stream, in blob order, each
+    /// with the file contents and chunks the TOC places in it. The TOC member
+    /// and the footer that follow aren't included.
+    ///
+    /// Every member is decompressed to find where it ends, so this reads the
+    /// whole data region of the blob.
+    pub fn members(&self) -> Result<Vec<Member>> {
+        let mut members = self.iter_members().collect::<Result<Vec<_>>>()?;
+        let starts: HashMap<u64, usize> = members
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (m.offset, i))
+            .collect();
+        for ent in self.toc.entries.iter().filter(|e| e.offset != 0) {
+            let Some(&i) = starts.get(&ent.offset) else {
+                return Err(Error::corrupt(format!(
+                    "{} points at offset {}, which isn't the start of a gzip member",
+                    ent.name, ent.offset
+                ))
+                .into());
+            };
+            members[i].entries.push(MemberEntry {
+                name: ent.name.clone(),
+                chunk_offset: ent.chunk_offset,
+                chunk_size: ent.chunk_size,
+            });
+        }
+
+        Ok(members)
+    }
+
+    /// The chunk of the regular file at `name` holding the byte at `offset`
+    /// of the file: the file's own entry when it isn't chunked. None if
+    /// there's no such file or `offset` is past its end.
+    pub fn chunk_entry_for_offset(&self, name: &str, offset: u64) -> Option<&TocEntry> {
+        let ent = self.lookup(name).ok()?;
+        if ent.entry_type != "reg" || offset >= ent.size {
+            return None;
+        }
+        let Some(chunks) = self.index().chunks.get(&ent.name) else {
+            // Unless it's in a hole
+            return covers(ent, offset).then_some(ent);
+        };
+        let i = chunks
+            .partition_point(|c| c.chunk_offset <= offset)
+            .checked_sub(1)?;
+        let chunk = &chunks[i];
+        covers(chunk, offset).then_some(chunk)
+    }
+}
+
+/// A regular file of the layer opened for reading, see
+/// [`GzReader::open_file`].
+///
+/// Reads decompress the gzip member holding the chunk at the current
+/// position, so reading front to back decompresses each member once;
+/// seeking elsewhere in the file only costs decompressing the chunk landed
+/// in.
+pub struct OpenedFile<'a> {
+    r: &'a GzReader,
+    size: u64,
+    // Whether what no chunk holds reads as zeros rather than the end
+    sparse: bool,
+    // Sorted by chunk offset
+    ents: Vec<TocEntry>,
+    pos: u64,
+    // Index and content of the chunk last decompressed
+    current: Option<(usize, Vec<u8>)>,
+}
+
+impl<'a> OpenedFile<'a> {
+    fn new(r: &'a GzReader, ent: &TocEntry) -> Self {
+        let mut ents = r.get_chunks(ent);
+        ents.sort_by_key(|e| e.chunk_offset);
+        OpenedFile {
+            r,
+            size: ent.size,
+            sparse: ent.is_sparse(),
+            ents,
+            pos: 0,
+            current: None,
+        }
+    }
+
+    /// Size of the file, holes included for sparse files.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Compressed byte range of the blob holding this file's chunks
+    fn blob_range(&self) -> (u64, u64) {
+        let start = self.ents.iter().map(|e| e.offset).min().unwrap_or(0);
+        let end = self
+            .ents
+            .iter()
+            .map(|e| e.next_offset())
+            .max()
+            .unwrap_or(start);
+        (start, end.max(start))
+    }
+
+    /// Read at `offset` of the file without moving the position used by
+    /// `Read`, like `pread(2)`. `buf` is filled entirely, across as many
+    /// chunks as needed, unless the end of the file comes first.
+    ///
+    /// Takes `&self`, so a file can be shared by threads serving random
+    /// reads (e.g. FUSE requests). Each call decompresses the chunks it
+    /// touches unless they come from the reader's chunk cache, see
+    /// [`ReaderOptions::chunk_cache_size`].
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.read_vectored_at(&mut [io::IoSliceMut::new(buf)], offset)
+    }
+
+    /// Like [`OpenedFile::read_at`], but scattering what is read over
+    /// `bufs`, filled in order like `preadv(2)` does. A chunk whose content
+    /// spans several buffers is only decompressed once.
+    pub fn read_vectored_at(
+        &self,
+        bufs: &mut [io::IoSliceMut<'_>],
+        offset: u64,
+    ) -> io::Result<usize> {
+        self.read_into(bufs, offset, &mut None)
+    }
+
+    // Fill `bufs` from `offset`, `fetched` keeping the chunk last
+    // decompressed between buffers, see read_vectored_at
+    fn read_into(
+        &self,
+        bufs: &mut [io::IoSliceMut<'_>],
+        offset: u64,
+        fetched: &mut Option<(usize, Vec<u8>)>,
+    ) -> io::Result<usize> {
+        let mut n = 0;
+        for buf in bufs.iter_mut() {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let pos = offset.saturating_add(n as u64);
+                if pos >= self.size {
+                    return io::Result::Ok(n);
+                }
+                let copied = match self.hole_end(pos) {
+                    Some(end) => {
+                        let len = (buf.len() - filled)
+                            .min(usize::try_from(end - pos).unwrap_or(usize::MAX));
+                        buf[filled..filled + len].fill(0);
+                        len
+                    }
+                    None => {
+                        let i = self.chunk_index(pos)?;
+                        let data = match (&self.current, &*fetched) {
+                            (Some((current, data)), _) | (_, Some((current, data)))
+                                if *current == i =>
+                            {
+                                data
+                            }
+                            _ => &fetched.insert((i, self.fetch_chunk(i)?)).1,
+                        };
+                        copy_from_chunk(&self.ents[i], data, pos, &mut buf[filled..])?
+                    }
+                };
+                if copied == 0 {
+                    return io::Result::Ok(n);
+                }
+                filled += copied;
+                n += copied;
+            }
+        }
+        io::Result::Ok(n)
+    }
+
+    // Index of the chunk holding `offset`, which must be inside the file
+    fn chunk_index(&self, offset: u64) -> io::Result<usize> {
+        self.ents
+            .partition_point(|e| e.chunk_offset <= offset)
+            .checked_sub(1)
+            .ok_or_else(|| no_chunk_at(offset))
+    }
+
+    // Where the hole `offset` is in ends, for sparse files
+    fn hole_end(&self, offset: u64) -> Option<u64> {
+        if !self.sparse {
+            return None;
+        }
+        let next = self.ents.partition_point(|e| e.chunk_offset <= offset);
+        let in_chunk = next
+            .checked_sub(1)
+            .is_some_and(|i| offset - self.ents[i].chunk_offset < self.ents[i].chunk_size);
+        match in_chunk {
+            true => None,
+            false => Some(self.ents.get(next).map_or(self.size, |e| e.chunk_offset)),
+        }
+    }
+
+    fn fetch_chunk(&self, i: usize) -> io::Result<Vec<u8>> {
+        self.r
+            .read_chunk(&self.ents[i])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+// Write the zeros of a sparse file's hole, from file offset `from` to `to`
+fn write_hole(w: &mut impl Write, from: u64, to: u64) -> io::Result<u64> {
+    io::copy(&mut io::repeat(0).take(to.saturating_sub(from)), w)
+}
+
+// Copy the content of `chunk` found at file offset `pos` into `buf`. A chunk
+// shorter than the TOC says reads as the end of the file.
+fn copy_from_chunk(chunk: &TocEntry, data: &[u8], pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let start = pos
+        .checked_sub(chunk.chunk_offset)
+        .ok_or_else(|| no_chunk_at(pos))?;
+    let Some(start) = usize::try_from(start)
+        .ok()
+        .filter(|&start| start < data.len())
+    else {
+        return io::Result::Ok(0);
+    };
+    let n = buf.len().min(data.len() - start);
+    buf[..n].copy_from_slice(&data[start..start + n]);
+    io::Result::Ok(n)
+}
+
+// A TOC whose chunks don't start at the beginning of the file, which only
+// permissive readers open
+fn no_chunk_at(offset: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        Error::corrupt(format!("no chunk holds file offset {offset}")),
+    )
+}
+
+impl Read for OpenedFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return io::Result::Ok(0);
+        }
+        if let Some(end) = self.hole_end(self.pos) {
+            let n = buf
+                .len()
+                .min(usize::try_from(end - self.pos).unwrap_or(usize::MAX));
+            buf[..n].fill(0);
+            self.pos += n as u64;
+            return io::Result::Ok(n);
+        }
+        let i = self.chunk_index(self.pos)?;
+        let data = match &self.current {
+            Some((current, data)) if *current == i => data,
+            _ => {
+                let data = self.fetch_chunk(i)?;
+                &self.current.insert((i, data)).1
+            }
+        };
+        let n = copy_from_chunk(&self.ents[i], data, self.pos, buf)?;
+        self.pos += n as u64;
+        io::Result::Ok(n)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        // Keep the last chunk around for the next call, like read does
+        let mut fetched = None;
+        let n = self.read_into(bufs, self.pos, &mut fetched)?;
+        if fetched.is_some() {
+            self.current = fetched;
+        }
+        self.pos += n as u64;
+        io::Result::Ok(n)
+    }
+}
+
+impl Seek for OpenedFile<'_> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            io::SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                io::Result::Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+pub fn open(input: File) -> Result<GzReader> {
+    open_with_options(input, ReaderOptions::default())
+}
+
+pub fn open_with_options(input: File, opts: ReaderOptions) -> Result<GzReader> {
+    open_blob(Box::new(input), opts)
+}
+
+/// Open a blob from any `Read + Seek` source, e.g. a buffered download or
+/// a custom reader, without writing it to a file first. Reads seek the
+/// source to where they need, so it can't be shared with other users.
+pub fn open_reader<R: Read + Seek + Send + 'static>(r: R) -> Result<GzReader> {
+    open_reader_with_options(r, ReaderOptions::default())
+}
+
+pub fn open_reader_with_options<R: Read + Seek + Send + 'static>(
+    r: R,
+    opts: ReaderOptions,
+) -> Result<GzReader> {
+    open_blob(Box::new(SeekReader::new(r)?), opts)
+}
+
+/// Open a blob held in memory, e.g. a small layer received over the
+/// network, without touching the disk.
+pub fn open_from_bytes(data: impl Into<Vec<u8>>) -> Result<GzReader> {
+    open_from_bytes_with_options(data, ReaderOptions::default())
+}
+
+pub fn open_from_bytes_with_options(
+    data: impl Into<Vec<u8>>,
+    opts: ReaderOptions,
+) -> Result<GzReader> {
+    open_blob(Box::new(data.into()), opts)
+}
+
+/// Open a blob whose TOC is shipped separately, as eStargz allows for
+/// footer-less layers, e.g. with the TOC in a registry blob of its own.
+/// `toc` holds either the TOC JSON or the gzipped tar member wrapping it,
+/// as found at the end of a regular blob. Its offsets are those of `input`,
+/// whose footer, if any, is ignored.
+pub fn open_with_external_toc(input: File, toc: impl Read) -> Result<GzReader> {
+    open_external_toc(Box::new(input), toc, ReaderOptions::default())
+}
+
+fn open_external_toc(input: Blob, mut toc: impl Read, opts: ReaderOptions) -> Result<GzReader> {
+    let mut raw = Vec::new();
+    toc.read_to_end(&mut raw).context("reading TOC")?;
+    let toc = if raw.starts_with(&[0x1f, 0x8b]) {
+        parse_toc_member(&raw)?
+    } else {
+        serde_json::from_slice(&raw).context(Error::corrupt("invalid TOC"))?
+    };
+    let size = input.size()?;
+    let footer = Footer {
+        blob_size: size,
+        toc_offset: size,
+        format: FooterFormat::External,
+    };
+    GzReader::from_toc(input, toc, footer, opts)
+}
+
+fn open_blob(input: Blob, opts: ReaderOptions) -> Result<GzReader> {
+    let footer = match read_footer(&*input) {
+        std::result::Result::Ok(footer) => footer,
+        Err(_) if opts.tar_gz_fallback && detect_format(&*input)? == BlobFormat::Gzip => {
+            return fallback::open_tar_gz(&*input, opts);
+        }
+        Err(e) => return Err(e),
+    };
+    let toc_offset = footer.toc_offset;
+    let toc_size = footer.toc_size();
+    let toc_size = usize::try_from(toc_size)
+        .map_err(|_| anyhow!("TOC size {toc_size} doesn't fit in memory on this platform"))?;
+    let mut toc_targz: Vec<u8> = vec![0; toc_size];
+
+    // Read the TOC which is a tar.gz file
+    input.read_exact_at(toc_targz.as_mut_slice(), toc_offset)?;
+    let toc = parse_toc_member(&toc_targz)?;
+
+    GzReader::from_toc(input, toc, footer, opts)
+}
+
+// The TOC out of its gzipped tar member
+fn parse_toc_member(toc_targz: &[u8]) -> Result<JToc> {
+    // Decompress gz
+    let tar = GzDecoder::new(toc_targz);
+
+    // Read tar
+    let mut archive = Archive::new(tar);
+    let mut header = archive
+        .entries()?
+        .next()
+        .ok_or_else(|| Error::corrupt("TOC member holds an empty tar"))??;
+    let header_name = String::from_utf8_lossy(&header.header().as_old().name);
+    if header_name.trim_end_matches('\0') != TOCT_TAR_NAME {
+        return Err(Error::corrupt(format!(
+            "header name {header_name}, doesn't match {TOCT_TAR_NAME}"
+        ))
+        .into());
+    }
+
+    // Parse the TOC straight out of the tar stream
+    let toc: JToc = serde_json::from_reader(BufReader::new(&mut header))
+        .context(Error::corrupt("invalid TOC"))?;
+
+    Ok(toc)
+}
+
+fn read_footer(input: &dyn ReadAt) -> Result<Footer> {
+    let size = input.size()?;
+
+    if size < FOOTER_SIZE.into() {
+        return Err(Error::corrupt("size too small").into());
+    }
+
+    let tail = read_footer_tail(input)?;
+    let (toc_offset, format) = find_footer(&tail).context(Error::corrupt("invalid footer"))?;
+    if toc_offset > size - format.size() {
+        return Err(
+            Error::corrupt(format!("TOC offset {toc_offset} is outside of the blob")).into(),
+        );
+    }
+
+    Ok(Footer {
+        blob_size: size,
+        toc_offset,
+        format,
+    })
+}
+
+// A path of an input tar as the TOC can hold it, which is only as UTF-8
+fn utf8_path(path: &[u8]) -> Result<String> {
+    String::from_utf8(path.to_vec()).map_err(|_| {
+        anyhow!(
+            "path \"{}\" isn't valid UTF-8, which the TOC needs",
+            path.escape_ascii()
+        )
+    })
+}
+
+// Normalize a path the way entries are keyed: relative to the root, without
+// `.` components, duplicate or trailing slashes. `..` is resolved lexically
+// and can't climb above the root, like path.Clean("/" + name) in Go.
+fn clean_entry_name(name: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+// Whether `chunk` holds the byte at `offset` of its file. The TOC is
+// untrusted, so the chunk may well start past it
+fn covers(chunk: &TocEntry, offset: u64) -> bool {
+    offset
+        .checked_sub(chunk.chunk_offset)
+        .is_some_and(|d| d < chunk.chunk_size)
+}
+
+// Entry name of a path given by a caller. TOC names are UTF-8, so a path
+// that isn't can't name anything in the layer.
+fn path_entry_name(path: &Path) -> Result<String> {
+    match path.to_str() {
+        Some(path) => Ok(clean_entry_name(path)),
+        None => {
+            Err(Error::new(ErrorKind::NotFound, format!("{} not found", path.display())).into())
+        }
+    }
+}
+
+// Entry name a symlink at `name` pointing to `target` refers to. Absolute
+// targets are relative to the layer root, not the host's.
+fn resolve_link_target(name: &str, target: &str) -> String {
+    if target.starts_with('/') {
+        clean_entry_name(target)
+    } else {
+        clean_entry_name(&format!("{}/{target}", parent_dir(name)))
+    }
+}
+
+// Entry types defined by the spec
+const TOC_ENTRY_TYPES: [&str; 8] = [
+    "dir", "reg", "symlink", "hardlink", "char", "block", "fifo", "chunk",
+];
+
+// Time formats seen in the wild besides RFC 3339: RFC 2822, ISO 8601 with
+// no timezone (taken as UTC) and plain Unix seconds.
+fn parse_lenient_time(s: &str) -> Option<chrono::DateTime<Utc>> {
+    if let std::result::Result::Ok(t) = chrono::DateTime::parse_from_rfc2822(s) {
+        return Some(t.into());
+    }
+    for fmt in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let std::result::Result::Ok(t) = chrono::NaiveDateTime::parse_from_str(s, fmt) {
+            return Some(t.and_utc());
+        }
+    }
+    s.parse::<i64>()
+        .ok()
+        .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+}
+
+// Xattr values are base64 strings per the spec (Go's JSON encoding of
+// []byte); plain byte arrays are accepted as well.
+fn deserialize_xattrs<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<HashMap<String, Vec<u8>>, D::Error> {
+    use base64::Engine;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Base64(String),
+        Bytes(Vec<u8>),
+    }
+
+    let raw = Option::<HashMap<String, Value>>::deserialize(d)?.unwrap_or_default();
+    raw.into_iter()
+        .map(|(k, v)| match v {
+            Value::Base64(s) => base64::engine::general_purpose::STANDARD
+                .decode(s)
+                .map(|v| (k, v))
+                .map_err(serde::de::Error::custom),
+            Value::Bytes(b) => std::result::Result::Ok((k, b)),
+        })
+        .collect()
+}
+
+fn serialize_xattrs<S: serde::Serializer>(
+    xattrs: &HashMap<String, Vec<u8>>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    use base64::Engine;
+    use serde::ser::SerializeMap;
+
+    // Sorted so the same entry always serializes to the same bytes
+    let mut sorted: Vec<_> = xattrs.iter().collect();
+    sorted.sort();
+    let mut map = s.serialize_map(Some(sorted.len()))?;
+    for (k, v) in sorted {
+        map.serialize_entry(k, &base64::engine::general_purpose::STANDARD.encode(v))?;
+    }
+    map.end()
+}
+
+fn is_zero<T: Default + PartialEq>(n: &T) -> bool {
+    *n == T::default()
+}
+
+// Parent directory of a cleaned entry name, "" being the root
+fn parent_dir(name: &str) -> &str {
+    match name.rfind('/') {
+        Some(i) => &name[..i],
+        None => "",
+    }
+}
+
+fn base_name(name: &str) -> &str {
+    match name.rfind('/') {
+        Some(i) => &name[i + 1..],
+        None => name,
+    }
+}
+
+// Footer of the given flavor: an empty gzip member whose extra field holds
+// the TOC offset, byte for byte what CRFS or the Go estargz package writes.
+fn write_footer(w: &mut dyn Write, toc_offset: u64, format: FooterFormat) -> io::Result<()> {
+    let mut extra = Vec::with_capacity(ESTARGZ_SUBFIELD.len() + 22);
+    if format == FooterFormat::Estargz {
+        extra.extend_from_slice(ESTARGZ_SUBFIELD);
+    }
+    extra.extend_from_slice(format!("{toc_offset:016x}STARGZ").as_bytes());
+    let mut footer = Vec::with_capacity(format.size() as usize);
+    footer.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, GZIP_OS_UNKNOWN]);
+    footer.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    footer.extend_from_slice(&extra);
+    // An empty final stored block, then the CRC and size of no data
+    footer.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    footer.extend_from_slice(&[0; 8]);
+    w.write_all(&footer)
+}
+
+// The serialized TOC as a gzip member holding a tar with the single
+// stargz.index.json entry. Returns the uncompressed tar.
+fn write_toc_member(w: &mut dyn Write, json: &[u8], level: Compression) -> Result<Vec<u8>> {
+    let mut h = tar::Header::new_ustar();
+    h.set_path(TOCT_TAR_NAME)?;
+    h.set_size(json.len() as u64);
+    h.set_mode(0o644);
+    h.set_entry_type(tar::EntryType::Regular);
+    h.set_cksum();
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append(&h, json)?;
+    let tar = builder.into_inner()?;
+    let mut gz = gz_member_encoder(w, level);
+    gz.write_all(&tar)?;
+    gz.finish()?;
+
+    Ok(tar)
+}
+
+// The last bytes of a blob, as many as the longest footer takes
+fn read_footer_tail(input: &(impl ReadAt + ?Sized)) -> io::Result<Vec<u8>> {
+    let size = input.size()?;
+    let len = size.min(ESTARGZ_FOOTER_SIZE.into());
+    let mut tail = vec![0; len as usize];
+    input.read_exact_at(&mut tail, size - len)?;
+    io::Result::Ok(tail)
+}
+
+// TOC offset and flavor of the footer `tail`, the last bytes of a blob (at
+// least the longest footer's worth, when the blob is that long), ends with
+fn find_footer(tail: &[u8]) -> Result<(u64, FooterFormat)> {
+    let estargz = tail
+        .len()
+        .checked_sub(ESTARGZ_FOOTER_SIZE as usize)
+        .map(|start| parse_footer(&tail[start..]));
+    if let Some(std::result::Result::Ok(footer)) = estargz {
+        return Ok(footer);
+    }
+    // Errors are reported for the older, more common flavor
+    let start = tail
+        .len()
+        .checked_sub(FOOTER_SIZE as usize)
+        .ok_or_else(|| anyhow!("{} bytes is too small for a footer", tail.len()))?;
+    parse_footer(&tail[start..])
+}
+
+// TOC offset and flavor of a footer, `content` being exactly its bytes: an
+// empty gzip member whose extra field holds the offset as 16 hex digits and
+// `STARGZ`, either as the whole field (stargz) or in an `SG` subfield
+// (eStargz)
+fn parse_footer(content: &[u8]) -> Result<(u64, FooterFormat)> {
+    let gz = GzDecoder::new(content);
+    let extra = gz
+        .header()
+        .and_then(|h| h.extra())
+        .ok_or_else(|| anyhow!("footer has no gzip extra field"))?;
+    let (payload, format) = match extra.strip_prefix(ESTARGZ_SUBFIELD) {
+        Some(payload) => (payload, FooterFormat::Estargz),
+        None => (extra, FooterFormat::Stargz),
+    };
+    if payload.len() != 16 + "STARGZ".len() || !payload.ends_with(b"STARGZ") {
+        return Err(anyhow!("footer extra field isn't a TOC offset and STARGZ"));
+    }
+    if !payload[..16].iter().all(u8::is_ascii_hexdigit) {
+        return Err(anyhow!("footer TOC offset isn't hexadecimal"));
+    }
+    if content.len() as u64 != format.size() {
+        return Err(anyhow!(
+            "{format:?} footer is {} bytes, not {}",
+            format.size(),
+            content.len()
+        ));
+    }
+    let toc_offset = u64::from_str_radix(std::str::from_utf8(&payload[..16])?, 16)?;
+
+    Ok((toc_offset, format))
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JToc {
+    version: u32,
+    entries: Vec<TocEntry>,
+}
+
+impl JToc {
+    pub fn new(version: u32) -> Self {
+        Self {
+            version,
+            entries: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct TocEntry {
+    name: String,
+
+    #[serde(rename(serialize = "type", deserialize = "type"))]
+    entry_type: String,
+
+    #[serde(default, skip_serializing_if = "is_zero")]
+    size: u64,
+
+    #[serde(
+        rename = "modtime",
+        alias = "mod_time_3339",
+        skip_serializing_if = "Option::is_none"
+    )]
+    mod_time_3339: Option<String>,
+    #[serde(skip)]
+    mod_time: Option<chrono::DateTime<Utc>>,
+
+    #[serde(default)]
+    mode: u32,
+
+    #[serde(default, rename = "linkName", skip_serializing_if = "String::is_empty")]
+    link_name: String,
+
+    #[serde(default)]
+    uid: u32,
+    #[serde(default)]
+    gid: u32,
+
+    #[serde(
+        default,
+        rename = "userName",
+        alias = "uname",
+        skip_serializing_if = "String::is_empty"
+    )]
+    uname: String,
+    #[serde(
+        default,
+        rename = "groupName",
+        alias = "gname",
+        skip_serializing_if = "String::is_empty"
+    )]
+    gname: String,
+
+    #[serde(default, skip_serializing_if = "is_zero")]
+    offset: u64,
+
+    #[serde(skip)]
+    next_offset: u64,
+
+    // Where the compressed content ends, only in zstd:chunked manifests
+    #[serde(default, rename = "endOffset", skip_serializing_if = "is_zero")]
+    end_offset: u64,
+
+    #[serde(default, rename = "devMajor", skip_serializing_if = "is_zero")]
+    dev_major: u64,
+
+    #[serde(default, rename = "devMinor", skip_serializing_if = "is_zero")]
+    dev_minor: u64,
+
+    #[serde(
+        default,
+        rename(serialize = "NumLink", deserialize = "NumLink"),
+        skip_serializing_if = "is_zero"
+    )]
+    num_link: u32,
+
+    #[serde(
+        default,
+        deserialize_with = "deserialize_xattrs",
+        serialize_with = "serialize_xattrs",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    xattrs: HashMap<String, Vec<u8>>,
+
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    digest: String,
+
+    #[serde(
+        default,
+        rename = "chunkDigest",
+        skip_serializing_if = "String::is_empty"
+    )]
+    chunk_digest: String,
+
+    #[serde(default, rename = "chunkOffset", skip_serializing_if = "is_zero")]
+    chunk_offset: u64,
+    // Where the content starts in the uncompressed gzip member at `offset`,
+    // when several small files share one
+    #[serde(default, rename = "innerOffset", skip_serializing_if = "is_zero")]
+    inner_offset: u64,
+    #[serde(default, rename = "chunkSize", skip_serializing_if = "is_zero")]
+    chunk_size: u64,
+
+    // (offset, length) of the regions of a sparse file holding data,
+    // everything else is a hole no chunk covers. Empty for regular files.
+    #[serde(default, rename = "sparseMap", skip_serializing_if = "Vec::is_empty")]
+    sparse_map: Vec<(u64, u64)>,
+
+    // Base name -> full path of the child, resolved through the reader
+    #[serde(skip)]
+    children: HashMap<String, String>,
+}
+
+impl TocEntry {
+    /// Full path of the entry in the layer, cleaned: no leading `./` or `/`,
+    /// no trailing slash, "" for the root.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// `dir`, `reg`, `symlink`, `hardlink`, `char`, `block`, `fifo` or
+    /// `chunk`.
+    pub fn entry_type(&self) -> &str {
+        &self.entry_type
+    }
+
+    /// Size of a regular file's content, 0 for other entries.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Modification time as written in the TOC, before parsing.
+    pub fn mod_time_3339(&self) -> Option<&str> {
+        self.mod_time_3339.as_deref()
+    }
+
+    pub fn mod_time(&self) -> Option<chrono::DateTime<Utc>> {
+        self.mod_time
+    }
+
+    /// Permission and mode bits as stored in the TOC.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Target of a symlink or hardlink, "" for other entries.
+    pub fn link_name(&self) -> &str {
+        &self.link_name
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn uname(&self) -> &str {
+        &self.uname
+    }
+
+    pub fn gname(&self) -> &str {
+        &self.gname
+    }
+
+    /// Blob offset of the gzip member holding the file's first chunk (or
+    /// this chunk, for `chunk` entries), 0 when there is no content.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn dev_major(&self) -> u64 {
+        self.dev_major
+    }
+
+    pub fn dev_minor(&self) -> u64 {
+        self.dev_minor
+    }
+
+    /// Number of names referencing the entry: hardlinks to it, plus
+    /// subdirectories for a directory.
+    pub fn num_link(&self) -> u32 {
+        self.num_link
+    }
+
+    pub fn xattrs(&self) -> &HashMap<String, Vec<u8>> {
+        &self.xattrs
+    }
+
+    /// `sha256:<hex>` of a regular file's whole content, "" if the TOC has
+    /// none.
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+
+    /// `sha256:<hex>` of the chunk's content, "" if the TOC has none.
+    pub fn chunk_digest(&self) -> &str {
+        &self.chunk_digest
+    }
+
+    /// Offset in the file of the chunk's content.
+    pub fn chunk_offset(&self) -> u64 {
+        self.chunk_offset
+    }
+
+    /// Length of the chunk's content. 0 in the TOC of an unchunked file is
+    /// normalized to the file size.
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    /// Offset of the content in the decompressed gzip member it's in,
+    /// non-zero when small files are packed in one member.
+    pub fn inner_offset(&self) -> u64 {
+        self.inner_offset
+    }
+
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        !self.sparse_map.is_empty()
+    }
+
+    /// Data regions of a sparse file as (offset, length) pairs.
+    pub fn sparse_map(&self) -> &[(u64, u64)] {
+        &self.sparse_map
+    }
+
+    /// Bytes actually stored for the file, which is less than its logical
+    /// size when it's sparse.
+    pub fn physical_size(&self) -> u64 {
+        if self.is_sparse() {
+            self.sparse_map.iter().map(|(_, len)| len).sum()
+        } else {
+            self.size
+        }
+    }
+
+    pub fn add_child(&mut self, child: &TocEntry, base_name: &str) {
+        if child.entry_type == "dir" {
+            self.num_link += 1;
+        }
+
+        self.children
+            .insert(base_name.to_owned(), child.name.clone());
+    }
+
+    /// Full path of the child named `base_name`, to be passed to
+    /// [`GzReader::lookup`].
+    pub fn lookup_child(&self, base_name: &str) -> Option<&str> {
+        self.children.get(base_name).map(String::as_str)
+    }
+
+    /// (base name, full path) of the children of a directory, sorted by
+    /// base name. Empty for anything but directories.
+    pub fn children(&self) -> impl Iterator<Item = (&str, &str)> {
+        let mut children: Vec<(&str, &str)> = self
+            .children
+            .iter()
+            .map(|(base_name, name)| (base_name.as_str(), name.as_str()))
+            .collect();
+        children.sort_unstable();
+        children.into_iter()
+    }
+
+    pub fn is_data_type(&self) -> bool {
+        self.entry_type == "reg" || self.entry_type == "chunk"
+    }
+}
+
+/// The `SOURCE_DATE_EPOCH` environment variable, if set, as defined by
+/// <https://reproducible-builds.org/specs/source-date-epoch/>. Pass it to
+/// [`Writer::with_mtime_clamp`].
+pub fn source_date_epoch() -> Result<Option<u64>> {
+    let Some(v) = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .filter(|v| !v.is_empty())
+    else {
+        return Ok(None);
+    };
+    let epoch = v
+        .trim()
+        .parse()
+        .map_err(|e| anyhow!("invalid SOURCE_DATE_EPOCH {v:?}: {e}"))?;
+
+    Ok(Some(epoch))
+}
+
+// Read until `buf` is full or EOF, returning how much was read. Unlike
+// read_exact, hitting EOF early isn't an error.
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            io::Result::Ok(0) => break,
+            io::Result::Ok(read) => n += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    io::Result::Ok(n)
+}
+
+// Counts the bytes read through it into a counter shared with the caller,
+// for readers handed over to something that takes ownership of them.
+struct CountingReader<R: Read> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        io::Result::Ok(n)
+    }
+}
+
+struct CountingWriterWrapper<W: Write>(Rc<RefCell<CountingWriter<W>>>);
+
+impl<W: Write> Write for CountingWriterWrapper<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (*self.0).borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (*self.0).borrow_mut().flush()
+    }
+}
+
+/// What the [`Writer`] does when an entry's path was already written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Fail the append that brings the duplicate in.
+    Error,
+    /// Drop the earlier entry from the TOC so only the new one is visible.
+    /// Its bytes are already in the blob and stay there.
+    Replace,
+    /// Keep both entries, readers resolve the path to the last one.
+    #[default]
+    KeepBoth,
+}
+
+/// Compression level of the data and TOC members written by a [`Writer`],
+/// see [`Writer::with_compression_level`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionLevel {
+    /// No compression: members hold stored deflate blocks, still valid
+    /// gzip. For content that's already compressed (media, archives), or
+    /// when pulls are fast enough that decompression is the bottleneck.
+    /// zstd has no such mode and uses level 1.
+    Store,
+    /// Level 1, several times faster than `Best` for somewhat larger blobs.
+    Fast,
+    /// Level 6, what gzip uses by default, or level 3 for zstd.
+    Default,
+    /// Level 9, or level 19 for zstd.
+    #[default]
+    Best,
+    /// A level from 1 to 9.
+    Level(u32),
+}
+
+impl CompressionLevel {
+    fn compression(self) -> Compression {
+        match self {
+            CompressionLevel::Store => Compression::none(),
+            CompressionLevel::Fast => Compression::fast(),
+            CompressionLevel::Default => Compression::default(),
+            CompressionLevel::Best => Compression::best(),
+            CompressionLevel::Level(level) => Compression::new(level),
+        }
+    }
+
+    fn zstd_level(self) -> i32 {
+        match self {
+            CompressionLevel::Store | CompressionLevel::Fast => 1,
+            CompressionLevel::Default => 3,
+            CompressionLevel::Best => 19,
+            CompressionLevel::Level(level) => level as i32,
+        }
+    }
+}
+
+const DEFAULT_CHUNK_SIZE: usize = 4 << 20;
+/// Smallest chunk size accepted by [`Writer::with_chunk_size`]. Below it the
+/// gzip header and trailer of every member outweigh what lazy reads save.
+pub const MIN_CHUNK_SIZE: usize = 4096;
+
+// (entries_done, bytes_in, bytes_out)
+type ProgressCallback<'a> = Box<dyn FnMut(u64, u64, u64) + 'a>;
+// Opens the output for the blob with the given index
+type NextBlob<'a, W> = Box<dyn FnMut(usize) -> io::Result<W> + 'a>;
+
+/// Summary of one blob produced by a [`Writer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobReport {
+    /// Compressed size of the blob
+    pub size: u64,
+    /// Number of TOC entries in the blob
+    pub entries: usize,
+    /// `sha256:<hex>` of the uncompressed blob, the diff ID of the layer in
+    /// the OCI image config.
+    pub diff_id: String,
+    /// `sha256:<hex>` of the stargz.index.json content, for the
+    /// `containerd.io/snapshot/stargz/toc.digest` annotation. For
+    /// zstd:chunked, of the compressed manifest, for the
+    /// `io.github.containers.zstd-chunked.manifest-checksum` one.
+    pub toc_digest: String,
+    /// With [`Writer::with_external_toc`], the TOC left out of the blob:
+    /// the gzipped tar holding stargz.index.json, or the compressed manifest
+    /// for zstd:chunked.
+    pub external_toc: Option<Vec<u8>>,
+}
+
+/// Metadata of an entry added with [`Writer::add_file`],
+/// [`Writer::add_dir`] or [`Writer::add_symlink`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryMeta {
+    /// Permission bits, setuid/setgid/sticky included.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub uname: String,
+    pub gname: String,
+    /// Modification time in seconds since the epoch.
+    pub mtime: u64,
+    pub xattrs: HashMap<String, Vec<u8>>,
+}
+
+/// A TOC entry as the [`Writer`] wrote it, see [`Writer::on_entry`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryEvent {
+    pub name: String,
+    /// TOC entry type: `reg`, `dir`, `symlink`...
+    pub entry_type: String,
+    /// Content size, holes included for sparse files.
+    pub size: u64,
+    /// Blob offset of the gzip member the content starts in.
+    pub member_offset: u64,
+    /// Offset of the content in the uncompressed tar stream of the blob.
+    pub tar_offset: u64,
+    /// `sha256:<hex>` of the content, for regular files.
+    pub digest: Option<String>,
+}
+
+/// A span of a regular file's content, see [`Writer::on_chunk`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkEvent {
+    pub name: String,
+    /// Offset of the chunk in the file.
+    pub chunk_offset: u64,
+    pub chunk_size: u64,
+    /// Blob offset of the gzip member the chunk starts in.
+    pub member_offset: u64,
+    /// Offset of the chunk in the uncompressed tar stream of the blob.
+    pub tar_offset: u64,
+    /// Offset of the chunk in the decompressed gzip member, see
+    /// [`Writer::with_min_chunk_size`].
+    pub inner_offset: u64,
+    /// `sha256:<hex>` of the chunk.
+    pub digest: String,
+}
+
+/// A gzip member the [`Writer`] finished, see [`Writer::on_member_close`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemberEvent {
+    /// Index of the blob the member is in, 0 unless the output is split.
+    pub blob: usize,
+    pub offset: u64,
+    pub compressed_size: u64,
+}
+
+type EntryHook<'a> = Box<dyn FnMut(&EntryEvent) + 'a>;
+type ChunkHook<'a> = Box<dyn FnMut(&ChunkEvent) + 'a>;
+type MemberHook<'a> = Box<dyn FnMut(&MemberEvent) + 'a>;
+
+struct SplitConfig<'a, W> {
+    max_blob_size: u64,
+    next_blob: NextBlob<'a, W>,
+}
+
+/// Builds a stargz blob, or a layer split over several, from tars and
+/// entries added by hand.
+///
+/// Every append and add call goes into the same layer: the headers and data
+/// of the entries are written without the end-of-archive blocks of their
+/// input, so the tar stream only ends once, when [`Writer::close`] finishes
+/// the blob.
+pub struct Writer<'a, W: Write> {
+    cw: Rc<RefCell<CountingWriter<W>>>,
+    gz: Option<MemberEncoder<CountingWriterWrapper<W>>>,
+    toc: JToc,
+    diff_hash: sha2::Sha256,
+    chunk_size: usize,
+    min_chunk_size: usize,
+    compression: Option<CompressionLevel>,
+    format: BlobFormat,
+    external_toc: bool,
+    threads: usize,
+    duplicate_policy: DuplicatePolicy,
+    // Cleaned names of the entries written so far
+    written: HashSet<String>,
+    on_progress: Option<ProgressCallback<'a>>,
+    split: Option<SplitConfig<'a, W>>,
+    // Content of the regular files written, kept while the output is split
+    link_copies: Option<LinkCopies>,
+    blobs: Vec<BlobReport>,
+    // Entry mtimes later than this (seconds since the epoch) are clamped
+    mtime_clamp: Option<u64>,
+    entries_done: u64,
+    // Input bytes consumed by previous append calls
+    bytes_in: u64,
+    on_entry: Option<EntryHook<'a>>,
+    on_chunk: Option<ChunkHook<'a>>,
+    on_member_close: Option<MemberHook<'a>>,
+    transforms: Vec<Box<dyn Transform + 'a>>,
+    // Cleaned paths of the entries to write first
+    prioritized: Option<Vec<String>>,
+    sort_entries: bool,
+    landmark_written: bool,
+    // Members of an earlier blob to copy instead of compressing chunks again
+    reuse: Option<ChunkReuse<'a>>,
+    // Blob offset where the open gzip member starts
+    member_offset: u64,
+    // Uncompressed bytes of tar stream written to the current blob
+    tar_offset: u64,
+    // Tar stream offset where the open gzip member starts
+    member_tar_offset: u64,
+    stats: WriterStats,
+    closed: bool,
+}
+
+impl<'a, W: Write> Writer<'a, W> {
+    // Accept a writer and build Writer from it
+    pub fn new(writer: W) -> Self {
+        let jtoc = JToc::new(1);
+        let cw = Rc::new(RefCell::new(CountingWriter::new(BufWriter::new(writer))));
+        Self {
+            cw,
+            gz: None,
+            toc: jtoc,
+            diff_hash: sha2::Digest::new(),
+            chunk_size: 0,
+            min_chunk_size: 0,
+            compression: None,
+            format: BlobFormat::Estargz,
+            external_toc: false,
+            threads: 1,
+            duplicate_policy: DuplicatePolicy::default(),
+            written: HashSet::new(),
+            on_progress: None,
+            split: None,
+            link_copies: None,
+            blobs: Vec::new(),
+            mtime_clamp: None,
+            entries_done: 0,
+            bytes_in: 0,
+            on_entry: None,
+            on_chunk: None,
+            on_member_close: None,
+            transforms: Vec::new(),
+            prioritized: None,
+            sort_entries: false,
+            landmark_written: false,
+            reuse: None,
+            member_offset: 0,
+            tar_offset: 0,
+            member_tar_offset: 0,
+            stats: WriterStats::default(),
+            closed: false,
+        }
+    }
+
+    /// Call `f(entries_done, bytes_in, bytes_out)` after each appended
+    /// entry: the number of entries written so far, the input bytes consumed
+    /// (compressed bytes for gzipped input) and the bytes of output produced.
+    /// Called once more by [`Writer::close`], with the TOC and footer
+    /// written.
+    pub fn on_progress(mut self, f: impl FnMut(u64, u64, u64) + 'a) -> Self {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Call `f` after each entry is written, with where its content landed
+    /// and its digest. Enough to build a side index (ztoc, dedup maps) in
+    /// the same pass as the blob.
+    pub fn on_entry(mut self, f: impl FnMut(&EntryEvent) + 'a) -> Self {
+        self.on_entry = Some(Box::new(f));
+        self
+    }
+
+    /// Call `f` for each chunk of regular file content written, after the
+    /// [`Writer::on_entry`] call for the file.
+    pub fn on_chunk(mut self, f: impl FnMut(&ChunkEvent) + 'a) -> Self {
+        self.on_chunk = Some(Box::new(f));
+        self
+    }
+
+    /// Call `f` whenever a gzip member of the tar stream is finished, with
+    /// its final position and size in the blob. The members of the TOC and
+    /// footer aren't reported.
+    pub fn on_member_close(mut self, f: impl FnMut(&MemberEvent) + 'a) -> Self {
+        self.on_member_close = Some(Box::new(f));
+        self
+    }
+
+    /// Rewrite every entry with `t` before it's written, after the
+    /// transforms added before it. See [`Transform`] for the built-in ones.
+    pub fn with_transform(mut self, t: impl Transform + 'a) -> Self {
+        self.transforms.push(Box::new(t));
+        self
+    }
+
+    /// Write the entries for `paths` first, in that order, each preceded by
+    /// its parent directories and hardlink target, and then a
+    /// `.prefetch.landmark` file, so runtimes can prefetch what a container
+    /// needs to start. A `.no.prefetch.landmark` is written first instead
+    /// if none of them is found.
+    ///
+    /// Each append call is reordered on its own, and the input is spooled
+    /// to a temporary file to do so. The landmark is only written by the
+    /// first one.
+    pub fn with_prioritized_files<S: AsRef<str>>(
+        mut self,
+        paths: impl IntoIterator<Item = S>,
+    ) -> Self {
+        let paths = paths
+            .into_iter()
+            .map(|p| clean_entry_name(p.as_ref()))
+            .collect();
+        self.prioritized = Some(paths);
+        self
+    }
+
+    /// Make the blobs depend only on what's in the input: entries are
+    /// written sorted by path, each after its parent directories and
+    /// hardlink target, rather than in input order, and with `epoch`,
+    /// modification times are clamped as with [`Writer::with_mtime_clamp`].
+    /// Gzip headers never carry a timestamp or the host OS, so the same
+    /// files and settings always give the same bytes.
+    ///
+    /// Prioritized files still come first, the rest is sorted. Like for
+    /// them, the input of each append call is spooled to a temporary file.
+    pub fn with_reproducible_output(mut self, epoch: Option<u64>) -> Self {
+        self.sort_entries = true;
+        if let Some(epoch) = epoch {
+            self.mtime_clamp = Some(epoch);
+        }
+        self
+    }
+
+    fn notify_entry(
+        &mut self,
+        ent: &TocEntry,
+        tar_offset: u64,
+        digest: String,
+        chunks: &[ChunkEvent],
+    ) {
+        let is_reg = ent.entry_type == "reg";
+        let member_offset = chunks
+            .first()
+            .map_or(self.member_offset, |c| c.member_offset);
+        if let Some(f) = self.on_entry.as_mut() {
+            f(&EntryEvent {
+                name: ent.name.clone(),
+                entry_type: ent.entry_type.clone(),
+                size: ent.size,
+                member_offset,
+                tar_offset,
+                digest: is_reg.then_some(digest),
+            });
+        }
+        if let Some(f) = self.on_chunk.as_mut() {
+            chunks.iter().for_each(f);
+        }
+    }
+
+    fn report_progress(&mut self, bytes_in: u64) {
+        if let Some(f) = self.on_progress.as_mut() {
+            let bytes_out = (*self.cw).borrow().count;
+            f(self.entries_done, bytes_in, bytes_out);
+        }
+    }
+
+    /// Spread the output over several blobs: once the current one reaches
+    /// `max_blob_size` compressed bytes, it's finished and the following
+    /// entries go to a new blob opened with `next_blob(index)`, index 1 being
+    /// the second blob. Blobs only ever split between entries, so one can
+    /// exceed the limit by up to an entry.
+    ///
+    /// Every blob is a layer of its own, so a hardlink to a file written to
+    /// an earlier blob is written as a copy of the file instead. To do so the
+    /// content of every regular file is kept in a temporary file until the
+    /// Writer is dropped.
+    pub fn with_split(
+        mut self,
+        max_blob_size: u64,
+        next_blob: impl FnMut(usize) -> io::Result<W> + 'a,
+    ) -> Self {
+        self.split = Some(SplitConfig {
+            max_blob_size,
+            next_blob: Box::new(next_blob),
+        });
+        self
+    }
+
+    /// Blobs finished so far, all of them once the Writer is closed.
+    pub fn blobs(&self) -> &[BlobReport] {
+        &self.blobs
+    }
+
+    /// `sha256:<hex>` of the uncompressed layer, for the `diff_ids` of the
+    /// image config, once the Writer is closed. None if the output was split
+    /// into several blobs, see [`BlobReport::diff_id`] for theirs.
+    pub fn diff_id(&self) -> Option<&str> {
+        match &self.blobs[..] {
+            [blob] if self.closed => Some(&blob.diff_id),
+            _ => None,
+        }
+    }
+
+    /// `sha256:<hex>` of the TOC JSON once the Writer is closed, to be set
+    /// as the `containerd.io/snapshot/stargz/toc.digest` annotation of the
+    /// layer. None if the output was split into several blobs, see
+    /// [`BlobReport::toc_digest`] for theirs.
+    pub fn toc_digest(&self) -> Option<&str> {
+        match &self.blobs[..] {
+            [blob] if self.closed => Some(&blob.toc_digest),
+            _ => None,
+        }
+    }
+
+    /// The TOC written apart from the blob once the Writer is closed, see
+    /// [`Writer::with_external_toc`]. None if the output was split into
+    /// several blobs, see [`BlobReport::external_toc`] for theirs.
+    pub fn external_toc(&self) -> Option<&[u8]> {
+        match &self.blobs[..] {
+            [blob] if self.closed => blob.external_toc.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Timings and compression ratios of what was written so far. The
+    /// compressed total only covers finished blobs, so it's complete once
+    /// the Writer is closed.
+    pub fn stats(&self) -> &WriterStats {
+        &self.stats
+    }
+
+    // Move on to a new blob if the current one is full. Called before an
+    // entry is written, so no blob ends up empty.
+    fn maybe_split(&mut self) -> Result<()> {
+        let Some(split) = &self.split else {
+            return Ok(());
+        };
+        if self.toc.entries.is_empty() || (*self.cw).borrow().count < split.max_blob_size {
+            return Ok(());
+        }
+        self.finish_blob()?;
+
+        let index = self.blobs.len();
+        let split = self.split.as_mut().unwrap();
+        let writer = (split.next_blob)(index)?;
+        self.cw = Rc::new(RefCell::new(CountingWriter::new(BufWriter::new(writer))));
+        self.toc = JToc::new(1);
+        self.written.clear();
+        if let Some(copies) = self.link_copies.as_mut() {
+            copies.copied.clear();
+        }
+        self.tar_offset = 0;
+
+        Ok(())
+    }
+
+    // Terminate the current blob with its TOC and footer, and record it
+    fn finish_blob(&mut self) -> Result<()> {
+        self.close_gz()?;
+        if self.format == BlobFormat::ZstdChunked || self.external_toc {
+            // There's no TOC entry in the tar stream to end it
+            self.write_tar(&[0; 1024])?;
+            self.close_gz()?;
+        }
+        let json = serde_json::to_vec(&self.toc)?;
+        let (toc, toc_digest) = match self.codec() {
+            MemberCodec::Zstd(level) => {
+                let manifest = zstd::encode_all(&json[..], level)?;
+                let digest = format!(
+                    "sha256:{:x}",
+                    <sha2::Sha256 as sha2::Digest>::digest(&manifest)
+                );
+                (manifest, digest)
+            }
+            MemberCodec::Gzip(level) => {
+                let mut member = Vec::new();
+                let toc_tar = write_toc_member(&mut member, &json, level)?;
+                if !self.external_toc {
+                    sha2::Digest::update(&mut self.diff_hash, &toc_tar);
+                }
+                let digest = format!("sha256:{:x}", <sha2::Sha256 as sha2::Digest>::digest(&json));
+                (member, digest)
+            }
+        };
+        let mut external_toc = None;
+        {
+            let mut cw = (*self.cw).borrow_mut();
+            let toc_offset = cw.position();
+            match self.format {
+                _ if self.external_toc => external_toc = Some(toc),
+                BlobFormat::ZstdChunked => {
+                    zstdchunked::write_manifest(&mut *cw, toc_offset, &toc, json.len())?
+                }
+                BlobFormat::Stargz => {
+                    cw.write_all(&toc)?;
+                    write_footer(&mut *cw, toc_offset, FooterFormat::Stargz)?;
+                }
+                _ => {
+                    cw.write_all(&toc)?;
+                    write_footer(&mut *cw, toc_offset, FooterFormat::Estargz)?;
+                }
+            }
+            cw.flush()?;
+        }
+        let diff_id = format!(
+            "sha256:{:x}",
+            sha2::Digest::finalize_reset(&mut self.diff_hash)
+        );
+        self.stats.compressed_bytes += (*self.cw).borrow().count;
+        self.blobs.push(BlobReport {
+            size: (*self.cw).borrow().count,
+            entries: self.toc.entries.len(),
+            diff_id,
+            toc_digest,
+            external_toc,
+        });
+
+        Ok(())
+    }
+
+    /// Clamp entry modification times to at most `epoch` seconds, in both
+    /// the tar headers and the TOC, for reproducible builds. See
+    /// [`source_date_epoch`] to honor `SOURCE_DATE_EPOCH`.
+    pub fn with_mtime_clamp(mut self, epoch: u64) -> Self {
+        self.mtime_clamp = Some(epoch);
+        self
+    }
+
+    fn clamp_mtime(&self, mtime: u64) -> u64 {
+        match self.mtime_clamp {
+            Some(clamp) => mtime.min(clamp),
+            None => mtime,
+        }
+    }
+
+    pub fn with_duplicate_policy(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = policy;
+        self
+    }
+
+    // Apply the duplicate policy before an entry named `name` is written
+    fn check_duplicate(&mut self, name: &str) -> Result<()> {
+        let name = clean_entry_name(name);
+        if self.written.contains(&name) {
+            match self.duplicate_policy {
+                DuplicatePolicy::Error => {
+                    return Err(anyhow!("duplicate entry {name}"));
+                }
+                DuplicatePolicy::Replace => {
+                    self.toc
+                        .entries
+                        .retain(|e| clean_entry_name(&e.name) != name);
+                }
+                DuplicatePolicy::KeepBoth => {}
+            }
+        }
+        self.written.insert(name);
+
+        Ok(())
+    }
+
+    /// Start a new gzip member every `chunk_size` bytes of a regular file,
+    /// 4 MiB by default. Smaller chunks make lazy reads fetch less for each
+    /// access at the cost of compression ratio and a larger TOC. Fails below
+    /// [`MIN_CHUNK_SIZE`].
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Result<Self> {
+        if chunk_size < MIN_CHUNK_SIZE {
+            return Err(anyhow!(
+                "chunk size {chunk_size} is below the minimum of {MIN_CHUNK_SIZE} bytes"
+            ));
+        }
+        self.chunk_size = chunk_size;
+        Ok(self)
+    }
+
+    /// Pack regular files into the open gzip member until it holds
+    /// `min_chunk_size` uncompressed bytes, rather than starting a member
+    /// for each one. Their TOC entries share the member's offset and record
+    /// where they start in it as `innerOffset`. Layers of many small files
+    /// compress much better, at the cost of reading a whole member to get
+    /// one of them. zstd:chunked has no inner offsets, this is ignored
+    /// there.
+    pub fn with_min_chunk_size(mut self, min_chunk_size: usize) -> Self {
+        self.min_chunk_size = min_chunk_size;
+        self
+    }
+
+    /// Compress with `level` instead of [`CompressionLevel::Best`], or
+    /// [`CompressionLevel::Default`] for zstd:chunked. Fails for a
+    /// [`CompressionLevel::Level`] outside of 1 to 9.
+    pub fn with_compression_level(mut self, level: CompressionLevel) -> Result<Self> {
+        if let CompressionLevel::Level(n) = level {
+            if !(1..=9).contains(&n) {
+                return Err(anyhow!("compression level {n} isn't between 1 and 9"));
+            }
+        }
+        self.compression = Some(level);
+        Ok(self)
+    }
+
+    fn codec(&self) -> MemberCodec {
+        match self.format {
+            BlobFormat::ZstdChunked => MemberCodec::Zstd(
+                self.compression
+                    .unwrap_or(CompressionLevel::Default)
+                    .zstd_level(),
+            ),
+            _ => MemberCodec::Gzip(self.compression.unwrap_or_default().compression()),
+        }
+    }
+
+    /// Leave the TOC and footer out of the blobs, which end with the data
+    /// and then the end of the tar stream, and return the TOC in
+    /// [`BlobReport::external_toc`] instead, e.g. to push it as an artifact
+    /// referring to the layer. Such blobs are opened with
+    /// [`open_with_external_toc`], and their diff ID doesn't cover the TOC.
+    pub fn with_external_toc(mut self) -> Self {
+        self.external_toc = true;
+        self
+    }
+
+    /// Copy the gzip member of a chunk from `previous`, an earlier blob,
+    /// when it holds the same content, rather than compressing the chunk
+    /// again: rebuilding a layer where few files changed mostly costs
+    /// reading them. Chunks are matched by digest, so `previous` should
+    /// have been written with the same chunk size.
+    ///
+    /// Only chunks with a member to themselves can be reused, which is every
+    /// chunk of a file but its last, and nothing with zstd:chunked output.
+    /// A member is checked to decompress to the chunk before it's copied,
+    /// and copied as it is, compression level included.
+    pub fn with_chunk_reuse(mut self, previous: &'a GzReader) -> Self {
+        self.reuse = Some(ChunkReuse {
+            members: previous.chunk_members(),
+            reader: previous,
+        });
+        self
+    }
+
+    /// Compress up to `threads` chunks of a file at once, each on a thread
+    /// of its own, 0 meaning one per CPU. Only chunks that get a member to
+    /// themselves are compressed in parallel, so it pays off for large
+    /// files; the output is the same as with a single thread. Up to twice
+    /// `threads` chunks are held in memory.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = match threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        self
+    }
+
+    /// Write blobs in `format`: [`BlobFormat::Estargz`] by default,
+    /// [`BlobFormat::Stargz`] for the footer of the original stargz
+    /// readers, or [`BlobFormat::ZstdChunked`] for the zstd:chunked layers
+    /// of podman and containers/storage, with zstd frames and a manifest in
+    /// place of gzip members and the TOC. Fails for the other formats.
+    pub fn with_format(mut self, format: BlobFormat) -> Result<Self> {
+        match format {
+            BlobFormat::Stargz | BlobFormat::Estargz | BlobFormat::ZstdChunked => {
+                self.format = format;
+                Ok(self)
+            }
+            _ => Err(anyhow!("can't write {format:?} blobs")),
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        if self.chunk_size == 0 {
+            return DEFAULT_CHUNK_SIZE;
+        }
+
+        self.chunk_size
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.finish_blob()?;
+        self.report_progress(self.bytes_in);
+
+        self.closed = true;
+
+        Ok(())
+    }
+
+    // Write the next `count` chunks of a regular file, from `chunk_offset`,
+    // each in a member of its own, compressed in parallel unless one can be
+    // reused, see with_chunk_reuse
+    fn write_chunks_parallel(
+        &mut self,
+        ent: &TocEntry,
+        r: &mut dyn Read,
+        chunk_offset: u64,
+        count: u64,
+        size: u64,
+    ) -> Result<Vec<(ChunkEvent, u64)>> {
+        self.close_gz()?;
+        let chunk_size = self.chunk_size() as u64;
+        let mut data = Vec::new();
+        for i in 0..count {
+            let offset = chunk_offset + i * chunk_size;
+            let len = chunk_size.min(size - offset);
+            let mut chunk = Vec::with_capacity(len as usize);
+            let read = (&mut *r).take(len).read_to_end(&mut chunk)?;
+            if read as u64 != len {
+                return Err(anyhow!(
+                    "{}: content ends after {} of {size} bytes",
+                    ent.name,
+                    offset + read as u64
+                ));
+            }
+            data.push(chunk);
+        }
+
+        let codec = self.codec();
+        let reuse = match codec {
+            MemberCodec::Gzip(_) => self.reuse.as_ref(),
+            MemberCodec::Zstd(_) => None,
+        };
+        let member_of = |chunk: &[u8]| {
+            let digest = format!("sha256:{:x}", <sha2::Sha256 as sha2::Digest>::digest(chunk));
+            match reuse.and_then(|reuse| reuse.member(&digest, chunk)) {
+                Some(member) => io::Result::Ok((member, digest, true)),
+                None => codec.compress(chunk).map(|member| (member, digest, false)),
+            }
+        };
+        let compressed = match &data[..] {
+            [chunk] => vec![member_of(chunk)?],
+            _ => std::thread::scope(|scope| {
+                let member_of = &member_of;
+                let handles: Vec<_> = data
+                    .iter()
+                    .map(|chunk| scope.spawn(move || member_of(chunk)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().expect("compression thread panicked"))
+                    .collect::<io::Result<Vec<_>>>()
+            })?,
+        };
+
+        let mut chunks = Vec::new();
+        for (i, (chunk, (member, digest, reused))) in data.iter().zip(compressed).enumerate() {
+            if reused {
+                self.stats.reused_chunks += 1;
+            }
+            self.member_offset = (*self.cw).borrow().position();
+            self.member_tar_offset = self.tar_offset;
+            (*self.cw).borrow_mut().write_all(&member)?;
+            sha2::Digest::update(&mut self.diff_hash, chunk);
+            let event = ChunkEvent {
+                name: ent.name.clone(),
+                chunk_offset: chunk_offset + i as u64 * chunk_size,
+                chunk_size: chunk.len() as u64,
+                member_offset: self.member_offset,
+                tar_offset: self.tar_offset,
+                inner_offset: 0,
+                digest,
+            };
+            self.tar_offset += chunk.len() as u64;
+            self.member_closed();
+            let end_offset = match codec {
+                MemberCodec::Zstd(_) => (*self.cw).borrow().position(),
+                MemberCodec::Gzip(_) => 0,
+            };
+            chunks.push((event, end_offset));
+        }
+        Ok(chunks)
+    }
+
+    fn cond_open_gz(&mut self) -> Result<()> {
+        if self.gz.is_none() {
+            let gz = self
+                .codec()
+                .encoder(CountingWriterWrapper(self.cw.clone()))?;
+            self.gz = Some(gz);
+            self.member_offset = (*self.cw).borrow().position();
+            self.member_tar_offset = self.tar_offset;
+        }
+
+        Ok(())
+    }
+
+    fn close_gz(&mut self) -> Result<()> {
+        if self.closed {
+            return Err(anyhow!("Writer is closed"));
+        }
+        if let Some(gz) = self.gz.take() {
+            let mut gz = gz.finish()?;
+            gz.flush()?;
+            self.member_closed();
+        }
+
+        Ok(())
+    }
+
+    fn member_closed(&mut self) {
+        self.stats.members += 1;
+        if let Some(f) = self.on_member_close.as_mut() {
+            f(&MemberEvent {
+                blob: self.blobs.len(),
+                offset: self.member_offset,
+                compressed_size: (*self.cw).borrow().position() - self.member_offset,
+            });
+        }
+    }
+
+    // Write tar stream bytes to the open gzip member, opening one if needed
+    fn write_tar(&mut self, buf: &[u8]) -> Result<()> {
+        self.cond_open_gz()?;
+        self.gz.as_mut().unwrap().write_all(buf)?;
+        sha2::Digest::update(&mut self.diff_hash, buf);
+        self.tar_offset += buf.len() as u64;
+
+        Ok(())
+    }
+
+    // Write the `size` bytes of an entry's content read from `r`, padding
+    // left to the caller. Regular files start a new gzip member every
+    // chunk_size() bytes so each chunk can be decompressed on its own, and
+    // `ent` gets where the first one is and its digest. Returns the chunks
+    // written, with where their zstd frame ends for zstd:chunked, which
+    // gives each chunk a frame of its own.
+    fn write_content(
+        &mut self,
+        ent: &mut TocEntry,
+        size: u64,
+        r: &mut dyn Read,
+    ) -> Result<Vec<(ChunkEvent, u64)>> {
+        self.write_regions(ent, &[(0, size)], r)
+    }
+
+    // Like write_content, for content that is the data `regions` of a file,
+    // (offset, length) pairs read back to back from `r`: chunks don't cross
+    // from one region to the next, and have their offset in the file.
+    fn write_regions(
+        &mut self,
+        ent: &mut TocEntry,
+        regions: &[(u64, u64)],
+        r: &mut dyn Read,
+    ) -> Result<Vec<(ChunkEvent, u64)>> {
+        let mut chunks = Vec::new();
+        let is_reg = ent.entry_type == "reg";
+        let own_frames = is_reg && self.format == BlobFormat::ZstdChunked;
+        let chunk_size = if is_reg {
+            self.chunk_size() as u64
+        } else {
+            regions.iter().map(|(_, len)| len).sum()
+        };
+        for (i, &(start, region_len)) in regions.iter().enumerate() {
+            let end = start + region_len;
+            let later: u64 = regions[i + 1..]
+                .iter()
+                .map(|(_, len)| len.div_ceil(chunk_size))
+                .sum();
+            let mut chunk_offset = start;
+            while chunk_offset < end {
+                let len = chunk_size.min(end - chunk_offset);
+                let tar_offset = self.tar_offset;
+                let member_size = self.tar_offset - self.member_tar_offset;
+                // Chunks that get a member to themselves: all of them with
+                // zstd, else all but the last, which the padding and next
+                // header follow, unless chunks are packed
+                let min_chunk_size = self.min_chunk_size as u64;
+                let here = (end - chunk_offset).div_ceil(chunk_size);
+                let standalone = match here + later {
+                    left if own_frames => left,
+                    left if member_size >= min_chunk_size && chunk_size >= min_chunk_size => {
+                        left - 1
+                    }
+                    _ => 0,
+                };
+                let reusing = self.reuse.is_some() && !own_frames;
+                if is_reg && (self.threads > 1 && standalone > 1 || reusing && standalone > 0) {
+                    let count = standalone.min(self.threads as u64).min(here);
+                    let batch = self.write_chunks_parallel(ent, r, chunk_offset, count, end)?;
+                    chunk_offset += batch.iter().map(|(c, _)| c.chunk_size).sum::<u64>();
+                    chunks.extend(batch);
+                    continue;
+                }
+                // Offset 0 means no content in TOCs, so nothing is packed
+                // into the first member
+                if is_reg
+                    && (own_frames
+                        || member_size >= self.min_chunk_size as u64
+                        || self.member_offset == 0)
+                {
+                    self.close_gz()?;
+                }
+                self.cond_open_gz()?;
+                let mut chunk = DigestReader::new((&mut *r).take(len));
+                let mut out = DigestWriter {
+                    inner: self.gz.as_mut().unwrap(),
+                    hasher: &mut self.diff_hash,
+                };
+                let copied = io::copy(&mut chunk, &mut out)?;
+                self.tar_offset += copied;
+                if copied != len {
+                    return Err(anyhow!(
+                        "{}: content ends after {} of {end} bytes",
+                        ent.name,
+                        chunk_offset + copied
+                    ));
+                }
+                if is_reg {
+                    let event = ChunkEvent {
+                        name: ent.name.clone(),
+                        chunk_offset,
+                        chunk_size: len,
+                        member_offset: self.member_offset,
+                        tar_offset,
+                        inner_offset: tar_offset - self.member_tar_offset,
+                        digest: chunk.finish(),
+                    };
+                    let mut end_offset = 0;
+                    if own_frames {
+                        self.close_gz()?;
+                        end_offset = (*self.cw).borrow().position();
+                    }
+                    chunks.push((event, end_offset));
+                }
+                chunk_offset += len;
+            }
+        }
+        if let Some((first, end_offset)) = chunks.first() {
+            ent.offset = first.member_offset;
+            ent.end_offset = *end_offset;
+            ent.inner_offset = first.inner_offset;
+            ent.chunk_digest = first.digest.clone();
+            // Left out when the chunk is the whole file
+            if chunks.len() > 1 || first.chunk_size != ent.size {
+                ent.chunk_offset = first.chunk_offset;
+                ent.chunk_size = first.chunk_size;
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Append the entries of the tar or tar.gz read from `r`. The input is
+    /// read once from front to back, so it can be a pipe or a download: file
+    /// content goes straight from the tar stream into the compressor, a chunk
+    /// at a time, and is never held whole. Only reordering entries, with
+    /// [`Writer::with_prioritized_files`] or
+    /// [`Writer::with_reproducible_output`], needs the whole input, which is
+    /// spooled to a temporary file rather than memory.
+    pub fn append_tar(&mut self, r: &mut dyn Read) -> Result<()> {
+        self.append_tar_with_filter(r, &mut EntryFilter::default())
+    }
+
+    /// Like [`Writer::append_tar`], but only the entries accepted by
+    /// `filter` are written, so caches, logs or secrets can be dropped from
+    /// the source tar on the way through.
+    pub fn append_tar_with_filter(
+        &mut self,
+        r: &mut dyn Read,
+        filter: &mut EntryFilter,
+    ) -> Result<()> {
+        let consumed = Rc::new(Cell::new(0));
+        let mut input = tar_stream(r, &consumed)?;
+        if self.prioritized.is_some() || self.sort_entries {
+            let mut spool = spool_file()?;
+            io::copy(&mut input, &mut spool)?;
+            let prioritized = self.prioritized.take();
+            let appended = self.append_reordered(&spool, prioritized.as_deref(), filter, &consumed);
+            self.prioritized = prioritized;
+            appended?;
+        } else {
+            let recording = Rc::new(RefCell::new(Recording::tail()));
+            let mut tar = Archive::new(TarRecorder {
+                inner: input,
+                recording: recording.clone(),
+            });
+            for entry in tar.entries()? {
+                if self.append_entry(&mut entry?, filter, &recording)? {
+                    self.report_progress(self.bytes_in + consumed.get());
+                }
+            }
+            // Read what follows the end of the archive, so the process
+            // writing to a pipe doesn't fail on a closed one
+            io::copy(&mut tar.into_inner().inner, &mut io::sink())?;
+        }
+        self.bytes_in += consumed.get();
+
+        Ok(())
+    }
+
+    // Append `spool`, a tar, writing the entries for `prioritized` first
+    // and then a landmark, and the rest sorted if asked to
+    fn append_reordered(
+        &mut self,
+        spool: &File,
+        prioritized: Option<&[String]>,
+        filter: &mut EntryFilter,
+        consumed: &Cell<u64>,
+    ) -> Result<()> {
+        let size = spool.metadata()?.len();
+        // (offset where the entry's headers start, name, hardlink target)
+        let mut entries = Vec::new();
+        let mut end = 0;
+        // Only counts where the tar crate is, the data of a GNU sparse entry
+        // starting after its extension headers
+        let recording = Rc::new(RefCell::new(Recording {
+            paused: true,
+            ..Default::default()
+        }));
+        let mut tar = Archive::new(TarRecorder {
+            inner: BufReader::new(SectionReader::new(spool, 0, size)),
+            recording: recording.clone(),
+        });
+        for entry in tar.entries()? {
+            let f = entry?;
+            let name = clean_entry_name(&String::from_utf8_lossy(&f.path_bytes()));
+            let link = match f.header().entry_type() {
+                tar::EntryType::Link => f
+                    .link_name_bytes()
+                    .map(|l| clean_entry_name(&String::from_utf8_lossy(&l))),
+                _ => None,
+            };
+            entries.push((end, name, link));
+            end = recording.borrow().pos + f.header().entry_size()?.next_multiple_of(512);
+        }
+
+        let mut moved = vec![false; entries.len()];
+        if let Some(prioritized) = prioritized {
+            let first = ordered_entries(&entries, prioritized, &mut moved);
+            for &i in first.iter() {
+                if self.append_spooled(spool, entries[i].0, size, filter)? {
+                    self.report_progress(self.bytes_in + consumed.get());
+                }
+            }
+            if !self.landmark_written {
+                self.landmark_written = true;
+                match first.is_empty() {
+                    true => self.append_landmark(NO_PREFETCH_LANDMARK)?,
+                    false => self.append_landmark(PREFETCH_LANDMARK)?,
+                }
+            }
+        }
+        let rest = if self.sort_entries {
+            // By path component, so directories come before what's in them
+            let mut names: Vec<String> = (0..entries.len())
+                .filter(|&i| !moved[i])
+                .map(|i| entries[i].1.clone())
+                .collect();
+            names.sort_by(|a, b| a.split('/').cmp(b.split('/')));
+            names.dedup();
+            ordered_entries(&entries, &names, &mut moved)
+        } else {
+            (0..entries.len()).filter(|&i| !moved[i]).collect()
+        };
+        for i in rest {
+            if self.append_spooled(spool, entries[i].0, size, filter)? {
+                self.report_progress(self.bytes_in + consumed.get());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Append the entry whose headers start at `start` in `spool`
+    fn append_spooled(
+        &mut self,
+        spool: &File,
+        start: u64,
+        size: u64,
+        filter: &mut EntryFilter,
+    ) -> Result<bool> {
+        let recording = Rc::new(RefCell::new(Recording::tail()));
+        let mut tar = Archive::new(TarRecorder {
+            inner: SectionReader::new(spool, start, size - start),
+            recording: recording.clone(),
+        });
+        let mut entries = tar.entries()?;
+        let mut f = entries
+            .next()
+            .ok_or_else(|| anyhow!("no tar entry at offset {start} of the spooled input"))??;
+        self.append_entry(&mut f, filter, &recording)
+    }
+
+    /// Add a regular file at `path` with the content read from `r`, without
+    /// going through a tar. Content larger than a chunk is spooled to a
+    /// temporary file first, its size being needed before it's written.
+    ///
+    /// `path` is cleaned like TOC names, so `/etc/conf` and `./etc/conf`
+    /// both add `etc/conf`. Entries added by hand go through the same
+    /// transforms, mtime clamp and duplicate checks as those of
+    /// [`Writer::append_tar`], but are written right away: neither
+    /// prioritized files nor reproducible output reorder them.
+    pub fn add_file(&mut self, path: &str, r: &mut dyn Read, meta: &EntryMeta) -> Result<()> {
+        let mut head = Vec::new();
+        let limit = self.chunk_size() as u64;
+        let mut size = (&mut *r).take(limit).read_to_end(&mut head)? as u64;
+        let mut rest: Box<dyn Read> = Box::new(io::empty());
+        if size == limit {
+            let mut spool = spool_file()?;
+            size += io::copy(r, &mut spool)?;
+            spool.rewind()?;
+            rest = Box::new(BufReader::new(spool));
+        }
+        let new = NewEntry {
+            size,
+            ..NewEntry::by_hand(path, tar::EntryType::Regular, None, meta)
+        };
+        self.write_entry(new, &mut (&head[..]).chain(rest))?;
+        self.report_progress(self.bytes_in);
+
+        Ok(())
+    }
+
+    /// Add a directory at `path`, see [`Writer::add_file`].
+    pub fn add_dir(&mut self, path: &str, meta: &EntryMeta) -> Result<()> {
+        let new = NewEntry::by_hand(path, tar::EntryType::Directory, None, meta);
+        self.write_entry(new, &mut io::empty())?;
+        self.report_progress(self.bytes_in);
+
+        Ok(())
+    }
+
+    /// Add a symlink at `path` pointing to `target`, see
+    /// [`Writer::add_file`].
+    pub fn add_symlink(&mut self, path: &str, target: &str, meta: &EntryMeta) -> Result<()> {
+        let new = NewEntry::by_hand(path, tar::EntryType::Symlink, Some(target), meta);
+        self.write_entry(new, &mut io::empty())?;
+        self.report_progress(self.bytes_in);
+
+        Ok(())
+    }
+
+    /// Add a whiteout deleting `path` from the lower layers when the layer is
+    /// applied: an empty file named `.wh.<name>` next to it. Whiteouts are
+    /// root-owned with mode 0 and mtime 0, the clamp aside.
+    pub fn add_whiteout(&mut self, path: &str) -> Result<()> {
+        let name = clean_entry_name(path);
+        if name.is_empty() {
+            return Err(anyhow!("can't white out the root of the layer"));
+        }
+        let whiteout = match parent_dir(&name) {
+            "" => format!("{WHITEOUT_PREFIX}{}", base_name(&name)),
+            parent => format!("{parent}/{WHITEOUT_PREFIX}{}", base_name(&name)),
+        };
+        self.add_file(&whiteout, &mut io::empty(), &EntryMeta::default())
+    }
+
+    /// Add an opaque whiteout in the directory `path`, hiding what the lower
+    /// layers have in it while keeping the directory and the entries this
+    /// layer puts there: an empty `.wh..wh..opq` file inside it. The
+    /// directory itself should be in the layer too, see
+    /// [`Writer::add_whiteout`] for the metadata.
+    pub fn add_opaque_dir(&mut self, path: &str) -> Result<()> {
+        let name = clean_entry_name(path);
+        if name.is_empty() {
+            return Err(anyhow!("the root of the layer can't be opaque"));
+        }
+        self.add_file(
+            &format!("{name}/{OPAQUE_WHITEOUT}"),
+            &mut io::empty(),
+            &EntryMeta::default(),
+        )
+    }
+
+    /// Add everything under the directory `dir`, with paths relative to it,
+    /// as `tar -C dir .` would, without the intermediate tar. Entries are
+    /// written parents first and siblings sorted by name.
+    ///
+    /// Mode, numeric owner, mtime, xattrs and device numbers are those on
+    /// disk; user and group names are left empty. Files with several names
+    /// in the tree, found by device and inode number, are written once, the
+    /// other names being hardlinks to the first. Symlinks are kept as such,
+    /// sockets are skipped. Like [`Writer::add_file`], the entries aren't
+    /// reordered.
+    pub fn append_dir_all(&mut self, dir: &Path) -> Result<()> {
+        self.append_dir_all_with(dir, &DirOptions::default())
+    }
+
+    /// Like [`Writer::append_dir_all`], leaving out what `opts` ignores,
+    /// e.g. following the `.dockerignore` of a build context, and handling
+    /// symlinks as it says.
+    pub fn append_dir_all_with(&mut self, dir: &Path, opts: &DirOptions) -> Result<()> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        // First name of every file with several
+        let mut links: HashMap<(u64, u64), String> = HashMap::new();
+        for disk in dirtree::walk(dir, opts)? {
+            let meta = &disk.meta;
+            let ft = meta.file_type();
+            if ft.is_socket() {
+                continue;
+            }
+            let entry_meta = EntryMeta {
+                mode: meta.mode() & 0o7777,
+                uid: meta.uid(),
+                gid: meta.gid(),
+                mtime: meta.mtime().max(0) as u64,
+                xattrs: dirtree::xattrs(&disk.path)
+                    .with_context(|| format!("reading xattrs of {}", disk.path.display()))?,
+                ..Default::default()
+            };
+            let hand = |entry_type, link_name: Option<&str>| {
+                NewEntry::by_hand(&disk.name, entry_type, link_name, &entry_meta)
+            };
+
+            if !ft.is_dir() && meta.nlink() > 1 {
+                if let Some(first) = links.get(&(meta.dev(), meta.ino())) {
+                    self.write_entry(hand(tar::EntryType::Link, Some(first)), &mut io::empty())?;
+                    self.report_progress(self.bytes_in);
+                    continue;
+                }
+                links.insert((meta.dev(), meta.ino()), disk.name.clone());
+            }
+            if ft.is_file() {
+                let mut f = File::open(&disk.path)
+                    .with_context(|| format!("opening {}", disk.path.display()))?;
+                let new = NewEntry {
+                    size: meta.len(),
+                    ..hand(tar::EntryType::Regular, None)
+                };
+                self.write_entry(new, &mut (&mut f).take(meta.len()))?;
+            } else if ft.is_dir() {
+                self.write_entry(hand(tar::EntryType::Directory, None), &mut io::empty())?;
+            } else if ft.is_symlink() {
+                let target = std::fs::read_link(&disk.path)?;
+                let new = hand(tar::EntryType::Symlink, Some(&target.to_string_lossy()));
+                self.write_entry(new, &mut io::empty())?;
+            } else if ft.is_fifo() {
+                self.write_entry(hand(tar::EntryType::Fifo, None), &mut io::empty())?;
+            } else {
+                let entry_type = if ft.is_char_device() {
+                    tar::EntryType::Char
+                } else {
+                    tar::EntryType::Block
+                };
+                let new = NewEntry {
+                    device: (libc::major(meta.rdev()), libc::minor(meta.rdev())),
+                    ..hand(entry_type, None)
+                };
+                self.write_entry(new, &mut io::empty())?;
+            }
+            self.report_progress(self.bytes_in);
+        }
+
+        Ok(())
+    }
+
+    // Write the landmark file telling runtimes where the entries to
+    // prefetch end
+    fn append_landmark(&mut self, name: &str) -> Result<()> {
+        self.maybe_split()?;
+        self.check_duplicate(name)?;
+        let start = self.entry_start();
+        let mut h = tar::Header::new_gnu();
+        h.set_path(name)?;
+        h.set_size(1);
+        h.set_mode(0o644);
+        h.set_entry_type(tar::EntryType::Regular);
+        h.set_cksum();
+        self.write_tar(h.as_bytes())?;
+        let data_tar_offset = self.tar_offset;
+        let mut ent = TocEntry {
+            name: name.to_string(),
+            entry_type: "reg".to_string(),
+            size: 1,
+            mode: 0o644,
+            ..Default::default()
+        };
+        let mut content = DigestReader::new(&[LANDMARK_CONTENTS][..]);
+        let chunks = self.write_content(&mut ent, 1, &mut content)?;
+        self.write_tar(&[0; 511])?;
+        self.add_entry(ent, content.finish(), chunks, data_tar_offset, start);
+
+        Ok(())
+    }
+
+    // Write one entry of an input tar, unless it's a TOC or `filter` drops
+    // it, `recording` holding the end of the input read so far. Returns
+    // whether it was written.
+    fn append_entry<R: Read>(
+        &mut self,
+        f: &mut tar::Entry<'_, R>,
+        filter: &mut EntryFilter,
+        recording: &RefCell<Recording>,
+    ) -> Result<bool> {
+        // check if name is TOCT_TAR_NAME
+        if String::from_utf8_lossy(&f.path_bytes()).contains(TOCT_TAR_NAME) {
+            return Ok(false);
+        }
+        if f.header().entry_type() == tar::EntryType::XGlobalHeader {
+            // Records for every entry after it rather than an entry, kept
+            // in the tar stream as they are
+            let mut global = f.header().as_bytes().to_vec();
+            f.read_to_end(&mut global)?;
+            global.resize(global.len().next_multiple_of(512), 0);
+            self.write_tar(&global)?;
+            return Ok(false);
+        }
+        let mut xattrs: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut pax = Vec::new();
+        if let Some(exts) = f.pax_extensions()? {
+            for ext in exts {
+                let ext = ext?;
+                let key = ext.key().unwrap_or("");
+                if let Some(name) = key.strip_prefix("SCHILY.xattr.") {
+                    xattrs.insert(name.to_string(), ext.value_bytes().to_vec());
+                } else {
+                    pax.push((key.to_string(), ext.value_bytes().to_vec()));
+                }
+            }
+        }
+        // PAX records override the header fields they're for
+        let pax_value = |key: &str| {
+            pax.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+        };
+        let pax_number = |key: &str| {
+            pax_value(key).and_then(|v| v.split('.').next().and_then(|n| n.parse::<u64>().ok()))
+        };
+        // The PAX sparse formats may give the file a made-up name in the
+        // header, the real one in a record
+        let path = match pax.iter().find(|(k, _)| k == "GNU.sparse.name") {
+            Some((_, name)) => name.clone(),
+            None => f.path_bytes().into_owned(),
+        };
+        // A path the TOC can't hold is only an error if the entry is kept
+        if !filter.accepts(&String::from_utf8_lossy(&path), f.header()) {
+            return Ok(false);
+        }
+        let path = utf8_path(&path)?;
+        let sparse = if f.header().entry_type().is_gnu_sparse() {
+            let ext = recording.borrow().sparse_headers(f);
+            Some(ext.and_then(|ext| sparse::from_gnu(f.header(), &ext)))
+        } else {
+            let size = f.size();
+            sparse::from_pax(&pax, f, size).transpose()
+        };
+        let sparse = sparse
+            .transpose()
+            .with_context(|| format!("{path}: reading the sparse map"))?;
+
+        let attrs = EntryAttrs {
+            path,
+            mode: f.header().mode()?,
+            uid: pax_number("uid").unwrap_or(f.header().uid()?).try_into()?,
+            gid: pax_number("gid").unwrap_or(f.header().gid()?).try_into()?,
+            uname: pax_value("uname").unwrap_or_else(|| {
+                f.header()
+                    .username()
+                    .ok()
+                    .flatten()
+                    .unwrap_or("")
+                    .to_string()
+            }),
+            gname: pax_value("gname").unwrap_or_else(|| {
+                f.header()
+                    .groupname()
+                    .ok()
+                    .flatten()
+                    .unwrap_or("")
+                    .to_string()
+            }),
+            mtime: pax_number("mtime").unwrap_or(f.header().mtime()?),
+            xattrs,
+            link_name: f.link_name_bytes().map(|l| utf8_path(&l)).transpose()?,
+            entry_type: match sparse {
+                Some(_) => tar::EntryType::Regular,
+                None => f.header().entry_type(),
+            },
+        };
+        let device = match attrs.entry_type {
+            tar::EntryType::Char | tar::EntryType::Block => (
+                f.header().device_major()?.unwrap_or(0),
+                f.header().device_minor()?.unwrap_or(0),
+            ),
+            _ => (0, 0),
+        };
+        let new = NewEntry {
+            attrs,
+            size: sparse.as_ref().map_or(f.size(), |map| map.size),
+            device,
+            pax,
+            sparse,
+        };
+        self.write_entry(new, &mut *f)?;
+
+        Ok(true)
+    }
+
+    // Write an entry, from an input tar or built by hand, and its content
+    // read from `r`
+    fn write_entry(&mut self, new: NewEntry, r: &mut dyn Read) -> Result<()> {
+        if self.closed {
+            return Err(anyhow!("Writer is closed"));
+        }
+        let NewEntry {
+            mut attrs,
+            size,
+            device,
+            pax,
+            sparse,
+        } = new;
+        let source_mtime = attrs.mtime;
+        for t in self.transforms.iter_mut() {
+            t.apply(&mut attrs)?;
+        }
+        self.maybe_split()?;
+        let link_spool = self.link_spool()?;
+        let mut size = size;
+        let mut copy = None;
+        let copied_from = self.relink(&mut attrs);
+        if let (Some((_, offset, len)), Some(spool)) = (&copied_from, &link_spool) {
+            size = *len;
+            copy = Some(SectionReader::new(&**spool, *offset, *len));
+        }
+        let r: &mut dyn Read = match &mut copy {
+            Some(copy) => copy,
+            None => r,
+        };
+
+        let mtime = self.clamp_mtime(attrs.mtime);
+        let datetime = Utc
+            .timestamp_opt(i64::try_from(mtime)?, 0)
+            .single()
+            .ok_or_else(|| anyhow!("mtime {mtime} out of range"))?;
+        let mut ent = TocEntry {
+            entry_type: "file".to_string(),
+            name: attrs.path.clone(),
+            size,
+            mod_time_3339: Some(datetime.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            mod_time: Some(datetime),
+            uid: attrs.uid,
+            gid: attrs.gid,
+            uname: attrs.uname.clone(),
+            gname: attrs.gname.clone(),
+            mode: attrs.mode,
+            xattrs: attrs.xattrs,
+            ..Default::default()
+        };
+        self.check_duplicate(&ent.name)?;
+        let start = self.entry_start();
+        // Create a new header and copy metadata from the entry's header
+        let mut h = tar::Header::new_gnu();
+        // Names too long for the header go in PAX records
+        let mut long_names: Vec<_> = headers::set_path(&mut h, &attrs.path)?
+            .into_iter()
+            .collect();
+        h.set_size(size);
+        h.set_mode(attrs.mode);
+        h.set_uid(attrs.uid.into());
+        h.set_gid(attrs.gid.into());
+        h.set_mtime(mtime);
+        if let Some(link_name) = &attrs.link_name {
+            long_names.extend(headers::set_link_name(&mut h, link_name)?);
+        }
+        if matches!(
+            attrs.entry_type,
+            tar::EntryType::Char | tar::EntryType::Block
+        ) {
+            h.set_device_major(device.0)?;
+            h.set_device_minor(device.1)?;
+        }
+
+        // The input's other PAX records, kept as they are, and those for
+        // what doesn't fit in the header
+        let mut records = long_names;
+        records.extend(pax.into_iter().filter(|(key, _)| match key.as_str() {
+            // Sub-second precision, unless the mtime was changed
+            "mtime" => mtime == source_mtime,
+            key => headers::keeps_record(key),
+        }));
+        if h.set_username(&attrs.uname).is_err() {
+            records.push(("uname".to_string(), attrs.uname.clone().into_bytes()));
+        }
+        if h.set_groupname(&attrs.gname).is_err() {
+            records.push(("gname".to_string(), attrs.gname.clone().into_bytes()));
+        }
+        let mut xattrs: Vec<_> = ent.xattrs.iter().collect();
+        xattrs.sort();
+        for (name, value) in xattrs {
+            records.push((format!("SCHILY.xattr.{name}"), value.clone()));
+        }
+        h.set_entry_type(attrs.entry_type);
+
+        match h.entry_type() {
+            tar::EntryType::Link => {
+                ent.entry_type = "hardlink".to_string();
+                ent.link_name = attrs.link_name.clone().unwrap_or_default();
+            }
+            tar::EntryType::Symlink => {
+                ent.entry_type = "symlink".to_string();
+                ent.link_name = attrs.link_name.clone().unwrap_or_default();
+            }
+            tar::EntryType::Directory => {
+                ent.entry_type = "dir".to_string();
+            }
+            tar::EntryType::Regular => {
+                ent.entry_type = "reg".to_string();
+                ent.size = h.size()?;
+            }
+            tar::EntryType::Char => {
+                ent.entry_type = "char".to_string();
+                ent.dev_major = h.device_major()?.unwrap_or(0).into();
+                ent.dev_minor = h.device_minor()?.unwrap_or(0).into();
+            }
+            tar::EntryType::Block => {
+                ent.entry_type = "block".to_string();
+                ent.dev_major = h.device_major()?.unwrap_or(0).into();
+                ent.dev_minor = h.device_minor()?.unwrap_or(0).into();
+            }
+            tar::EntryType::Fifo => {
+                ent.entry_type = "fifo".to_string();
+            }
+            _ => {
+                return Err(anyhow!("unsupported input tar entry {:?}", h.entry_type()));
+            }
+        }
+
+        // Only the data of sparse files is stored, the holes are left to
+        // the map in the header
+        let stored = sparse.as_ref().map(sparse::SparseMap::aligned);
+        let sparse_headers = match &stored {
+            Some(map) => sparse::set_gnu_header(&mut h, map)?,
+            None => Vec::new(),
+        };
+        h.set_cksum();
+        if !records.is_empty() {
+            self.write_tar(&headers::pax_header(&records)?)?;
+        }
+        self.write_tar(h.as_bytes())?;
+        self.write_tar(&sparse_headers)?;
+        let data_tar_offset = self.tar_offset;
+        let mut filled;
+        let r: &mut dyn Read = match &sparse {
+            Some(map) if map.packed => {
+                filled = sparse::Filled::new(r, map);
+                &mut filled
+            }
+            _ => r,
+        };
+        let spool_at = self.link_copies.as_ref().map_or(0, |c| c.end);
+        let mut tee = SpoolTee {
+            inner: r,
+            spool: link_spool.as_deref(),
+            at: spool_at,
+        };
+        // The digest is of the whole content, holes included
+        let mut content = DigestReader::new(&mut tee);
+        let chunks = match &stored {
+            Some(map) => {
+                ent.sparse_map = map.toc_map();
+                let mut data = sparse::DataOnly::new(&mut content, map);
+                let chunks = self.write_regions(&mut ent, &map.regions, &mut data)?;
+                io::copy(&mut content, &mut io::sink())?;
+                chunks
+            }
+            None => self.write_content(&mut ent, h.size()?, &mut content)?,
+        };
+        let digest = content.finish();
+        if let Some(copies) = self.link_copies.as_mut() {
+            let name = clean_entry_name(&ent.name);
+            match ent.entry_type.as_str() {
+                "reg" => {
+                    if let Some((target, ..)) = copied_from {
+                        copies.copied.insert(target, name.clone());
+                    }
+                    copies.files.insert(name, (spool_at, tee.at - spool_at));
+                    copies.end = tee.at;
+                }
+                "hardlink" => {
+                    let target = clean_entry_name(&ent.link_name);
+                    if let Some(&content) = copies.files.get(&target) {
+                        copies.files.insert(name, content);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let padding = h.size()?.next_multiple_of(512) - h.size()?;
+        self.write_tar(&[0; 512][..padding as usize])?;
+        self.add_entry(ent, digest, chunks, data_tar_offset, start);
+
+        Ok(())
+    }
+
+    // The spool keeping the content of regular files while the output is
+    // split, created on first use
+    fn link_spool(&mut self) -> Result<Option<Rc<File>>> {
+        if self.split.is_some() && self.link_copies.is_none() {
+            self.link_copies = Some(LinkCopies {
+                spool: Rc::new(spool_file()?),
+                end: 0,
+                files: HashMap::new(),
+                copied: HashMap::new(),
+            });
         }
-        let file_reader = &FileReader {
-            r: self,
-            size: ent.size,
-            ents: self.get_chunks(ent),
-        };
-
-        return Ok(SectionReader::new(
-            &file_reader.r.sr,
-            0,
-            file_reader.size as u32,
-        ));
+        Ok(self.link_copies.as_ref().map(|c| c.spool.clone()))
     }
 
-    pub fn chunk_entry_for_offset(&self, name: &str, offset: u64) -> Option<&TocEntry> {
-        let ent = self.lookup(name);
-        if ent.is_err() {
+    // Point a hardlink whose target is in an earlier blob to the copy of it
+    // made in this one, or make it that copy: a regular file, returned with
+    // the target and where its content is in the spool
+    fn relink(&self, attrs: &mut EntryAttrs) -> Option<(String, u64, u64)> {
+        let copies = self.link_copies.as_ref()?;
+        if attrs.entry_type != tar::EntryType::Link {
             return None;
         }
-        let ent = ent.unwrap();
-        if !ent.is_data_type() {
+        let target = clean_entry_name(attrs.link_name.as_deref().unwrap_or_default());
+        if self.written.contains(&target) {
             return None;
         }
-        let ents = self.chunks.get(&ent.name).unwrap();
-        if ents.len() < 2 {
-            if offset >= ent.chunk_size {
-                return None;
-            }
-            return Some(ent);
-        }
-        let i = ents
-            .iter()
-            .position(|e| {
-                e.offset >= offset
-                    || (offset > e.chunk_offset && offset < e.chunk_offset + e.chunk_size)
-            })
-            .unwrap_or(ents.len() - 1);
-        if i == ents.len() - 1 {
+        if let Some(copy) = copies.copied.get(&target) {
+            attrs.link_name = Some(copy.clone());
             return None;
         }
-        return Some(&ents[i]);
+        let &(offset, len) = copies.files.get(&target)?;
+        attrs.entry_type = tar::EntryType::Regular;
+        attrs.link_name = None;
+        Some((target, offset, len))
     }
-}
-
-struct FileReader<'a> {
-    r: &'a GzReader,
-    size: u64,
-    ents: Vec<TocEntry>,
-}
 
-impl<'a> FileReader<'a> {
-    fn read_at(&self, buf: &mut [u8], mut offset: u64) -> Result<usize> {
-        if offset > self.size {
-            return Err(anyhow!("offset is greater than file size"));
+    /// Like [`Writer::append_tar`], but the tar headers of the input (PAX
+    /// records, GNU long names, padding...) are copied byte for byte rather
+    /// than rebuilt, so the data of the blob decompresses to the input tar
+    /// up to its end-of-archive marker, followed by the TOC entry.
+    ///
+    /// Headers can't be rewritten then: fails if transforms, an mtime clamp,
+    /// prioritized files or reproducible output are set, and on sparse
+    /// files.
+    pub fn append_tar_lossless(&mut self, r: &mut dyn Read) -> Result<()> {
+        if !self.transforms.is_empty() || self.mtime_clamp.is_some() {
+            return Err(anyhow!(
+                "lossless append can't apply transforms or an mtime clamp"
+            ));
         }
-        let mut i: usize = 0;
-        if self.ents.len() > 1 {
-            // Is sorting useful here?
-            let mut sorted = self.ents.clone();
-            sorted.sort_unstable_by_key(|e| e.offset);
-
-            // Find the first entity with an offset equal or great to offset
-            i = sorted
-                .iter()
-                .position(|e| e.offset >= offset)
-                .unwrap_or(self.ents.len() - 1);
+        if self.prioritized.is_some() || self.sort_entries {
+            return Err(anyhow!("lossless append can't reorder entries"));
         }
-
-        let mut entry = self.ents.get(i).unwrap();
-        if entry.chunk_offset > offset {
-            if i == 0 {
-                return Err(anyhow!("internal error; first chunk offset is non-zero"));
+        let consumed = Rc::new(Cell::new(0));
+        let recording = Rc::new(RefCell::new(Recording::default()));
+        let mut tar = Archive::new(TarRecorder {
+            inner: tar_stream(r, &consumed)?,
+            recording: recording.clone(),
+        });
+        // Stream offset up to which the input was written or dropped
+        let mut emitted = 0;
+        // Stream offset where the last entry, padding included, ends
+        let mut entry_end = 0;
+        for entry in tar.entries()? {
+            let mut f = entry?;
+            let name = String::from_utf8_lossy(&f.path_bytes()).into_owned();
+            let data_start = f.raw_file_position();
+            let size = f.header().entry_size()?;
+            let header_start = entry_end;
+            entry_end = data_start + size.next_multiple_of(512);
+            if f.header().entry_type() == tar::EntryType::XGlobalHeader {
+                // Not a file, copied along with the headers of the next entry
+                continue;
+            }
+            if clean_entry_name(&name) == TOCT_TAR_NAME {
+                let head = recording.borrow().take(emitted, header_start);
+                self.write_tar(&head)?;
+                recording.borrow_mut().pause();
+                io::copy(&mut f, &mut io::sink())?;
+                recording.borrow_mut().resume();
+                emitted = entry_end;
+                continue;
+            }
+            if f.header().entry_type().is_gnu_sparse() || sparse::is_pax_sparse(&mut f)? {
+                return Err(anyhow!("{name}: sparse files can't be copied losslessly"));
             }
-            entry = self.ents.get(i - 1).unwrap();
-        }
-
-        offset -= entry.chunk_offset;
-        let final_entry = &self.ents[self.ents.len() - 1];
-        let gz_offset = entry.offset;
-        let gz_bytes_remain = final_entry.next_offset() - gz_offset;
-        let sr = SectionReader::new(&self.r.sr, gz_offset as u32, gz_bytes_remain as u32);
-
-        const MAX_GZ_READ: i32 = 2 << 20;
 
-        let mut buf_size = MAX_GZ_READ;
-        if gz_bytes_remain > buf_size as u64 {
-            buf_size = gz_bytes_remain as i32;
+            let mut ent = repair::toc_entry(&mut f, &name)?;
+            // The padding of the previous entry goes to the blob it's in
+            let padding_end = emitted.next_multiple_of(512).min(header_start);
+            let padding = recording.borrow().take(emitted, padding_end);
+            self.write_tar(&padding)?;
+            self.maybe_split()?;
+            if ent.entry_type == "hardlink"
+                && !self.blobs.is_empty()
+                && !self.written.contains(&clean_entry_name(&ent.link_name))
+            {
+                return Err(anyhow!(
+                    "{name}: hardlink to {} in an earlier blob can't be copied losslessly",
+                    ent.link_name
+                ));
+            }
+            self.check_duplicate(&ent.name)?;
+            let start = self.entry_start();
+            let headers = recording.borrow().take(padding_end, data_start);
+            self.write_tar(&headers)?;
+            let data_tar_offset = self.tar_offset;
+            recording.borrow_mut().pause();
+            let mut content = DigestReader::new(&mut f);
+            let chunks = self.write_content(&mut ent, size, &mut content)?;
+            recording.borrow_mut().resume();
+            emitted = data_start + size;
+            self.add_entry(ent, content.finish(), chunks, data_tar_offset, start);
+            self.report_progress(self.bytes_in + consumed.get());
         }
+        let padding = recording.borrow().take(emitted, entry_end);
+        self.write_tar(&padding)?;
+        self.bytes_in += consumed.get();
 
-        // Create a buffered reader with buf_size wrapper for sr
-        let br = BufReader::with_capacity(buf_size as usize, sr);
-        let mut gz = flate2::bufread::GzDecoder::new(br);
-        // Discard until offset
-        io::copy(&mut gz.by_ref().take(offset), &mut io::sink())?;
-        let mut gz = gz.take(self.size as u64 - offset);
-        return Ok(gz.read(buf)?);
+        Ok(())
     }
-}
 
-pub fn open<'a, R: FileExt>(input: File) -> Result<GzReader> {
-    let size = input.metadata().unwrap().size();
-    println!("File size {size}");
-
-    if size < FOOTER_SIZE.into() {
-        return Err(anyhow::anyhow!("size too small"));
+    fn entry_start(&self) -> EntryStart {
+        EntryStart {
+            at: Instant::now(),
+            tar_offset: self.tar_offset,
+            compressed: (*self.cw).borrow().count,
+        }
     }
 
-    let mut footer = [0; FOOTER_SIZE as usize];
-    input.read_at(&mut footer, size - FOOTER_SIZE as u64)?;
-    let toc_offset = parse_footer(&footer)?;
-    println!("TOC offset {toc_offset:?}");
-    let toc_size = size as usize - toc_offset as usize - FOOTER_SIZE as usize;
-    println!("TOC size {toc_size}");
-    let mut toc_targz: Vec<u8> = vec![0; toc_size];
-
-    // Read the TOC which is a tar.gz file
-    input.read_at(toc_targz.as_mut_slice(), toc_offset as u64)?;
-
-    // Decompress gz
-    let tar = GzDecoder::new(&toc_targz[..]);
-
-    // Read tar
-    let mut archive = Archive::new(tar);
-    let mut header = archive.entries().unwrap().next().unwrap()?;
-    let header_name = String::from_utf8_lossy(&header.header().as_old().name);
-    if header_name.trim_end_matches('\0') != TOCT_TAR_NAME {
-        return Err(anyhow!(
-            "header name {header_name}, doesn't match {TOCT_TAR_NAME}"
-        ));
+    // Add a written entry and its chunks to the TOC, stats and hooks
+    fn add_entry(
+        &mut self,
+        mut ent: TocEntry,
+        digest: String,
+        chunks: Vec<(ChunkEvent, u64)>,
+        data_tar_offset: u64,
+        start: EntryStart,
+    ) {
+        let is_reg = ent.entry_type == "reg";
+        if is_reg {
+            ent.digest = digest.clone();
+        }
+        self.stats.record_entry(
+            &ent.name,
+            is_reg.then_some(ent.physical_size()),
+            self.tar_offset - start.tar_offset,
+            (*self.cw).borrow().count - start.compressed,
+            start.at.elapsed(),
+        );
+        if ent.is_sparse() {
+            self.stats.record_sparse(ent.size, ent.physical_size());
+        }
+        let (chunks, end_offsets): (Vec<_>, Vec<_>) = chunks.into_iter().unzip();
+        self.notify_entry(&ent, data_tar_offset, digest, &chunks);
+        self.toc.entries.push(ent);
+        // The first chunk is described by the file entry itself
+        for (chunk, end_offset) in chunks.into_iter().zip(end_offsets).skip(1) {
+            self.toc.entries.push(TocEntry {
+                name: chunk.name,
+                entry_type: "chunk".to_string(),
+                offset: chunk.member_offset,
+                chunk_offset: chunk.chunk_offset,
+                chunk_size: chunk.chunk_size,
+                inner_offset: chunk.inner_offset,
+                end_offset,
+                chunk_digest: chunk.digest,
+                ..Default::default()
+            });
+        }
+        self.entries_done += 1;
     }
-
-    // Now build the actual TOC
-    header.set_preserve_permissions(true);
-    header.set_unpack_xattrs(true);
-    header.unpack_in(".")?;
-
-    // Fix permissions, for some reason the index doesn't have permissions
-    let mut permissions = fs::metadata(TOCT_TAR_NAME)?.permissions();
-    permissions.set_readonly(true);
-    permissions.set_mode(0o644);
-    fs::set_permissions(TOCT_TAR_NAME, permissions)?;
-
-    let f = File::options().read(true).open(TOCT_TAR_NAME)?;
-    let toc: JToc = serde_json::from_reader(f)?;
-
-    let mut reader = GzReader {
-        sr: input,
-        toc,
-        m: HashMap::new(),
-        chunks: HashMap::new(),
-    };
-
-    reader.init_fields()?;
-
-    Ok(reader)
 }
 
-fn parse_footer(content: &[u8]) -> Result<i64> {
-    let gz = GzDecoder::new(content);
-    if FOOTER_SIZE < content.len().try_into()? {
-        return Err(anyhow::anyhow!("Footer less than footer size"));
-    }
-
-    let extra = gz.header().unwrap().extra().unwrap();
-    if extra.len() != 16 + "STARGZ".len() {
-        return Err(anyhow::anyhow!("FOOTER is not STARGZ+16"));
+// Indices of the entries (headers offset, name, hardlink target) to write
+// for `names`, in that order, each after its parent directories and
+// hardlink target. Those already `moved` are skipped, and those returned
+// marked as moved.
+fn ordered_entries(
+    entries: &[(u64, String, Option<String>)],
+    names: &[String],
+    moved: &mut [bool],
+) -> Vec<usize> {
+    let mut by_name: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, (_, name, _)) in entries.iter().enumerate() {
+        by_name.entry(name).or_default().push(i);
     }
-
-    if std::str::from_utf8(&extra[16..])? != "STARGZ" {
-        return Err(anyhow::anyhow!("FOOTER not ending in STARGZ"));
+    let mut expanded = HashSet::new();
+    let mut order = Vec::new();
+    let mut pending: Vec<(&str, bool)> = Vec::new();
+    for path in names {
+        pending.push((path, false));
+        while let Some((name, ready)) = pending.pop() {
+            let Some(indices) = by_name.get(name) else {
+                continue;
+            };
+            if ready {
+                for &i in indices {
+                    if !moved[i] {
+                        moved[i] = true;
+                        order.push(i);
+                    }
+                }
+                continue;
+            }
+            if !expanded.insert(name) {
+                continue;
+            }
+            // Come back to it once its dependencies are moved, the parent
+            // closest to the root first
+            pending.push((name, true));
+            for &i in indices {
+                if let Some(target) = &entries[i].2 {
+                    pending.push((target, false));
+                }
+            }
+            let mut parent = name;
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                pending.push((dir, false));
+                parent = dir;
+            }
+        }
     }
+    order
+}
 
-    let toc_offset = i64::from_str_radix(std::str::from_utf8(&extra[..16])?, 16)?;
+// An unnamed temporary file, gone once closed
+fn spool_file() -> io::Result<File> {
+    static SPOOLED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let n = SPOOLED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("stargz-rs-{}-{n}.tar", std::process::id()));
+    let f = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    io::Result::Ok(f)
+}
 
-    Ok(toc_offset)
+// Where the content of each regular file written is, while the output is
+// split, so a hardlink to a file in an earlier blob can be a copy of it
+struct LinkCopies {
+    spool: Rc<File>,
+    end: u64,
+    // Cleaned name -> offset and size in the spool, hardlinks included
+    files: HashMap<String, (u64, u64)>,
+    // Files of earlier blobs copied to the current one -> name of the copy
+    copied: HashMap<String, String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct JToc {
-    version: u32,
-    entries: Vec<TocEntry>,
+// Copies what's read through it to the spool, if any, from `at` on
+struct SpoolTee<'s, R: Read> {
+    inner: R,
+    spool: Option<&'s File>,
+    at: u64,
 }
 
-impl JToc {
-    pub fn new(version: u32) -> Self {
-        Self {
-            version,
-            entries: Vec::new(),
+impl<R: Read> Read for SpoolTee<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(spool) = self.spool {
+            spool.write_all_at(&buf[..n], self.at)?;
+            self.at += n as u64;
         }
+        io::Result::Ok(n)
     }
 }
 
-#[derive(Debug, Deserialize, Clone, Default)]
-pub struct TocEntry {
-    name: String,
-
-    #[serde(rename(serialize = "type", deserialize = "type"))]
-    entry_type: String,
-
-    #[serde(default)]
+// An entry to write, from an input tar or built by hand
+struct NewEntry {
+    attrs: EntryAttrs,
     size: u64,
+    // Major and minor of a device
+    device: (u32, u32),
+    // PAX records of an input entry, xattrs aside
+    pax: Vec<(String, Vec<u8>)>,
+    // Where the data of a sparse file is, `size` being that of the file
+    sparse: Option<sparse::SparseMap>,
+}
 
-    mod_time_3339: Option<String>,
-    mod_time: Option<chrono::DateTime<Utc>>,
-
-    #[serde(default)]
-    mode: u32,
-
-    #[serde(default, rename = "linkName")]
-    link_name: String,
-
-    #[serde(default)]
-    uid: u32,
-    #[serde(default)]
-    gid: u32,
-
-    #[serde(default)]
-    uname: String,
-    #[serde(default)]
-    gname: String,
-
-    #[serde(default)]
-    offset: u64,
-
-    #[serde(default)]
-    next_offset: u64,
-
-    #[serde(default, rename = "devMajor")]
-    dev_major: u64,
-
-    #[serde(default, rename = "devMinor")]
-    dev_minor: u64,
-
-    #[serde(default, rename(serialize = "NumLink", deserialize = "NumLink"))]
-    num_link: u32,
-
-    #[serde(default)]
-    xattrs: HashMap<String, Vec<u8>>,
+impl NewEntry {
+    fn by_hand(
+        path: &str,
+        entry_type: tar::EntryType,
+        link_name: Option<&str>,
+        meta: &EntryMeta,
+    ) -> Self {
+        NewEntry {
+            attrs: EntryAttrs {
+                path: clean_entry_name(path),
+                mode: meta.mode,
+                uid: meta.uid,
+                gid: meta.gid,
+                uname: meta.uname.clone(),
+                gname: meta.gname.clone(),
+                mtime: meta.mtime,
+                xattrs: meta.xattrs.clone(),
+                link_name: link_name.map(str::to_string),
+                entry_type,
+            },
+            size: 0,
+            device: (0, 0),
+            pax: Vec::new(),
+            sparse: None,
+        }
+    }
+}
 
-    #[serde(default)]
-    digest: String,
+// Where the output stood when an entry started, for its stats
+struct EntryStart {
+    at: Instant,
+    tar_offset: u64,
+    compressed: u64,
+}
 
-    #[serde(default, rename = "chunkOffset")]
-    chunk_offset: u64,
-    #[serde(default, rename = "chunkSize")]
-    chunk_size: u64,
+// The tar stream of `r`, gunzipped if it's gzipped, counting the bytes read
+// from `r` in `consumed`.
+fn tar_stream<'r>(r: &'r mut dyn Read, consumed: &Rc<Cell<u64>>) -> io::Result<Box<dyn Read + 'r>> {
+    let mut br = BufReader::new(CountingReader {
+        inner: r,
+        count: consumed.clone(),
+    });
+    // Sniff the gzip magic without losing it: the input may be a pipe, so
+    // the bytes are put back in front of the stream instead of seeking back.
+    let mut magic = [0; 3];
+    let n = read_full(&mut br, &mut magic)?;
+    let is_gzipped = magic[..n] == [0x1f, 0x8b, 0x08];
+    let input = io::Cursor::new(magic).take(n as u64).chain(br);
+    if is_gzipped {
+        // Several members when it's a stargz blob or from parallel gzip
+        io::Result::Ok(Box::new(MultiGzDecoder::new(input)))
+    } else {
+        io::Result::Ok(Box::new(input))
+    }
+}
 
-    #[serde(skip)]
-    children: HashMap<String, TocEntry>,
+// The bytes of the tar stream read by the tar crate while not paused, from
+// the offset `start` on, so raw headers can be copied once they're parsed.
+#[derive(Default)]
+struct Recording {
+    paused: bool,
+    // Stream offset of what's read next
+    pos: u64,
+    start: u64,
+    buf: Vec<u8>,
+    // Keep only about the last this many bytes
+    limit: Option<usize>,
 }
 
-impl TocEntry {
-    pub fn mod_time(&self) -> Option<chrono::DateTime<Utc>> {
-        self.mod_time
+// What's kept of the input stream to read the GNU sparse map of an entry
+// from, the extension headers after its header: 21 regions per 512 bytes
+const HEADER_TAIL: usize = 1 << 20;
+
+impl Recording {
+    // Stop recording, while reading content that's handled elsewhere
+    fn pause(&mut self) {
+        self.paused = true;
     }
 
-    pub fn next_offset(&self) -> u64 {
-        self.next_offset
+    // Record again from the current offset, forgetting what came before
+    fn resume(&mut self) {
+        self.paused = false;
+        self.buf.clear();
+        self.start = self.pos;
     }
 
-    pub fn add_child(&mut self, child: TocEntry, base_name: &str) {
-        if child.entry_type == "dir" {
-            self.num_link += 1;
+    // Recording only the last HEADER_TAIL bytes, for what's left of the
+    // headers of the entry just read
+    fn tail() -> Self {
+        Recording {
+            limit: Some(HEADER_TAIL),
+            ..Default::default()
         }
+    }
 
-        self.children.insert(base_name.to_owned(), child);
+    // Forget the oldest bytes past the limit, a few at a time
+    fn trim(&mut self) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+        if self.buf.len() > 2 * limit {
+            let dropped = self.buf.len() - limit;
+            self.buf.drain(..dropped);
+            self.start += dropped as u64;
+        }
     }
 
-    pub fn lookup_child(&self, base_name: &str) -> Option<&TocEntry> {
-        self.children.get(base_name)
+    // The GNU sparse extension headers of `f`, read right after its header
+    fn sparse_headers<R: Read>(&self, f: &tar::Entry<'_, R>) -> Result<Vec<u8>> {
+        let from = f.raw_header_position() + 512;
+        if from < self.start {
+            return Err(anyhow!("sparse map larger than {HEADER_TAIL} bytes"));
+        }
+        Ok(self.take(from, self.pos))
     }
 
-    pub fn is_data_type(&self) -> bool {
-        self.entry_type == "reg" || self.entry_type == "chunk"
+    // The recorded bytes between stream offsets `from` and `to`
+    fn take(&self, from: u64, to: u64) -> Vec<u8> {
+        let from = from.max(self.start);
+        let to = to.max(from);
+        self.buf[(from - self.start) as usize..(to - self.start) as usize].to_vec()
     }
 }
 
-struct FileInfo<'a>(&'a TocEntry);
+struct TarRecorder<R: Read> {
+    inner: R,
+    recording: Rc<RefCell<Recording>>,
+}
 
-impl<'a> FileInfo<'a> {
-    pub fn new(toc_entry: &'a TocEntry) -> Self {
-        Self(toc_entry)
+impl<R: Read> Read for TarRecorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut recording = (*self.recording).borrow_mut();
+        recording.pos += n as u64;
+        if !recording.paused {
+            recording.buf.extend_from_slice(&buf[..n]);
+            recording.trim();
+        }
+        io::Result::Ok(n)
     }
+}
 
-    pub fn is_dir(&self) -> bool {
-        self.0.entry_type == "dir"
-    }
+// OS byte of every gzip member we write, "unknown" as in Go's compress/gzip
+const GZIP_OS_UNKNOWN: u8 = 255;
 
-    pub fn mode(&self) -> u32 {
-        match self.0.entry_type.as_str() {
-            "dir" => 0o755,
-            "file" => 0o644,
-            "symlink" => 0o777,
-            "char" => 0o666,
-            "block" => 0o666,
-            "fifo" => 0o666,
-            _ => 0,
-        }
+// Start a gzip member whose header only depends on the compression level:
+// no mtime, no file name and a fixed OS byte, so the same input always
+// produces the same bytes whatever host or flate2 defaults are in play.
+fn gz_member_encoder<W: Write>(w: W, level: Compression) -> GzEncoder<W> {
+    GzBuilder::new()
+        .mtime(0)
+        .operating_system(GZIP_OS_UNKNOWN)
+        .write(w, level)
+}
+
+// Gzip members of an earlier blob, see Writer::with_chunk_reuse
+struct ChunkReuse<'a> {
+    reader: &'a GzReader,
+    // Blob offset and size of the member of each chunk, by digest
+    members: HashMap<String, (u64, u64)>,
+}
+
+impl ChunkReuse<'_> {
+    // The member holding `chunk`, whose digest is `digest`, if there is one
+    // that actually decompresses to it
+    fn member(&self, digest: &str, chunk: &[u8]) -> Option<Vec<u8>> {
+        let &(offset, size) = self.members.get(digest)?;
+        let mut member = vec![0; usize::try_from(size).ok()?];
+        self.reader.sr.read_exact_at(&mut member, offset).ok()?;
+        let mut content = Vec::with_capacity(chunk.len());
+        GzDecoder::new(&member[..]).read_to_end(&mut content).ok()?;
+        (content == chunk).then_some(member)
     }
 }
 
-struct CountingWriterWrapper<W: Write>(Rc<RefCell<CountingWriter<W>>>);
+// How members are compressed: gzip at a level, or zstd frames at a level
+// for zstd:chunked
+#[derive(Clone, Copy)]
+enum MemberCodec {
+    Gzip(Compression),
+    Zstd(i32),
+}
 
-impl<W: Write> Write for CountingWriterWrapper<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        (*self.0).borrow_mut().write(buf)
+impl MemberCodec {
+    fn encoder<W: Write>(self, w: W) -> io::Result<MemberEncoder<W>> {
+        io::Result::Ok(match self {
+            MemberCodec::Gzip(level) => MemberEncoder::Gzip(gz_member_encoder(w, level)),
+            MemberCodec::Zstd(level) => MemberEncoder::Zstd(zstd::Encoder::new(w, level)?),
+        })
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        (*self.0).borrow_mut().flush()
+    // A whole member holding `data`
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = self.encoder(Vec::new())?;
+        encoder.write_all(data)?;
+        encoder.finish()
     }
 }
 
-pub struct Writer<'a, W: Write> {
-    cw: Rc<RefCell<CountingWriter<W>>>,
-    gz: Option<GzEncoder<CountingWriterWrapper<W>>>,
-    toc: JToc,
-    diff_hash: sha2::Sha256,
-    last_username: HashMap<i32, &'a str>,
-    last_groupname: HashMap<i32, &'a str>,
-    chunk_size: usize,
-    closed: bool,
+// The member being written: a gzip member, or a zstd frame for zstd:chunked
+enum MemberEncoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
 }
 
-impl<'a, W: Write> Writer<'a, W> {
-    // Accept a writer and build Writer from it
-    pub fn new(writer: W) -> Self {
-        let jtoc = JToc::new(1);
-        let bw = BufWriter::new(writer);
-        let cw = Rc::new(RefCell::new(CountingWriter::new(bw)));
-        Self {
-            cw,
-            gz: None,
-            toc: jtoc,
-            diff_hash: sha2::Digest::new(),
-            last_username: HashMap::new(),
-            last_groupname: HashMap::new(),
-            chunk_size: 0,
-            closed: false,
+impl<W: Write> MemberEncoder<W> {
+    fn finish(self) -> io::Result<W> {
+        match self {
+            MemberEncoder::Gzip(gz) => gz.finish(),
+            MemberEncoder::Zstd(zst) => zst.finish(),
         }
     }
+}
 
-    pub fn chunk_size(&self) -> usize {
-        if self.chunk_size <= 0 {
-            return 4 << 20;
+impl<W: Write> Write for MemberEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MemberEncoder::Gzip(gz) => gz.write(buf),
+            MemberEncoder::Zstd(zst) => zst.write(buf),
         }
-
-        self.chunk_size
     }
 
-    pub fn close(&mut self) -> Result<()> {
-        if self.closed {
-            return Ok(());
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MemberEncoder::Gzip(gz) => gz.flush(),
+            MemberEncoder::Zstd(zst) => zst.flush(),
         }
-        self.close_gz()?;
-
-        //let toc_offset = self.
-
-        self.closed = true;
-
-        Ok(())
     }
+}
 
-    fn cond_open_gz(&mut self) -> Result<()> {
-        if self.gz.is_none() {
-            let gz = GzEncoder::new(CountingWriterWrapper(self.cw.clone()), Compression::best());
-            self.gz = Some(gz);
-        }
+// Hashes the content read through it
+struct DigestReader<R: Read> {
+    inner: R,
+    hasher: sha2::Sha256,
+}
 
-        Ok(())
+impl<R: Read> DigestReader<R> {
+    fn new(inner: R) -> Self {
+        DigestReader {
+            inner,
+            hasher: sha2::Digest::new(),
+        }
     }
 
-    fn close_gz(&mut self) -> Result<()> {
-        if self.closed {
-            return Err(anyhow!("Writer is closed"));
-        }
-        if let Some(gz) = self.gz.take() {
-            let mut gz = gz.finish()?;
-            gz.flush()?;
-        }
+    // "sha256:<hex>" of everything read
+    fn finish(self) -> String {
+        format!("sha256:{:x}", sha2::Digest::finalize(self.hasher))
+    }
+}
 
-        Ok(())
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        sha2::Digest::update(&mut self.hasher, &buf[..n]);
+        io::Result::Ok(n)
     }
+}
 
-    pub fn append_tar(&mut self, r: &mut dyn Read) -> Result<()> {
-        let mut br = BufReader::new(r);
-        let mut is_gzipped = [0; 3];
-        br.read_exact(&mut is_gzipped)?;
-        let is_gzipped = is_gzipped == [0x1f, 0x8b, 0x08];
-        let mut tar: Archive<Box<dyn Read>>;
-        if is_gzipped {
-            let gz = GzDecoder::new(br);
-            tar = tar::Archive::new(Box::new(gz));
-        } else {
-            tar = tar::Archive::new(Box::new(br));
-        }
-        for entry in tar.entries()? {
-            let mut f = entry?;
-            // check if name is TOCT_TAR_NAME
-            if f.path()?.to_str().unwrap().contains(TOCT_TAR_NAME) {
-                continue;
-            }
-            let mut xattrs: HashMap<String, Vec<u8>> = HashMap::new();
-            if let Some(exts) = f.pax_extensions()? {
-                for ext in exts {
-                    let ext = ext?;
-                    let key = ext.key().unwrap_or("");
-                    if key.starts_with("SCHILY.xattr.") {
-                        xattrs.insert(
-                            key["SCHILY.xattr.".len()..].to_string(),
-                            ext.value_bytes().to_vec(),
-                        );
-                    }
-                }
-            }
+// Feeds what's written through it to a hasher kept by the caller
+struct DigestWriter<'h, W: Write> {
+    inner: W,
+    hasher: &'h mut sha2::Sha256,
+}
 
-            // TODO: Might want to check the variant of LocalResult
-            let datetime = Utc.timestamp_opt(f.header().mtime()? as i64, 0).unwrap();
-            let mut ent = TocEntry {
-                entry_type: "file".to_string(),
-                name: f.path()?.to_str().unwrap().to_string(),
-                size: f.size(),
-                mod_time: Some(datetime),
-                uid: f.header().uid()? as u32,
-                gid: f.header().gid()? as u32,
-                uname: f.header().username()?.unwrap_or("").to_string(),
-                gname: f.header().groupname()?.unwrap_or("").to_string(),
-                mode: f.header().mode()?,
-                xattrs,
-                ..Default::default()
-            };
-            self.cond_open_gz()?;
-            let mut builder = tar::Builder::new(self.gz.as_mut().unwrap());
-            // Create a new header and copy metadata from the entry's header
-            let mut h = tar::Header::new_gnu();
-            h.set_path(f.path()?)?;
-            h.set_size(f.header().size()?);
-            h.set_mode(f.header().mode()?);
-            h.set_uid(f.header().uid()?);
-            h.set_gid(f.header().gid()?);
-            h.set_mtime(f.header().mtime()?);
-            h.set_entry_type(f.header().entry_type());
-
-            // Append the new header and the entry's content to the tar builder
-            builder.append(&h, &mut f)?;
-
-            match h.entry_type() {
-                tar::EntryType::Link => {
-                    ent.entry_type = "hardlink".to_string();
-                    // TODO: do something more sensible here
-                    ent.link_name = h.link_name()?.unwrap().to_str().unwrap().to_string();
-                }
-                tar::EntryType::Symlink => {
-                    ent.entry_type = "symlink".to_string();
-
-                    // TODO: do something more sensible here
-                    ent.link_name = h.link_name()?.unwrap().to_str().unwrap().to_string();
-                }
-                tar::EntryType::Directory => {
-                    ent.entry_type = "dir".to_string();
-                }
-                tar::EntryType::Regular => {
-                    ent.entry_type = "reg".to_string();
-                    ent.size = h.size()?;
-                }
-                tar::EntryType::Char => {
-                    ent.entry_type = "char".to_string();
-                    ent.dev_major = h.device_major()?.unwrap_or(0).try_into()?;
-                    ent.dev_minor = h.device_minor()?.unwrap_or(0).try_into()?;
-                }
-                tar::EntryType::Block => {
-                    ent.entry_type = "block".to_string();
-                    ent.dev_major = h.device_major()?.unwrap_or(0).try_into()?;
-                    ent.dev_minor = h.device_minor()?.unwrap_or(0).try_into()?;
-                }
-                tar::EntryType::Fifo => {
-                    ent.entry_type = "fifo".to_string();
-                }
-                _ => {
-                    return Err(anyhow!("unsupported input tar entry {:?}", h.entry_type()));
-                }
-            }
-        }
+impl<W: Write> Write for DigestWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        sha2::Digest::update(self.hasher, &buf[..n]);
+        io::Result::Ok(n)
+    }
 
-        Ok(())
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
 #[derive(Debug)]
+/// Buffered writer keeping track of the offset it's at, for laying a stargz
+/// blob (or anything else) out inside a larger output.
+///
+/// Bytes count as written once they are accepted into the buffer; use
+/// [`CountingWriter::flushed_position`] for what actually reached the inner
+/// writer.
 pub struct CountingWriter<W: std::io::Write> {
     inner: BufWriter<W>,
     count: u64,
+    // Offset of the first byte written through this writer
+    base: u64,
 }
 
 impl<W: std::io::Write> CountingWriter<W> {
     pub fn new(bw: BufWriter<W>) -> Self {
+        Self::with_offset(bw, 0)
+    }
+
+    /// Start counting at `offset`, for output that already holds `offset`
+    /// bytes written by someone else.
+    pub fn with_offset(bw: BufWriter<W>, offset: u64) -> Self {
         Self {
             inner: bw,
             count: 0,
+            base: offset,
         }
     }
+
+    /// Bytes written through this writer.
+    pub fn bytes_written(&self) -> u64 {
+        self.count
+    }
+
+    /// Offset the next write lands at.
+    pub fn position(&self) -> u64 {
+        self.base + self.count
+    }
+
+    /// Offset up to which data has been handed to the inner writer, short of
+    /// [`CountingWriter::position`] by what's still buffered.
+    pub fn flushed_position(&self) -> u64 {
+        self.position() - self.inner.buffer().len() as u64
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref()
+    }
+
+    /// Flush the buffer and return the inner writer.
+    pub fn into_inner(self) -> io::Result<W> {
+        self.inner.into_inner().map_err(|e| e.into_error())
+    }
 }
+
 impl<W: Write> Write for CountingWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let result = self.inner.write(buf);
@@ -1,10 +1,319 @@
-use std::fs::File;
+use std::{
+    env,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    process,
+};
 
-use stargz_rs::open;
+use stargz_rs::{
+    doctor, open, repair, source_date_epoch, BlobFormat, CompressionLevel, DirOptions, DirWatcher,
+    ReaderOptions, Symlinks, Writer,
+};
+
+const USAGE: &str = "usage:
+    stargz-rs create [--split-size BYTES] [--chunk-size BYTES] [--min-chunk-size BYTES]
+                     [--level LEVEL] [--format FORMAT] [--threads N] [--mtime EPOCH]
+                     [--prioritize PATH]... [--external-toc PATH] [--reproducible]
+                     [--exclude PATTERN]... [--ignore-file PATH] [--symlinks POLICY]
+                     [--lossless] [--stats] <input.tar[.gz]|dir|-> <output|->
+    stargz-rs open [blob]
+    stargz-rs doctor <blob>
+    stargz-rs tree <blob> [path]
+    stargz-rs debug <blob>
+    stargz-rs repair [--index] <blob> <output>
+    stargz-rs watch [--chunk-size BYTES] [--exclude PATTERN]... [--ignore-file PATH] <dir> <output>";
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let f = File::open("output.stargz").unwrap();
-    open::<File>(f)?;
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("create") => create(&args[1..]),
+        Some("repair") => repair(&args[1..]),
+        Some("watch") => watch(&args[1..]),
+        Some("debug") => {
+            let [_, blob] = &args[..] else { usage() };
+            doctor::dump(&File::open(blob)?, &mut io::stdout().lock())?;
+            Ok(())
+        }
+        Some("doctor") => {
+            let [_, blob] = &args[..] else { usage() };
+            let diagnosis = doctor::diagnose(&File::open(blob)?)?;
+            println!("{diagnosis}");
+            if !diagnosis.is_healthy() {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Some("tree") => {
+            let (blob, path) = match &args[1..] {
+                [blob] => (blob, ""),
+                [blob, path] => (blob, path.as_str()),
+                _ => usage(),
+            };
+            let reader = ReaderOptions::new().open(File::open(blob)?)?;
+            print!("{}", reader.render_tree(path)?);
+            Ok(())
+        }
+        Some("open") => {
+            let f = File::open(args.get(1).map_or("output.stargz", String::as_str))?;
+            open(f)?;
+            Ok(())
+        }
+        None => {
+            let f = File::open("output.stargz")?;
+            open(f)?;
+            Ok(())
+        }
+        Some(_) => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(2)
+}
+
+// Rebuild the TOC of a damaged blob from its tar stream and write a repaired
+// blob, or with --index only the rebuilt stargz.index.json.
+fn repair(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let (index_only, args) = match args {
+        [flag, rest @ ..] if flag == "--index" => (true, rest),
+        _ => (false, args),
+    };
+    let [blob, output] = args else { usage() };
+    let blob = File::open(blob)?;
+    let recovery = repair::recover(&blob)?;
+
+    let mut out = File::create(output)?;
+    if index_only {
+        recovery.write_index(&mut out)?;
+    } else {
+        recovery.write_blob(&blob, &mut out)?;
+    }
+
+    eprintln!(
+        "recovered {} entries from the first {} bytes",
+        recovery.entries(),
+        recovery.data_end
+    );
+    for (name, reason) in &recovery.lost {
+        eprintln!("lost {name}: {reason}");
+    }
+    if let Some(reason) = &recovery.stopped {
+        eprintln!("scan stopped early: {reason}");
+    }
+    Ok(())
+}
+
+// Build a blob from a directory, then again whenever it changes, reusing
+// the compressed chunks of files left alone. --chunk-size, --exclude and
+// --ignore-file work like for create.
+fn watch(mut args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut chunk_size = None;
+    let mut dir_opts = DirOptions::new();
+    while let [flag, value, rest @ ..] = args {
+        match flag.as_str() {
+            "--chunk-size" => chunk_size = Some(value.parse::<usize>()?),
+            "--exclude" => dir_opts = dir_opts.ignore(value)?,
+            "--ignore-file" => dir_opts = dir_opts.ignore_file(value)?,
+            _ => break,
+        }
+        args = rest;
+    }
+    let [dir, output] = args else { usage() };
+
+    let mut watcher = DirWatcher::new(dir, output).dir_options(dir_opts);
+    if let Some(chunk_size) = chunk_size {
+        watcher = watcher.chunk_size(chunk_size);
+    }
+    watcher.run(|stats| {
+        eprintln!(
+            "wrote {output}: {} entries, {} bytes, {} chunks reused",
+            stats.entries, stats.compressed_bytes, stats.reused_chunks
+        )
+    })?;
+    Ok(())
+}
+
+// Convert a tar or tar.gz into a stargz blob, "-" meaning stdin/stdout. The
+// input can also be a directory, whose content becomes the layer.
+// With --split-size, blobs after the first go to <output>.1, <output>.2...
+// Entry mtimes are clamped to --mtime, or SOURCE_DATE_EPOCH when it's set.
+// --chunk-size sets how much of a file goes in each gzip member, and
+// --min-chunk-size how much small files are packed in one. --level sets the
+// gzip level: store (no compression), fast, default, best (the default) or 1
+// to 9. --format picks estargz (the default), stargz for the legacy footer
+// or zstd:chunked. --threads compresses chunks in parallel, 0 meaning one
+// thread per CPU.
+// Each --prioritize puts a path before the prefetch landmark, in that order.
+// --external-toc writes the TOC to PATH (PATH.1... for split blobs) instead of
+// the end of the blob, which gets no footer.
+// --reproducible sorts entries by path so the blob only depends on the files.
+// --exclude leaves out what a pattern matches from a directory input, and
+// --ignore-file the paths a .dockerignore style file lists, see DirOptions.
+// --symlinks keeps its symlinks (keep, the default), follows them or fails
+// on them (forbid).
+// --lossless copies the tar headers of the input as they are.
+// --stats prints timings and compression ratios to stderr when done.
+fn create(mut args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut split_size = None;
+    let mut chunk_size = None;
+    let mut min_chunk_size = None;
+    let mut level = None;
+    let mut format = None;
+    let mut threads = None;
+    let mut external_toc = None;
+    let mut mtime = source_date_epoch()?;
+    let mut stats = false;
+    let mut lossless = false;
+    let mut reproducible = false;
+    let mut prioritized = Vec::new();
+    let mut dir_opts = DirOptions::new();
+    let mut dir_flags = false;
+    loop {
+        args = match args {
+            [flag, rest @ ..] if flag == "--stats" => {
+                stats = true;
+                rest
+            }
+            [flag, rest @ ..] if flag == "--reproducible" => {
+                reproducible = true;
+                rest
+            }
+            [flag, rest @ ..] if flag == "--lossless" => {
+                lossless = true;
+                rest
+            }
+            [flag, value, rest @ ..] => {
+                match flag.as_str() {
+                    "--split-size" => split_size = Some(value.parse::<u64>()?),
+                    "--chunk-size" => chunk_size = Some(value.parse::<usize>()?),
+                    "--min-chunk-size" => min_chunk_size = Some(value.parse::<usize>()?),
+                    "--level" => {
+                        level = Some(match value.as_str() {
+                            "store" => CompressionLevel::Store,
+                            "fast" => CompressionLevel::Fast,
+                            "default" => CompressionLevel::Default,
+                            "best" => CompressionLevel::Best,
+                            n => CompressionLevel::Level(n.parse()?),
+                        })
+                    }
+                    "--format" => {
+                        format = Some(match value.as_str() {
+                            "estargz" => BlobFormat::Estargz,
+                            "stargz" => BlobFormat::Stargz,
+                            "zstd:chunked" => BlobFormat::ZstdChunked,
+                            other => return Err(format!("unknown format {other}").into()),
+                        })
+                    }
+                    "--external-toc" => external_toc = Some(value.clone()),
+                    "--threads" => threads = Some(value.parse::<usize>()?),
+                    "--mtime" => mtime = Some(value.parse::<u64>()?),
+                    "--prioritize" => prioritized.push(value.clone()),
+                    "--exclude" => {
+                        dir_opts = dir_opts.ignore(value)?;
+                        dir_flags = true;
+                    }
+                    "--ignore-file" => {
+                        dir_opts = dir_opts.ignore_file(value)?;
+                        dir_flags = true;
+                    }
+                    "--symlinks" => {
+                        dir_opts = dir_opts.symlinks(match value.as_str() {
+                            "keep" => Symlinks::Keep,
+                            "follow" => Symlinks::Follow,
+                            "forbid" => Symlinks::Forbid,
+                            other => return Err(format!("unknown symlink policy {other}").into()),
+                        });
+                        dir_flags = true;
+                    }
+                    _ => break,
+                }
+                rest
+            }
+            _ => break,
+        };
+    }
+    let [input, output] = args else { usage() };
+    let dir = Path::new(input);
+    let input: Option<Box<dyn Read>> = match input.as_str() {
+        "-" => Some(Box::new(io::stdin().lock())),
+        _ if dir.is_dir() => None,
+        path => Some(Box::new(File::open(path)?)),
+    };
+    if input.is_none() && (lossless || !prioritized.is_empty()) {
+        return Err("--lossless and --prioritize need a tar input".into());
+    }
+    if input.is_some() && dir_flags {
+        return Err("--exclude, --ignore-file and --symlinks need a directory input".into());
+    }
+    let first: Box<dyn Write> = match output.as_str() {
+        "-" if split_size.is_some() => return Err("can't split output written to stdout".into()),
+        "-" => Box::new(io::stdout().lock()),
+        path => Box::new(File::create(path)?),
+    };
+
+    let mut w = Writer::new(first);
+    if reproducible {
+        w = w.with_reproducible_output(mtime);
+    } else if let Some(mtime) = mtime {
+        w = w.with_mtime_clamp(mtime);
+    }
+    if let Some(chunk_size) = chunk_size {
+        w = w.with_chunk_size(chunk_size)?;
+    }
+    if let Some(min_chunk_size) = min_chunk_size {
+        w = w.with_min_chunk_size(min_chunk_size);
+    }
+    if let Some(level) = level {
+        w = w.with_compression_level(level)?;
+    }
+    if external_toc.is_some() {
+        w = w.with_external_toc();
+    }
+    if let Some(threads) = threads {
+        w = w.with_threads(threads);
+    }
+    if let Some(format) = format {
+        w = w.with_format(format)?;
+    }
+    if !prioritized.is_empty() {
+        w = w.with_prioritized_files(&prioritized);
+    }
+    if let Some(split_size) = split_size {
+        w = w.with_split(split_size, |i| {
+            let f: Box<dyn Write> = Box::new(File::create(format!("{output}.{i}"))?);
+            Ok(f)
+        });
+    }
+    match input {
+        None => w.append_dir_all_with(dir, &dir_opts)?,
+        Some(mut input) if lossless => w.append_tar_lossless(&mut input)?,
+        Some(mut input) => w.append_tar(&mut input)?,
+    }
+    w.close()?;
+
+    if let Some(path) = &external_toc {
+        for (i, blob) in w.blobs().iter().enumerate() {
+            let toc = blob.external_toc.as_deref().unwrap_or_default();
+            match i {
+                0 => std::fs::write(path, toc)?,
+                i => std::fs::write(format!("{path}.{i}"), toc)?,
+            }
+        }
+    }
+
+    if split_size.is_some() {
+        for (i, blob) in w.blobs().iter().enumerate() {
+            eprintln!(
+                "blob {i}: {} bytes, {} entries, diff ID {}",
+                blob.size, blob.entries, blob.diff_id
+            );
+        }
+    }
+    if stats {
+        eprint!("{}", w.stats());
+    }
 
     Ok(())
 }
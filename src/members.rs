@@ -0,0 +1,112 @@
+use std::io::{self, BufRead, BufReader, Read};
+
+use anyhow::Result;
+use flate2::bufread::GzDecoder;
+
+use crate::{Error, ReadAt, SectionReader};
+
+/// One gzip member of a blob, see [`crate::GzReader::members`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    /// Blob offset of the member's header.
+    pub offset: u64,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    /// File contents starting at the beginning of the member. Empty for
+    /// members holding only tar headers and padding.
+    pub entries: Vec<MemberEntry>,
+}
+
+/// A file, or one chunk of it, stored at the start of a [`Member`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberEntry {
+    pub name: String,
+    /// Offset of the chunk in the file, 0 for unchunked files.
+    pub chunk_offset: u64,
+    /// Uncompressed bytes of the file in the chunk.
+    pub chunk_size: u64,
+}
+
+/// Iterator over consecutive gzip members of a blob region, yielding each
+/// member's boundaries as it is decompressed.
+///
+/// The region must start at a member boundary. Walking from 0 to the TOC
+/// offset ([`crate::GzReader::iter_members`]) covers the tar stream,
+/// landmark files included; going up to the footer offset adds the TOC
+/// member. The footer is a member of its own too, an empty one.
+///
+/// Iteration stops after the first error, as the next boundary can't be
+/// known past a member that doesn't decompress.
+pub struct MemberIter<'a> {
+    input: Tracked<BufReader<SectionReader<'a, dyn ReadAt + 'a>>>,
+    start: u64,
+    end: u64,
+    failed: bool,
+}
+
+impl<'a> MemberIter<'a> {
+    pub fn new(blob: &'a (dyn ReadAt + 'a), start: u64, end: u64) -> Self {
+        MemberIter {
+            input: Tracked {
+                inner: BufReader::new(SectionReader::new(blob, start, end.saturating_sub(start))),
+                pos: 0,
+            },
+            start,
+            end,
+            failed: false,
+        }
+    }
+}
+
+impl Iterator for MemberIter<'_> {
+    type Item = Result<Member>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.start + self.input.pos;
+        if self.failed || offset >= self.end {
+            return None;
+        }
+        let uncompressed_size =
+            match io::copy(&mut GzDecoder::new(&mut self.input), &mut io::sink()) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(anyhow::Error::new(e).context(Error::corrupt(format!(
+                        "no valid gzip member at offset {offset}"
+                    )))));
+                }
+            };
+        Some(Ok(Member {
+            offset,
+            compressed_size: self.start + self.input.pos - offset,
+            uncompressed_size,
+            entries: Vec::new(),
+        }))
+    }
+}
+
+// The decoder reads exactly one member from a BufRead, so the bytes consumed
+// so far are where the next member starts.
+struct Tracked<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: BufRead> Read for Tracked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Tracked<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.pos += amt as u64;
+    }
+}
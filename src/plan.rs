@@ -0,0 +1,122 @@
+use anyhow::{anyhow, Result};
+
+use crate::{fadvise, fadvise::Advice, GzReader, OpenedFile};
+
+/// Blob ranges to fetch so a set of files can be read without further I/O,
+/// see [`GzReader::fetch_plan`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchPlan {
+    /// Disjoint ranges in blob order.
+    pub ranges: Vec<FetchRange>,
+}
+
+impl FetchPlan {
+    /// Total bytes covered by the plan, gaps merged into ranges included.
+    pub fn bytes(&self) -> u64 {
+        self.ranges.iter().map(|r| r.end - r.start).sum()
+    }
+}
+
+/// A contiguous compressed range of the blob and the files it serves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchRange {
+    pub start: u64,
+    pub end: u64,
+    /// The requested paths whose content lies in the range.
+    pub files: Vec<String>,
+}
+
+/// Where one chunk of a file is stored, see [`GzReader::chunks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    /// Offset of the chunk in the file.
+    pub offset: u64,
+    /// Uncompressed length of the chunk.
+    pub size: u64,
+    /// Compressed range of the blob holding the chunk's gzip member, end
+    /// excluded. Fetching it is enough to decompress the chunk.
+    pub blob_start: u64,
+    pub blob_end: u64,
+}
+
+impl GzReader {
+    /// The chunks of the regular file at `path` in file order, for clients
+    /// pulling blobs lazily to decide which ranges to fetch. Symlinks are
+    /// followed; an empty file has no chunks.
+    pub fn chunks(&self, path: &str) -> Result<Vec<ChunkInfo>> {
+        let ent = self.lookup_follow(path)?;
+        if ent.entry_type != "reg" {
+            return Err(anyhow!("{path} is not a regular file"));
+        }
+        let mut chunks: Vec<ChunkInfo> = self
+            .get_chunks(ent)
+            .iter()
+            .filter(|chunk| chunk.chunk_size > 0)
+            .map(|chunk| ChunkInfo {
+                offset: chunk.chunk_offset,
+                size: chunk.chunk_size,
+                blob_start: chunk.offset,
+                blob_end: chunk.next_offset().max(chunk.offset),
+            })
+            .collect();
+        chunks.sort_by_key(|c| c.offset);
+
+        Ok(chunks)
+    }
+
+    /// Plan the reads needed for `paths` as few blob ranges as possible:
+    /// each file's gzip members are located, then ranges closer than
+    /// `max_gap` bytes are merged, trading some unneeded bytes for fewer
+    /// requests.
+    ///
+    /// Symlinks are followed; directories and other entries without content
+    /// need nothing fetched. A path that doesn't exist fails the whole plan.
+    pub fn fetch_plan<'p>(
+        &self,
+        paths: impl IntoIterator<Item = &'p str>,
+        max_gap: u64,
+    ) -> Result<FetchPlan> {
+        let mut spans = Vec::new();
+        for path in paths {
+            let ent = self.lookup_follow(path)?;
+            if ent.entry_type != "reg" || ent.size == 0 {
+                continue;
+            }
+            let (start, end) = OpenedFile::new(self, ent).blob_range();
+            if end > start {
+                spans.push((start, end, path.to_string()));
+            }
+        }
+        spans.sort();
+
+        let mut plan = FetchPlan::default();
+        for (start, end, path) in spans {
+            match plan.ranges.last_mut() {
+                Some(last) if start <= last.end.saturating_add(max_gap) => {
+                    last.end = last.end.max(end);
+                    last.files.push(path);
+                }
+                _ => plan.ranges.push(FetchRange {
+                    start,
+                    end,
+                    files: vec![path],
+                }),
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Execute `plan` against the local blob: ask the kernel to read every
+    /// range in ahead of the files being opened.
+    pub fn prefetch(&self, plan: &FetchPlan) {
+        for range in &plan.ranges {
+            fadvise::advise(
+                &*self.sr,
+                range.start,
+                range.end - range.start,
+                Advice::WillNeed,
+            );
+        }
+    }
+}
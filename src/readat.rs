@@ -0,0 +1,110 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::prelude::{AsRawFd, FileExt, RawFd},
+    sync::Mutex,
+};
+
+/// Positional reads from a blob, what a [`crate::GzReader`] needs from the
+/// source it reads from. Implemented by `File`, in-memory buffers, and by
+/// [`SeekReader`] for any `Read + Seek` source.
+pub trait ReadAt {
+    /// Read from `offset` without moving any cursor, like `pread(2)`.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Size of the blob in bytes.
+    fn size(&self) -> io::Result<u64>;
+
+    /// The file descriptor behind the source, if any, used for page cache
+    /// hints and vectored reads.
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ReadAt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(AsRawFd::as_raw_fd(self))
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = usize::try_from(offset).map_or(self.len(), |o| o.min(self.len()));
+        let n = buf.len().min(self.len() - start);
+        buf[..n].copy_from_slice(&self[start..start + n]);
+        Ok(n)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.as_slice().read_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+}
+
+/// Adapts a `Read + Seek` source to [`ReadAt`]: every read seeks to its
+/// offset first, under a lock so the reader can be shared between threads.
+pub struct SeekReader<R> {
+    inner: Mutex<R>,
+    size: u64,
+}
+
+impl<R: Read + Seek> SeekReader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let size = inner.seek(SeekFrom::End(0))?;
+        Ok(SeekReader {
+            inner: Mutex::new(inner),
+            size,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<R: Read + Seek> ReadAt for SeekReader<R> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        // A panic while holding the lock leaves the cursor anywhere, which
+        // doesn't matter as every read seeks first
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.seek(SeekFrom::Start(offset))?;
+        inner.read(buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+}
@@ -0,0 +1,255 @@
+//! Recovering blobs whose TOC or footer is damaged or missing.
+//!
+//! The data members of a stargz blob are still an ordinary gzipped tar, so
+//! the entries can be rebuilt by reading it front to back. Each file's data
+//! is indexed from the gzip member it starts in, at an inner offset when it
+//! doesn't start the member.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, Write},
+    os::unix::prelude::MetadataExt,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use flate2::{read::MultiGzDecoder, Compression};
+use tar::{Archive, EntryType};
+
+use crate::{
+    clean_entry_name, write_footer, write_toc_member, FooterFormat, JToc, MemberIter,
+    SectionReader, TocEntry, TOCT_TAR_NAME,
+};
+
+/// A TOC rebuilt from the tar stream of a damaged blob, see [`recover`].
+pub struct Recovery {
+    toc: JToc,
+    /// Where the data members covered by the rebuilt TOC end. A repaired blob
+    /// keeps the original bytes up to here.
+    pub data_end: u64,
+    /// Entries found in the stream but left out of the TOC, with the reason.
+    pub lost: Vec<(String, String)>,
+    /// Why the scan ended before the end of the tar stream, if it did.
+    pub stopped: Option<String>,
+}
+
+impl Recovery {
+    /// Number of entries in the rebuilt TOC, chunks not counted.
+    pub fn entries(&self) -> usize {
+        self.toc
+            .entries
+            .iter()
+            .filter(|e| e.entry_type != "chunk")
+            .count()
+    }
+
+    /// Write the rebuilt TOC as stargz.index.json content. Offsets are those
+    /// of the original blob.
+    pub fn write_index(&self, w: &mut dyn Write) -> Result<()> {
+        serde_json::to_writer_pretty(&mut *w, &self.toc)?;
+        writeln!(w)?;
+        Ok(())
+    }
+
+    /// Write a repaired copy of `blob`: its data members up to
+    /// [`Recovery::data_end`], followed by the rebuilt TOC and a new footer.
+    pub fn write_blob(&self, blob: &File, w: &mut dyn Write) -> Result<()> {
+        let copied = io::copy(&mut SectionReader::new(blob, 0, self.data_end), w)?;
+        if copied != self.data_end {
+            return Err(anyhow!(
+                "blob shrank while repairing it, copied {copied} of {} bytes",
+                self.data_end
+            ));
+        }
+        write_toc_member(w, &serde_json::to_vec(&self.toc)?, Compression::best())?;
+        write_footer(w, self.data_end, FooterFormat::Stargz)?;
+        Ok(())
+    }
+}
+
+/// Rebuild the TOC of `blob` by decompressing its members in order and
+/// walking the tar stream they hold, without looking at the footer or the
+/// existing TOC.
+///
+/// The scan stops at the old TOC entry, at the end of the tar stream, or at
+/// the first member that doesn't decompress; everything before that point is
+/// recovered.
+pub fn recover(blob: &File) -> Result<Recovery> {
+    let size = blob.metadata()?.size();
+
+    // (uncompressed offset, blob offset) of every member start
+    let mut starts: Vec<(u64, u64)> = Vec::new();
+    let mut uncompressed = 0;
+    let mut valid_end = 0;
+    let mut stopped = None;
+    for member in MemberIter::new(blob, 0, size) {
+        match member {
+            Ok(m) => {
+                starts.push((uncompressed, m.offset));
+                uncompressed += m.uncompressed_size;
+                valid_end = m.offset + m.compressed_size;
+            }
+            Err(e) => {
+                stopped = Some(format!("{e:#}"));
+                break;
+            }
+        }
+    }
+
+    let mut toc = JToc::new(1);
+    let mut lost = Vec::new();
+    let mut kept = HashSet::new();
+    // Uncompressed offset of the first byte not covered by a recovered entry
+    let mut stream_end = 0;
+    let mut archive = Archive::new(MultiGzDecoder::new(SectionReader::new(blob, 0, valid_end)));
+    let mut entries = archive.entries()?;
+    loop {
+        let mut entry = match entries.next() {
+            None => break,
+            Some(Ok(entry)) => entry,
+            Some(Err(e)) => {
+                stopped.get_or_insert_with(|| format!("tar stream unreadable: {e}"));
+                break;
+            }
+        };
+        let header_pos = entry.raw_header_position();
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        if name == TOCT_TAR_NAME {
+            stream_end = header_pos;
+            break;
+        }
+        let data_pos = entry.raw_file_position();
+        let data_len = entry.header().entry_size()?;
+        if data_pos + data_len > uncompressed {
+            lost.push((name, "data is truncated".to_string()));
+            break;
+        }
+        stream_end = data_pos + data_len.div_ceil(512) * 512;
+
+        let mut ent = match toc_entry(&mut entry, &name) {
+            Ok(ent) => ent,
+            Err(e) => {
+                lost.push((name, e.to_string()));
+                continue;
+            }
+        };
+        if ent.entry_type == "hardlink" && !kept.contains(&clean_entry_name(&ent.link_name)) {
+            lost.push((name, format!("link target {} is lost", ent.link_name)));
+            continue;
+        }
+        let mut chunks = Vec::new();
+        if ent.entry_type == "reg" && ent.size > 0 {
+            // The data starts in the last member starting at or before it,
+            // and every member starting inside the data is a chunk boundary
+            let first = starts.partition_point(|&(u, _)| u <= data_pos);
+            let (member_start, member_offset) = starts[first - 1];
+            let boundaries: Vec<(u64, u64)> = [(0, member_offset)]
+                .into_iter()
+                .chain(
+                    starts[first..]
+                        .iter()
+                        .take_while(|&&(u, _)| u < data_pos + ent.size)
+                        .map(|&(u, offset)| (u - data_pos, offset)),
+                )
+                .collect();
+            for (i, &(chunk_offset, offset)) in boundaries.iter().enumerate() {
+                let chunk_end = boundaries.get(i + 1).map_or(ent.size, |b| b.0);
+                if i == 0 {
+                    ent.offset = offset;
+                    ent.inner_offset = data_pos - member_start;
+                    if boundaries.len() > 1 {
+                        ent.chunk_size = chunk_end;
+                    }
+                } else {
+                    chunks.push(TocEntry {
+                        name: ent.name.clone(),
+                        entry_type: "chunk".to_string(),
+                        offset,
+                        chunk_offset,
+                        chunk_size: chunk_end - chunk_offset,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        kept.insert(clean_entry_name(&name));
+        toc.entries.push(ent);
+        toc.entries.append(&mut chunks);
+    }
+
+    // Keep whole members: if the stream ends inside one, it's kept entirely
+    let data_end = match starts.iter().find(|&&(u, _)| u >= stream_end) {
+        Some(&(_, offset)) => offset,
+        None => valid_end,
+    };
+
+    Ok(Recovery {
+        toc,
+        data_end,
+        lost,
+        stopped,
+    })
+}
+
+// The TOC entry for a tar entry, data offsets left for the caller
+pub(crate) fn toc_entry<R: io::Read>(entry: &mut tar::Entry<R>, name: &str) -> Result<TocEntry> {
+    let mut xattrs = HashMap::new();
+    if let Some(exts) = entry.pax_extensions()? {
+        for ext in exts {
+            let ext = ext?;
+            if let Some(key) = ext.key()?.strip_prefix("SCHILY.xattr.") {
+                xattrs.insert(key.to_string(), ext.value_bytes().to_vec());
+            }
+        }
+    }
+    let h = entry.header();
+    let mod_time = Utc
+        .timestamp_opt(i64::try_from(h.mtime()?)?, 0)
+        .single()
+        .ok_or_else(|| anyhow!("invalid mtime"))?;
+    let link_name = || -> Result<String> {
+        let link = entry
+            .link_name_bytes()
+            .ok_or_else(|| anyhow!("link without a target"))?;
+        Ok(String::from_utf8_lossy(&link).into_owned())
+    };
+    let mut ent = TocEntry {
+        name: name.to_string(),
+        mod_time_3339: Some(mod_time.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        mode: h.mode()?,
+        uid: h.uid()?.try_into()?,
+        gid: h.gid()?.try_into()?,
+        uname: h.username()?.unwrap_or("").to_string(),
+        gname: h.groupname()?.unwrap_or("").to_string(),
+        xattrs,
+        ..Default::default()
+    };
+    match h.entry_type() {
+        EntryType::Regular | EntryType::Continuous => {
+            ent.entry_type = "reg".to_string();
+            ent.size = h.entry_size()?;
+        }
+        EntryType::Directory => ent.entry_type = "dir".to_string(),
+        EntryType::Symlink => {
+            ent.entry_type = "symlink".to_string();
+            ent.link_name = link_name()?;
+        }
+        EntryType::Link => {
+            ent.entry_type = "hardlink".to_string();
+            ent.link_name = link_name()?;
+        }
+        EntryType::Char | EntryType::Block => {
+            ent.entry_type = if h.entry_type() == EntryType::Char {
+                "char".to_string()
+            } else {
+                "block".to_string()
+            };
+            ent.dev_major = h.device_major()?.unwrap_or(0).into();
+            ent.dev_minor = h.device_minor()?.unwrap_or(0).into();
+        }
+        EntryType::Fifo => ent.entry_type = "fifo".to_string(),
+        other => return Err(anyhow!("{other:?} entries can't be indexed")),
+    }
+    Ok(ent)
+}
@@ -1,72 +1,158 @@
-use std::{
-    io::{Error, ErrorKind, Read},
-    os::unix::prelude::FileExt,
-};
+use std::io::{Error, ErrorKind, IoSliceMut, Read};
 
-pub struct SectionReader<'a, R: FileExt> {
+use crate::ReadAt;
+
+/// Reads `n` bytes of a [`ReadAt`] source starting at an offset, like Go's
+/// `io.SectionReader`.
+pub struct SectionReader<'a, R: ReadAt + ?Sized> {
     reader: &'a R,
-    base: u32,
-    offset: u32,
-    limit: u32,
+    base: u64,
+    offset: u64,
+    limit: u64,
 }
 
-impl<'a, R: FileExt> SectionReader<'a, R> {
-    pub fn new(reader: &'a R, offset: u32, n: u32) -> Self {
-        let remaining: u32;
-        if offset <= u32::MAX - n {
-            remaining = n + offset;
-        } else {
-            remaining = u32::MAX;
-        }
+impl<'a, R: ReadAt + ?Sized> SectionReader<'a, R> {
+    pub fn new(reader: &'a R, offset: u64, n: u64) -> Self {
         SectionReader {
             reader,
             base: offset,
             offset,
-            limit: remaining,
+            limit: offset.saturating_add(n),
         }
     }
 
-    pub fn read_at(&mut self, buf: &mut [u8], mut offset: u32) -> std::io::Result<usize> {
+    pub fn read_at(&mut self, buf: &mut [u8], mut offset: u64) -> std::io::Result<usize> {
         if offset >= self.limit - self.base {
             return Err(Error::new(ErrorKind::InvalidInput, "Invalid offset"));
         }
 
         offset += self.base;
-        let max = (self.limit - self.offset) as usize;
-        let mut n: usize = 0;
-
-        if buf.len() > max {
-            n = self.reader.read_at(&mut buf[0..max], offset.into())?;
-        } else {
-            n = self.reader.read_at(buf, offset.into())?;
-        }
+        let max = clamp_len(buf.len(), self.limit - offset);
+        let n = self.reader.read_at(&mut buf[0..max], offset)?;
 
         Ok(n)
     }
 
     pub fn inner(&self) -> &R {
-        return &self.reader;
+        self.reader
+    }
+    /// Scatter read at `offset` of the section: fill `bufs` in order from a
+    /// single `preadv(2)`, stopping at the end of the section.
+    pub fn read_vectored_at(
+        &self,
+        bufs: &mut [IoSliceMut<'_>],
+        offset: u64,
+    ) -> std::io::Result<usize> {
+        if offset >= self.limit - self.base {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid offset"));
+        }
+
+        let offset = offset + self.base;
+        let mut clamped = clamp_bufs(bufs, self.limit - offset);
+        preadv(self.reader, &mut clamped, offset)
     }
 }
 
-impl<'a, R: FileExt> Read for SectionReader<'a, R> {
+impl<'a, R: ReadAt + ?Sized> Read for SectionReader<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         if self.offset >= self.limit {
-            return Err(Error::new(
-                ErrorKind::UnexpectedEof,
-                "offset larger than limit",
-            ));
+            return Ok(0);
         }
-        let max = (self.limit - self.offset) as usize;
-        let mut n: usize = 0;
-        if buf.len() > max {
-            n = self.reader.read_at(&mut buf[0..max], self.offset.into())?;
-        } else {
-            n = self.reader.read_at(buf, self.offset.into())?;
+        let max = clamp_len(buf.len(), self.limit - self.offset);
+        let n = self.reader.read_at(&mut buf[0..max], self.offset)?;
+
+        self.offset += n as u64;
+
+        Ok(n)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+        if self.offset >= self.limit {
+            return Ok(0);
         }
+        let mut clamped = clamp_bufs(bufs, self.limit - self.offset);
+        let n = preadv(self.reader, &mut clamped, self.offset)?;
 
-        self.offset += n as u32;
+        self.offset += n as u64;
 
         Ok(n)
     }
 }
+
+// Reborrow as many of the buffers as fit in the bytes left in the section,
+// the last one shortened if needed.
+fn clamp_bufs<'b>(bufs: &'b mut [IoSliceMut<'_>], mut remaining: u64) -> Vec<IoSliceMut<'b>> {
+    let mut clamped = Vec::with_capacity(bufs.len());
+    for buf in bufs.iter_mut() {
+        if remaining == 0 {
+            break;
+        }
+        let n = clamp_len(buf.len(), remaining);
+        clamped.push(IoSliceMut::new(&mut buf[..n]));
+        remaining -= n as u64;
+    }
+    clamped
+}
+
+// Same as the offsets of posix_fadvise in fadvise
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+use libc::{off64_t as off_t, preadv64 as sys_preadv};
+#[cfg(all(
+    any(target_os = "linux", target_os = "android", target_os = "freebsd"),
+    not(all(target_os = "linux", target_env = "gnu"))
+))]
+use libc::{off_t, preadv as sys_preadv};
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn preadv<R: ReadAt + ?Sized>(
+    reader: &R,
+    bufs: &mut [IoSliceMut<'_>],
+    offset: u64,
+) -> std::io::Result<usize> {
+    let Some(fd) = reader.as_raw_fd() else {
+        return read_first(reader, bufs, offset);
+    };
+    // Anything past IOV_MAX is left for the caller's next read, like a short
+    // read
+    const IOV_MAX: usize = 1024;
+    let offset = off_t::try_from(offset)
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "offset out of range"))?;
+    let count = bufs.len().min(IOV_MAX) as libc::c_int;
+    // IoSliceMut is guaranteed to be ABI compatible with iovec on unix
+    let n = unsafe { sys_preadv(fd, bufs.as_ptr() as *const libc::iovec, count, offset) };
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn preadv<R: ReadAt + ?Sized>(
+    reader: &R,
+    bufs: &mut [IoSliceMut<'_>],
+    offset: u64,
+) -> std::io::Result<usize> {
+    read_first(reader, bufs, offset)
+}
+
+// No preadv, read into the first non-empty buffer
+fn read_first<R: ReadAt + ?Sized>(
+    reader: &R,
+    bufs: &mut [IoSliceMut<'_>],
+    offset: u64,
+) -> std::io::Result<usize> {
+    match bufs.iter_mut().find(|b| !b.is_empty()) {
+        Some(buf) => reader.read_at(buf, offset),
+        None => Ok(0),
+    }
+}
+
+// Bound a buffer length by the bytes left in the section. The remainder is a
+// u64 and may not fit in usize on 32-bit targets, in which case the buffer
+// is the limit anyway.
+fn clamp_len(buf_len: usize, remaining: u64) -> usize {
+    match usize::try_from(remaining) {
+        Ok(remaining) => buf_len.min(remaining),
+        Err(_) => buf_len,
+    }
+}
@@ -0,0 +1,388 @@
+//! Sparse files of input tars: where their data is, from GNU sparse headers
+//! or the PAX records of the 0.0, 0.1 and 1.0 formats, and the GNU sparse
+//! headers the [`crate::Writer`] stores them with, holes left out.
+
+use std::io::{self, Read};
+
+use anyhow::{anyhow, Context, Result};
+use tar::{EntryType, GnuExtSparseHeader, Header};
+
+const BLOCK_SIZE: usize = 512;
+
+/// The data regions of a sparse file.
+#[derive(Debug, Clone)]
+pub(crate) struct SparseMap {
+    /// (offset, length) of the regions holding data, in file order and none
+    /// of them empty. Everything else is a hole.
+    pub regions: Vec<(u64, u64)>,
+    /// Size of the file, holes included.
+    pub size: u64,
+    /// Whether the content comes as the data regions back to back, as in
+    /// the PAX formats, rather than with the holes filled in, as the tar
+    /// crate hands out GNU sparse files.
+    pub packed: bool,
+}
+
+impl SparseMap {
+    // Check `regions` against the logical `size` and the `data` bytes the
+    // entry holds for them
+    fn new(regions: Vec<(u64, u64)>, size: u64, data: u64, packed: bool) -> Result<Self> {
+        let mut end = 0;
+        let mut total: u64 = 0;
+        for &(offset, len) in &regions {
+            let region_end = offset
+                .checked_add(len)
+                .ok_or_else(|| anyhow!("sparse region at {offset} overflows"))?;
+            if offset < end || region_end > size {
+                return Err(anyhow!(
+                    "sparse region {offset}+{len} overlaps the previous one or ends past {size}"
+                ));
+            }
+            end = region_end;
+            total += len;
+        }
+        if total != data {
+            return Err(anyhow!(
+                "sparse map lists {total} bytes of data, the entry holds {data}"
+            ));
+        }
+        Ok(SparseMap {
+            regions: regions.into_iter().filter(|&(_, len)| len > 0).collect(),
+            size,
+            packed,
+        })
+    }
+
+    /// The map with every region but the last a whole number of blocks
+    /// long, taking in the start of the hole after it or merging it with the
+    /// next region: the tar crate, for one, wants the data of each region
+    /// to start on a block boundary of the archive.
+    pub fn aligned(&self) -> SparseMap {
+        let block = BLOCK_SIZE as u64;
+        let mut regions: Vec<(u64, u64)> = Vec::new();
+        for &(offset, len) in &self.regions {
+            match regions.last_mut() {
+                Some(last) if last.0 + last.1.next_multiple_of(block) >= offset => {
+                    last.1 = offset + len - last.0;
+                }
+                Some(last) => {
+                    last.1 = last.1.next_multiple_of(block);
+                    regions.push((offset, len));
+                }
+                None => regions.push((offset, len)),
+            }
+        }
+        SparseMap {
+            regions,
+            ..self.clone()
+        }
+    }
+
+    /// Bytes of data, holes left out.
+    pub fn data_size(&self) -> u64 {
+        self.regions.iter().map(|(_, len)| len).sum()
+    }
+
+    /// The map as kept in the TOC, where a file that is a hole from start
+    /// to end still needs a region to be told apart from a regular file.
+    pub fn toc_map(&self) -> Vec<(u64, u64)> {
+        match self.regions.is_empty() {
+            true => vec![(self.size, 0)],
+            false => self.regions.clone(),
+        }
+    }
+}
+
+/// The map of a GNU sparse entry ('S'), from its header and the extension
+/// headers following it in `ext`. Its content is read with the holes filled
+/// in.
+pub(crate) fn from_gnu(h: &Header, ext: &[u8]) -> Result<SparseMap> {
+    let gnu = h
+        .as_gnu()
+        .ok_or_else(|| anyhow!("sparse entry without a GNU header"))?;
+    let mut regions = Vec::new();
+    for block in gnu.sparse.iter().filter(|b| !b.is_empty()) {
+        regions.push((block.offset()?, block.length()?));
+    }
+    let mut extended = gnu.is_extended();
+    let mut blocks = ext.chunks_exact(BLOCK_SIZE);
+    while extended {
+        let block = blocks
+            .next()
+            .ok_or_else(|| anyhow!("sparse map cut short"))?;
+        let mut header = GnuExtSparseHeader::new();
+        header.as_mut_bytes().copy_from_slice(block);
+        for block in header.sparse().iter().filter(|b| !b.is_empty()) {
+            regions.push((block.offset()?, block.length()?));
+        }
+        extended = header.is_extended();
+    }
+    SparseMap::new(regions, gnu.real_size()?, h.entry_size()?, false)
+}
+
+/// The map of a sparse file stored in the PAX formats, or `None` if
+/// `records` don't describe one. Formats 0.0 and 0.1 keep the map in the
+/// records, 1.0 at the start of the content, where it's read from `data`;
+/// `data_size` is the size of the content, map included.
+///
+/// The name to extract the file as is in the `GNU.sparse.name` record when
+/// the header holds a made-up one.
+pub(crate) fn from_pax(
+    records: &[(String, Vec<u8>)],
+    data: &mut dyn Read,
+    data_size: u64,
+) -> Result<Option<SparseMap>> {
+    let value = |key: &str| {
+        records
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+    };
+    let number = |key: &str| -> Result<Option<u64>> {
+        value(key)
+            .map(|v| v.trim().parse().with_context(|| format!("{key} is {v:?}")))
+            .transpose()
+    };
+    let size = match number("GNU.sparse.realsize")? {
+        Some(size) => Some(size),
+        None => number("GNU.sparse.size")?,
+    };
+
+    let (regions, map_size) = match (number("GNU.sparse.major")?, number("GNU.sparse.minor")?) {
+        (Some(1), Some(0) | None) => read_map(data)?,
+        (Some(major), minor) if major != 0 => {
+            return Err(anyhow!(
+                "unsupported PAX sparse format {major}.{}",
+                minor.unwrap_or(0)
+            ));
+        }
+        _ => {
+            let regions = if let Some(map) = value("GNU.sparse.map") {
+                // 0.1: "offset,length,offset,length..."
+                let numbers = map
+                    .split(',')
+                    .filter(|n| !n.is_empty())
+                    .map(|n| n.trim().parse::<u64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("GNU.sparse.map is {map:?}"))?;
+                pairs(&numbers)?
+            } else {
+                // 0.0: an offset then a length record per region
+                let numbers = records
+                    .iter()
+                    .filter(|(k, _)| k == "GNU.sparse.offset" || k == "GNU.sparse.numbytes")
+                    .map(|(k, v)| {
+                        let v = String::from_utf8_lossy(v);
+                        v.trim()
+                            .parse::<u64>()
+                            .with_context(|| format!("{k} is {v:?}"))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if numbers.is_empty() {
+                    return match size {
+                        Some(_) => Err(anyhow!("PAX sparse records without a map")),
+                        None => Ok(None),
+                    };
+                }
+                pairs(&numbers)?
+            };
+            (regions, 0)
+        }
+    };
+    let size = size.ok_or_else(|| anyhow!("PAX sparse records without the file size"))?;
+    let data = data_size
+        .checked_sub(map_size)
+        .ok_or_else(|| anyhow!("sparse map runs past the end of the entry"))?;
+    SparseMap::new(regions, size, data, true).map(Some)
+}
+
+// (offset, length) pairs from a flat list of numbers
+fn pairs(numbers: &[u64]) -> Result<Vec<(u64, u64)>> {
+    if !numbers.len().is_multiple_of(2) {
+        return Err(anyhow!("sparse map has an offset without a length"));
+    }
+    Ok(numbers.chunks_exact(2).map(|p| (p[0], p[1])).collect())
+}
+
+// The map of the 1.0 format at the start of the content: the number of
+// regions then their offsets and lengths, one decimal number a line, padded
+// to whole blocks. Returns it with the bytes it took.
+fn read_map(data: &mut dyn Read) -> Result<(Vec<(u64, u64)>, u64)> {
+    let mut map = Vec::new();
+    let mut block = [0; BLOCK_SIZE];
+    loop {
+        data.read_exact(&mut block)
+            .context("sparse map runs past the end of the entry")?;
+        map.extend_from_slice(&block);
+        // Every line but the last is complete
+        let mut lines = map.split(|&b| b == b'\n');
+        lines.next_back();
+        let numbers = lines
+            .map(|line| {
+                let line = String::from_utf8_lossy(line);
+                line.parse::<u64>()
+                    .with_context(|| format!("sparse map line is {line:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let Some((&count, rest)) = numbers.split_first() else {
+            continue;
+        };
+        let wanted = usize::try_from(count)
+            .ok()
+            .and_then(|c| c.checked_mul(2))
+            .ok_or_else(|| anyhow!("sparse map of {count} regions"))?;
+        if rest.len() >= wanted {
+            return Ok((pairs(&rest[..wanted])?, map.len() as u64));
+        }
+    }
+}
+
+/// Turn `h`, whose size is that of the file, into a GNU sparse header for
+/// `map`, its size becoming that of the data. Returns the extension headers
+/// to write after it, for the regions the header has no room for.
+pub(crate) fn set_gnu_header(h: &mut Header, map: &SparseMap) -> Result<Vec<u8>> {
+    // Readers expect the map to reach the end of the file
+    let mut regions = map.regions.clone();
+    if regions
+        .last()
+        .is_none_or(|&(offset, len)| offset + len < map.size)
+    {
+        regions.push((map.size, 0));
+    }
+
+    h.set_entry_type(EntryType::GNUSparse);
+    h.set_size(map.data_size());
+    let gnu = h
+        .as_gnu_mut()
+        .ok_or_else(|| anyhow!("sparse files need a GNU header"))?;
+    gnu.set_real_size(map.size);
+    let (first, mut rest) = regions.split_at(regions.len().min(gnu.sparse.len()));
+    for (block, &(offset, len)) in gnu.sparse.iter_mut().zip(first) {
+        block.set_offset(offset);
+        block.set_length(len);
+    }
+    gnu.set_is_extended(!rest.is_empty());
+
+    let mut ext = Vec::new();
+    while !rest.is_empty() {
+        let mut header = GnuExtSparseHeader::new();
+        let (these, others) = rest.split_at(rest.len().min(header.sparse().len()));
+        for (block, &(offset, len)) in header.sparse_mut().iter_mut().zip(these) {
+            block.set_offset(offset);
+            block.set_length(len);
+        }
+        header.set_is_extended(!others.is_empty());
+        ext.extend_from_slice(header.as_bytes());
+        rest = others;
+    }
+    Ok(ext)
+}
+
+/// The content of a sparse file, holes included, from its data regions
+/// read back to back from `inner`.
+pub(crate) struct Filled<R: Read> {
+    inner: R,
+    regions: Vec<(u64, u64)>,
+    size: u64,
+    pos: u64,
+    // First region not entirely read
+    next: usize,
+}
+
+impl<R: Read> Filled<R> {
+    pub fn new(inner: R, map: &SparseMap) -> Self {
+        Filled {
+            inner,
+            regions: map.regions.clone(),
+            size: map.size,
+            pos: 0,
+            next: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Filled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self
+            .regions
+            .get(self.next)
+            .is_some_and(|&(offset, len)| offset + len <= self.pos)
+        {
+            self.next += 1;
+        }
+        let (n, in_data) = match self.regions.get(self.next) {
+            Some(&(offset, len)) if offset <= self.pos => (offset + len - self.pos, true),
+            Some(&(offset, _)) => (offset - self.pos, false),
+            None => (self.size.saturating_sub(self.pos), false),
+        };
+        let n = buf.len().min(usize::try_from(n).unwrap_or(usize::MAX));
+        let n = match in_data {
+            true => self.inner.read(&mut buf[..n])?,
+            false => {
+                buf[..n].fill(0);
+                n
+            }
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// The data regions of a sparse file back to back, from its content read
+/// with the holes filled in from `inner`. Nothing past the last region is
+/// read.
+pub(crate) struct DataOnly<'m, R: Read> {
+    inner: R,
+    regions: &'m [(u64, u64)],
+    pos: u64,
+}
+
+impl<'m, R: Read> DataOnly<'m, R> {
+    pub fn new(inner: R, map: &'m SparseMap) -> Self {
+        DataOnly {
+            inner,
+            regions: &map.regions,
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for DataOnly<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(&(offset, len)) = self.regions.first() {
+            if self.pos >= offset + len {
+                self.regions = &self.regions[1..];
+                continue;
+            }
+            if self.pos < offset {
+                let hole = offset - self.pos;
+                let skipped = io::copy(&mut (&mut self.inner).take(hole), &mut io::sink())?;
+                self.pos += skipped;
+                if skipped < hole {
+                    return Ok(0);
+                }
+            }
+            let n = buf
+                .len()
+                .min(usize::try_from(offset + len - self.pos).unwrap_or(usize::MAX));
+            let n = self.inner.read(&mut buf[..n])?;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+        Ok(0)
+    }
+}
+
+/// Whether the PAX records of `f` make it a sparse file.
+pub(crate) fn is_pax_sparse<R: Read>(f: &mut tar::Entry<'_, R>) -> Result<bool> {
+    let Some(exts) = f.pax_extensions()? else {
+        return Ok(false);
+    };
+    for ext in exts {
+        if ext?.key_bytes().starts_with(b"GNU.sparse.") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
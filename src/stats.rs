@@ -0,0 +1,249 @@
+use std::{collections::BTreeMap, fmt, path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+use crate::{parent_dir, walk::Walk, GzReader};
+
+// Upper bounds of the file size classes, the last one is open-ended
+const SIZE_CLASSES: [u64; 4] = [4 << 10, 64 << 10, 1 << 20, 16 << 20];
+
+/// What a [`crate::Writer`] did, for tuning chunking and compression
+/// settings, see [`crate::Writer::stats`].
+///
+/// Compressed sizes are attributed to entries by how much output each one
+/// produced. The compressor buffers its input, so for small files they are
+/// an approximation; the totals are exact.
+#[derive(Debug, Clone)]
+pub struct WriterStats {
+    /// Gzip members written, over all blobs.
+    pub members: u64,
+    /// Chunks whose member was copied from the blob given to
+    /// [`crate::Writer::with_chunk_reuse`] rather than compressed.
+    pub reused_chunks: u64,
+    /// Entries written.
+    pub entries: u64,
+    /// Size of the tar stream before compression.
+    pub uncompressed_bytes: u64,
+    /// Size of the output.
+    pub compressed_bytes: u64,
+    /// Regular files by content size, holes of sparse files left out.
+    pub size_classes: Vec<SizeClass>,
+    /// Sparse files written.
+    pub sparse_files: u64,
+    /// Size of the sparse files, holes included.
+    pub sparse_logical_bytes: u64,
+    /// Data actually stored for the sparse files.
+    pub sparse_physical_bytes: u64,
+    /// Bytes per top-level directory of the layer, "" holding the entries at
+    /// the root.
+    pub directories: BTreeMap<String, Ratio>,
+}
+
+/// Regular files whose size is below `max_size` (and at least the previous
+/// class's), `None` being unbounded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeClass {
+    pub max_size: Option<u64>,
+    pub files: u64,
+    pub bytes: u64,
+    /// Time spent compressing the files' content.
+    pub compress_time: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ratio {
+    pub uncompressed: u64,
+    pub compressed: u64,
+}
+
+impl Ratio {
+    /// Compressed over uncompressed size, 1.0 when there is nothing.
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed == 0 {
+            return 1.0;
+        }
+        self.compressed as f64 / self.uncompressed as f64
+    }
+}
+
+impl Default for WriterStats {
+    fn default() -> Self {
+        let bounds = SIZE_CLASSES.iter().map(|&b| Some(b)).chain([None]);
+        WriterStats {
+            members: 0,
+            reused_chunks: 0,
+            entries: 0,
+            uncompressed_bytes: 0,
+            compressed_bytes: 0,
+            size_classes: bounds
+                .map(|max_size| SizeClass {
+                    max_size,
+                    files: 0,
+                    bytes: 0,
+                    compress_time: Duration::ZERO,
+                })
+                .collect(),
+            sparse_files: 0,
+            sparse_logical_bytes: 0,
+            sparse_physical_bytes: 0,
+            directories: BTreeMap::new(),
+        }
+    }
+}
+
+impl WriterStats {
+    /// Compressed over uncompressed size of the whole output.
+    pub fn ratio(&self) -> f64 {
+        Ratio {
+            uncompressed: self.uncompressed_bytes,
+            compressed: self.compressed_bytes,
+        }
+        .ratio()
+    }
+
+    // Account for one entry: `uncompressed` tar bytes (headers and padding
+    // included) that produced `compressed` bytes of output in `elapsed`
+    pub(crate) fn record_entry(
+        &mut self,
+        name: &str,
+        content_size: Option<u64>,
+        uncompressed: u64,
+        compressed: u64,
+        elapsed: Duration,
+    ) {
+        self.entries += 1;
+        self.uncompressed_bytes += uncompressed;
+        let dir = match name.trim_start_matches('/').split_once('/') {
+            Some((top, _)) => top,
+            None => "",
+        };
+        let ratio = self.directories.entry(dir.to_string()).or_default();
+        ratio.uncompressed += uncompressed;
+        ratio.compressed += compressed;
+
+        if let Some(size) = content_size {
+            let class = SIZE_CLASSES
+                .iter()
+                .position(|&max| size < max)
+                .unwrap_or(SIZE_CLASSES.len());
+            let class = &mut self.size_classes[class];
+            class.files += 1;
+            class.bytes += size;
+            class.compress_time += elapsed;
+        }
+    }
+
+    // Account for a sparse file of `logical` bytes holding `physical` bytes
+    // of data
+    pub(crate) fn record_sparse(&mut self, logical: u64, physical: u64) {
+        self.sparse_files += 1;
+        self.sparse_logical_bytes += logical;
+        self.sparse_physical_bytes += physical;
+    }
+}
+
+impl fmt::Display for WriterStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} entries in {} gzip members, {} -> {} bytes ({:.1}%)",
+            self.entries,
+            self.members,
+            self.uncompressed_bytes,
+            self.compressed_bytes,
+            self.ratio() * 100.0
+        )?;
+        if self.reused_chunks > 0 {
+            writeln!(f, "  {} chunks reused", self.reused_chunks)?;
+        }
+        let mut min = 0;
+        for class in &self.size_classes {
+            let range = match class.max_size {
+                Some(max) => format!("{min}..{max}"),
+                None => format!("{min}.."),
+            };
+            min = class.max_size.unwrap_or(min);
+            if class.files == 0 {
+                continue;
+            }
+            writeln!(
+                f,
+                "  files {range:>20}: {:>8} files {:>12} bytes {:>10.3?}",
+                class.files, class.bytes, class.compress_time
+            )?;
+        }
+        if self.sparse_files > 0 {
+            writeln!(
+                f,
+                "  sparse files: {} holding {} bytes of data, {} with the holes",
+                self.sparse_files, self.sparse_physical_bytes, self.sparse_logical_bytes
+            )?;
+        }
+        for (dir, ratio) in &self.directories {
+            writeln!(
+                f,
+                "  dir /{dir:<20} {:>12} -> {:>12} bytes ({:.1}%)",
+                ratio.uncompressed,
+                ratio.compressed,
+                ratio.ratio() * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What a directory of a layer holds, everything below it counted, see
+/// [`GzReader::du`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Content size of the regular files, uncompressed. Hardlinks don't
+    /// count the file they point to again.
+    pub bytes: u64,
+    /// What of `bytes` is stored in the layer, the holes of sparse files
+    /// left out.
+    pub stored_bytes: u64,
+    /// Regular files, hardlinks not included.
+    pub files: u64,
+    /// Entries of any type, the directory itself excluded.
+    pub entries: u64,
+}
+
+impl GzReader {
+    /// Usage of the directory at `path` and of every directory below it,
+    /// keyed by full path, to see which parts of a layer take up the
+    /// space. Symlinks leading to the directory are followed, the ones in
+    /// it aren't.
+    pub fn du(&self, path: impl AsRef<Path>) -> Result<BTreeMap<String, DiskUsage>> {
+        let path = path.as_ref();
+        let dir = self.lookup_follow(path)?;
+        if dir.entry_type != "dir" {
+            return Err(anyhow!("{} is not a directory", path.display()));
+        }
+        let mut usage = BTreeMap::from([(dir.name.clone(), DiskUsage::default())]);
+        for (name, ent) in Walk::new(self, dir) {
+            if ent.entry_type == "dir" {
+                // Walked before anything it contains
+                usage.insert(name.to_string(), DiskUsage::default());
+            }
+            let (bytes, stored_bytes, files) = match ent.entry_type.as_str() {
+                "reg" => (ent.size, ent.physical_size(), 1),
+                _ => (0, 0, 0),
+            };
+            let mut parent = parent_dir(name);
+            loop {
+                if let Some(u) = usage.get_mut(parent) {
+                    u.bytes += bytes;
+                    u.stored_bytes += stored_bytes;
+                    u.files += files;
+                    u.entries += 1;
+                }
+                if parent == dir.name {
+                    break;
+                }
+                parent = parent_dir(parent);
+            }
+        }
+
+        Ok(usage)
+    }
+}
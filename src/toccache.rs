@@ -0,0 +1,637 @@
+//! Parsed TOCs kept on disk across process restarts.
+//!
+//! A cache entry holds the reader's index exactly as `open` builds it, in a
+//! compact binary encoding, so reopening a layer costs a footer read and a
+//! file read instead of decompressing, parsing and indexing the TOC again.
+//! Entries are checksummed: a corrupt one is rebuilt rather than trusted
+//! with offsets into the blob.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use flate2::Crc;
+
+use crate::{
+    cache::ChunkCache, open_with_options, read_footer, Footer, GzReader, Index, JToc, ParseMode,
+    ReadAt, ReaderOptions, TocEntry,
+};
+
+const MAGIC: &[u8; 7] = b"SGZTOC\x00";
+// Bumped whenever the encoding or what init_fields produces changes
+const VERSION: u8 = 8;
+
+// Temporary files older than this are left over from a crashed store
+const STALE_TMP: Duration = Duration::from_secs(3600);
+
+/// A directory of parsed TOCs keyed by blob digest.
+///
+/// The digest is trusted to identify the blob, as OCI layer digests do; the
+/// cache only double checks the blob size and TOC offset from the footer.
+///
+/// Without a [`TocCache::budget`] the cache grows with every layer opened.
+/// With one, the least recently used entries are evicted once the cache is
+/// over it, except those of the digests [pinned](TocCache::pin), e.g. the
+/// layers currently mounted.
+#[derive(Debug, Clone)]
+pub struct TocCache {
+    dir: PathBuf,
+    budget: Option<u64>,
+    // Digest -> pin count, shared by clones
+    pins: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl TocCache {
+    /// Use `dir` for the cache, creating it on first store.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        TocCache {
+            dir: dir.into(),
+            budget: None,
+            pins: Arc::default(),
+        }
+    }
+
+    /// Keep the entries under `bytes` in total, evicting the least recently
+    /// used ones after each store, see [`TocCache::gc`].
+    pub fn budget(mut self, bytes: u64) -> Self {
+        self.budget = Some(bytes);
+        self
+    }
+
+    /// Keep the entry of `digest` through evictions until as many
+    /// [`TocCache::unpin`] calls. Pins are shared by the clones of the cache,
+    /// not by other processes using the directory.
+    pub fn pin(&self, digest: &str) -> Result<()> {
+        self.path(digest)?;
+        *self.pins().entry(digest.to_string()).or_default() += 1;
+        Ok(())
+    }
+
+    /// Undo one [`TocCache::pin`] of `digest`.
+    pub fn unpin(&self, digest: &str) {
+        let mut pins = self.pins();
+        if let Some(count) = pins.get_mut(digest) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(digest);
+            }
+        }
+    }
+
+    /// Evict the least recently used entries not pinned until the cache is
+    /// within its budget, if it has one, and remove temporary files left
+    /// over by interrupted stores. Returns the number of bytes freed.
+    ///
+    /// Runs after each store already; calling it is only needed after
+    /// unpinning, or to clean up a cache shared with other processes.
+    pub fn gc(&self) -> Result<u64> {
+        let dir = match fs::read_dir(&self.dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            dir => dir?,
+        };
+        let pinned: HashSet<PathBuf> = self
+            .pins()
+            .keys()
+            .filter_map(|digest| self.path(digest).ok())
+            .collect();
+        let now = SystemTime::now();
+        let mut freed = 0;
+        let mut entries = Vec::new();
+        let mut total = 0;
+        for dent in dir {
+            let dent = dent?;
+            let path = dent.path();
+            // Gone already when another process collects at the same time
+            let Ok(meta) = dent.metadata() else { continue };
+            let used = meta.modified()?;
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("toc") => {
+                    total += meta.len();
+                    if !pinned.contains(&path) {
+                        entries.push((used, meta.len(), path));
+                    }
+                }
+                Some(ext)
+                    if ext.starts_with("tmp")
+                        && now.duration_since(used).is_ok_and(|age| age > STALE_TMP)
+                        && fs::remove_file(&path).is_ok() =>
+                {
+                    freed += meta.len();
+                }
+                _ => {}
+            }
+        }
+
+        let Some(budget) = self.budget else {
+            return Ok(freed);
+        };
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= budget {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            total -= len;
+            freed += len;
+        }
+        Ok(freed)
+    }
+
+    fn pins(&self) -> MutexGuard<'_, HashMap<String, usize>> {
+        self.pins.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Open `input`, whose digest is `digest` (`sha256:<hex>`), from its
+    /// cached TOC when there is a usable one. Otherwise it's opened like
+    /// [`open_with_options`] and the result is cached for next time.
+    ///
+    /// The cache is best effort: an unreadable or stale entry is replaced,
+    /// and failing to store one doesn't fail the open.
+    pub fn open(&self, input: File, digest: &str, opts: ReaderOptions) -> Result<GzReader> {
+        let path = self.path(digest)?;
+        let footer = match read_footer(&input) {
+            Ok(footer) => footer,
+            // A TOC built from a plain tar.gz points into a temporary copy
+            // of the data, there's nothing to cache
+            Err(_) if opts.tar_gz_fallback => return open_with_options(input, opts),
+            Err(e) => return Err(e),
+        };
+        if let Some(index) = fs::read(&path)
+            .ok()
+            .and_then(|data| decode(&data, footer.blob_size, footer.toc_offset).ok())
+        {
+            // A strict open must not accept what a permissive one let through
+            if opts.parse_mode == ParseMode::Permissive || index.warnings.is_empty() {
+                // Its modification time is when it was last used, for gc
+                let _ = File::options()
+                    .append(true)
+                    .open(&path)
+                    .and_then(|f| f.set_modified(SystemTime::now()));
+                return Ok(index.into_reader(Box::new(input), footer, opts));
+            }
+        }
+
+        let reader = open_with_options(input, opts)?;
+        if self.store(&path, &reader).is_ok() && self.budget.is_some() {
+            let _ = self.gc();
+        }
+        Ok(reader)
+    }
+
+    /// Drop the cached TOC of `digest`, if any.
+    pub fn remove(&self, digest: &str) -> Result<()> {
+        match fs::remove_file(self.path(digest)?) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, digest: &str) -> Result<PathBuf> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, ':' | '-' | '_' | '.');
+        if digest.is_empty() || digest.starts_with('.') || !digest.chars().all(valid) {
+            return Err(anyhow!("invalid digest {digest:?}"));
+        }
+        Ok(self.dir.join(format!("{}.toc", digest.replace(':', "-"))))
+    }
+
+    // Written to a temporary file of its own first so concurrent openers,
+    // threads of one process included, never see a partial entry
+    fn store(&self, path: &PathBuf, reader: &GzReader) -> Result<()> {
+        static STORED: AtomicU64 = AtomicU64::new(0);
+        fs::create_dir_all(&self.dir)?;
+        let n = STORED.fetch_add(1, Ordering::Relaxed);
+        let tmp = path.with_extension(format!("tmp{}-{n}", std::process::id()));
+        let mut f = File::options().write(true).create_new(true).open(&tmp)?;
+        f.write_all(&encode(reader))?;
+        f.sync_all()?;
+        fs::rename(&tmp, path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })?;
+        Ok(())
+    }
+}
+
+// What opening computes from a TOC: the normalized entries and their index
+struct Cached {
+    version: u32,
+    entries: Vec<TocEntry>,
+    m: HashMap<String, TocEntry>,
+    chunks: HashMap<String, Vec<TocEntry>>,
+    warnings: Vec<String>,
+}
+
+impl Cached {
+    fn into_reader(
+        self,
+        sr: Box<dyn ReadAt + Send + Sync>,
+        footer: Footer,
+        opts: ReaderOptions,
+    ) -> GzReader {
+        let mut toc = JToc::new(self.version);
+        toc.entries = self.entries;
+        let mut index = Index {
+            m: self.m,
+            chunks: self.chunks,
+            ..Default::default()
+        };
+        if opts.case_insensitive {
+            index.build_case_index();
+        }
+        GzReader {
+            sr,
+            toc,
+            footer,
+            index: OnceLock::from(index),
+            warnings: self.warnings,
+            cache: ChunkCache::new(opts.chunk_cache_size),
+            opts,
+        }
+    }
+}
+
+// MAGIC, VERSION, the CRC-32 of the rest and the rest
+fn encode(reader: &GzReader) -> Vec<u8> {
+    let mut e = Encoder(Vec::new());
+    e.u64(reader.footer.blob_size);
+    e.u64(reader.footer.toc_offset);
+    e.u64(reader.toc.version.into());
+    e.u64(reader.warnings.len() as u64);
+    for warning in &reader.warnings {
+        e.str(warning);
+    }
+    e.u64(reader.toc.entries.len() as u64);
+    for ent in &reader.toc.entries {
+        e.entry(ent);
+    }
+    let index = reader.index();
+    e.u64(index.m.len() as u64);
+    for (name, ent) in &index.m {
+        e.str(name);
+        e.entry(ent);
+    }
+    e.u64(index.chunks.len() as u64);
+    for (name, chunks) in &index.chunks {
+        e.str(name);
+        e.u64(chunks.len() as u64);
+        for chunk in chunks {
+            e.entry(chunk);
+        }
+    }
+    let mut crc = Crc::new();
+    crc.update(&e.0);
+    let mut data = MAGIC.to_vec();
+    data.push(VERSION);
+    data.extend_from_slice(&crc.sum().to_le_bytes());
+    data.extend_from_slice(&e.0);
+    data
+}
+
+fn decode(data: &[u8], size: u64, toc_offset: u64) -> Result<Cached> {
+    let Some(data) = data.strip_prefix(MAGIC) else {
+        return Err(anyhow!("not a TOC cache entry"));
+    };
+    let Some((&version, data)) = data.split_first() else {
+        return Err(anyhow!("truncated TOC cache entry"));
+    };
+    if version != VERSION {
+        return Err(anyhow!("TOC cache entry version {version}, not {VERSION}"));
+    }
+    let Some((sum, data)) = data.split_first_chunk::<4>() else {
+        return Err(anyhow!("truncated TOC cache entry"));
+    };
+    let mut crc = Crc::new();
+    crc.update(data);
+    if crc.sum() != u32::from_le_bytes(*sum) {
+        return Err(anyhow!("TOC cache entry checksum mismatch"));
+    }
+    let mut d = Decoder(data);
+    if d.u64()? != size || d.u64()? != toc_offset {
+        return Err(anyhow!("cached for a different blob"));
+    }
+    let version = u32::try_from(d.u64()?)?;
+    let warnings = (0..d.len()?).map(|_| d.str()).collect::<Result<_>>()?;
+    let entries = (0..d.len()?).map(|_| d.entry()).collect::<Result<_>>()?;
+    let m = (0..d.len()?)
+        .map(|_| Ok((d.str()?, d.entry()?)))
+        .collect::<Result<_>>()?;
+    let chunks = (0..d.len()?)
+        .map(|_| {
+            let name = d.str()?;
+            let chunks = (0..d.len()?).map(|_| d.entry()).collect::<Result<_>>()?;
+            Ok((name, chunks))
+        })
+        .collect::<Result<_>>()?;
+    if !d.0.is_empty() {
+        return Err(anyhow!("trailing bytes in TOC cache entry"));
+    }
+    Ok(Cached {
+        version,
+        entries,
+        m,
+        chunks,
+        warnings,
+    })
+}
+
+// Integers as LEB128 varints, strings and byte strings length-prefixed
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u64(&mut self, mut n: u64) {
+        while n >= 0x80 {
+            self.0.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        self.0.push(n as u8);
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.u64(b.len() as u64);
+        self.0.extend_from_slice(b);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    fn opt_str(&mut self, s: &Option<String>) {
+        match s {
+            None => self.u64(0),
+            Some(s) => {
+                self.u64(1);
+                self.str(s);
+            }
+        }
+    }
+
+    fn entry(&mut self, ent: &TocEntry) {
+        self.str(&ent.name);
+        self.str(&ent.entry_type);
+        self.u64(ent.size);
+        self.opt_str(&ent.mod_time_3339);
+        match ent.mod_time {
+            None => self.u64(0),
+            Some(t) => {
+                self.u64(1);
+                // Zigzag, modtimes before the epoch are negative
+                let secs = t.timestamp();
+                self.u64(((secs << 1) ^ (secs >> 63)) as u64);
+                self.u64(t.timestamp_subsec_nanos().into());
+            }
+        }
+        self.u64(ent.mode.into());
+        self.str(&ent.link_name);
+        self.u64(ent.uid.into());
+        self.u64(ent.gid.into());
+        self.str(&ent.uname);
+        self.str(&ent.gname);
+        self.u64(ent.offset);
+        self.u64(ent.next_offset);
+        self.u64(ent.dev_major);
+        self.u64(ent.dev_minor);
+        self.u64(ent.num_link.into());
+        self.u64(ent.xattrs.len() as u64);
+        for (key, value) in &ent.xattrs {
+            self.str(key);
+            self.bytes(value);
+        }
+        self.str(&ent.digest);
+        self.str(&ent.chunk_digest);
+        self.u64(ent.chunk_offset);
+        self.u64(ent.chunk_size);
+        self.u64(ent.inner_offset);
+        self.u64(ent.end_offset);
+        self.u64(ent.sparse_map.len() as u64);
+        for &(offset, len) in &ent.sparse_map {
+            self.u64(offset);
+            self.u64(len);
+        }
+        self.u64(ent.children.len() as u64);
+        for (base_name, name) in &ent.children {
+            self.str(base_name);
+            self.str(name);
+        }
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn u64(&mut self) -> Result<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&b, rest) = self
+                .0
+                .split_first()
+                .ok_or_else(|| anyhow!("truncated TOC cache entry"))?;
+            self.0 = rest;
+            // The 10th byte only has room for the top bit
+            if shift == 63 && b > 1 {
+                break;
+            }
+            n |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(anyhow!("varint overflow in TOC cache entry"))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::try_from(self.u64()?)?)
+    }
+
+    // A count of items that each take at least a byte, so a corrupt count
+    // can't trigger a huge allocation
+    fn len(&mut self) -> Result<u64> {
+        let n = self.u64()?;
+        if n > self.0.len() as u64 {
+            return Err(anyhow!("truncated TOC cache entry"));
+        }
+        Ok(n)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let n = self.len()? as usize;
+        let (b, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(b.to_vec())
+    }
+
+    fn str(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?)?)
+    }
+
+    fn opt_str(&mut self) -> Result<Option<String>> {
+        match self.u64()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.str()?)),
+        }
+    }
+
+    fn entry(&mut self) -> Result<TocEntry> {
+        let name = self.str()?;
+        let entry_type = self.str()?;
+        let size = self.u64()?;
+        let mod_time_3339 = self.opt_str()?;
+        let mod_time = match self.u64()? {
+            0 => None,
+            _ => {
+                let zigzag = self.u64()?;
+                let secs = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+                let nanos = self.u32()?;
+                Some(
+                    Utc.timestamp_opt(secs, nanos)
+                        .single()
+                        .ok_or_else(|| anyhow!("invalid modtime in TOC cache entry"))?,
+                )
+            }
+        };
+        Ok(TocEntry {
+            name,
+            entry_type,
+            size,
+            mod_time_3339,
+            mod_time,
+            mode: self.u32()?,
+            link_name: self.str()?,
+            uid: self.u32()?,
+            gid: self.u32()?,
+            uname: self.str()?,
+            gname: self.str()?,
+            offset: self.u64()?,
+            next_offset: self.u64()?,
+            dev_major: self.u64()?,
+            dev_minor: self.u64()?,
+            num_link: self.u32()?,
+            xattrs: (0..self.len()?)
+                .map(|_| Ok((self.str()?, self.bytes()?)))
+                .collect::<Result<_>>()?,
+            digest: self.str()?,
+            chunk_digest: self.str()?,
+            chunk_offset: self.u64()?,
+            chunk_size: self.u64()?,
+            inner_offset: self.u64()?,
+            end_offset: self.u64()?,
+            sparse_map: (0..self.len()?)
+                .map(|_| Ok((self.u64()?, self.u64()?)))
+                .collect::<Result<_>>()?,
+            children: (0..self.len()?)
+                .map(|_| Ok((self.str()?, self.str()?)))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{open_from_bytes, EntryMeta, Writer};
+
+    fn reader() -> GzReader {
+        let mut blob = Vec::new();
+        let mut w = Writer::new(&mut blob).with_chunk_size(4096).unwrap();
+        let meta = EntryMeta {
+            mode: 0o644,
+            mtime: 1600000000,
+            ..Default::default()
+        };
+        w.add_file("etc/passwd", &mut &b"root"[..], &meta).unwrap();
+        w.add_file("big", &mut &[7; 10_000][..], &meta).unwrap();
+        w.close().unwrap();
+        drop(w);
+        open_from_bytes(blob).unwrap()
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let values = [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX.into(), u64::MAX];
+        let mut e = Encoder(Vec::new());
+        for n in values {
+            e.u64(n);
+        }
+        assert_eq!(e.0[..4], [0, 1, 0x7f, 0x80]);
+        let mut d = Decoder(&e.0);
+        for n in values {
+            assert_eq!(d.u64().unwrap(), n);
+        }
+        assert!(d.0.is_empty());
+
+        assert!(Decoder(&[0x80]).u64().is_err());
+        assert!(Decoder(&[]).u64().is_err());
+        // More bits than fit in a u64
+        let mut overflow = vec![0xff; 9];
+        overflow.push(0x02);
+        assert!(Decoder(&overflow).u64().is_err());
+        assert!(Decoder(&[0x80; 11]).u64().is_err());
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let r = reader();
+        let data = encode(&r);
+        let cached = decode(&data, r.footer.blob_size, r.footer.toc_offset).unwrap();
+        assert_eq!(cached.version, r.toc.version);
+        assert_eq!(
+            format!("{:?}", cached.entries),
+            format!("{:?}", r.toc.entries)
+        );
+        let index = r.index();
+        // Children are a HashMap, in no particular order
+        let describe = |ent: &TocEntry| {
+            let mut ent = ent.clone();
+            let children: BTreeMap<_, _> = std::mem::take(&mut ent.children).into_iter().collect();
+            format!("{ent:?} {children:?}")
+        };
+        for (name, ent) in &index.m {
+            assert_eq!(describe(&cached.m[name]), describe(ent));
+        }
+        assert_eq!(cached.chunks.len(), index.chunks.len());
+        assert_eq!(cached.chunks["big"].len(), index.chunks["big"].len());
+    }
+
+    #[test]
+    fn damaged_entries_are_rejected() {
+        let r = reader();
+        let (size, toc_offset) = (r.footer.blob_size, r.footer.toc_offset);
+        let data = encode(&r);
+
+        let mut magic = data.clone();
+        magic[0] ^= 1;
+        assert!(decode(&magic, size, toc_offset).is_err());
+        let mut version = data.clone();
+        version[MAGIC.len()] = VERSION - 1;
+        let Err(err) = decode(&version, size, toc_offset) else {
+            panic!("decoded another version");
+        };
+        assert!(err.to_string().contains("version"), "{err}");
+
+        for len in 0..data.len() {
+            assert!(decode(&data[..len], size, toc_offset).is_err(), "{len}");
+        }
+        for i in MAGIC.len() + 1..data.len() {
+            let mut flipped = data.clone();
+            flipped[i] ^= 0x10;
+            assert!(decode(&flipped, size, toc_offset).is_err(), "{i}");
+        }
+        assert!(decode(&[data.clone(), vec![0]].concat(), size, toc_offset).is_err());
+
+        assert!(decode(&data, size + 1, toc_offset).is_err());
+        assert!(decode(&data, size, toc_offset - 1).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::clean_entry_name;
+
+/// The attributes of an entry a [`Transform`] can rewrite before the
+/// [`Writer`](crate::Writer) writes it, to both the tar header and the TOC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryAttrs {
+    /// Path of the entry, as found in the input.
+    pub path: String,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub uname: String,
+    pub gname: String,
+    /// Modification time in seconds since the epoch. The Writer's mtime
+    /// clamp applies after the transforms.
+    pub mtime: u64,
+    pub xattrs: HashMap<String, Vec<u8>>,
+    /// Target of a symlink or hardlink.
+    pub link_name: Option<String>,
+    pub(crate) entry_type: tar::EntryType,
+}
+
+impl EntryAttrs {
+    /// Type of the entry in the input tar, which transforms can't change.
+    pub fn entry_type(&self) -> tar::EntryType {
+        self.entry_type
+    }
+}
+
+/// A rewrite applied to every entry on its way into a layer, see
+/// [`Writer::with_transform`](crate::Writer::with_transform).
+///
+/// Transforms run in the order they were added, each one seeing the output
+/// of the previous one, after the entry filter has accepted the entry.
+/// Closures taking `&mut EntryAttrs` are transforms too.
+pub trait Transform {
+    fn apply(&mut self, entry: &mut EntryAttrs) -> Result<()>;
+}
+
+impl<F: FnMut(&mut EntryAttrs) -> Result<()>> Transform for F {
+    fn apply(&mut self, entry: &mut EntryAttrs) -> Result<()> {
+        self(entry)
+    }
+}
+
+/// Set every modification time to the same value, 0 by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StripTimestamps {
+    pub mtime: u64,
+}
+
+impl Transform for StripTimestamps {
+    fn apply(&mut self, entry: &mut EntryAttrs) -> Result<()> {
+        entry.mtime = self.mtime;
+        Ok(())
+    }
+}
+
+/// Give every entry the same owner. User and group names are cleared
+/// unless set with [`Chown::names`].
+#[derive(Debug, Clone, Default)]
+pub struct Chown {
+    uid: u32,
+    gid: u32,
+    uname: String,
+    gname: String,
+}
+
+impl Chown {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Chown {
+            uid,
+            gid,
+            ..Default::default()
+        }
+    }
+
+    pub fn names(mut self, uname: &str, gname: &str) -> Self {
+        self.uname = uname.to_string();
+        self.gname = gname.to_string();
+        self
+    }
+}
+
+impl Transform for Chown {
+    fn apply(&mut self, entry: &mut EntryAttrs) -> Result<()> {
+        entry.uid = self.uid;
+        entry.gid = self.gid;
+        entry.uname.clone_from(&self.uname);
+        entry.gname.clone_from(&self.gname);
+        Ok(())
+    }
+}
+
+/// Drop the extended attributes whose name starts with one of the
+/// prefixes, e.g. `security.` to leave SELinux labels and capabilities out.
+#[derive(Debug, Clone, Default)]
+pub struct DropXattrs {
+    prefixes: Vec<String>,
+}
+
+impl DropXattrs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefixes.push(prefix.to_string());
+        self
+    }
+}
+
+impl Transform for DropXattrs {
+    fn apply(&mut self, entry: &mut EntryAttrs) -> Result<()> {
+        entry
+            .xattrs
+            .retain(|name, _| !self.prefixes.iter().any(|p| name.starts_with(p)));
+        Ok(())
+    }
+}
+
+/// Replace a leading directory of entry paths, to keep user names or build
+/// directories out of a layer: with `home/alice` -> `home/user`,
+/// `home/alice/.bashrc` becomes `home/user/.bashrc`. Hardlink targets are
+/// rewritten the same way so they keep pointing at the right file; symlink
+/// targets are left alone.
+#[derive(Debug, Clone)]
+pub struct RedactPaths {
+    from: String,
+    to: String,
+}
+
+impl RedactPaths {
+    pub fn new(from: &str, to: &str) -> Self {
+        RedactPaths {
+            from: clean_entry_name(from),
+            to: clean_entry_name(to),
+        }
+    }
+
+    fn rewrite(&self, path: &str) -> Option<String> {
+        let path = clean_entry_name(path);
+        if path == self.from {
+            return Some(self.to.clone());
+        }
+        let rest = path.strip_prefix(&self.from)?.strip_prefix('/')?;
+        match self.to.as_str() {
+            "" => Some(rest.to_string()),
+            to => Some(format!("{to}/{rest}")),
+        }
+    }
+}
+
+impl Transform for RedactPaths {
+    fn apply(&mut self, entry: &mut EntryAttrs) -> Result<()> {
+        if let Some(path) = self.rewrite(&entry.path) {
+            entry.path = path;
+        }
+        if entry.entry_type == tar::EntryType::Link {
+            if let Some(target) = entry.link_name.as_deref().and_then(|t| self.rewrite(t)) {
+                entry.link_name = Some(target);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,812 @@
+//! Extracting a layer to a directory.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    fmt,
+    fs::{self, File, Permissions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, symlink, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
+
+use crate::{
+    clean_entry_name,
+    compare::{compare_entry, Difference, Drift},
+    filter::matches_any,
+    headers::toc_header,
+    parent_dir, EntryFilter, Error, GzReader, TocEntry,
+};
+
+/// How entries are restored when extracting a layer. Also a builder: finish
+/// with [`UnpackOptions::unpack`], [`UnpackOptions::unpack_matching`] or
+/// [`UnpackOptions::unpack_filtered`].
+///
+/// Xattrs and device nodes are off by default as they usually need
+/// privileges: `trusted.` and `security.` xattrs, and device nodes, can only
+/// be created by root. Ownership is restored by default only when running
+/// as root, like `tar -x` does.
+#[derive(Debug, Clone)]
+pub struct UnpackOptions {
+    xattrs: bool,
+    devices: bool,
+    hardlinks: bool,
+    owner: Ownership,
+    umask: u32,
+    special_bits: bool,
+    verify: bool,
+    journal: Option<PathBuf>,
+}
+
+/// Who extracted entries belong to, see [`UnpackOptions::owner`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ownership {
+    /// The uid and gid of the entries.
+    Numeric,
+    /// The local user and group with the user and group names of the
+    /// entries, or their uid and gid for names that aren't set or don't
+    /// exist here, like `tar -x` does.
+    Names,
+    /// The uid and gid of the entries mapped, e.g. into the range of a user
+    /// namespace. Ids missing from the maps are kept.
+    Map {
+        uids: HashMap<u32, u32>,
+        gids: HashMap<u32, u32>,
+    },
+    /// The process's own, ownership isn't restored.
+    Ignore,
+}
+
+impl Default for UnpackOptions {
+    fn default() -> Self {
+        // Only root may give files away
+        let owner = match unsafe { libc::geteuid() } {
+            0 => Ownership::Numeric,
+            _ => Ownership::Ignore,
+        };
+        UnpackOptions {
+            xattrs: false,
+            devices: false,
+            hardlinks: true,
+            owner,
+            umask: 0,
+            special_bits: true,
+            verify: false,
+            journal: None,
+        }
+    }
+}
+
+impl UnpackOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Recreate hardlinks as links to the file they point at, on by
+    /// default. Turned off, each gets its own copy of the content. Where
+    /// linking fails, e.g. with the target on another filesystem mounted
+    /// into `dest`, a copy is made anyway and the report says so.
+    pub fn hardlinks(mut self, hardlinks: bool) -> Self {
+        self.hardlinks = hardlinks;
+        self
+    }
+
+    /// Who entries belong to once extracted. Failing to give an entry away
+    /// fails the extraction.
+    pub fn owner(mut self, owner: Ownership) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    /// Clear the permission bits in `umask` from the mode of every entry,
+    /// 0 (the default) restores modes as they are in the layer.
+    pub fn umask(mut self, umask: u32) -> Self {
+        self.umask = umask & 0o777;
+        self
+    }
+
+    /// Restore the setuid, setgid and sticky bits, on by default. Turned
+    /// off they're dropped from the mode of every entry.
+    pub fn special_bits(mut self, special_bits: bool) -> Self {
+        self.special_bits = special_bits;
+        self
+    }
+
+    /// Once everything is extracted, read it back and compare it with the
+    /// layer: content with the TOC digests (or the layer itself, for files
+    /// without one), and type, size, link targets and the mode, owner and
+    /// modification time meant to be applied. What doesn't match is listed
+    /// in the report's `drifts`, rather than failing the extraction.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Keep track of the regular files extracted in a journal at `path`, so
+    /// an extraction that failed or was killed half-way can pick up where it
+    /// stopped: run again with the same journal, files it lists are skipped
+    /// as long as their size, modification time, mode and owner on disk are
+    /// still the ones they were given. The journal is removed once the
+    /// extraction completes.
+    ///
+    /// Only files with a TOC digest are tracked, the journal records it to
+    /// tell whether it's still the same layer being extracted. Nothing is
+    /// synced to disk, which a crash of the whole machine could undo.
+    pub fn journal(mut self, path: impl Into<PathBuf>) -> Self {
+        self.journal = Some(path.into());
+        self
+    }
+
+    // The mode bits to give an entry
+    fn mode(&self, ent: &TocEntry) -> u32 {
+        let keep = if self.special_bits { 0o7777 } else { 0o777 };
+        ent.mode & keep & !self.umask
+    }
+
+    /// Set the extended attributes of entries. Failing to set one fails the
+    /// extraction.
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Create character and block devices and fifos with `mknod(2)`,
+    /// instead of skipping them. Device nodes the process isn't allowed to
+    /// create are skipped all the same, with a warning in the report rather
+    /// than failing the extraction.
+    pub fn devices(mut self, devices: bool) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    /// See [`GzReader::unpack`].
+    pub fn unpack(&self, reader: &GzReader, dest: impl AsRef<Path>) -> Result<UnpackReport> {
+        reader.unpack_where(dest.as_ref(), self, |_| true)
+    }
+
+    /// See [`GzReader::unpack_matching`].
+    pub fn unpack_matching<'p>(
+        &self,
+        reader: &GzReader,
+        dest: impl AsRef<Path>,
+        patterns: impl IntoIterator<Item = &'p str>,
+    ) -> Result<UnpackReport> {
+        let patterns = patterns
+            .into_iter()
+            .map(|p| Pattern::new(&clean_entry_name(p)))
+            .collect::<Result<Vec<_>, _>>()?;
+        reader.unpack_where(dest.as_ref(), self, |name| matches_any(&patterns, name))
+    }
+
+    /// See [`GzReader::unpack_filtered`].
+    pub fn unpack_filtered(
+        &self,
+        reader: &GzReader,
+        dest: impl AsRef<Path>,
+        filter: &mut EntryFilter,
+    ) -> Result<UnpackReport> {
+        let selected: HashSet<&str> = reader
+            .walk()
+            .filter(|(name, ent)| filter.accepts(name, &toc_header(ent)))
+            .map(|(name, _)| name)
+            .collect();
+        reader.unpack_where(dest.as_ref(), self, |name| selected.contains(name))
+    }
+}
+
+/// Outcome of [`GzReader::unpack`] and its selective variants.
+#[derive(Debug, Clone, Default)]
+pub struct UnpackReport {
+    /// Entries left out or only partly restored, in layer order.
+    pub warnings: Vec<UnpackWarning>,
+    /// Entries whose extracted copy differs from the layer, in layer order.
+    /// Only filled in with [`UnpackOptions::verify`].
+    pub drifts: Vec<Drift>,
+    /// Regular files left as an earlier run extracted them, see
+    /// [`UnpackOptions::journal`].
+    pub resumed: usize,
+}
+
+impl UnpackReport {
+    pub fn is_clean(&self) -> bool {
+        self.warnings.is_empty() && self.drifts.is_empty()
+    }
+}
+
+/// An entry extraction went past without failing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnpackWarning {
+    pub name: String,
+    pub warning: Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// A device node or fifo, not created as [`UnpackOptions::devices`] is
+    /// off.
+    DeviceSkipped,
+    /// A device node `mknod(2)` refused to create for lack of privileges.
+    DeviceNotPermitted,
+    /// A hardlink extracted as a copy of its target, as linking failed with
+    /// this error.
+    HardlinkCopied(String),
+}
+
+impl fmt::Display for UnpackWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.name)?;
+        match &self.warning {
+            Warning::DeviceSkipped => write!(f, "device nodes aren't extracted, skipped"),
+            Warning::DeviceNotPermitted => write!(f, "not permitted to create the node, skipped"),
+            Warning::HardlinkCopied(e) => write!(f, "can't link ({e}), copied instead"),
+        }
+    }
+}
+
+impl GzReader {
+    /// Extract the layer into `dest`, created if it doesn't exist:
+    /// directories, regular files, symlinks and hardlinks, with their mode,
+    /// ownership and modification time. Sparse files get their holes back.
+    ///
+    /// Ownership is only restored when running as root; otherwise
+    /// everything belongs to the caller, like with `tar -x` run by a regular
+    /// user. Device nodes, fifos and xattrs are skipped, the device nodes and
+    /// fifos left out are listed in the report. [`UnpackOptions`] changes
+    /// all of that. Entries already in `dest` are replaced, directories are
+    /// merged into.
+    ///
+    /// Nothing is written outside of `dest`: symlinks are created once
+    /// everything else is written, so a link in the layer can't redirect
+    /// where later entries land, and entry names are checked for absolute
+    /// paths and `..`.
+    pub fn unpack(&self, dest: impl AsRef<Path>) -> Result<UnpackReport> {
+        UnpackOptions::new().unpack(self, dest)
+    }
+
+    /// Like [`GzReader::unpack`], but only the entries matching one of the
+    /// glob `patterns`, matched like [`EntryFilter`](crate::EntryFilter)
+    /// does: a directory matching brings everything below it along.
+    ///
+    /// Directories leading to the entries are created if missing; existing
+    /// ones must be actual directories, not symlinks. A hardlink whose
+    /// target isn't extracted gets a copy of the file instead.
+    pub fn unpack_matching<'p>(
+        &self,
+        dest: impl AsRef<Path>,
+        patterns: impl IntoIterator<Item = &'p str>,
+    ) -> Result<UnpackReport> {
+        UnpackOptions::new().unpack_matching(self, dest, patterns)
+    }
+
+    /// Like [`GzReader::unpack_matching`], but the entries are those
+    /// `filter` accepts: its include and exclude patterns, then its
+    /// callback, which gets a header made up from the TOC entry. The
+    /// selection is made on the TOC alone, before any file content is read,
+    /// so nothing left out is ever fetched.
+    pub fn unpack_filtered(
+        &self,
+        dest: impl AsRef<Path>,
+        filter: &mut EntryFilter,
+    ) -> Result<UnpackReport> {
+        UnpackOptions::new().unpack_filtered(self, dest, filter)
+    }
+
+    fn unpack_where(
+        &self,
+        dest: &Path,
+        opts: &UnpackOptions,
+        selected: impl Fn(&str) -> bool,
+    ) -> Result<UnpackReport> {
+        let mut report = UnpackReport::default();
+        let attrs = Attrs::new(opts, self.walk().filter(|(name, _)| selected(name)))?;
+        fs::create_dir_all(dest).with_context(|| format!("creating {}", dest.display()))?;
+        let mut journal = match &opts.journal {
+            Some(path) => Some(
+                Journal::open(path)
+                    .with_context(|| format!("opening journal {}", path.display()))?,
+            ),
+            None => None,
+        };
+        // Directories under dest known not to be symlinks, and the regular
+        // files extracted
+        let mut checked = HashSet::new();
+        let mut files = HashSet::new();
+        let mut dirs = Vec::new();
+        let mut links = Vec::new();
+        for (name, ent) in self.walk().filter(|(name, _)| selected(name)) {
+            let path = safe_join(dest, name)?;
+            make_parents(dest, name, &mut checked)?;
+            match ent.entry_type.as_str() {
+                "dir" => {
+                    create_dir(&path).with_context(|| format!("creating {}", path.display()))?;
+                    checked.insert(name);
+                    dirs.push((ent, path));
+                }
+                "reg"
+                    if journal
+                        .as_ref()
+                        .is_some_and(|j| j.done(name, ent, &path, &attrs)) =>
+                {
+                    report.resumed += 1;
+                    files.insert(name);
+                }
+                "reg" => {
+                    self.unpack_file(ent, &path, &attrs)
+                        .with_context(|| format!("extracting {name}"))?;
+                    if let Some(journal) = &mut journal {
+                        journal.record(name, ent).context("writing the journal")?;
+                    }
+                    files.insert(name);
+                }
+                "symlink" | "hardlink" => links.push((ent, path)),
+                "char" | "block" | "fifo" if opts.devices => match make_node(ent, &path, &attrs) {
+                    // A regular user, or root in a user namespace
+                    Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                        report.warn(name, Warning::DeviceNotPermitted)
+                    }
+                    r => r.with_context(|| format!("extracting {name}"))?,
+                },
+                "char" | "block" | "fifo" => report.warn(name, Warning::DeviceSkipped),
+                _ => {}
+            }
+        }
+
+        // Hardlinks first, they may only point at regular files
+        links.sort_by_key(|(ent, _)| ent.entry_type == "symlink");
+        for (ent, path) in links {
+            let target = clean_entry_name(&ent.link_name);
+            let copy = || {
+                self.lookup(&ent.name)
+                    .and_then(|file| self.unpack_file(file, &path, &attrs))
+            };
+            let linked = match ent.entry_type.as_str() {
+                "hardlink" if opts.hardlinks && files.contains(target.as_str()) => {
+                    safe_join(dest, &target).and_then(|target| {
+                        remove_existing(&path)?;
+                        match fs::hard_link(target, &path) {
+                            std::result::Result::Ok(()) => Ok(()),
+                            Err(e) => {
+                                report.warn(&ent.name, Warning::HardlinkCopied(e.to_string()));
+                                copy()
+                            }
+                        }
+                    })
+                }
+                "hardlink" => copy(),
+                _ => remove_existing(&path)
+                    .and_then(|_| symlink(&ent.link_name, &path))
+                    .and_then(|_| attrs.set_owner(ent, &path))
+                    .and_then(|_| set_mtime(ent, &path))
+                    .and_then(|_| set_xattrs(ent, &path, opts))
+                    .map_err(Into::into),
+            };
+            linked.with_context(|| format!("extracting {}", ent.name))?;
+        }
+
+        // Deepest first, and only now: writing into a directory bumps its
+        // mtime, and a read-only mode would have prevented writing at all
+        for (ent, path) in dirs.iter().rev() {
+            attrs
+                .set(ent, path)
+                .and_then(|_| set_xattrs(ent, path, opts))
+                .with_context(|| format!("extracting {}", ent.name))?;
+        }
+
+        if opts.verify {
+            let names = self
+                .walk()
+                .map(|(name, _)| name)
+                .filter(|name| selected(name));
+            report.drifts = self.verify_unpacked(dest, names, &attrs, &report.warnings)?;
+        }
+        if let Some(path) = &opts.journal {
+            fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+        }
+
+        Ok(report)
+    }
+
+    // Compare the entries `names` extracted into `dest` with the layer,
+    // except for those `warnings` say were left out
+    fn verify_unpacked<'n>(
+        &self,
+        dest: &Path,
+        names: impl Iterator<Item = &'n str>,
+        attrs: &Attrs,
+        warnings: &[UnpackWarning],
+    ) -> Result<Vec<Drift>> {
+        let skipped: HashSet<&str> = warnings
+            .iter()
+            .filter(|w| {
+                matches!(
+                    w.warning,
+                    Warning::DeviceSkipped | Warning::DeviceNotPermitted
+                )
+            })
+            .map(|w| w.name.as_str())
+            .collect();
+        let mut drifts = Vec::new();
+        for name in names.filter(|name| !skipped.contains(name)) {
+            // Hardlinks as the file they point at
+            let ent = self.lookup(name)?;
+            let path = dest.join(name);
+            let mut diff = |difference| {
+                drifts.push(Drift {
+                    path: name.to_string(),
+                    difference,
+                })
+            };
+            match fs::symlink_metadata(&path) {
+                std::result::Result::Ok(meta) => {
+                    let mode = attrs.opts.mode(ent);
+                    compare_entry(self, ent, &path, &meta, mode, attrs.owner(ent), diff)
+                        .with_context(|| format!("verifying {name}"))?
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => diff(Difference::MissingOnDisk),
+                Err(e) => return Err(e).with_context(|| format!("verifying {name}")),
+            }
+        }
+        Ok(drifts)
+    }
+
+    fn unpack_file(&self, ent: &TocEntry, path: &Path, attrs: &Attrs) -> Result<()> {
+        remove_existing(path)?;
+        // Never follows a symlink, remove_existing just cleared the way
+        let mut out = File::options().write(true).create_new(true).open(path)?;
+        if ent.is_sparse() {
+            // Only the data regions are written, the rest stays holes
+            let mut file = self.open_entry(ent);
+            for &(offset, len) in &ent.sparse_map {
+                file.seek(SeekFrom::Start(offset))?;
+                out.seek(SeekFrom::Start(offset))?;
+                let copied = io::copy(&mut (&mut file).take(len), &mut out)?;
+                if copied != len {
+                    return Err(Error::corrupt(format!(
+                        "{} holds {copied} of {len} bytes at {offset}",
+                        ent.name
+                    ))
+                    .into());
+                }
+            }
+            out.set_len(ent.size)?;
+        } else {
+            self.copy_file_to(&ent.name, &mut out)?;
+        }
+        // The layer is read once front to back, no point keeping it cached
+        self.drop_file_cache(&ent.name)?;
+        attrs.set(ent, path)?;
+        set_xattrs(ent, path, attrs.opts)?;
+
+        Ok(())
+    }
+}
+
+impl UnpackReport {
+    fn warn(&mut self, name: &str, warning: Warning) {
+        self.warnings.push(UnpackWarning {
+            name: name.to_string(),
+            warning,
+        });
+    }
+}
+
+// The regular files an extraction wrote, one JSON array of name and TOC
+// digest per line, written once the file is complete
+struct Journal {
+    file: File,
+    done: HashMap<String, String>,
+}
+
+impl Journal {
+    fn open(path: &Path) -> Result<Self> {
+        let mut done = HashMap::new();
+        match File::open(path) {
+            std::result::Result::Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    // The last line may have been cut short
+                    let Some((name, digest)) = serde_json::from_str(&line?).ok() else {
+                        break;
+                    };
+                    done.insert(name, digest);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let mut file = File::options().create(true).append(true).open(path)?;
+        // Past a line cut short
+        if file.metadata()?.len() > 0 {
+            file.write_all(b"\n")?;
+        }
+        Ok(Journal { file, done })
+    }
+
+    // Whether the file `name` is listed as extracted from this same entry,
+    // and looks untouched since
+    fn done(&self, name: &str, ent: &TocEntry, path: &Path, attrs: &Attrs) -> bool {
+        if ent.digest.is_empty() || self.done.get(name) != Some(&ent.digest) {
+            return false;
+        }
+        let Some(meta) = fs::symlink_metadata(path).ok().filter(|m| m.is_file()) else {
+            return false;
+        };
+        let mtime = ent
+            .mod_time()
+            .map(|t| (t.timestamp(), t.timestamp_subsec_nanos()));
+        meta.len() == ent.size
+            && mtime.is_none_or(|t| t == (meta.mtime(), meta.mtime_nsec() as u32))
+            && meta.mode() & 0o7777 == attrs.opts.mode(ent)
+            && attrs
+                .owner(ent)
+                .is_none_or(|owner| owner == (meta.uid(), meta.gid()))
+    }
+
+    fn record(&mut self, name: &str, ent: &TocEntry) -> Result<()> {
+        if ent.digest.is_empty() {
+            return Ok(());
+        }
+        let mut line = serde_json::to_vec(&(name, &ent.digest))?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+// `dest`/`name`, refusing names that could lead out of `dest`. Names are
+// cleaned when the layer is opened, this only guards against that changing.
+fn safe_join(dest: &Path, name: &str) -> Result<PathBuf> {
+    if name.starts_with('/') || name.split('/').any(|c| matches!(c, "" | "." | "..")) {
+        return Err(anyhow!(
+            "refusing to extract {name:?}, it could end up outside of {}",
+            dest.display()
+        ));
+    }
+    Ok(dest.join(name))
+}
+
+// Create the directories leading to `name` the walk didn't, checking the
+// ones already there are real directories: writing through a symlink left
+// in `dest` could land anywhere.
+fn make_parents<'n>(dest: &Path, name: &'n str, checked: &mut HashSet<&'n str>) -> Result<()> {
+    let parent = parent_dir(name);
+    if parent.is_empty() || checked.contains(parent) {
+        return Ok(());
+    }
+    let mut dir = dest.to_path_buf();
+    for component in parent.split('/') {
+        dir.push(component);
+        match fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => return Err(anyhow!("{} isn't a directory", dir.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&dir)?,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    checked.insert(parent);
+    Ok(())
+}
+
+fn create_dir(path: &Path) -> io::Result<()> {
+    remove_existing(path)?;
+    match fs::create_dir(path) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Make way for a new entry at `path`. An existing directory is kept, but not
+// a symlink to one.
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.is_dir() => fs::remove_file(path),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn make_node(ent: &TocEntry, path: &Path, attrs: &Attrs) -> io::Result<()> {
+    remove_existing(path)?;
+    let kind = match ent.entry_type.as_str() {
+        "char" => libc::S_IFCHR,
+        "block" => libc::S_IFBLK,
+        _ => libc::S_IFIFO,
+    };
+    let dev = libc::makedev(ent.dev_major as u32, ent.dev_minor as u32);
+    let cpath = c_path(path)?;
+    if unsafe { libc::mknod(cpath.as_ptr(), kind | attrs.opts.mode(ent), dev) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    attrs.set(ent, path)?;
+    set_xattrs(ent, path, attrs.opts)
+}
+
+// The options of an extraction, with the user and group names of its
+// entries looked up
+struct Attrs<'o> {
+    opts: &'o UnpackOptions,
+    users: HashMap<&'o str, u32>,
+    groups: HashMap<&'o str, u32>,
+}
+
+impl<'o> Attrs<'o> {
+    fn new(
+        opts: &'o UnpackOptions,
+        entries: impl Iterator<Item = (&'o str, &'o TocEntry)>,
+    ) -> Result<Self> {
+        let mut users = HashMap::new();
+        let mut groups = HashMap::new();
+        if opts.owner == Ownership::Names {
+            for (_, ent) in entries {
+                if !ent.uname.is_empty() && !users.contains_key(ent.uname.as_str()) {
+                    if let Some(uid) = lookup_user(&ent.uname)? {
+                        users.insert(ent.uname.as_str(), uid);
+                    }
+                }
+                if !ent.gname.is_empty() && !groups.contains_key(ent.gname.as_str()) {
+                    if let Some(gid) = lookup_group(&ent.gname)? {
+                        groups.insert(ent.gname.as_str(), gid);
+                    }
+                }
+            }
+        }
+        Ok(Attrs {
+            opts,
+            users,
+            groups,
+        })
+    }
+
+    // Owner first, changing it clears the setuid and setgid bits
+    fn set(&self, ent: &TocEntry, path: &Path) -> io::Result<()> {
+        self.set_owner(ent, path)?;
+        fs::set_permissions(path, Permissions::from_mode(self.opts.mode(ent)))?;
+        set_mtime(ent, path)
+    }
+
+    // The uid and gid to give an entry, None to leave it to the process
+    fn owner(&self, ent: &TocEntry) -> Option<(u32, u32)> {
+        match &self.opts.owner {
+            Ownership::Numeric => Some((ent.uid, ent.gid)),
+            Ownership::Names => Some((
+                self.users
+                    .get(ent.uname.as_str())
+                    .copied()
+                    .unwrap_or(ent.uid),
+                self.groups
+                    .get(ent.gname.as_str())
+                    .copied()
+                    .unwrap_or(ent.gid),
+            )),
+            Ownership::Map { uids, gids } => Some((
+                uids.get(&ent.uid).copied().unwrap_or(ent.uid),
+                gids.get(&ent.gid).copied().unwrap_or(ent.gid),
+            )),
+            Ownership::Ignore => None,
+        }
+    }
+
+    fn set_owner(&self, ent: &TocEntry, path: &Path) -> io::Result<()> {
+        let Some((uid, gid)) = self.owner(ent) else {
+            return Ok(());
+        };
+        lchown(path, Some(uid), Some(gid))
+            .map_err(|e| io::Error::new(e.kind(), format!("changing owner to {uid}:{gid}: {e}")))
+    }
+}
+
+// The uid of the local user `name`, None if there's no such user
+fn lookup_user(name: &str) -> io::Result<Option<u32>> {
+    let cname = CString::new(name)?;
+    lookup_id(|buf, found| unsafe {
+        let mut pwd: libc::passwd = std::mem::zeroed();
+        let mut result = std::ptr::null_mut();
+        let ret = libc::getpwnam_r(
+            cname.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+        *found = (!result.is_null()).then_some(pwd.pw_uid);
+        ret
+    })
+}
+
+// The gid of the local group `name`, None if there's no such group
+fn lookup_group(name: &str) -> io::Result<Option<u32>> {
+    let cname = CString::new(name)?;
+    lookup_id(|buf, found| unsafe {
+        let mut grp: libc::group = std::mem::zeroed();
+        let mut result = std::ptr::null_mut();
+        let ret = libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+        *found = (!result.is_null()).then_some(grp.gr_gid);
+        ret
+    })
+}
+
+// Call `get` with a buffer for the entry's strings, growing it for as long
+// as it's too small
+fn lookup_id(
+    get: impl Fn(&mut [libc::c_char], &mut Option<u32>) -> libc::c_int,
+) -> io::Result<Option<u32>> {
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut found = None;
+        match get(&mut buf, &mut found) {
+            0 => return Ok(found),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+// After the owner is set, changing it drops `security.capability`
+fn set_xattrs(ent: &TocEntry, path: &Path, opts: &UnpackOptions) -> io::Result<()> {
+    if !opts.xattrs || ent.xattrs.is_empty() {
+        return Ok(());
+    }
+    let cpath = c_path(path)?;
+    let mut xattrs: Vec<_> = ent.xattrs.iter().collect();
+    xattrs.sort();
+    for (name, value) in xattrs {
+        let cname = CString::new(name.as_bytes())?;
+        let ret = unsafe {
+            libc::lsetxattr(
+                cpath.as_ptr(),
+                cname.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            return Err(io::Error::new(
+                err.kind(),
+                format!("setting xattr {name}: {err}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+// Access time too, like tar does. Symlinks aren't followed.
+fn set_mtime(ent: &TocEntry, path: &Path) -> io::Result<()> {
+    let Some(mtime) = ent.mod_time() else {
+        return Ok(());
+    };
+    let path = c_path(path)?;
+    let time = libc::timespec {
+        tv_sec: mtime.timestamp() as libc::time_t,
+        tv_nsec: mtime.timestamp_subsec_nanos() as libc::c_long,
+    };
+    let times = [time, time];
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::{GzReader, TocEntry};
+
+/// Outcome of [`GzReader::verify`].
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Regular files whose content matched every digest the TOC has for it.
+    pub verified: usize,
+    /// Regular files with no digest to check against.
+    pub skipped: usize,
+    /// Everything that didn't check out, in TOC order.
+    pub failures: Vec<Corruption>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Where a file's content failed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corruption {
+    pub file: String,
+    /// Index of the failing chunk in the file, `None` when only the digest of
+    /// the whole file is wrong.
+    pub chunk: Option<usize>,
+    /// Compressed byte range of the blob holding the chunk or file.
+    pub blob_range: (u64, u64),
+    pub problem: Problem,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The member doesn't decompress, or holds less than the TOC says.
+    Decompress(String),
+    /// The TOC digest uses an algorithm other than sha256.
+    UnsupportedDigest(String),
+    DigestMismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+        if let Some(chunk) = self.chunk {
+            write!(f, " chunk {chunk}")?;
+        }
+        write!(f, " (blob {}..{}): ", self.blob_range.0, self.blob_range.1)?;
+        match &self.problem {
+            Problem::Decompress(e) => write!(f, "{e}"),
+            Problem::UnsupportedDigest(d) => write!(f, "unsupported digest {d}"),
+            Problem::DigestMismatch { expected, actual } => {
+                write!(f, "content hashes to {actual}, TOC says {expected}")
+            }
+        }
+    }
+}
+
+impl GzReader {
+    /// Check every regular file against the digests in the TOC, per chunk
+    /// where the TOC has chunk digests and for the file as a whole.
+    ///
+    /// Failures don't stop the scan, the report lists all of them.
+    pub fn verify(&self) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        let entries = &self.toc.entries;
+        for (i, ent) in entries.iter().enumerate() {
+            if ent.entry_type != "reg" {
+                continue;
+            }
+            // Taken from the TOC rather than the index, where a later entry
+            // with the same name would hide them
+            let mut chunks = vec![ent.clone()];
+            chunks.extend(
+                entries[i + 1..]
+                    .iter()
+                    .take_while(|e| e.entry_type == "chunk")
+                    .cloned(),
+            );
+            chunks.sort_by_key(|c| c.chunk_offset);
+            let has_digest =
+                !ent.digest.is_empty() || chunks.iter().any(|c| !c.chunk_digest.is_empty());
+            if !has_digest {
+                report.skipped += 1;
+                continue;
+            }
+            let failures = report.failures.len();
+            self.verify_file(ent, &chunks, &mut report.failures);
+            if report.failures.len() == failures {
+                report.verified += 1;
+            }
+        }
+        report
+    }
+
+    fn verify_file(&self, ent: &TocEntry, chunks: &[TocEntry], failures: &mut Vec<Corruption>) {
+        let range = |c: &TocEntry| (c.offset, c.next_offset().max(c.offset));
+        let mut hasher = Sha256::new();
+        let mut intact = true;
+        // The holes of a sparse file count as zeros
+        let mut pos = 0;
+        for (i, chunk) in chunks.iter().enumerate().filter(|(_, c)| c.chunk_size > 0) {
+            if ent.is_sparse() {
+                hash_zeros(&mut hasher, chunk.chunk_offset.saturating_sub(pos));
+                pos = chunk.chunk_offset + chunk.chunk_size;
+            }
+            let mut fail = |problem| {
+                failures.push(Corruption {
+                    file: ent.name.clone(),
+                    chunk: Some(i),
+                    blob_range: range(chunk),
+                    problem,
+                })
+            };
+            let data = match self.decompress_chunk(chunk) {
+                Ok(data) => data,
+                Err(e) => {
+                    fail(Problem::Decompress(format!("{e:#}")));
+                    intact = false;
+                    continue;
+                }
+            };
+            if !chunk.chunk_digest.is_empty() {
+                if let Err(problem) = check_digest(&chunk.chunk_digest, &data) {
+                    fail(problem);
+                }
+            }
+            hasher.update(&data);
+        }
+        if ent.is_sparse() {
+            hash_zeros(&mut hasher, ent.size.saturating_sub(pos));
+        }
+
+        // The whole-file digest can't say anything more once a chunk is missing
+        if !intact || ent.digest.is_empty() {
+            return;
+        }
+        let problem = match ent.digest.strip_prefix("sha256:") {
+            None => Problem::UnsupportedDigest(ent.digest.clone()),
+            Some(expected) => {
+                let actual = format!("{:x}", hasher.finalize());
+                if actual == expected {
+                    return;
+                }
+                Problem::DigestMismatch {
+                    expected: ent.digest.clone(),
+                    actual: format!("sha256:{actual}"),
+                }
+            }
+        };
+        let start = chunks.iter().map(|c| c.offset).min().unwrap_or(0);
+        let end = chunks.iter().map(|c| range(c).1).max().unwrap_or(start);
+        failures.push(Corruption {
+            file: ent.name.clone(),
+            chunk: None,
+            blob_range: (start, end),
+            problem,
+        });
+    }
+}
+
+fn hash_zeros(hasher: &mut Sha256, mut len: u64) {
+    let zeros = [0; 8192];
+    while len > 0 {
+        let n = len.min(zeros.len() as u64) as usize;
+        hasher.update(&zeros[..n]);
+        len -= n as u64;
+    }
+}
+
+fn check_digest(digest: &str, data: &[u8]) -> Result<(), Problem> {
+    let Some(expected) = digest.strip_prefix("sha256:") else {
+        return Err(Problem::UnsupportedDigest(digest.to_string()));
+    };
+    let actual = format!("{:x}", Sha256::digest(data));
+    if actual != expected {
+        return Err(Problem::DigestMismatch {
+            expected: digest.to_string(),
+            actual: format!("sha256:{actual}"),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,199 @@
+//! A read-only filesystem interface shared by layers and directories.
+//!
+//! Code written against [`Vfs`] works the same on a [`GzReader`] and on a
+//! [`Dir`], so a layer can be swapped for an unpacked copy of it (or the
+//! other way round) in tests and tools.
+
+use std::{
+    fs::{self, File, Permissions},
+    io::Read,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::{clean_entry_name, compare::entry_type, GzReader, TocEntry};
+
+/// Paths are relative to the root of the filesystem and cleaned like
+/// [`GzReader::lookup`] does, "" being the root itself.
+pub trait Vfs {
+    /// Open a regular file for reading, following symlinks.
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>>;
+
+    /// Metadata of the entry at `path`. Symlinks leading to it are followed,
+    /// the entry itself isn't if it is one, like `lstat(2)`.
+    fn metadata(&self, path: &str) -> Result<Metadata>;
+
+    /// Names of the entries of a directory, sorted, following symlinks.
+    fn read_dir(&self, path: &str) -> Result<Vec<String>>;
+
+    /// The whole content of a regular file.
+    fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// What [`Vfs::metadata`] knows about an entry, along the lines of
+/// `std::fs::Metadata`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// TOC entry type: `reg`, `dir`, `symlink`, `char`, `block` or `fifo`.
+    /// Hardlinks are reported as the file they point to.
+    pub entry_type: String,
+    /// Content size of regular files, 0 otherwise.
+    pub size: u64,
+    /// Permission bits, setuid/setgid/sticky included.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mod_time: Option<DateTime<Utc>>,
+    /// Target of a symlink.
+    pub link_target: Option<String>,
+    /// Device numbers of a character or block device, 0 otherwise.
+    pub dev_major: u64,
+    pub dev_minor: u64,
+}
+
+impl Metadata {
+    // Hardlinks must have been resolved by the caller
+    fn from_entry(ent: &TocEntry) -> Self {
+        Metadata {
+            entry_type: ent.entry_type.clone(),
+            size: if ent.entry_type == "reg" { ent.size } else { 0 },
+            mode: ent.mode & 0o7777,
+            uid: ent.uid,
+            gid: ent.gid,
+            mod_time: ent.mod_time(),
+            link_target: (ent.entry_type == "symlink").then(|| ent.link_name.clone()),
+            dev_major: ent.dev_major,
+            dev_minor: ent.dev_minor,
+        }
+    }
+
+    /// Same as [`Metadata::size`].
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        Permissions::from_mode(self.mode)
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.mod_time.map(SystemTime::from)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.entry_type == "dir"
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.entry_type == "reg"
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.entry_type == "symlink"
+    }
+}
+
+impl Vfs for GzReader {
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>> {
+        let ent = self.lookup_follow(path)?;
+        if ent.entry_type != "reg" {
+            return Err(anyhow!("{path} is not a regular file"));
+        }
+        Ok(Box::new(self.open_entry(ent)))
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata> {
+        Ok(Metadata::from_entry(self.resolve(path.as_ref(), false)?))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<String>> {
+        let ent = self.lookup_follow(path)?;
+        if ent.entry_type != "dir" {
+            return Err(anyhow!("{path} is not a directory"));
+        }
+        let mut names: Vec<String> = ent.children.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// A directory of the host filesystem seen through [`Vfs`].
+///
+/// Paths can't climb out of the root with `..`, but symlinks inside the
+/// directory are followed by [`Vfs::open`] wherever they point.
+#[derive(Debug, Clone)]
+pub struct Dir {
+    root: PathBuf,
+}
+
+impl Dir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Dir { root: root.into() }
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.root.join(clean_entry_name(path))
+    }
+}
+
+impl Vfs for Dir {
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>> {
+        let full = self.path(path);
+        let file = File::open(&full).with_context(|| format!("opening {}", full.display()))?;
+        if !file.metadata()?.is_file() {
+            return Err(anyhow!("{path} is not a regular file"));
+        }
+        Ok(Box::new(file))
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata> {
+        let full = self.path(path);
+        let meta = fs::symlink_metadata(&full)
+            .with_context(|| format!("reading metadata of {}", full.display()))?;
+        let entry_type = entry_type(&meta);
+        let is_device = matches!(entry_type, "char" | "block");
+        let link_target = if meta.file_type().is_symlink() {
+            Some(fs::read_link(&full)?.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+        Ok(Metadata {
+            entry_type: entry_type.to_string(),
+            size: if meta.is_file() { meta.len() } else { 0 },
+            mode: meta.mode() & 0o7777,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            mod_time: Utc.timestamp_opt(meta.mtime(), 0).single(),
+            link_target,
+            dev_major: if is_device {
+                libc::major(meta.rdev()).into()
+            } else {
+                0
+            },
+            dev_minor: if is_device {
+                libc::minor(meta.rdev()).into()
+            } else {
+                0
+            },
+        })
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<String>> {
+        let full = self.path(path);
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&full).with_context(|| format!("reading {}", full.display()))? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+}
@@ -0,0 +1,158 @@
+use std::{fmt::Write, path::Path};
+
+use anyhow::Result;
+use glob::{MatchOptions, Pattern};
+use regex::RegexBuilder;
+
+use crate::{base_name, clean_entry_name, filter::MATCH_OPTIONS, GzReader, TocEntry};
+
+/// Depth-first iterator over the entries of a layer, see [`GzReader::walk`].
+pub struct Walk<'a> {
+    reader: &'a GzReader,
+    // Entries left to visit, the next one last
+    stack: Vec<&'a TocEntry>,
+}
+
+impl<'a> Walk<'a> {
+    // Walk what's below `dir`
+    pub(crate) fn new(reader: &'a GzReader, dir: &'a TocEntry) -> Self {
+        let mut walk = Walk {
+            reader,
+            stack: Vec::new(),
+        };
+        walk.push_children(dir);
+        walk
+    }
+
+    fn push_children(&mut self, dir: &'a TocEntry) {
+        let mut children: Vec<(&String, &String)> = dir.children.iter().collect();
+        // Reversed, so children come off the stack sorted
+        children.sort_unstable_by(|a, b| b.cmp(a));
+        let m = &self.reader.index().m;
+        self.stack
+            .extend(children.into_iter().filter_map(|(_, name)| m.get(name)));
+    }
+}
+
+impl<'a> Iterator for Walk<'a> {
+    /// Full path of the entry, and the entry.
+    type Item = (&'a str, &'a TocEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ent = self.stack.pop()?;
+        if ent.entry_type == "dir" {
+            self.push_children(ent);
+        }
+        Some((ent.name.as_str(), ent))
+    }
+}
+
+impl GzReader {
+    /// Every entry of the layer but the root directory, depth first: a
+    /// directory comes right before its contents, and siblings are sorted
+    /// by name. Symlinks aren't followed, and hardlinks are returned as
+    /// they are in the TOC.
+    pub fn walk(&self) -> Walk<'_> {
+        Walk::new(self, self.root())
+    }
+
+    /// The entries whose path matches the glob `pattern`, in
+    /// [`GzReader::walk`] order. `*` and `?` stop at `/` while `**` spans
+    /// directories, so `usr/lib/**/*.so` finds shared objects at any depth
+    /// under `usr/lib`. Case-insensitive readers match ignoring case.
+    pub fn glob(&self, pattern: &str) -> Result<impl Iterator<Item = &TocEntry>> {
+        let pattern = Pattern::new(&clean_entry_name(pattern))?;
+        let options = MatchOptions {
+            case_sensitive: !self.opts.case_insensitive,
+            ..MATCH_OPTIONS
+        };
+        Ok(self
+            .walk()
+            .filter(move |(path, _)| pattern.matches_with(path, options))
+            .map(|(_, ent)| ent))
+    }
+
+    /// The entries whose path matches the regular expression `pattern`, in
+    /// [`GzReader::walk`] order. The match can be anywhere in the path, e.g.
+    /// `\.pem$` finds every PEM file; anchor with `^` to match from the
+    /// root. Case-insensitive readers match ignoring case.
+    pub fn find(&self, pattern: &str) -> Result<impl Iterator<Item = &TocEntry>> {
+        let re = RegexBuilder::new(pattern)
+            .case_insensitive(self.opts.case_insensitive)
+            .build()?;
+        Ok(self
+            .walk()
+            .filter(move |(path, _)| re.is_match(path))
+            .map(|(_, ent)| ent))
+    }
+
+    /// A `tree(1)` style listing of `path` and everything below it, with
+    /// file sizes, link targets and device numbers, ending with a count of
+    /// directories and files. Symlinks leading to `path` are followed, the
+    /// ones below it aren't.
+    pub fn render_tree(&self, path: impl AsRef<Path>) -> Result<String> {
+        let top = self.lookup_follow(path)?;
+        let mut out = String::new();
+        match top.name.as_str() {
+            "" => writeln!(out, ".")?,
+            _ => writeln!(out, "{}", describe(top))?,
+        }
+        let (mut dirs, mut files) = (0u64, 0u64);
+        // (entry, prefix of its line, whether it's the last of its siblings),
+        // the next one to print last
+        let mut stack = Vec::new();
+        self.push_tree_children(&mut stack, top, "");
+        while let Some((ent, prefix, last)) = stack.pop() {
+            let branch = if last { "└── " } else { "├── " };
+            writeln!(out, "{prefix}{branch}{}", describe(ent))?;
+            if ent.entry_type == "dir" {
+                dirs += 1;
+                let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+                self.push_tree_children(&mut stack, ent, &prefix);
+            } else {
+                files += 1;
+            }
+        }
+        let plural =
+            |n: u64, one: &str, many: &str| format!("{n} {}", if n == 1 { one } else { many });
+        writeln!(
+            out,
+            "\n{}, {}",
+            plural(dirs, "directory", "directories"),
+            plural(files, "file", "files")
+        )?;
+
+        Ok(out)
+    }
+
+    fn push_tree_children<'a>(
+        &'a self,
+        stack: &mut Vec<(&'a TocEntry, String, bool)>,
+        dir: &'a TocEntry,
+        prefix: &str,
+    ) {
+        let children: Vec<&TocEntry> = dir
+            .children()
+            .filter_map(|(_, name)| self.index().m.get(name))
+            .collect();
+        for (i, ent) in children.iter().enumerate().rev() {
+            stack.push((ent, prefix.to_string(), i == children.len() - 1));
+        }
+    }
+}
+
+// An entry's line in a tree listing
+fn describe(ent: &TocEntry) -> String {
+    let name = base_name(&ent.name);
+    match ent.entry_type.as_str() {
+        "dir" => format!("{name}/"),
+        "reg" => format!("{name} ({} bytes)", ent.size),
+        "symlink" => format!("{name} -> {}", ent.link_name),
+        "hardlink" => format!("{name} (hardlink to {})", ent.link_name),
+        "char" | "block" => format!(
+            "{name} ({} {}:{})",
+            ent.entry_type, ent.dev_major, ent.dev_minor
+        ),
+        other => format!("{name} ({other})"),
+    }
+}
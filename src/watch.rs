@@ -0,0 +1,197 @@
+//! Keeping a layer up to date with a directory that changes, see
+//! [`DirWatcher`].
+
+use std::{
+    ffi::CString,
+    fs::{self, File},
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{dirtree, open_with_options, DirOptions, ReaderOptions, Writer, WriterStats};
+
+// Changes to a watched directory that call for a rebuild
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF;
+// How long the directory must stay quiet before a rebuild, in milliseconds,
+// so that a burst of changes (an unpacked archive, a build) makes one
+const QUIET_MS: i32 = 200;
+
+/// Rebuilds the layer of a directory, as
+/// [`Writer::append_dir_all_with`] writes it, whenever something in the
+/// directory changes.
+///
+/// Each build reuses the compressed chunks of the previous output whose
+/// content didn't change, see [`Writer::with_chunk_reuse`], so rebuilding
+/// after a few files changed mostly costs reading the tree. The new blob
+/// is written next to the output and renamed over it once complete, so
+/// readers never see a partial layer.
+///
+/// Changes are noticed with inotify, on every directory of the tree. The
+/// output must not be in the watched directory, which would change with
+/// every build.
+pub struct DirWatcher {
+    dir: PathBuf,
+    output: PathBuf,
+    opts: DirOptions,
+    chunk_size: Option<usize>,
+    // Watching the directories as of the last build
+    inotify: Option<OwnedFd>,
+}
+
+impl DirWatcher {
+    pub fn new(dir: impl Into<PathBuf>, output: impl Into<PathBuf>) -> Self {
+        DirWatcher {
+            dir: dir.into(),
+            output: output.into(),
+            opts: DirOptions::default(),
+            chunk_size: None,
+            inotify: None,
+        }
+    }
+
+    /// What to leave out of the layer and how to handle symlinks.
+    pub fn dir_options(mut self, opts: DirOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// See [`Writer::with_chunk_size`]. Changing it between builds of the
+    /// same output defeats chunk reuse for one build.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Build the layer now, reusing what it can of the current output, and
+    /// start watching the directories found for [`DirWatcher::wait`].
+    pub fn build(&mut self) -> Result<WriterStats> {
+        let dir = fs::canonicalize(&self.dir)
+            .with_context(|| format!("reading {}", self.dir.display()))?;
+        let parent = match self.output.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        if fs::canonicalize(parent).is_ok_and(|p| p.starts_with(&dir)) {
+            return Err(anyhow!(
+                "{} is inside of the watched {}",
+                self.output.display(),
+                self.dir.display()
+            ));
+        }
+
+        // Watching before walking, so nothing changing during the build is
+        // missed
+        self.inotify = Some(self.watch()?);
+
+        // A missing or broken output is rebuilt from scratch
+        let previous = File::open(&self.output)
+            .ok()
+            .and_then(|f| open_with_options(f, ReaderOptions::new()).ok());
+        let mut tmp = self.output.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let out = File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+
+        let mut w = Writer::new(out);
+        if let Some(chunk_size) = self.chunk_size {
+            w = w.with_chunk_size(chunk_size)?;
+        }
+        if let Some(previous) = &previous {
+            w = w.with_chunk_reuse(previous);
+        }
+        let written = w
+            .append_dir_all_with(&self.dir, &self.opts)
+            .and_then(|_| w.close());
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        let stats = w.stats().clone();
+        drop(w);
+        fs::rename(&tmp, &self.output)
+            .with_context(|| format!("replacing {}", self.output.display()))?;
+
+        Ok(stats)
+    }
+
+    /// Block until something changes in the directory since the last
+    /// [`DirWatcher::build`], and then until it has been quiet for a moment.
+    pub fn wait(&mut self) -> Result<()> {
+        let fd = self
+            .inotify
+            .as_ref()
+            .ok_or_else(|| anyhow!("nothing is watched before the first build"))?
+            .as_raw_fd();
+        let mut timeout = -1;
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err).context("waiting for changes");
+                    }
+                }
+                0 => return Ok(()),
+                _ => {
+                    // Only that there was an event matters
+                    let mut events = [0u8; 4096];
+                    let n = unsafe { libc::read(fd, events.as_mut_ptr().cast(), events.len()) };
+                    if n < 0 {
+                        return Err(io::Error::last_os_error()).context("reading changes");
+                    }
+                    timeout = QUIET_MS;
+                }
+            }
+        }
+    }
+
+    /// Build, call `on_build` with what was written, wait for changes and
+    /// start over, until an error.
+    pub fn run(&mut self, mut on_build: impl FnMut(&WriterStats)) -> Result<()> {
+        loop {
+            on_build(&self.build()?);
+            self.wait()?;
+        }
+    }
+
+    // A new inotify instance watching the directory and every directory
+    // under it not ignored
+    fn watch(&self) -> Result<OwnedFd> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("starting inotify");
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let dirs = dirtree::walk(&self.dir, &self.opts)?
+            .into_iter()
+            .filter(|disk| disk.meta.is_dir())
+            .map(|disk| disk.path);
+        for dir in [self.dir.clone()].into_iter().chain(dirs) {
+            let path = CString::new(dir.as_os_str().as_bytes())?;
+            let wd = unsafe { libc::inotify_add_watch(fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("watching {}", dir.display()));
+            }
+        }
+        Ok(fd)
+    }
+}
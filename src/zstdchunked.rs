@@ -0,0 +1,49 @@
+//! The zstd:chunked layout of containers/storage: zstd frames in place of
+//! gzip members, and the TOC as a zstd-compressed manifest in a skippable
+//! frame followed by a footer frame, neither of which decompresses to
+//! anything.
+
+use std::io::Write;
+
+use anyhow::Result;
+
+// Frames with this magic are skipped by zstd decoders
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184d_2a50;
+const FOOTER_MAGIC: &[u8; 8] = b"GNUlInUx";
+// The manifest is a TOC in the format CRFS and eStargz use
+const MANIFEST_TYPE_CRFS: u64 = 1;
+
+fn write_skippable_frame(w: &mut dyn Write, data: &[u8]) -> Result<()> {
+    w.write_all(&SKIPPABLE_FRAME_MAGIC.to_le_bytes())?;
+    w.write_all(&u32::try_from(data.len())?.to_le_bytes())?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+// Write the compressed `manifest` of a blob whose data ends at `offset`, and
+// the footer pointing to it
+pub(crate) fn write_manifest(
+    w: &mut dyn Write,
+    offset: u64,
+    manifest: &[u8],
+    uncompressed_len: usize,
+) -> Result<()> {
+    write_skippable_frame(w, manifest)?;
+
+    // The manifest offset, its compressed and uncompressed lengths and
+    // type, then the same for a tar-split stream, which isn't written
+    let mut footer = Vec::with_capacity(64);
+    for field in [
+        offset + 8,
+        manifest.len() as u64,
+        uncompressed_len as u64,
+        MANIFEST_TYPE_CRFS,
+        0,
+        0,
+        0,
+    ] {
+        footer.extend_from_slice(&field.to_le_bytes());
+    }
+    footer.extend_from_slice(FOOTER_MAGIC);
+    write_skippable_frame(w, &footer)
+}
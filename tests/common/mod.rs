@@ -0,0 +1,175 @@
+//! Fixtures shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use sha2::Digest;
+use stargz_rs::{EntryMeta, Writer};
+
+/// A directory under the system temp dir, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "stargz-rs-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A GNU header for a root-owned entry with mode 644 and mtime 1600000000.
+pub fn header(entry_type: tar::EntryType, size: u64) -> tar::Header {
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(entry_type);
+    h.set_size(size);
+    h.set_mode(0o644);
+    h.set_uid(0);
+    h.set_gid(0);
+    h.set_mtime(1600000000);
+    h
+}
+
+/// A tar holding the regular files `files`, see [`header`].
+pub fn tar_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut b = tar::Builder::new(Vec::new());
+    for (path, data) in files {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, *data).unwrap();
+    }
+    b.into_inner().unwrap()
+}
+
+/// Content that doesn't compress away, so chunk boundaries matter.
+pub fn pattern(len: usize) -> Vec<u8> {
+    let mut x: u64 = 0x9e3779b97f4a7c15;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+/// The blob `build` writes with a Writer over `w`, closed afterwards.
+pub fn blob_with<'a>(
+    setup: impl FnOnce(Writer<'a, &'a mut Vec<u8>>) -> Writer<'a, &'a mut Vec<u8>>,
+    build: impl FnOnce(&mut Writer<'a, &'a mut Vec<u8>>),
+    out: &'a mut Vec<u8>,
+) {
+    let mut w = setup(Writer::new(out));
+    build(&mut w);
+    w.close().unwrap();
+}
+
+/// The blob holding the tar `input`, written with a chunk size of `chunk`.
+pub fn blob_of(input: &[u8], chunk: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    blob_with(
+        |w| w.with_chunk_size(chunk).unwrap(),
+        |w| w.append_tar(&mut &input[..]).unwrap(),
+        &mut out,
+    );
+    out
+}
+
+/// The tar stream of the gzip blob `blob`, TOC entry and all.
+pub fn decompress(blob: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    flate2::read::MultiGzDecoder::new(blob)
+        .read_to_end(&mut out)
+        .unwrap();
+    out
+}
+
+/// The blob `blob` with its TOC replaced by what `edit` makes of it, to get
+/// TOCs the Writer would never write. The footer is kept as is, so is the
+/// TOC offset.
+pub fn with_toc(blob: &[u8], edit: impl FnOnce(&mut serde_json::Value)) -> Vec<u8> {
+    let (toc_offset, footer_len) = footer_of(blob);
+    let mut toc: serde_json::Value = serde_json::from_slice(&toc_json(blob)).unwrap();
+    edit(&mut toc);
+    let json = serde_json::to_vec(&toc).unwrap();
+
+    let mut out = blob[..toc_offset].to_vec();
+    let gz = flate2::write::GzEncoder::new(&mut out, flate2::Compression::default());
+    let mut b = tar::Builder::new(gz);
+    let mut h = header(tar::EntryType::Regular, json.len() as u64);
+    b.append_data(&mut h, "stargz.index.json", &json[..])
+        .unwrap();
+    b.into_inner().unwrap().finish().unwrap();
+    out.extend_from_slice(&blob[blob.len() - footer_len..]);
+    out
+}
+
+/// The TOC offset the footer of `blob` holds, and the footer's size.
+pub fn footer_of(blob: &[u8]) -> (usize, usize) {
+    let tail = &blob[blob.len() - 51..];
+    let at = tail.windows(6).position(|w| w == b"STARGZ").unwrap();
+    let toc_offset = std::str::from_utf8(&tail[at - 16..at]).unwrap();
+    let toc_offset = usize::from_str_radix(toc_offset, 16).unwrap();
+    if tail.windows(4).any(|w| w == b"SG\x16\x00") {
+        (toc_offset, 51)
+    } else {
+        (toc_offset, 47)
+    }
+}
+
+/// The stargz.index.json content of `blob`, as written.
+pub fn toc_json(blob: &[u8]) -> Vec<u8> {
+    let (toc_offset, _) = footer_of(blob);
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&blob[toc_offset..]));
+    let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(&*entry.path_bytes(), b"stargz.index.json");
+    let mut json = Vec::new();
+    entry.read_to_end(&mut json).unwrap();
+    json
+}
+
+/// The manifest of the zstd:chunked blob `blob`, decompressed, found
+/// through the footer at its end.
+pub fn zstd_manifest(blob: &[u8]) -> Vec<u8> {
+    let footer = &blob[blob.len() - 64..];
+    assert_eq!(&footer[56..], b"GNUlInUx");
+    let field = |i: usize| u64::from_le_bytes(footer[i * 8..i * 8 + 8].try_into().unwrap());
+    let (offset, len) = (field(0) as usize, field(1) as usize);
+    let manifest = zstd::decode_all(&blob[offset..offset + len]).unwrap();
+    assert_eq!(manifest.len() as u64, field(2));
+    manifest
+}
+
+/// `sha256:<hex>` of `data`, the way the Writer reports digests.
+pub fn sha256(data: &[u8]) -> String {
+    let hash = sha2::Sha256::digest(data);
+    let hex: String = hash.iter().map(|b| format!("{b:02x}")).collect();
+    format!("sha256:{hex}")
+}
+
+pub fn file_meta() -> EntryMeta {
+    EntryMeta {
+        mode: 0o644,
+        mtime: 1600000000,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,1666 @@
+mod common;
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{IoSliceMut, Read, Seek, SeekFrom, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use common::{
+    blob_of, blob_with, decompress, footer_of, header, pattern, tar_of, with_toc, TempDir,
+};
+use flate2::read::GzDecoder;
+use stargz_rs::{
+    detect_format,
+    doctor::{self, Severity},
+    is_stargz, open_from_bytes, open_with_external_toc, repair, BlobFormat, CompressionLevel,
+    DiskUsage, ErrorKind, FooterFormat, GzReader, MemberIter, ParseMode, ReaderOptions, TocCache,
+};
+
+const CHUNK: usize = 4096;
+
+#[test]
+fn chunk_entry_for_offset_finds_every_chunk() {
+    // Two full chunks and a shorter last one
+    let big = pattern(2 * CHUNK + 1808);
+    let r = open_from_bytes(blob_of(
+        &tar_of(&[("big", &big), ("small", b"small")]),
+        CHUNK,
+    ))
+    .unwrap();
+
+    let size = big.len() as u64;
+    let chunk = CHUNK as u64;
+    let mut starts = Vec::new();
+    for offset in [0, 1, chunk - 1, chunk, 2 * chunk - 1, 2 * chunk, size - 1] {
+        let ent = r.chunk_entry_for_offset("big", offset).unwrap();
+        assert_eq!(ent.chunk_offset(), offset / chunk * chunk, "at {offset}");
+        assert!(
+            offset < ent.chunk_offset() + ent.chunk_size(),
+            "at {offset}"
+        );
+        starts.push(ent.chunk_offset());
+    }
+    starts.dedup();
+    assert_eq!(starts, [0, chunk, 2 * chunk]);
+    let last = r.chunk_entry_for_offset("big", size - 1).unwrap();
+    assert_eq!(last.chunk_size(), size - 2 * chunk);
+    assert!(r.chunk_entry_for_offset("big", size).is_none());
+
+    // An unchunked file is its own chunk
+    let small = r.chunk_entry_for_offset("small", 4).unwrap();
+    assert_eq!(small.name(), "small");
+    assert_eq!((small.chunk_offset(), small.chunk_size()), (0, 5));
+    assert!(r.chunk_entry_for_offset("small", 5).is_none());
+    assert!(r.chunk_entry_for_offset("missing", 0).is_none());
+
+    // A TOC claiming the only chunk of a file starts past the offset
+    let blob = blob_of(&tar_of(&[("small", b"small")]), CHUNK);
+    let shifted = with_toc(&blob, |toc| {
+        for e in toc["entries"].as_array_mut().unwrap() {
+            if e["name"] == "small" {
+                e["chunkOffset"] = 3.into();
+            }
+        }
+    });
+    let r = ReaderOptions::new()
+        .parse_mode(ParseMode::Permissive)
+        .open_from_bytes(shifted)
+        .unwrap();
+    assert!(r.chunk_entry_for_offset("small", 0).is_none());
+    assert_eq!(
+        r.chunk_entry_for_offset("small", 4).unwrap().name(),
+        "small"
+    );
+}
+
+#[test]
+fn opened_file_reads_and_seeks_across_chunks() {
+    let big = pattern(2 * CHUNK + 1808);
+    let r = open_from_bytes(blob_of(&tar_of(&[("big", &big)]), CHUNK)).unwrap();
+    let mut f = r.open_file("big").unwrap();
+
+    let mut all = Vec::new();
+    f.read_to_end(&mut all).unwrap();
+    assert!(all == big);
+    assert_eq!(f.read(&mut [0; 10]).unwrap(), 0);
+
+    // Across the first boundary, then the second one from the end
+    let mut buf = [0; 200];
+    assert_eq!(f.seek(SeekFrom::Start(CHUNK as u64 - 100)).unwrap(), 3996);
+    f.read_exact(&mut buf).unwrap();
+    assert_eq!(buf[..], big[CHUNK - 100..CHUNK + 100]);
+    let at = f.seek(SeekFrom::End(-1908)).unwrap();
+    assert_eq!(at, 2 * CHUNK as u64 - 100);
+    f.read_exact(&mut buf).unwrap();
+    assert_eq!(buf[..], big[2 * CHUNK - 100..2 * CHUNK + 100]);
+    // Back into the first chunk from where that left off
+    let at = f.seek(SeekFrom::Current(-(CHUNK as i64) - 150)).unwrap();
+    assert_eq!(at, CHUNK as u64 - 50);
+    f.read_exact(&mut buf[..100]).unwrap();
+    assert_eq!(buf[..100], big[CHUNK - 50..CHUNK + 50]);
+
+    // Positional reads leave the position alone
+    let mut buf = vec![0; CHUNK + 200];
+    assert_eq!(f.read_at(&mut buf, 100).unwrap(), buf.len());
+    assert!(buf[..] == big[100..CHUNK + 300]);
+    assert_eq!(f.stream_position().unwrap(), CHUNK as u64 + 50);
+
+    // Short at the end, nothing past it
+    let mut tail = Vec::new();
+    f.seek(SeekFrom::End(-10)).unwrap();
+    f.read_to_end(&mut tail).unwrap();
+    assert_eq!(tail[..], big[big.len() - 10..]);
+    assert_eq!(f.read_at(&mut [0; 10], big.len() as u64).unwrap(), 0);
+    f.seek(SeekFrom::End(100)).unwrap();
+    assert_eq!(f.read(&mut [0; 10]).unwrap(), 0);
+    assert!(f
+        .seek(SeekFrom::Current(-(big.len() as i64) - 200))
+        .is_err());
+}
+
+// What the gzip member at `offset` of `blob` decompresses to
+fn member(blob: &[u8], offset: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    GzDecoder::new(&blob[offset as usize..])
+        .read_to_end(&mut out)
+        .unwrap();
+    out
+}
+
+#[test]
+fn chunks_get_members_and_small_files_share_them() {
+    // Each chunk of a big file gets a member, the first one after the
+    // file's header and the others starting one of their own
+    let big = pattern(2 * CHUNK + 1808);
+    let blob = blob_of(&tar_of(&[("big", &big)]), CHUNK);
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let chunks: Vec<_> = r.entries().iter().filter(|e| e.name() == "big").collect();
+    assert_eq!(chunks.len(), 3);
+    for pair in chunks.windows(2) {
+        assert!(pair[0].offset() < pair[1].offset());
+        assert_eq!(pair[1].inner_offset(), 0);
+    }
+    for chunk in &chunks {
+        let start = chunk.chunk_offset() as usize;
+        let end = start + chunk.chunk_size() as usize;
+        let content = member(&blob, chunk.offset());
+        assert!(content[chunk.inner_offset() as usize..].starts_with(&big[start..end]));
+    }
+
+    // Small files share members until they hold min_chunk_size, each at its
+    // inner offset
+    let small: Vec<(String, Vec<u8>)> = (0..20)
+        .map(|i| (format!("s{i:02}"), pattern(100 + i)))
+        .collect();
+    let files: Vec<_> = small
+        .iter()
+        .map(|(name, data)| (name.as_str(), &data[..]))
+        .collect();
+    let input = tar_of(&files);
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_min_chunk_size(8192),
+        |w| w.append_tar(&mut &input[..]).unwrap(),
+        &mut blob,
+    );
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let mut members: Vec<u64> = Vec::new();
+    let mut last_inner = 0;
+    for (name, data) in &small {
+        let ent = r.lookup(name).unwrap();
+        let content = member(&blob, ent.offset());
+        let at = ent.inner_offset() as usize;
+        assert!(content[at..].starts_with(data), "{name}");
+        if members.last() == Some(&ent.offset()) {
+            assert!(ent.inner_offset() > last_inner, "{name}");
+        } else {
+            members.push(ent.offset());
+        }
+        last_inner = ent.inner_offset();
+        assert_eq!(r.read_file(name).unwrap(), *data);
+    }
+    assert!(
+        members.len() > 1 && members.len() < small.len(),
+        "{members:?}"
+    );
+    assert!(r.verify().is_ok());
+}
+
+#[test]
+fn lookup_errors_tell_missing_from_broken() {
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Regular, 4);
+    b.append_data(&mut h, "f", &b"data"[..]).unwrap();
+    for (path, target) in [("h1", "f"), ("h2", "h1")] {
+        let mut h = header(tar::EntryType::Link, 0);
+        b.append_link(&mut h, path, target).unwrap();
+    }
+    let blob = blob_of(&b.into_inner().unwrap(), CHUNK);
+
+    let r = open_from_bytes(blob.clone()).unwrap();
+    assert_eq!(r.lookup("h2").unwrap().name(), "f");
+    let err = r.lookup("nope").unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::NotFound));
+    let err = r.lookup("f/nope").unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::NotFound));
+
+    // Hardlinks to each other: every name exists, none leads to a file
+    let looped = with_toc(&blob, |toc| {
+        for e in toc["entries"].as_array_mut().unwrap() {
+            if e["name"] == "h1" {
+                e["linkName"] = "h2".into();
+            }
+        }
+    });
+    let r = open_from_bytes(looped).unwrap();
+    for path in ["h1", "h2"] {
+        let err = r.lookup(path).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Corrupt), "{err:#}");
+    }
+    assert_eq!(r.lookup("f").unwrap().name(), "f");
+
+    // Hardlinks leading out of the layer are caught when opening
+    let dangling = with_toc(&blob, |toc| {
+        for e in toc["entries"].as_array_mut().unwrap() {
+            if e["name"] == "h1" {
+                e["linkName"] = "gone".into();
+            }
+        }
+    });
+    assert!(open_from_bytes(dangling).is_err());
+}
+
+#[test]
+fn case_insensitive_lookup_picks_the_first_spelling() {
+    let input = tar_of(&[
+        ("etc/passwd", b"lower"),
+        ("Etc/Passwd", b"upper"),
+        ("Readme", b"readme"),
+    ]);
+    let r = ReaderOptions::new()
+        .case_insensitive(true)
+        .open_from_bytes(blob_of(&input, CHUNK))
+        .unwrap();
+
+    assert_eq!(r.lookup("README").unwrap().name(), "Readme");
+    assert_eq!(r.read_file("readme").unwrap(), b"readme");
+    // Among spellings that fold together, the first one sorted wins, but
+    // an exact match always resolves to itself
+    assert_eq!(r.lookup("ETC/PASSWD").unwrap().name(), "Etc/Passwd");
+    assert_eq!(r.lookup("etc/passwd").unwrap().name(), "etc/passwd");
+    assert_eq!(r.read_file("etc/passwd").unwrap(), b"lower");
+    assert_eq!(
+        r.case_conflicts(),
+        [
+            vec!["Etc".to_string(), "etc".to_string()],
+            vec!["Etc/Passwd".to_string(), "etc/passwd".to_string()],
+        ]
+    );
+
+    let r = open_from_bytes(blob_of(&input, CHUNK)).unwrap();
+    let err = r.lookup("README").unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::NotFound));
+    assert!(r.case_conflicts().is_empty());
+}
+
+#[test]
+fn lookup_cleans_the_path() {
+    let r = open_from_bytes(blob_of(&tar_of(&[("etc/passwd", b"root")]), CHUNK)).unwrap();
+    for path in [
+        "etc/passwd",
+        "/etc/passwd",
+        "./etc//passwd",
+        "etc/passwd/",
+        "etc/./passwd",
+    ] {
+        assert_eq!(r.lookup(path).unwrap().name(), "etc/passwd", "{path}");
+    }
+    for path in ["/", ".", "", "//"] {
+        assert_eq!(r.lookup(path).unwrap().name(), "", "{path}");
+    }
+    let names: Vec<_> = r.readdir("/etc/").unwrap().map(|e| e.name()).collect();
+    assert_eq!(names, ["etc/passwd"]);
+
+    // Paths as the standard library builds them
+    let path = Path::new("/").join("etc").join("passwd");
+    assert_eq!(r.lookup(&path).unwrap().name(), "etc/passwd");
+    assert_eq!(
+        r.lookup(Path::new("./etc//passwd")).unwrap().name(),
+        "etc/passwd"
+    );
+    assert_eq!(
+        r.lookup_follow(PathBuf::from("etc/")).unwrap().name(),
+        "etc"
+    );
+    let not_utf8 = Path::new(OsStr::from_bytes(b"etc/\xff"));
+    let err = r.lookup(not_utf8).unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::NotFound));
+
+    // Names in the TOC are cleaned the same way
+    let mut input = Vec::new();
+    let mut b = tar::Builder::new(&mut input);
+    let mut h = header(tar::EntryType::Regular, 4);
+    h.as_gnu_mut().unwrap().name[..13].copy_from_slice(b"./etc//passwd");
+    h.set_cksum();
+    b.append(&h, &b"root"[..]).unwrap();
+    b.finish().unwrap();
+    drop(b);
+    let r = open_from_bytes(blob_of(&input, CHUNK)).unwrap();
+    assert_eq!(r.lookup("/etc/passwd").unwrap().name(), "etc/passwd");
+    assert_eq!(r.read_file("etc/passwd").unwrap(), b"root");
+}
+
+// A layer with the regular files `files` and the symlinks `links`, given as
+// (path, target)
+fn links_tar(files: &[(&str, &[u8])], links: &[(&str, &str)]) -> Vec<u8> {
+    let mut b = tar::Builder::new(Vec::new());
+    for (path, data) in files {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, *data).unwrap();
+    }
+    for (path, target) in links {
+        let mut h = header(tar::EntryType::Symlink, 0);
+        b.append_link(&mut h, path, target).unwrap();
+    }
+    b.into_inner().unwrap()
+}
+
+#[test]
+fn lookup_follow_stops_at_loops_and_deep_chains() {
+    let deep: Vec<(String, String)> = (0..45)
+        .map(|i| (format!("deep{i}"), format!("deep{}", i + 1)))
+        .collect();
+    let mut links = vec![
+        ("a", "b"),
+        ("b", "/lib/c"),
+        ("lib/c", "../f"),
+        ("loop1", "loop2"),
+        ("loop2", "./loop1"),
+        ("self", "self/x"),
+    ];
+    links.extend(deep.iter().map(|(p, t)| (p.as_str(), t.as_str())));
+    let input = links_tar(&[("f", b"data"), ("deep45", b"end")], &links);
+    let r = open_from_bytes(blob_of(&input, CHUNK)).unwrap();
+
+    // A chain of relative and absolute targets
+    assert_eq!(r.lookup_follow("a").unwrap().name(), "f");
+    assert_eq!(r.lookup("a").unwrap().entry_type(), "symlink");
+
+    for path in ["loop1", "loop2/x"] {
+        let err = r.lookup_follow(path).unwrap_err();
+        assert!(format!("{err:#}").contains("loop"), "{path}: {err:#}");
+    }
+    // Never the same path twice, but never ending either
+    assert!(r.lookup_follow("self").is_err());
+
+    // 40 links are fine, 41 are too many
+    assert_eq!(r.lookup_follow("deep5").unwrap().name(), "deep45");
+    let err = r.lookup_follow("deep4").unwrap_err();
+    assert!(format!("{err:#}").contains("too many levels"), "{err:#}");
+}
+
+#[test]
+fn lookup_follow_resolves_links_inside_the_path() {
+    let input = links_tar(
+        &[("usr/lib/ld.so", b"ld"), ("etc/os-release", b"os")],
+        &[
+            ("lib64", "usr/lib"),
+            ("usr/lib/ld-linux.so", "./ld.so"),
+            ("usr/bin/os", "../../etc/os-release"),
+            ("etc/alias", "/lib64/ld-linux.so"),
+            ("dangling", "usr/nope"),
+        ],
+    );
+    let r = open_from_bytes(blob_of(&input, CHUNK)).unwrap();
+
+    // A directory link in the middle, then a relative file link
+    assert_eq!(
+        r.lookup_follow("lib64/ld-linux.so").unwrap().name(),
+        "usr/lib/ld.so"
+    );
+    assert_eq!(
+        r.lookup_follow("/lib64/ld.so").unwrap().name(),
+        "usr/lib/ld.so"
+    );
+    // `..` is taken from the link's directory, not the path's
+    assert_eq!(
+        r.lookup_follow("usr/bin/os").unwrap().name(),
+        "etc/os-release"
+    );
+    assert_eq!(
+        r.lookup_follow("etc/alias").unwrap().name(),
+        "usr/lib/ld.so"
+    );
+    assert_eq!(
+        r.read_file(r.lookup_follow("etc/alias").unwrap().name())
+            .unwrap(),
+        b"ld"
+    );
+
+    // lookup doesn't follow any of them
+    assert_eq!(r.lookup("etc/alias").unwrap().entry_type(), "symlink");
+    let err = r.lookup("lib64/ld.so").unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::NotFound));
+
+    let err = r.lookup_follow("dangling").unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::NotFound));
+    assert_eq!(r.lookup("dangling").unwrap().link_name(), "usr/nope");
+}
+
+#[test]
+fn parse_modes_reject_or_clean_names_outside_the_layer() {
+    let blob = blob_of(
+        &tar_of(&[("etc/passwd", b"root"), ("bin/sh", b"sh")]),
+        CHUNK,
+    );
+    let blob = with_toc(&blob, |toc| {
+        for ent in toc["entries"].as_array_mut().unwrap() {
+            match ent["name"].as_str().unwrap() {
+                "etc/passwd" => ent["name"] = "../../etc/passwd".into(),
+                "bin/sh" => ent["name"] = "/bin/sh".into(),
+                _ => {}
+            }
+        }
+    });
+
+    let err = ReaderOptions::new()
+        .parse_mode(ParseMode::Strict)
+        .open_from_bytes(blob.clone())
+        .err()
+        .unwrap();
+    assert!(format!("{err:#}").contains("isn't relative"), "{err:#}");
+
+    let r = ReaderOptions::new()
+        .parse_mode(ParseMode::Permissive)
+        .open_from_bytes(blob.clone())
+        .unwrap();
+    assert_eq!(r.read_file("etc/passwd").unwrap(), b"root");
+    assert_eq!(r.read_file("bin/sh").unwrap(), b"sh");
+    assert_eq!(r.warnings().len(), 2, "{:?}", r.warnings());
+    assert!(r.warnings()[0].contains("../../etc/passwd"));
+    assert!(r.entries().iter().all(|e| !e.name().contains("..")));
+
+    // The default
+    assert_eq!(open_from_bytes(blob).unwrap().warnings().len(), 2);
+}
+
+// `blob` written to a file under `dir`, opened for reading
+fn blob_file(dir: &TempDir, blob: &[u8]) -> File {
+    let path = dir.path().join("blob");
+    std::fs::write(&path, blob).unwrap();
+    File::open(path).unwrap()
+}
+
+#[test]
+fn doctor_tells_which_check_fails() {
+    let dir = TempDir::new();
+    let blob = blob_of(&tar_of(&[("a", b"aaaa"), ("b", b"bbbb")]), CHUNK);
+    // The checks with findings of `severity`, in order
+    let findings = |blob: &[u8], severity| {
+        let diagnosis = doctor::diagnose(&blob_file(&dir, blob)).unwrap();
+        let mut checks: Vec<_> = diagnosis
+            .findings
+            .into_iter()
+            .filter(|f| f.severity == severity)
+            .map(|f| f.check)
+            .collect();
+        checks.dedup();
+        checks
+    };
+
+    let diagnosis = doctor::diagnose(&blob_file(&dir, &blob)).unwrap();
+    assert!(diagnosis.is_healthy(), "{diagnosis}");
+    assert!(diagnosis.producer.is_some());
+    assert!(findings(&blob, Severity::Warning).is_empty());
+
+    // A deviation only warns, content not matching its digest fails
+    let escaping = with_toc(&blob, |toc| toc["entries"][0]["name"] = "/a".into());
+    assert_eq!(findings(&escaping, Severity::Warning), ["index"]);
+    assert!(findings(&escaping, Severity::Error).is_empty());
+    let wrong_digest = with_toc(&blob, |toc| {
+        toc["entries"][1]["digest"] = format!("sha256:{}", "0".repeat(64)).into()
+    });
+    assert_eq!(findings(&wrong_digest, Severity::Error), ["digests"]);
+
+    // Nothing past a broken footer
+    let mut truncated = blob.clone();
+    truncated.truncate(blob.len() - 10);
+    assert_eq!(findings(&truncated, Severity::Error), ["footer"]);
+    let diagnosis = doctor::diagnose(&blob_file(&dir, &truncated)).unwrap();
+    assert_eq!(diagnosis.findings.len(), 1);
+}
+
+#[test]
+fn vectored_reads_fill_buffers_across_chunks() {
+    let big = pattern(2 * CHUNK + 1808);
+    let fetched = Arc::new(AtomicUsize::new(0));
+    let count = fetched.clone();
+    let r = ReaderOptions::new()
+        .on_read(move |ev| {
+            if !ev.cached {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+        .open_from_bytes(blob_of(&tar_of(&[("big", &big)]), CHUNK))
+        .unwrap();
+    let mut f = r.open_file("big").unwrap();
+
+    // Across the first boundary, inside a buffer and between two
+    let (mut a, mut b, mut c) = ([0; 100], [0; 300], [0; 50]);
+    let at = CHUNK - 200;
+    let n = f
+        .read_vectored_at(
+            &mut [
+                IoSliceMut::new(&mut a),
+                IoSliceMut::new(&mut []),
+                IoSliceMut::new(&mut b),
+                IoSliceMut::new(&mut c),
+            ],
+            at as u64,
+        )
+        .unwrap();
+    assert_eq!(n, 450);
+    assert_eq!(a[..], big[at..at + 100]);
+    assert_eq!(b[..], big[at + 100..at + 400]);
+    assert_eq!(c[..], big[at + 400..at + 450]);
+    assert_eq!(fetched.load(Ordering::Relaxed), 2);
+
+    // Short at the end of the file
+    let at = big.len() - 120;
+    let n = f
+        .read_vectored_at(
+            &mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)],
+            at as u64,
+        )
+        .unwrap();
+    assert_eq!(n, 120);
+    assert_eq!(a[..], big[at..at + 100]);
+    assert_eq!(b[..20], big[at + 100..]);
+
+    // Through Read, from and to the position
+    f.seek(SeekFrom::Start(2 * CHUNK as u64 - 10)).unwrap();
+    let n = f
+        .read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut c)])
+        .unwrap();
+    assert_eq!(n, 150);
+    assert_eq!(a[..], big[2 * CHUNK - 10..2 * CHUNK + 90]);
+    assert_eq!(c[..], big[2 * CHUNK + 90..2 * CHUNK + 140]);
+    assert_eq!(f.stream_position().unwrap(), 2 * CHUNK as u64 + 140);
+}
+
+#[test]
+fn repair_rebuilds_the_toc_from_the_tar_stream() {
+    let dir = TempDir::new();
+    let big = pattern(2 * CHUNK + 100);
+    let blob = blob_of(
+        &tar_of(&[("a", b"aaaa"), ("big", &big), ("z", b"zz")]),
+        CHUNK,
+    );
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let toc_offset = r.info().toc_offset as usize;
+
+    // TOC and footer gone
+    let recovery = repair::recover(&blob_file(&dir, &blob[..toc_offset])).unwrap();
+    assert_eq!(recovery.entries(), 3);
+    assert!(recovery.lost.is_empty(), "{:?}", recovery.lost);
+    let mut repaired = Vec::new();
+    recovery
+        .write_blob(&blob_file(&dir, &blob[..toc_offset]), &mut repaired)
+        .unwrap();
+    let r = open_from_bytes(repaired).unwrap();
+    assert_eq!(r.read_file("a").unwrap(), b"aaaa");
+    assert!(r.read_file("big").unwrap() == big);
+    assert_eq!(r.read_file("z").unwrap(), b"zz");
+    assert!(r.verify().is_ok());
+
+    // Cut inside the last chunk of big: what comes before is kept
+    let last_chunk = open_from_bytes(blob.clone())
+        .unwrap()
+        .chunk_entry_for_offset("big", big.len() as u64 - 1)
+        .unwrap()
+        .offset() as usize;
+    let cut = &blob[..last_chunk + 10];
+    let recovery = repair::recover(&blob_file(&dir, cut)).unwrap();
+    assert_eq!(recovery.entries(), 1);
+    assert_eq!(recovery.lost[0].0, "big");
+    assert!(recovery.stopped.is_some());
+    let mut repaired = Vec::new();
+    recovery
+        .write_blob(&blob_file(&dir, cut), &mut repaired)
+        .unwrap();
+    let r = open_from_bytes(repaired).unwrap();
+    assert_eq!(r.read_file("a").unwrap(), b"aaaa");
+    assert!(r.lookup("big").is_err());
+}
+
+#[test]
+fn toc_cache_serves_reopens_of_a_digest() {
+    let dir = TempDir::new();
+    let cache = TocCache::new(dir.path().join("cache"));
+    let big = pattern(2 * CHUNK + 100);
+    let blob = blob_of(&tar_of(&[("a", b"aaaa"), ("big", &big)]), CHUNK);
+    let path = dir.path().join("blob");
+    std::fs::write(&path, &blob).unwrap();
+    let digest = "sha256:0123abcd";
+    let entry = dir.path().join("cache/sha256-0123abcd.toc");
+
+    let r = cache
+        .open(File::open(&path).unwrap(), digest, ReaderOptions::new())
+        .unwrap();
+    let ino = std::fs::metadata(&entry).unwrap().ino();
+    let names: Vec<_> = r.entries().iter().map(|e| e.name().to_string()).collect();
+
+    // Served from the entry, left as it is
+    let r = cache
+        .open(File::open(&path).unwrap(), digest, ReaderOptions::new())
+        .unwrap();
+    assert_eq!(std::fs::metadata(&entry).unwrap().ino(), ino);
+    let cached: Vec<_> = r.entries().iter().map(|e| e.name().to_string()).collect();
+    assert_eq!(cached, names);
+    assert!(r.read_file("big").unwrap() == big);
+    assert_eq!(r.stat("a").unwrap().nlink(), 1);
+    assert!(r.verify().is_ok());
+
+    cache.remove(digest).unwrap();
+    assert!(!entry.exists());
+    cache.remove(digest).unwrap();
+    assert!(cache
+        .open(File::open(&path).unwrap(), "../x", ReaderOptions::new())
+        .is_err());
+
+    // What a permissive open let through doesn't get past a strict one
+    let escaping = with_toc(&blob, |toc| toc["entries"][0]["name"] = "/a".into());
+    std::fs::write(&path, &escaping).unwrap();
+    let r = cache
+        .open(
+            File::open(&path).unwrap(),
+            "sha256:ff",
+            ReaderOptions::new(),
+        )
+        .unwrap();
+    assert_eq!(r.warnings().len(), 1);
+    let strict = ReaderOptions::new().parse_mode(ParseMode::Strict);
+    assert!(cache
+        .open(File::open(&path).unwrap(), "sha256:ff", strict)
+        .is_err());
+}
+
+#[test]
+fn corrupt_toc_cache_entries_are_rebuilt() {
+    let dir = TempDir::new();
+    let cache = TocCache::new(dir.path().join("cache"));
+    let big = pattern(2 * CHUNK + 100);
+    let path = dir.path().join("blob");
+    std::fs::write(
+        &path,
+        blob_of(&tar_of(&[("a", b"aaaa"), ("big", &big)]), CHUNK),
+    )
+    .unwrap();
+    let digest = "sha256:0123abcd";
+    let entry = dir.path().join("cache/sha256-0123abcd.toc");
+    let open = || {
+        cache
+            .open(File::open(&path).unwrap(), digest, ReaderOptions::new())
+            .unwrap()
+    };
+    open();
+    let good = std::fs::read(&entry).unwrap();
+
+    let mut flipped = good.clone();
+    let last = flipped.len() - 1;
+    flipped[last / 2] ^= 0x01;
+    let mut version = good.clone();
+    version[7] ^= 0xff;
+    for damaged in [
+        flipped,
+        version,
+        good[..good.len() / 2].to_vec(),
+        good[..4].to_vec(),
+        Vec::new(),
+        b"not a cache entry".to_vec(),
+    ] {
+        std::fs::write(&entry, &damaged).unwrap();
+        let r = open();
+        assert_eq!(r.read_file("a").unwrap(), b"aaaa");
+        assert!(r.read_file("big").unwrap() == big);
+        assert!(r.verify().is_ok());
+        // Replaced by a good entry, which the next open uses as is
+        assert_ne!(std::fs::read(&entry).unwrap(), damaged);
+        let ino = std::fs::metadata(&entry).unwrap().ino();
+        open();
+        assert_eq!(std::fs::metadata(&entry).unwrap().ino(), ino);
+    }
+}
+
+#[test]
+fn toc_cache_stores_from_threads_at_once() {
+    let dir = TempDir::new();
+    let cache = TocCache::new(dir.path().join("cache"));
+    let big = pattern(2 * CHUNK + 100);
+    let path = dir.path().join("blob");
+    std::fs::write(
+        &path,
+        blob_of(&tar_of(&[("a", b"aaaa"), ("big", &big)]), CHUNK),
+    )
+    .unwrap();
+    let digest = "sha256:0123abcd";
+
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                for _ in 0..4 {
+                    cache.remove(digest).unwrap();
+                    cache
+                        .open(File::open(&path).unwrap(), digest, ReaderOptions::new())
+                        .unwrap();
+                }
+            });
+        }
+    });
+    // Whichever entry won is whole, and used as is
+    let entry = dir.path().join("cache/sha256-0123abcd.toc");
+    let ino = std::fs::metadata(&entry).unwrap().ino();
+    let r = cache
+        .open(File::open(&path).unwrap(), digest, ReaderOptions::new())
+        .unwrap();
+    assert_eq!(std::fs::metadata(&entry).unwrap().ino(), ino);
+    assert!(r.read_file("big").unwrap() == big);
+    let names: Vec<_> = std::fs::read_dir(dir.path().join("cache"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["sha256-0123abcd.toc"]);
+}
+
+#[test]
+fn toc_cache_evicts_least_recently_used_entries_not_pinned() {
+    let dir = TempDir::new();
+    let cache_dir = dir.path().join("cache");
+    let path = dir.path().join("blob");
+    std::fs::write(&path, blob_of(&tar_of(&[("a", b"aaaa")]), CHUNK)).unwrap();
+    let entry = |d: &str| cache_dir.join(format!("sha256-{d}.toc"));
+    let open = |cache: &TocCache, d: &str| {
+        cache
+            .open(
+                File::open(&path).unwrap(),
+                &format!("sha256:{d}"),
+                ReaderOptions::new(),
+            )
+            .unwrap();
+    };
+    let exists = |d: &str| entry(d).exists();
+
+    let unbounded = TocCache::new(&cache_dir);
+    for (d, secs) in [("a", 1000), ("b", 2000), ("c", 3000)] {
+        open(&unbounded, d);
+        File::options()
+            .append(true)
+            .open(entry(d))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+    let len = std::fs::metadata(entry("a")).unwrap().len();
+    assert_eq!(unbounded.gc().unwrap(), 0);
+
+    // A hit makes a the most recently used, so b goes first
+    open(&unbounded, "a");
+    let cache = TocCache::new(&cache_dir).budget(2 * len + len / 2);
+    assert_eq!(cache.gc().unwrap(), len);
+    assert!(exists("a") && !exists("b") && exists("c"));
+    // Stores evict too
+    open(&cache, "d");
+    assert!(exists("a") && !exists("c") && exists("d"));
+
+    // Pins are shared by clones and counted
+    cache.pin("sha256:a").unwrap();
+    cache.pin("sha256:a").unwrap();
+    let empty = cache.clone().budget(0);
+    assert_eq!(empty.gc().unwrap(), len);
+    assert!(exists("a") && !exists("d"));
+    cache.unpin("sha256:a");
+    empty.gc().unwrap();
+    assert!(exists("a"));
+    cache.unpin("sha256:a");
+    assert_eq!(empty.gc().unwrap(), len);
+    assert!(!exists("a"));
+    assert!(cache.pin("../x").is_err());
+
+    // Only temporary files old enough to be abandoned go
+    let stale = cache_dir.join("sha256-e.tmp1-0");
+    let fresh = cache_dir.join("sha256-e.tmp1-1");
+    for tmp in [&stale, &fresh] {
+        std::fs::write(tmp, b"partial").unwrap();
+    }
+    File::options()
+        .append(true)
+        .open(&stale)
+        .unwrap()
+        .set_modified(UNIX_EPOCH)
+        .unwrap();
+    assert_eq!(unbounded.gc().unwrap(), 7);
+    assert!(!stale.exists() && fresh.exists());
+}
+
+#[test]
+fn chunks_must_cover_the_file_from_its_start() {
+    let big = pattern(3 * CHUNK);
+    let blob = blob_of(&tar_of(&[("big", &big)]), CHUNK);
+    // The file's own entry holds the first chunk
+    let late_start = with_toc(&blob, |toc| toc["entries"][0]["chunkOffset"] = 100.into());
+    let gap = with_toc(&blob, |toc| {
+        toc["entries"].as_array_mut().unwrap().remove(1);
+    });
+
+    for (blob, problem) in [
+        (&late_start, "no chunk at offset 0"),
+        (&gap, "no chunk at offset 4096"),
+    ] {
+        let err = ReaderOptions::new()
+            .parse_mode(ParseMode::Strict)
+            .open_from_bytes(blob.clone())
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains(problem), "{err:#}");
+
+        let r = open_from_bytes(blob.clone()).unwrap();
+        assert!(r.warnings()[0].contains(problem), "{:?}", r.warnings());
+    }
+    let r = open_from_bytes(gap).unwrap();
+    assert!(r.read_file("big").is_err());
+
+    // Reads before the first chunk fail rather than land in it
+    let r = open_from_bytes(late_start).unwrap();
+    let f = r.open_file("big").unwrap();
+    let err = f.read_at(&mut [0; 10], 0).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let mut buf = [0; 10];
+    assert_eq!(f.read_at(&mut buf, 200).unwrap(), 10);
+    assert!(r.chunk_entry_for_offset("big", 0).is_none());
+}
+
+#[test]
+fn legacy_stargz_footers_open() {
+    let input = tar_of(&[("a", b"aaaa"), ("big", &pattern(2 * CHUNK + 100))]);
+    let mut blob = Vec::new();
+    blob_with(
+        |w| {
+            w.with_chunk_size(CHUNK)
+                .unwrap()
+                .with_format(BlobFormat::Stargz)
+                .unwrap()
+        },
+        |w| w.append_tar(&mut &input[..]).unwrap(),
+        &mut blob,
+    );
+
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let info = r.info();
+    assert_eq!(info.footer, FooterFormat::Stargz);
+    assert_eq!(info.blob_size, blob.len() as u64);
+    assert_eq!(info.toc_offset + info.toc_size + 47, info.blob_size);
+    assert_eq!(r.read_file("a").unwrap(), b"aaaa");
+    assert!(r.verify().is_ok());
+
+    // What CRFS writes: the offset and STARGZ as the whole extra field
+    let footer = &blob[blob.len() - 47..];
+    assert_eq!(footer[..4], [0x1f, 0x8b, 0x08, 0x04]);
+    assert_eq!(footer[10..12], 22u16.to_le_bytes());
+    let extra = format!("{:016x}STARGZ", info.toc_offset);
+    assert_eq!(footer[12..34], *extra.as_bytes());
+}
+
+#[test]
+fn estargz_footers_are_51_bytes_and_either_size_opens() {
+    let input = tar_of(&[("a", b"aaaa"), ("big", &pattern(2 * CHUNK + 100))]);
+    let blob = blob_of(&input, CHUNK);
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let info = r.info();
+    assert_eq!(info.footer, FooterFormat::Estargz);
+    assert_eq!(info.toc_offset + info.toc_size + 51, info.blob_size);
+
+    // The offset in an `SG` subfield of 22 bytes
+    let footer = &blob[blob.len() - 51..];
+    assert_eq!(footer[10..12], 26u16.to_le_bytes());
+    assert_eq!(footer[12..16], *b"SG\x16\x00");
+    let extra = format!("{:016x}STARGZ", info.toc_offset);
+    assert_eq!(footer[16..38], *extra.as_bytes());
+
+    // The same data and TOC behind the shorter footer
+    let mut legacy = Vec::new();
+    blob_with(
+        |w| {
+            w.with_chunk_size(CHUNK)
+                .unwrap()
+                .with_format(BlobFormat::Stargz)
+                .unwrap()
+        },
+        |w| w.append_tar(&mut &input[..]).unwrap(),
+        &mut legacy,
+    );
+    assert_eq!(legacy[..legacy.len() - 47], blob[..blob.len() - 51]);
+    let r = open_from_bytes(legacy).unwrap();
+    assert_eq!(r.info().footer, FooterFormat::Stargz);
+    assert_eq!(r.info().toc_offset, info.toc_offset);
+    assert_eq!(r.read_file("a").unwrap(), b"aaaa");
+
+    // Neither footer: a cut one
+    let Err(err) = open_from_bytes(&blob[..blob.len() - 1]) else {
+        panic!("opened a blob without a footer");
+    };
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Corrupt));
+}
+
+#[test]
+fn external_tocs_open_blobs_without_one() {
+    let dir = TempDir::new();
+    let big = pattern(2 * CHUNK + 100);
+    let blob = blob_of(&tar_of(&[("a", b"aaaa"), ("big", &big)]), CHUNK);
+    let info = open_from_bytes(blob.clone()).unwrap().info();
+    let data = &blob[..info.toc_offset as usize];
+    let member = &blob[info.toc_offset as usize..blob.len() - 51];
+    let mut json = Vec::new();
+    let mut toc = tar::Archive::new(GzDecoder::new(member));
+    let mut entry = toc.entries().unwrap().next().unwrap().unwrap();
+    entry.read_to_end(&mut json).unwrap();
+
+    // The TOC as its gzip member or as bare JSON, the data cut before it
+    for toc in [member, &json[..]] {
+        let r = open_with_external_toc(blob_file(&dir, data), toc).unwrap();
+        assert_eq!(r.info().footer, FooterFormat::External);
+        assert_eq!(r.info().toc_offset, data.len() as u64);
+        assert_eq!(r.read_file("a").unwrap(), b"aaaa");
+        assert!(r.read_file("big").unwrap() == big);
+        assert!(r.verify().is_ok());
+    }
+
+    // A footer left in place is ignored
+    let r = ReaderOptions::new()
+        .open_with_external_toc(blob_file(&dir, &blob), &json[..])
+        .unwrap();
+    assert_eq!(r.info().footer, FooterFormat::External);
+    assert!(r.read_file("big").unwrap() == big);
+
+    let Err(err) = open_with_external_toc(blob_file(&dir, data), &b"{\"version\""[..]) else {
+        panic!("opened with a broken TOC");
+    };
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Corrupt));
+}
+
+#[test]
+fn lazy_readers_answer_like_eager_ones() {
+    let big = pattern(2 * CHUNK + 100);
+    let mut b = tar::Builder::new(Vec::new());
+    for (path, data) in [("etc/passwd", &b"root"[..]), ("big", &big), ("Big", b"B")] {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, data).unwrap();
+    }
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "etc/hard", "etc/passwd").unwrap();
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "lnk", "etc").unwrap();
+    let blob = blob_of(&b.into_inner().unwrap(), CHUNK);
+
+    let eager = open_from_bytes(blob.clone()).unwrap();
+    let lazy = ReaderOptions::new()
+        .lazy_index(true)
+        .open_from_bytes(blob.clone())
+        .unwrap();
+    // The first lookups from several threads at once
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| assert_eq!(lazy.lookup_follow("lnk/hard").unwrap().name(), "etc/passwd"));
+        }
+    });
+    let names = |r: &stargz_rs::GzReader| -> Vec<String> {
+        r.walk().map(|(path, _)| path.to_string()).collect()
+    };
+    assert_eq!(names(&lazy), names(&eager));
+    assert_eq!(lazy.stat("etc/passwd").unwrap().nlink(), 2);
+    let offsets = |r: &stargz_rs::GzReader| -> Vec<u64> {
+        (0..big.len() as u64)
+            .step_by(1000)
+            .map(|at| r.chunk_entry_for_offset("big", at).unwrap().chunk_offset())
+            .collect()
+    };
+    assert_eq!(offsets(&lazy), offsets(&eager));
+    assert!(lazy.read_file("big").unwrap() == big);
+    assert!(lazy.lookup("nope").is_err());
+
+    let lazy = ReaderOptions::new()
+        .lazy_index(true)
+        .case_insensitive(true)
+        .open_from_bytes(blob.clone())
+        .unwrap();
+    assert_eq!(lazy.lookup("ETC/PASSWD").unwrap().name(), "etc/passwd");
+    assert_eq!(
+        lazy.case_conflicts(),
+        [vec!["Big".to_string(), "big".to_string()]]
+    );
+
+    // What's wrong with the TOC is still found when opening
+    let dangling = with_toc(&blob, |toc| {
+        for e in toc["entries"].as_array_mut().unwrap() {
+            if e["name"] == "etc/hard" {
+                e["linkName"] = "gone".into();
+            }
+        }
+    });
+    assert!(ReaderOptions::new()
+        .lazy_index(true)
+        .open_from_bytes(dangling)
+        .is_err());
+}
+
+#[test]
+fn stat_counts_every_name_of_a_file() {
+    let mut b = tar::Builder::new(Vec::new());
+    for (path, data) in [
+        ("f", &b"data"[..]),
+        ("d/x/1", b"1"),
+        ("d/y/2", b"2"),
+        ("d/z", b""),
+    ] {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, data).unwrap();
+    }
+    for (path, target) in [("h1", "f"), ("d/h2", "h1")] {
+        let mut h = header(tar::EntryType::Link, 0);
+        b.append_link(&mut h, path, target).unwrap();
+    }
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "s", "d/h2").unwrap();
+    let r = open_from_bytes(blob_of(&b.into_inner().unwrap(), CHUNK)).unwrap();
+
+    for path in ["f", "h1", "d/h2", "s"] {
+        let stat = r.stat(path).unwrap();
+        assert_eq!(stat.nlink(), 3, "{path}");
+        assert_eq!(stat.target.name(), "f", "{path}");
+        assert_eq!(stat.target.size(), 4, "{path}");
+    }
+    let stat = r.stat("d/h2").unwrap();
+    assert!(stat.is_hardlink());
+    assert_eq!(stat.entry.name(), "d/h2");
+    assert!(!r.stat("f").unwrap().is_hardlink());
+
+    // lstat stops at the symlink, stat follows it to the hardlink
+    let stat = r.lstat("s").unwrap();
+    assert_eq!(stat.entry.entry_type(), "symlink");
+    assert_eq!(stat.nlink(), 1);
+    assert_eq!(r.stat("s").unwrap().entry.name(), "d/h2");
+
+    // Directories: 2 plus their subdirectories
+    assert_eq!(r.stat("d").unwrap().nlink(), 4);
+    assert_eq!(r.stat("d/x").unwrap().nlink(), 2);
+    assert_eq!(r.stat("/").unwrap().nlink(), 3);
+
+    let err = r.stat("d/nope").unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::NotFound));
+}
+
+#[test]
+fn fetch_plans_merge_close_ranges_in_blob_order() {
+    let files: Vec<(String, Vec<u8>)> = (0..20)
+        .map(|i| (format!("f{i:02}"), pattern(1000 + i)))
+        .collect();
+    let mut b = tar::Builder::new(Vec::new());
+    for (name, data) in &files {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, name, &data[..]).unwrap();
+    }
+    let mut h = header(tar::EntryType::Directory, 0);
+    b.append_data(&mut h, "d", &[][..]).unwrap();
+    let mut h = header(tar::EntryType::Regular, 0);
+    b.append_data(&mut h, "empty", &[][..]).unwrap();
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "link", "f07").unwrap();
+    let r = open_from_bytes(blob_of(&b.into_inner().unwrap(), CHUNK)).unwrap();
+    let span = |name: &str| {
+        let chunks = r.chunks(name).unwrap();
+        (chunks[0].blob_start, chunks.last().unwrap().blob_end)
+    };
+
+    // Out of order in, blob order out, each range covering its file
+    let plan = r.fetch_plan(["f10", "f00", "f05"], 0).unwrap();
+    let ranges: Vec<_> = plan.ranges.iter().map(|r| (r.start, r.end)).collect();
+    assert_eq!(ranges, [span("f00"), span("f05"), span("f10")]);
+    assert!(ranges.windows(2).all(|w| w[0].1 < w[1].0));
+    assert_eq!(plan.ranges[1].files, ["f05"]);
+    assert_eq!(
+        plan.bytes(),
+        ranges.iter().map(|(start, end)| end - start).sum::<u64>()
+    );
+
+    // Neighbours touch, so they merge even without a gap allowed
+    let plan = r.fetch_plan(["f02", "f01", "f03"], 0).unwrap();
+    assert_eq!(plan.ranges.len(), 1);
+    assert_eq!(plan.ranges[0].files, ["f01", "f02", "f03"]);
+    assert_eq!(
+        (plan.ranges[0].start, plan.ranges[0].end),
+        (span("f01").0, span("f03").1)
+    );
+
+    // Merged up to max_gap bytes of what wasn't asked for
+    let gap = span("f04").0 - span("f00").1;
+    assert!(gap > 0);
+    let plan = r.fetch_plan(["f00", "f04"], gap - 1).unwrap();
+    assert_eq!(plan.ranges.len(), 2);
+    let plan = r.fetch_plan(["f00", "f04"], gap).unwrap();
+    assert_eq!(plan.ranges.len(), 1);
+    assert_eq!(
+        (plan.ranges[0].start, plan.ranges[0].end),
+        (span("f00").0, span("f04").1)
+    );
+    assert_eq!(plan.bytes(), span("f04").1 - span("f00").0);
+    let all: Vec<_> = files.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(r.fetch_plan(all, u64::MAX).unwrap().ranges.len(), 1);
+
+    // Links are followed, entries without content need nothing
+    let plan = r.fetch_plan(["link", "d", "empty"], 0).unwrap();
+    assert_eq!(plan.ranges.len(), 1);
+    assert_eq!((plan.ranges[0].start, plan.ranges[0].end), span("f07"));
+    assert_eq!(plan.ranges[0].files, ["link"]);
+    assert!(r.fetch_plan(["d"], 0).unwrap().ranges.is_empty());
+
+    let err = r.fetch_plan(["f00", "missing"], 0).unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::NotFound));
+    r.prefetch(&r.fetch_plan(["f00"], 0).unwrap());
+}
+
+#[test]
+fn plain_tar_gz_layers_open_with_the_fallback() {
+    let big = pattern(3 * CHUNK + 5);
+    let mut b = tar::Builder::new(Vec::new());
+    for (path, data) in [("big", &big[..]), ("empty", b""), ("d/small", b"small")] {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, data).unwrap();
+    }
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "link", "d/small").unwrap();
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&b.into_inner().unwrap()).unwrap();
+    let tar_gz = gz.finish().unwrap();
+
+    let Err(err) = open_from_bytes(tar_gz.clone()) else {
+        panic!("opened a tar.gz without the fallback");
+    };
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Corrupt), "{err:#}");
+
+    let dir = TempDir::new();
+    let path = dir.path().join("layer.tar.gz");
+    std::fs::write(&path, &tar_gz).unwrap();
+    let opts = || ReaderOptions::new().tar_gz_fallback(true);
+    for r in [
+        opts().open_from_bytes(tar_gz.clone()).unwrap(),
+        opts().open(File::open(&path).unwrap()).unwrap(),
+    ] {
+        assert_eq!(r.info().footer, FooterFormat::Missing);
+        assert!(r.read_file("big").unwrap() == big);
+        assert_eq!(r.read_file("d/small").unwrap(), b"small");
+        assert_eq!(r.lookup("link").unwrap().link_name(), "d/small");
+        assert_eq!(r.read_file("empty").unwrap(), b"");
+        assert_eq!(r.lookup("d").unwrap().entry_type(), "dir");
+        let mut buf = [0; 4];
+        r.open_file("big")
+            .unwrap()
+            .read_at(&mut buf, 2 * CHUNK as u64)
+            .unwrap();
+        assert_eq!(buf, big[2 * CHUNK..][..4]);
+        assert!(r.verify().is_ok());
+    }
+}
+
+#[test]
+fn detect_format_tells_layer_formats_apart() {
+    let input = tar_of(&[("a", b"aaaa"), ("big", &pattern(2 * CHUNK + 100))]);
+    let blob_as = |format| {
+        let mut blob = Vec::new();
+        blob_with(
+            |w| w.with_format(format).unwrap(),
+            |w| w.append_tar(&mut &input[..]).unwrap(),
+            &mut blob,
+        );
+        blob
+    };
+    let estargz = blob_as(BlobFormat::Estargz);
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&input).unwrap();
+    let gzip = gz.finish().unwrap();
+    // A footer pointing past the blob isn't one
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(b"x").unwrap();
+    let mut bad_offset = gz.finish().unwrap();
+    bad_offset.extend_from_slice(&estargz[estargz.len() - 51..]);
+    let mut garbage = pattern(1000);
+    garbage[0] = 0;
+
+    for (blob, format) in [
+        (blob_as(BlobFormat::Stargz), BlobFormat::Stargz),
+        (estargz.clone(), BlobFormat::Estargz),
+        (blob_as(BlobFormat::ZstdChunked), BlobFormat::ZstdChunked),
+        (gzip, BlobFormat::Gzip),
+        (bad_offset, BlobFormat::Gzip),
+        (zstd::encode_all(&input[..], 3).unwrap(), BlobFormat::Zstd),
+        (input.clone(), BlobFormat::Tar),
+        (input[..300].to_vec(), BlobFormat::Tar),
+        (Vec::new(), BlobFormat::Unknown),
+        (b"\x1f".to_vec(), BlobFormat::Unknown),
+        (garbage, BlobFormat::Unknown),
+    ] {
+        assert_eq!(
+            detect_format(&blob).unwrap(),
+            format,
+            "{} bytes",
+            blob.len()
+        );
+        assert_eq!(detect_format(&blob[..]).unwrap(), format);
+        assert_eq!(
+            is_stargz(&blob),
+            matches!(format, BlobFormat::Stargz | BlobFormat::Estargz),
+            "{format:?}"
+        );
+    }
+
+    let dir = TempDir::new();
+    let path = dir.path().join("blob");
+    std::fs::write(&path, &estargz).unwrap();
+    let f = File::open(&path).unwrap();
+    assert_eq!(detect_format(&f).unwrap(), BlobFormat::Estargz);
+    assert!(is_stargz(&f));
+}
+
+// A layer to search and list: a few directories of files, a hardlink, a
+// symlink to a directory and a device
+fn tree_blob() -> Vec<u8> {
+    let mut b = tar::Builder::new(Vec::new());
+    for dir in ["etc", "etc/ssl", "usr", "usr/bin", "usr/lib", "usr/lib/x86"] {
+        let mut h = header(tar::EntryType::Directory, 0);
+        b.append_data(&mut h, dir, &[][..]).unwrap();
+    }
+    for (path, len) in [
+        ("etc/ssl/KEY.PEM", 3),
+        ("etc/ssl/cert.pem", 4),
+        ("usr/bin/sh", 7),
+        ("usr/lib/libA.so", 10),
+        ("usr/lib/readme", 5),
+        ("usr/lib/x86/libB.so", 20),
+        ("usr/lib/x86/libC.SO", 30),
+    ] {
+        let mut h = header(tar::EntryType::Regular, len);
+        b.append_data(&mut h, path, &pattern(len as usize)[..])
+            .unwrap();
+    }
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "usr/bin/ls", "usr/bin/sh").unwrap();
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "lib", "usr/lib").unwrap();
+    let mut h = header(tar::EntryType::Char, 0);
+    h.set_device_major(1).unwrap();
+    h.set_device_minor(3).unwrap();
+    b.append_data(&mut h, "null", &[][..]).unwrap();
+    blob_of(&b.into_inner().unwrap(), CHUNK)
+}
+
+#[test]
+fn glob_stars_stop_at_slashes_and_double_stars_do_not() {
+    let blob = tree_blob();
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let glob = |r: &GzReader, pattern: &str| -> Vec<String> {
+        r.glob(pattern)
+            .unwrap()
+            .map(|e| e.name().to_string())
+            .collect()
+    };
+
+    assert_eq!(glob(&r, "usr/lib/*.so"), ["usr/lib/libA.so"]);
+    assert_eq!(
+        glob(&r, "usr/lib/**/*.so"),
+        ["usr/lib/libA.so", "usr/lib/x86/libB.so"]
+    );
+    assert_eq!(glob(&r, "usr/*.so"), [] as [&str; 0]);
+    assert_eq!(glob(&r, "usr/*/libB.so"), [] as [&str; 0]);
+    assert_eq!(glob(&r, "usr/*/x86/lib?.so"), ["usr/lib/x86/libB.so"]);
+    assert_eq!(glob(&r, "**/*.pem"), ["etc/ssl/cert.pem"]);
+    // Directories match too
+    assert_eq!(glob(&r, "usr/*"), ["usr/bin", "usr/lib"]);
+    // Cleaned like lookups, and symlinks aren't followed
+    assert_eq!(glob(&r, "/usr/bin/./l*"), ["usr/bin/ls"]);
+    assert_eq!(glob(&r, "lib/*"), [] as [&str; 0]);
+    assert!(r.glob("usr/[").is_err());
+
+    let r = ReaderOptions::new()
+        .case_insensitive(true)
+        .open_from_bytes(blob)
+        .unwrap();
+    assert_eq!(
+        glob(&r, "usr/lib/**/*.so"),
+        [
+            "usr/lib/libA.so",
+            "usr/lib/x86/libB.so",
+            "usr/lib/x86/libC.SO"
+        ]
+    );
+    assert_eq!(
+        glob(&r, "ETC/**/*.pem"),
+        ["etc/ssl/KEY.PEM", "etc/ssl/cert.pem"]
+    );
+}
+
+#[test]
+fn find_matches_regexes_anywhere_in_paths() {
+    let blob = tree_blob();
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let find = |r: &GzReader, pattern: &str| -> Vec<String> {
+        r.find(pattern)
+            .unwrap()
+            .map(|e| e.name().to_string())
+            .collect()
+    };
+
+    assert_eq!(find(&r, r"\.pem$"), ["etc/ssl/cert.pem"]);
+    assert_eq!(
+        find(&r, r"lib[AB]\.so"),
+        ["usr/lib/libA.so", "usr/lib/x86/libB.so"]
+    );
+    // Unanchored unless told otherwise
+    assert_eq!(find(&r, "lib").len(), 7);
+    assert_eq!(find(&r, "^lib"), ["lib"]);
+    // Across slashes
+    assert_eq!(find(&r, "usr/.*/libC"), ["usr/lib/x86/libC.SO"]);
+    assert!(r.find("(").is_err());
+
+    let r = ReaderOptions::new()
+        .case_insensitive(true)
+        .open_from_bytes(blob)
+        .unwrap();
+    assert_eq!(find(&r, r"\.pem$"), ["etc/ssl/KEY.PEM", "etc/ssl/cert.pem"]);
+}
+
+#[test]
+fn du_totals_every_directory_below_the_path() {
+    let r = open_from_bytes(tree_blob()).unwrap();
+    let usage = |bytes, files, entries| DiskUsage {
+        bytes,
+        stored_bytes: bytes,
+        files,
+        entries,
+    };
+
+    let du = r.du("").unwrap();
+    let expected = [
+        ("", usage(79, 7, 16)),
+        ("etc", usage(7, 2, 3)),
+        ("etc/ssl", usage(7, 2, 2)),
+        ("usr", usage(72, 5, 9)),
+        ("usr/bin", usage(7, 1, 2)),
+        ("usr/lib", usage(65, 4, 5)),
+        ("usr/lib/x86", usage(50, 2, 2)),
+    ];
+    assert_eq!(
+        du.iter().map(|(k, v)| (k.as_str(), *v)).collect::<Vec<_>>(),
+        expected
+    );
+
+    // Just that part, reached through a symlink as well
+    let lib: Vec<_> = expected[5..]
+        .iter()
+        .map(|&(k, v)| (k.to_string(), v))
+        .collect();
+    assert_eq!(
+        r.du("usr/lib").unwrap().into_iter().collect::<Vec<_>>(),
+        lib
+    );
+    assert_eq!(r.du("/lib/").unwrap().into_iter().collect::<Vec<_>>(), lib);
+    assert!(r.du("usr/bin/sh").is_err());
+    assert!(r.du("missing").is_err());
+}
+
+#[test]
+fn render_tree_lists_like_tree() {
+    let r = open_from_bytes(tree_blob()).unwrap();
+    assert_eq!(
+        r.render_tree("").unwrap(),
+        "\
+.
+├── etc/
+│   └── ssl/
+│       ├── KEY.PEM (3 bytes)
+│       └── cert.pem (4 bytes)
+├── lib -> usr/lib
+├── null (char 1:3)
+└── usr/
+    ├── bin/
+    │   ├── ls (hardlink to usr/bin/sh)
+    │   └── sh (7 bytes)
+    └── lib/
+        ├── libA.so (10 bytes)
+        ├── readme (5 bytes)
+        └── x86/
+            ├── libB.so (20 bytes)
+            └── libC.SO (30 bytes)
+
+6 directories, 10 files
+"
+    );
+    // Through the symlink, counting what's below the top only
+    assert_eq!(
+        r.render_tree("lib").unwrap(),
+        "\
+lib/
+├── libA.so (10 bytes)
+├── readme (5 bytes)
+└── x86/
+    ├── libB.so (20 bytes)
+    └── libC.SO (30 bytes)
+
+1 directory, 4 files
+"
+    );
+    assert_eq!(
+        r.render_tree("usr/bin/sh").unwrap(),
+        "sh (7 bytes)\n\n0 directories, 0 files\n"
+    );
+    assert!(r.render_tree("missing").is_err());
+}
+
+#[test]
+fn verify_crc_catches_corruption_the_toc_has_no_digest_for() {
+    let big = pattern(3 * CHUNK);
+    let mut blob = Vec::new();
+    blob_with(
+        |w| {
+            w.with_chunk_size(CHUNK)
+                .unwrap()
+                .with_compression_level(CompressionLevel::Store)
+                .unwrap()
+        },
+        |w| {
+            w.append_tar(&mut &tar_of(&[("big", &big), ("small", b"small")])[..])
+                .unwrap()
+        },
+        &mut blob,
+    );
+    let blob = with_toc(&blob, |toc| {
+        for e in toc["entries"].as_array_mut().unwrap() {
+            let e = e.as_object_mut().unwrap();
+            e.remove("digest");
+            e.remove("chunkDigest");
+        }
+    });
+    // Stored, so one byte of the second chunk can be flipped in place and
+    // still decompress
+    let mut corrupt = blob.clone();
+    let at = blob
+        .windows(64)
+        .position(|w| w == &big[CHUNK + 100..][..64])
+        .unwrap();
+    corrupt[at] ^= 0xff;
+
+    let read = |blob: &[u8], opts: ReaderOptions| {
+        let r = opts.open_from_bytes(blob.to_vec()).unwrap();
+        assert_eq!(r.read_file("small").unwrap(), b"small");
+        r.read_file("big")
+    };
+    assert!(read(&blob, ReaderOptions::new().verify_crc(true)).unwrap() == big);
+    // Unnoticed without the option, even when checking digests
+    for opts in [
+        ReaderOptions::new(),
+        ReaderOptions::new().verify_digests(true),
+    ] {
+        let data = read(&corrupt, opts).unwrap();
+        assert_eq!(data[CHUNK + 100], big[CHUNK + 100] ^ 0xff);
+    }
+    let err = read(&corrupt, ReaderOptions::new().verify_crc(true)).unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Corrupt), "{err:#}");
+    assert!(format!("{err:#}").contains("CRC"), "{err:#}");
+}
+
+// A blob with a chunked file, small files packed into one member, an
+// empty file and a symlink
+fn layout_blob() -> (Vec<u8>, Vec<u8>) {
+    let big = pattern(2 * CHUNK + 1808);
+    let mut b = tar::Builder::new(Vec::new());
+    for (path, data) in [
+        ("big", &big[..]),
+        ("s1", b"one"),
+        ("empty", b""),
+        ("s2", b"two"),
+    ] {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, data).unwrap();
+    }
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "link", "s2").unwrap();
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_chunk_size(CHUNK).unwrap().with_min_chunk_size(4000),
+        |w| w.append_tar(&mut &b.into_inner().unwrap()[..]).unwrap(),
+        &mut blob,
+    );
+    (blob, big)
+}
+
+#[test]
+fn members_tile_the_data_region_and_hold_their_chunks() {
+    let (blob, _) = layout_blob();
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let (toc_offset, footer_len) = footer_of(&blob);
+
+    // Back to back from 0 up to the TOC, decompressing to the tar stream
+    let members = r.members().unwrap();
+    let mut at = 0;
+    for m in &members {
+        assert_eq!(m.offset, at);
+        assert_eq!(m.uncompressed_size, member(&blob, m.offset).len() as u64);
+        at += m.compressed_size;
+    }
+    assert_eq!(at, toc_offset as u64);
+    let toc_tar = member(&blob, toc_offset as u64).len();
+    assert_eq!(
+        members.iter().map(|m| m.uncompressed_size).sum::<u64>() as usize,
+        decompress(&blob).len() - toc_tar
+    );
+    let bare: Vec<_> = r.iter_members().map(Result::unwrap).collect();
+    assert_eq!(bare.len(), members.len());
+    assert!(bare.iter().all(|m| m.entries.is_empty()));
+
+    // Each chunk listed in the member it starts, none for the empty file
+    let listed: Vec<_> = members
+        .iter()
+        .flat_map(|m| m.entries.iter().map(move |e| (m.offset, e)))
+        .map(|(at, e)| (e.name.as_str(), e.chunk_offset, e.chunk_size, at))
+        .collect();
+    let s1 = r.lookup("s1").unwrap();
+    let big = |i: u64| {
+        let ent = r.chunk_entry_for_offset("big", i * CHUNK as u64).unwrap();
+        ("big", ent.chunk_offset(), ent.chunk_size(), ent.offset())
+    };
+    assert_eq!(
+        listed,
+        [
+            big(0),
+            big(1),
+            big(2),
+            ("s1", 0, 3, s1.offset()),
+            ("s2", 0, 3, r.lookup("s2").unwrap().offset()),
+        ]
+    );
+    assert_eq!(listed[2].2, 1808);
+    // Packed after the last chunk of big
+    assert_eq!(s1.offset(), listed[2].3);
+    assert!(s1.inner_offset() > 1808);
+
+    // Past the data region: the TOC, then the empty member of the footer
+    let rest: Vec<_> = MemberIter::new(&blob, toc_offset as u64, blob.len() as u64)
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(rest.len(), 2);
+    assert_eq!(rest[0].uncompressed_size, toc_tar as u64);
+    assert_eq!(rest[1].offset, (blob.len() - footer_len) as u64);
+    assert_eq!(
+        (rest[1].compressed_size, rest[1].uncompressed_size),
+        (footer_len as u64, 0)
+    );
+
+    // Nothing past a member that doesn't decompress
+    let mut corrupt = blob.clone();
+    let third = members[2].offset as usize;
+    corrupt[third..third + 10].fill(0);
+    let mut it = MemberIter::new(&corrupt, 0, toc_offset as u64);
+    assert!(it.next().unwrap().is_ok());
+    assert!(it.next().unwrap().is_ok());
+    let err = it.next().unwrap().unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Corrupt), "{err:#}");
+    assert!(it.next().is_none());
+    assert!(open_from_bytes(corrupt).unwrap().members().is_err());
+}
+
+#[test]
+fn chunks_give_the_blob_range_of_each_part_of_a_file() {
+    let (blob, big) = layout_blob();
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let members = r.members().unwrap();
+    let end_of = |offset: u64| {
+        let m = members.iter().find(|m| m.offset == offset).unwrap();
+        m.offset + m.compressed_size
+    };
+
+    let chunks = r.chunks("big").unwrap();
+    let layout: Vec<_> = chunks.iter().map(|c| (c.offset, c.size)).collect();
+    assert_eq!(
+        layout,
+        [
+            (0, CHUNK as u64),
+            (CHUNK as u64, CHUNK as u64),
+            (2 * CHUNK as u64, 1808)
+        ]
+    );
+    for c in &chunks {
+        let ent = r.chunk_entry_for_offset("big", c.offset).unwrap();
+        assert_eq!(c.blob_start, ent.offset());
+        assert_eq!(c.blob_end, end_of(c.blob_start));
+        // Enough to get the chunk
+        let mut content = Vec::new();
+        GzDecoder::new(&blob[c.blob_start as usize..c.blob_end as usize])
+            .read_to_end(&mut content)
+            .unwrap();
+        let at = ent.inner_offset() as usize;
+        assert!(content[at..].starts_with(&big[c.offset as usize..][..c.size as usize]));
+    }
+
+    // A packed file's range is the member it shares
+    let s1 = r.chunks("s1").unwrap();
+    assert_eq!(s1.len(), 1);
+    assert_eq!((s1[0].offset, s1[0].size), (0, 3));
+    assert_eq!(s1[0].blob_start, chunks[2].blob_start);
+    assert_eq!(s1[0].blob_end, chunks[2].blob_end);
+    // Through the link
+    let s2 = r.chunks("s2").unwrap();
+    assert_eq!(r.chunks("link").unwrap(), s2);
+    assert_eq!(s2[0].blob_end, end_of(s2[0].blob_start));
+
+    assert_eq!(r.chunks("empty").unwrap(), []);
+    assert!(r.chunks("").is_err());
+    assert!(r.chunks("missing").is_err());
+}
+
+#[test]
+fn drop_file_cache_works_on_file_and_memory_backed_readers() {
+    let dir = TempDir::new();
+    let path = dir.path().join("blob");
+    // The same blob on disk and in memory
+    let readers = |blob: Vec<u8>| {
+        std::fs::write(&path, &blob).unwrap();
+        [
+            ReaderOptions::new()
+                .open(File::open(&path).unwrap())
+                .unwrap(),
+            open_from_bytes(blob).unwrap(),
+        ]
+    };
+
+    let big = pattern(3 * CHUNK);
+    for r in readers(blob_of(&tar_of(&[("big", &big), ("empty", b"")]), CHUNK)) {
+        r.drop_file_cache("big").unwrap();
+        r.drop_file_cache("empty").unwrap();
+        // Only a hint, reading afterwards still works
+        assert_eq!(r.read_file("big").unwrap(), big);
+        let err = r.drop_file_cache("missing").unwrap_err();
+        assert_eq!(ErrorKind::of(&err), Some(ErrorKind::NotFound));
+    }
+
+    // Directories, links and devices: nothing to drop, or their target's
+    for r in readers(tree_blob()) {
+        for name in ["usr", "usr/bin/ls", "lib", "null"] {
+            r.drop_file_cache(name).unwrap();
+        }
+    }
+}
@@ -0,0 +1,31 @@
+// Alone in its test binary: setting the environment while other threads
+// read it (std::env::temp_dir does) isn't safe.
+
+use std::env;
+
+use stargz_rs::source_date_epoch;
+
+#[test]
+fn source_date_epoch_reads_the_environment() {
+    env::remove_var("SOURCE_DATE_EPOCH");
+    assert_eq!(source_date_epoch().unwrap(), None);
+
+    // Set but empty counts as unset, as the spec says
+    env::set_var("SOURCE_DATE_EPOCH", "");
+    assert_eq!(source_date_epoch().unwrap(), None);
+
+    for (value, epoch) in [("1700000000", 1_700_000_000), (" 0\n", 0)] {
+        env::set_var("SOURCE_DATE_EPOCH", value);
+        assert_eq!(source_date_epoch().unwrap(), Some(epoch), "{value:?}");
+    }
+
+    for value in ["yesterday", "-1", "1.5", "0x10", "99999999999999999999"] {
+        env::set_var("SOURCE_DATE_EPOCH", value);
+        let err = source_date_epoch().unwrap_err();
+        assert!(
+            err.to_string().contains("invalid SOURCE_DATE_EPOCH"),
+            "{err}"
+        );
+    }
+    env::remove_var("SOURCE_DATE_EPOCH");
+}
@@ -0,0 +1,260 @@
+mod common;
+
+use std::{
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+};
+
+use common::{blob_of, blob_with, header, pattern, tar_of, TempDir};
+use flate2::read::MultiGzDecoder;
+use stargz_rs::open_from_bytes;
+
+const SIZE: u64 = 1_000_000;
+
+// The content of a sparse file of SIZE bytes holding `data` in `regions`
+fn logical(regions: &[(u64, u64)], data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; SIZE as usize];
+    let mut at = 0;
+    for &(offset, len) in regions {
+        let (offset, len) = (offset as usize, len as usize);
+        out[offset..offset + len].copy_from_slice(&data[at..at + len]);
+        at += len;
+    }
+    out
+}
+
+fn padded(mut data: Vec<u8>) -> Vec<u8> {
+    data.resize(data.len().next_multiple_of(512), 0);
+    data
+}
+
+// A tar with the GNU sparse file `f`, its map ending with the empty region
+// GNU tar puts at the end of the file
+fn gnu_sparse_tar(regions: &[(u64, u64)], data: &[u8]) -> Vec<u8> {
+    let mut map = regions.to_vec();
+    map.push((SIZE, 0));
+    let mut h = header(tar::EntryType::GNUSparse, data.len() as u64);
+    h.set_path("f").unwrap();
+    let gnu = h.as_gnu_mut().unwrap();
+    gnu.set_real_size(SIZE);
+    for (block, &(offset, len)) in gnu.sparse.iter_mut().zip(&map) {
+        block.set_offset(offset);
+        block.set_length(len);
+    }
+    gnu.set_is_extended(map.len() > 4);
+    h.set_cksum();
+    let mut ext = tar::GnuExtSparseHeader::new();
+    for (block, &(offset, len)) in ext.sparse_mut().iter_mut().zip(map.iter().skip(4)) {
+        block.set_offset(offset);
+        block.set_length(len);
+    }
+
+    let mut tar = h.as_bytes().to_vec();
+    if map.len() > 4 {
+        tar.extend_from_slice(ext.as_bytes());
+    }
+    tar.extend(padded(data.to_vec()));
+    tar.extend([0; 1024]);
+    tar
+}
+
+// A tar with a regular file named `name` holding `data`, after a PAX header
+// with `records`
+fn pax_tar(name: &str, records: &[(&str, String)], data: &[u8]) -> Vec<u8> {
+    let mut pax = Vec::new();
+    for (key, value) in records {
+        let rest = format!(" {key}={value}\n");
+        let mut len = rest.len() + 1;
+        while format!("{len}").len() + rest.len() != len {
+            len = format!("{len}").len() + rest.len();
+        }
+        pax.extend_from_slice(format!("{len}{rest}").as_bytes());
+    }
+    let mut x = header(tar::EntryType::XHeader, pax.len() as u64);
+    x.set_path("././@PaxHeader").unwrap();
+    x.set_cksum();
+    let mut h = header(tar::EntryType::Regular, data.len() as u64);
+    h.set_path(name).unwrap();
+    h.set_cksum();
+
+    let mut tar = x.as_bytes().to_vec();
+    tar.extend(padded(pax));
+    tar.extend_from_slice(h.as_bytes());
+    tar.extend(padded(data.to_vec()));
+    tar.extend([0; 1024]);
+    tar
+}
+
+// Check the file `f` of `blob` reads back as SIZE bytes with `data` in
+// `regions`, and is stored as a GNU sparse file without its holes
+fn check(blob: Vec<u8>, regions: &[(u64, u64)], data: &[u8]) {
+    let want = logical(regions, data);
+    let r = open_from_bytes(blob.clone()).unwrap();
+
+    let ent = r.lookup("f").unwrap();
+    assert_eq!(ent.size(), SIZE);
+    assert!(ent.is_sparse());
+    for &(offset, len) in regions {
+        assert!(
+            ent.sparse_map()
+                .iter()
+                .any(|&(o, l)| o <= offset && offset + len <= o + l),
+            "{offset}+{len} not in {:?}",
+            ent.sparse_map()
+        );
+    }
+    let stored = ent.physical_size();
+    assert!(stored < data.len() as u64 + 4096, "{stored} bytes stored");
+
+    assert!(r.read_file("f").unwrap() == want);
+    let mut copied = Vec::new();
+    r.copy_file_to("f", &mut copied).unwrap();
+    assert!(copied == want);
+    let mut f = r.open_file("f").unwrap();
+    let mut read = Vec::new();
+    f.read_to_end(&mut read).unwrap();
+    assert!(read == want);
+    // Across the end of a region and into the hole after it
+    let (offset, len) = regions[0];
+    let at = offset + len - 10;
+    let mut buf = [1; 100];
+    assert_eq!(f.read_at(&mut buf, at).unwrap(), 100);
+    assert_eq!(buf[..], want[at as usize..at as usize + 100]);
+    f.seek(SeekFrom::Start(at)).unwrap();
+    let mut buf = [1; 100];
+    f.read_exact(&mut buf).unwrap();
+    assert_eq!(buf[..], want[at as usize..at as usize + 100]);
+    assert!(r.chunk_entry_for_offset("f", SIZE - 1).is_none());
+
+    let report = r.verify();
+    assert!(report.is_ok(), "{report:?}");
+    assert_eq!(report.verified, 1);
+
+    // The tar crate reads the tar stream back the same
+    let mut tar = tar::Archive::new(MultiGzDecoder::new(&blob[..]));
+    let mut f = tar
+        .entries()
+        .unwrap()
+        .map(Result::unwrap)
+        .find(|e| e.path_bytes().as_ref() == b"f")
+        .unwrap();
+    assert_eq!(f.header().entry_type(), tar::EntryType::GNUSparse);
+    assert_eq!(f.header().entry_size().unwrap(), stored);
+    let mut content = Vec::new();
+    f.read_to_end(&mut content).unwrap();
+    assert!(content == want);
+
+    let dest = TempDir::new();
+    r.unpack(dest.path()).unwrap();
+    let path = dest.path().join("f");
+    assert!(std::fs::read(&path).unwrap() == want);
+    let meta = std::fs::metadata(&path).unwrap();
+    assert!(meta.blocks() * 512 < SIZE / 2, "{} blocks", meta.blocks());
+}
+
+#[test]
+fn gnu_sparse_files_keep_their_holes() {
+    // More regions than the header has room for, the rest in an extension
+    // header, and lengths in whole blocks but the last as the tar crate wants
+    let regions = [
+        (0, 4096),
+        (100_000, 10_240),
+        (200_000, 512),
+        (300_000, 20_480),
+        (500_000, 1024),
+        (800_000, 9000),
+    ];
+    let data = pattern(regions.iter().map(|r| r.1 as usize).sum());
+    let blob = blob_of(&gnu_sparse_tar(&regions, &data), 8192);
+
+    let mut out = Vec::new();
+    let mut w = stargz_rs::Writer::new(&mut out);
+    w.append_tar(&mut &gnu_sparse_tar(&regions, &data)[..])
+        .unwrap();
+    assert_eq!(w.stats().sparse_files, 1);
+    assert_eq!(w.stats().sparse_logical_bytes, SIZE);
+    assert_eq!(w.stats().sparse_physical_bytes, data.len() as u64);
+    w.close().unwrap();
+
+    check(blob, &regions, &data);
+
+    // Spooled and sorted, the next entry is found after the extension header
+    let mut input = gnu_sparse_tar(&regions, &data);
+    input.truncate(input.len() - 1024);
+    input.extend(tar_of(&[("a", b"after")]));
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_reproducible_output(None),
+        |w| w.append_tar(&mut &input[..]).unwrap(),
+        &mut blob,
+    );
+    let r = open_from_bytes(blob).unwrap();
+    assert_eq!(r.read_file("a").unwrap(), b"after");
+    assert!(r.read_file("f").unwrap() == logical(&regions, &data));
+}
+
+#[test]
+fn pax_sparse_files_keep_their_holes() {
+    // Lengths the GNU format can't store as they are
+    let regions = [(3, 100), (5000, 7), (70_000, 9000), (600_000, 1)];
+    let data = pattern(regions.iter().map(|r| r.1 as usize).sum());
+    let map: Vec<String> = regions
+        .iter()
+        .flat_map(|&(o, l)| [o.to_string(), l.to_string()])
+        .collect();
+
+    // 0.0: a record per offset and length
+    let mut records = vec![
+        ("GNU.sparse.size", SIZE.to_string()),
+        ("GNU.sparse.numblocks", regions.len().to_string()),
+    ];
+    for &(offset, len) in &regions {
+        records.push(("GNU.sparse.offset", offset.to_string()));
+        records.push(("GNU.sparse.numbytes", len.to_string()));
+    }
+    check(
+        blob_of(&pax_tar("f", &records, &data), 4096),
+        &regions,
+        &data,
+    );
+
+    // 0.1: the map in one record, the real name in another
+    let records = [
+        ("GNU.sparse.major", "0".to_string()),
+        ("GNU.sparse.minor", "1".to_string()),
+        ("GNU.sparse.name", "f".to_string()),
+        ("GNU.sparse.size", SIZE.to_string()),
+        ("GNU.sparse.numblocks", regions.len().to_string()),
+        ("GNU.sparse.map", map.join(",")),
+    ];
+    let tar = pax_tar("GNUSparseFile.0/f", &records, &data);
+    check(blob_of(&tar, 4096), &regions, &data);
+
+    // 1.0: the map at the start of the content
+    let records = [
+        ("GNU.sparse.major", "1".to_string()),
+        ("GNU.sparse.minor", "0".to_string()),
+        ("GNU.sparse.name", "f".to_string()),
+        ("GNU.sparse.realsize", SIZE.to_string()),
+    ];
+    let mut content = padded(format!("{}\n{}\n", regions.len(), map.join("\n")).into_bytes());
+    content.extend_from_slice(&data);
+    let tar = pax_tar("./GNUSparseFile.1234/f", &records, &content);
+    check(blob_of(&tar, 4096), &regions, &data);
+}
+
+#[test]
+fn sparse_map_must_match_the_data() {
+    let records = [
+        ("GNU.sparse.major", "0".to_string()),
+        ("GNU.sparse.minor", "1".to_string()),
+        ("GNU.sparse.size", SIZE.to_string()),
+        ("GNU.sparse.map", "0,10,5,10".to_string()),
+    ];
+    let tar = pax_tar("f", &records, &pattern(20));
+    let mut out = Vec::new();
+    let err = stargz_rs::Writer::new(&mut out)
+        .append_tar(&mut &tar[..])
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("sparse"), "{err:#}");
+}
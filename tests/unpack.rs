@@ -0,0 +1,573 @@
+mod common;
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Write},
+    os::unix::fs::{FileExt, FileTypeExt, MetadataExt, PermissionsExt},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use common::{blob_of, header, tar_of, TempDir};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use stargz_rs::{
+    compare_with_dir, open_from_bytes, Difference, Dir, Drift, EntryFilter, Ownership,
+    ReaderOptions, UnpackOptions, UnpackWarning, Vfs, Warning,
+};
+
+// A layer with the file `f`, the character device `null` and the fifo `pipe`
+fn devices_tar() -> Vec<u8> {
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Regular, 2);
+    b.append_data(&mut h, "f", &b"hi"[..]).unwrap();
+    let mut h = header(tar::EntryType::Char, 0);
+    h.set_device_major(1).unwrap();
+    h.set_device_minor(3).unwrap();
+    b.append_data(&mut h, "null", &[][..]).unwrap();
+    let mut h = header(tar::EntryType::Fifo, 0);
+    b.append_data(&mut h, "pipe", &[][..]).unwrap();
+    b.into_inner().unwrap()
+}
+
+fn warning(name: &str, warning: Warning) -> UnpackWarning {
+    UnpackWarning {
+        name: name.to_string(),
+        warning,
+    }
+}
+
+// A layer with the setuid file `f` of user root (uid 1234) and group
+// nosuchgroup (gid 2345), and the sticky directory `d` of uid 1234
+fn owners_tar() -> Vec<u8> {
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Directory, 0);
+    h.set_mode(0o1777);
+    h.set_uid(1234);
+    b.append_data(&mut h, "d", &[][..]).unwrap();
+    let mut h = header(tar::EntryType::Regular, 2);
+    h.set_mode(0o4775);
+    h.set_uid(1234);
+    h.set_gid(2345);
+    h.set_username("root").unwrap();
+    h.set_groupname("nosuchgroup").unwrap();
+    b.append_data(&mut h, "f", &b"hi"[..]).unwrap();
+    b.into_inner().unwrap()
+}
+
+#[test]
+fn ownership_and_modes_follow_the_options() {
+    let r = open_from_bytes(blob_of(&owners_tar(), 4096)).unwrap();
+    let unpack = |opts: UnpackOptions| {
+        let dest = TempDir::new();
+        opts.unpack(&r, dest.path()).unwrap();
+        dest
+    };
+    let meta = |dest: &TempDir, name| fs::symlink_metadata(dest.path().join(name)).unwrap();
+    let owner = |dest: &TempDir, name| {
+        let meta = meta(dest, name);
+        (meta.uid(), meta.gid())
+    };
+    let mode = |dest: &TempDir, name| meta(dest, name).mode() & 0o7777;
+    // Whoever runs the tests
+    let me = owner(&TempDir::new(), "");
+
+    let dest = unpack(UnpackOptions::new().owner(Ownership::Ignore));
+    assert_eq!(owner(&dest, "f"), me);
+    assert_eq!(owner(&dest, "d"), me);
+    assert_eq!(mode(&dest, "f"), 0o4775);
+    assert_eq!(mode(&dest, "d"), 0o1777);
+
+    let dest = unpack(
+        UnpackOptions::new()
+            .owner(Ownership::Ignore)
+            .umask(0o022)
+            .special_bits(false),
+    );
+    assert_eq!(mode(&dest, "f"), 0o755);
+    assert_eq!(mode(&dest, "d"), 0o755);
+
+    if me.0 != 0 {
+        // Giving files away fails, rather than being skipped silently
+        let dest = TempDir::new();
+        let err = UnpackOptions::new()
+            .owner(Ownership::Numeric)
+            .unpack(&r, dest.path())
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("owner"), "{err:#}");
+        return;
+    }
+
+    // Root restores ownership unless told not to
+    let dest = unpack(UnpackOptions::new());
+    assert_eq!(owner(&dest, "f"), (1234, 2345));
+    assert_eq!(owner(&dest, "d"), (1234, 0));
+    // Restored before the mode, which giving the file away would clear
+    assert_eq!(mode(&dest, "f"), 0o4775);
+
+    let dest = unpack(UnpackOptions::new().owner(Ownership::Map {
+        uids: HashMap::from([(1234, 5000)]),
+        gids: HashMap::from([(0, 6000)]),
+    }));
+    assert_eq!(owner(&dest, "f"), (5000, 2345));
+    assert_eq!(owner(&dest, "d"), (5000, 6000));
+
+    // By name where the name exists here, by id otherwise
+    let dest = unpack(UnpackOptions::new().owner(Ownership::Names));
+    assert_eq!(owner(&dest, "f"), (0, 2345));
+    assert_eq!(owner(&dest, "d"), (1234, 0));
+}
+
+#[test]
+fn hardlinks_are_links_or_copies() {
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Regular, 2);
+    b.append_data(&mut h, "f", &b"hi"[..]).unwrap();
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "g", "f").unwrap();
+    let r = open_from_bytes(blob_of(&b.into_inner().unwrap(), 4096)).unwrap();
+    let inode = |dest: &TempDir, name| fs::metadata(dest.path().join(name)).unwrap().ino();
+
+    let dest = TempDir::new();
+    assert!(r.unpack(dest.path()).unwrap().is_clean());
+    assert_eq!(inode(&dest, "g"), inode(&dest, "f"));
+
+    let dest = TempDir::new();
+    let report = UnpackOptions::new()
+        .hardlinks(false)
+        .unpack(&r, dest.path())
+        .unwrap();
+    assert!(report.is_clean());
+    assert_ne!(inode(&dest, "g"), inode(&dest, "f"));
+    assert_eq!(fs::read(dest.path().join("g")).unwrap(), b"hi");
+}
+
+#[test]
+fn verify_checks_what_was_meant_to_be_applied() {
+    let mut input = owners_tar();
+    input.truncate(input.len() - 1024);
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Char, 0);
+    h.set_device_major(1).unwrap();
+    h.set_device_minor(3).unwrap();
+    b.append_data(&mut h, "null", &[][..]).unwrap();
+    let mut h = header(tar::EntryType::Fifo, 0);
+    b.append_data(&mut h, "pipe", &[][..]).unwrap();
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "d/l", "../f").unwrap();
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "d/g", "f").unwrap();
+    input.extend(b.into_inner().unwrap());
+    let r = open_from_bytes(blob_of(&input, 4096)).unwrap();
+
+    let dest = TempDir::new();
+    let report = UnpackOptions::new()
+        .verify(true)
+        .umask(0o027)
+        .special_bits(false)
+        .unpack(&r, dest.path())
+        .unwrap();
+    // The devices left out aren't missing
+    assert!(report.drifts.is_empty(), "{:?}", report.drifts);
+    assert_eq!(report.warnings.len(), 2);
+
+    let dest = TempDir::new();
+    let report = UnpackOptions::new()
+        .verify(true)
+        .owner(Ownership::Ignore)
+        .unpack(&r, dest.path())
+        .unwrap();
+    assert!(report.drifts.is_empty(), "{:?}", report.drifts);
+
+    // A file that doesn't hash to its TOC digest
+    let blob = with_wrong_digest(&blob_of(&tar_of(&[("f", b"hi")]), 4096));
+    let r = open_from_bytes(blob).unwrap();
+    let dest = TempDir::new();
+    let report = UnpackOptions::new()
+        .verify(true)
+        .unpack(&r, dest.path())
+        .unwrap();
+    assert_eq!(
+        report.drifts,
+        [Drift {
+            path: "f".to_string(),
+            difference: Difference::Content
+        }]
+    );
+    assert!(!report.is_clean());
+}
+
+// `blob` with the TOC digest of its first file changed
+fn with_wrong_digest(blob: &[u8]) -> Vec<u8> {
+    let find = |data: &[u8], what: &[u8]| data.windows(what.len()).position(|w| w == what);
+    let tail = &blob[blob.len() - 51..];
+    let at = find(tail, b"STARGZ").unwrap();
+    let toc_offset = std::str::from_utf8(&tail[at - 16..at]).unwrap();
+    let toc_offset = usize::from_str_radix(toc_offset, 16).unwrap();
+    let footer_len = if find(tail, b"SG\x16\x00").is_some() {
+        51
+    } else {
+        47
+    };
+
+    let mut toc = Vec::new();
+    GzDecoder::new(&blob[toc_offset..])
+        .read_to_end(&mut toc)
+        .unwrap();
+    let at = find(&toc, b"\"digest\":\"sha256:").unwrap() + 18;
+    toc[at] = if toc[at] == b'0' { b'1' } else { b'0' };
+
+    let mut out = blob[..toc_offset].to_vec();
+    let mut gz = GzEncoder::new(&mut out, Compression::default());
+    gz.write_all(&toc).unwrap();
+    gz.finish().unwrap();
+    out.extend_from_slice(&blob[blob.len() - footer_len..]);
+    out
+}
+
+#[test]
+fn skipped_devices_are_reported() {
+    let r = open_from_bytes(blob_of(&devices_tar(), 4096)).unwrap();
+
+    let dest = TempDir::new();
+    let report = r.unpack(dest.path()).unwrap();
+    assert_eq!(
+        report.warnings,
+        [
+            warning("null", Warning::DeviceSkipped),
+            warning("pipe", Warning::DeviceSkipped)
+        ]
+    );
+    assert_eq!(std::fs::read(dest.path().join("f")).unwrap(), b"hi");
+    assert!(!dest.path().join("null").exists());
+    assert!(!dest.path().join("pipe").exists());
+
+    // A fifo needs no privileges, a device node does: without them it's
+    // left out rather than failing the rest
+    let dest = TempDir::new();
+    let report = UnpackOptions::new()
+        .devices(true)
+        .unpack(&r, dest.path())
+        .unwrap();
+    let meta = |name| std::fs::symlink_metadata(dest.path().join(name));
+    assert!(meta("pipe").unwrap().file_type().is_fifo());
+    match &report.warnings[..] {
+        [] => assert!(meta("null").unwrap().file_type().is_char_device()),
+        warnings => {
+            assert_eq!(warnings, [warning("null", Warning::DeviceNotPermitted)]);
+            assert!(meta("null").is_err());
+        }
+    }
+    assert_eq!(std::fs::read(dest.path().join("f")).unwrap(), b"hi");
+}
+
+#[test]
+fn extraction_resumes_from_the_journal() {
+    let input = tar_of(&[("a", b"aaaa"), ("b", b"bbbb"), ("c", b"cccc")]);
+    let r = open_from_bytes(blob_of(&input, 4096)).unwrap();
+    let dest = TempDir::new();
+    let journal = dest.path().join("journal");
+    let opts = UnpackOptions::new().journal(&journal);
+
+    // A directory in the way fails the extraction after a and b
+    fs::create_dir_all(dest.path().join("c/x")).unwrap();
+    opts.unpack(&r, dest.path()).unwrap_err();
+    assert!(journal.exists());
+
+    // Left as they were, a is skipped even with other content: only b,
+    // touched since, is extracted again
+    let set = |name, data: &[u8], mtime| {
+        let path = dest.path().join(name);
+        let file = File::options().write(true).open(path).unwrap();
+        file.write_all_at(data, 0).unwrap();
+        file.set_modified(mtime).unwrap();
+    };
+    let mtime = fs::metadata(dest.path().join("a"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    set("a", b"AAAA", mtime);
+    set("b", b"BBBB", SystemTime::now());
+    fs::remove_dir_all(dest.path().join("c")).unwrap();
+
+    let report = opts.unpack(&r, dest.path()).unwrap();
+    assert_eq!(report.resumed, 1);
+    let read = |name| fs::read(dest.path().join(name)).unwrap();
+    assert_eq!(read("a"), b"AAAA");
+    assert_eq!(read("b"), b"bbbb");
+    assert_eq!(read("c"), b"cccc");
+    assert!(!journal.exists());
+}
+
+#[test]
+fn filtered_extraction_reads_only_what_is_selected() {
+    let input = tar_of(&[
+        ("etc/passwd", b"root"),
+        ("etc/ssl/openssl.cnf", b"cnf"),
+        ("etc/ssl/certs/ca.pem", b"pem"),
+        ("etc/motd", &[b'x'; 5000]),
+        ("usr/bin/sh", b"sh"),
+    ]);
+    let read = Arc::new(Mutex::new(Vec::new()));
+    let log = read.clone();
+    let r = ReaderOptions::new()
+        .on_read(move |ev| log.lock().unwrap().push(ev.name.clone()))
+        .open_from_bytes(blob_of(&input, 4096))
+        .unwrap();
+
+    let dest = TempDir::new();
+    let mut filter = EntryFilter::new()
+        .include("etc/**")
+        .unwrap()
+        .exclude("etc/ssl/certs")
+        .unwrap()
+        .filter(|_, h| h.size().unwrap() < 1000);
+    let report = r.unpack_filtered(dest.path(), &mut filter).unwrap();
+    assert!(report.is_clean());
+
+    let exists = |name| dest.path().join(name).exists();
+    assert_eq!(fs::read(dest.path().join("etc/passwd")).unwrap(), b"root");
+    assert_eq!(
+        fs::read(dest.path().join("etc/ssl/openssl.cnf")).unwrap(),
+        b"cnf"
+    );
+    for name in ["etc/ssl/certs", "etc/motd", "usr"] {
+        assert!(!exists(name), "{name}");
+    }
+    let mut read = read.lock().unwrap().clone();
+    read.sort();
+    assert_eq!(read, ["etc/passwd", "etc/ssl/openssl.cnf"]);
+}
+
+#[test]
+fn compare_with_dir_reports_each_kind_of_drift() {
+    let mut b = tar::Builder::new(Vec::new());
+    for (path, data) in [
+        ("content", &b"same size"[..]),
+        ("mode", b"mode"),
+        ("deleted", b"deleted"),
+        ("size", b"size"),
+        ("mtime", b"mtime"),
+        ("kind", b"kind"),
+        ("same", b"same"),
+    ] {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, data).unwrap();
+    }
+    let mut h = header(tar::EntryType::Directory, 0);
+    h.set_mode(0o755);
+    b.append_data(&mut h, "d", &[][..]).unwrap();
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "d/link", "../same").unwrap();
+    let r = open_from_bytes(blob_of(&b.into_inner().unwrap(), 4096)).unwrap();
+    let dest = TempDir::new();
+    UnpackOptions::new()
+        .owner(Ownership::Ignore)
+        .unpack(&r, dest.path())
+        .unwrap();
+    // Whoever runs the tests may not own what was unpacked
+    let drifts = || -> Vec<Drift> {
+        compare_with_dir(&r, dest.path())
+            .unwrap()
+            .into_iter()
+            .filter(|d| !matches!(d.difference, Difference::Owner { .. }))
+            .collect()
+    };
+    assert_eq!(drifts(), []);
+
+    let path = |p: &str| dest.path().join(p);
+    let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1600000000);
+    let rewrite = |p: &str, data: &[u8]| {
+        let f = File::create(path(p)).unwrap();
+        f.write_all_at(data, 0).unwrap();
+        f.set_modified(mtime).unwrap();
+    };
+    rewrite("content", b"same SIZE");
+    rewrite("size", b"bigger size");
+    fs::set_permissions(path("mode"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::remove_file(path("deleted")).unwrap();
+    fs::write(path("d/added"), b"added").unwrap();
+    File::options()
+        .append(true)
+        .open(path("mtime"))
+        .unwrap()
+        .set_modified(mtime + std::time::Duration::from_secs(1))
+        .unwrap();
+    fs::remove_file(path("kind")).unwrap();
+    fs::create_dir(path("kind")).unwrap();
+    fs::remove_file(path("d/link")).unwrap();
+    std::os::unix::fs::symlink("../content", path("d/link")).unwrap();
+
+    let drift = |path: &str, difference| Drift {
+        path: path.to_string(),
+        difference,
+    };
+    // The new link is as old as the test
+    let mut drifts = drifts();
+    let relinked = drifts
+        .iter()
+        .position(|d| d.path == "d/link" && matches!(d.difference, Difference::ModTime { .. }))
+        .unwrap();
+    drifts.remove(relinked);
+    assert_eq!(
+        drifts,
+        [
+            drift("content", Difference::Content),
+            drift("d/added", Difference::MissingInLayer),
+            drift(
+                "d/link",
+                Difference::LinkTarget {
+                    layer: "../same".to_string(),
+                    disk: "../content".to_string(),
+                }
+            ),
+            drift("deleted", Difference::MissingOnDisk),
+            drift(
+                "kind",
+                Difference::Type {
+                    layer: "reg".to_string(),
+                    disk: "dir".to_string(),
+                }
+            ),
+            drift(
+                "mode",
+                Difference::Mode {
+                    layer: 0o644,
+                    disk: 0o600,
+                }
+            ),
+            drift(
+                "mtime",
+                Difference::ModTime {
+                    layer: 1600000000,
+                    disk: 1600000001,
+                }
+            ),
+            drift("size", Difference::Size { layer: 4, disk: 11 }),
+        ]
+    );
+}
+
+#[test]
+fn matching_extraction_takes_subtrees_parents_and_link_targets() {
+    let mut b = tar::Builder::new(Vec::new());
+    for (path, data) in [
+        ("etc/hosts", &b"hosts"[..]),
+        ("etc/passwd", b"passwd"),
+        ("usr/bin/sh", b"sh"),
+        ("usr/lib/readme", b"readme"),
+        ("usr/lib/x/a.so", b"a"),
+        ("usr/lib/x/sub/b.so", b"b"),
+    ] {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, data).unwrap();
+    }
+    for (path, target) in [
+        ("usr/lib/x/a2", "usr/lib/x/a.so"),
+        ("usr/lib/x/sh", "usr/bin/sh"),
+    ] {
+        let mut h = header(tar::EntryType::Link, 0);
+        b.append_link(&mut h, path, target).unwrap();
+    }
+    let r = open_from_bytes(blob_of(&b.into_inner().unwrap(), 4096)).unwrap();
+
+    let dest = TempDir::new();
+    let report = r
+        .unpack_matching(dest.path(), ["usr/lib/x", "/etc/h*"])
+        .unwrap();
+    assert!(report.is_clean(), "{report:?}");
+    let path = |p: &str| dest.path().join(p);
+    let read = |p: &str| fs::read(path(p)).unwrap();
+
+    // The whole subtree of a matching directory
+    assert_eq!(read("usr/lib/x/a.so"), b"a");
+    assert_eq!(read("usr/lib/x/sub/b.so"), b"b");
+    assert_eq!(read("etc/hosts"), b"hosts");
+    // Under the directories it needs, and nothing else
+    for dir in ["usr", "usr/lib", "etc"] {
+        assert!(path(dir).is_dir(), "{dir}");
+    }
+    for name in ["etc/passwd", "usr/bin", "usr/lib/readme"] {
+        assert!(!path(name).exists(), "{name}");
+    }
+    // Linked to a target extracted along, copied from one left out
+    let ino = |p: &str| fs::metadata(path(p)).unwrap().ino();
+    assert_eq!(ino("usr/lib/x/a2"), ino("usr/lib/x/a.so"));
+    assert_eq!(read("usr/lib/x/sh"), b"sh");
+    assert_eq!(fs::metadata(path("usr/lib/x/sh")).unwrap().nlink(), 1);
+
+    assert!(r.unpack_matching(TempDir::new().path(), ["usr/["]).is_err());
+}
+
+// What `fs` answers to a set of calls, errors reduced to the fact
+fn vfs_answers(fs: &dyn Vfs) -> Vec<String> {
+    // Not the root's metadata: extraction leaves the destination's own alone
+    let mut answers = vec![format!("read_dir: {:?}", fs.read_dir("").ok())];
+    for path in [
+        "etc",
+        "/etc/conf",
+        "etc/link",
+        "etc/hard",
+        "lib",
+        "lib/conf",
+        "etc/../etc/conf",
+        "missing",
+    ] {
+        answers.push(format!("metadata {path}: {:?}", fs.metadata(path).ok()));
+        answers.push(format!("read_dir {path}: {:?}", fs.read_dir(path).ok()));
+        answers.push(format!("read {path}: {:?}", fs.read(path).ok()));
+    }
+    answers
+}
+
+#[test]
+fn vfs_answers_the_same_for_a_layer_and_its_extraction() {
+    // Owned by whoever runs the tests, so extracting keeps the owner
+    let me = fs::metadata(TempDir::new().path()).unwrap();
+    let mut b = tar::Builder::new(Vec::new());
+    let mut append = |kind, path: &str, mode, data: &[u8], target: Option<&str>| {
+        let mut h = header(kind, data.len() as u64);
+        h.set_mode(mode);
+        h.set_uid(me.uid().into());
+        h.set_gid(me.gid().into());
+        match target {
+            Some(target) => b.append_link(&mut h, path, target).unwrap(),
+            None => b.append_data(&mut h, path, data).unwrap(),
+        }
+    };
+    append(tar::EntryType::Directory, "etc", 0o750, b"", None);
+    append(tar::EntryType::Regular, "etc/conf", 0o640, b"conf", None);
+    append(tar::EntryType::Regular, "etc/empty", 0o600, b"", None);
+    append(
+        tar::EntryType::Symlink,
+        "etc/link",
+        0o777,
+        b"",
+        Some("conf"),
+    );
+    append(
+        tar::EntryType::Link,
+        "etc/hard",
+        0o640,
+        b"",
+        Some("etc/conf"),
+    );
+    append(tar::EntryType::Symlink, "lib", 0o777, b"", Some("etc"));
+    let r = open_from_bytes(blob_of(&b.into_inner().unwrap(), 4096)).unwrap();
+    let dest = TempDir::new();
+    r.unpack(dest.path()).unwrap();
+
+    let layer = vfs_answers(&r);
+    assert_eq!(layer, vfs_answers(&Dir::new(dest.path())));
+    // Not vacuous
+    assert_eq!(r.read("lib/conf").unwrap(), b"conf");
+    assert_eq!(r.metadata("etc/hard").unwrap().entry_type, "reg");
+    assert_eq!(
+        r.metadata("lib").unwrap().link_target.as_deref(),
+        Some("etc")
+    );
+    assert_eq!(
+        r.read_dir("lib").unwrap(),
+        ["conf", "empty", "hard", "link"]
+    );
+}
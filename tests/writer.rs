@@ -0,0 +1,1983 @@
+mod common;
+
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    ffi::OsStr,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::symlink, fs::PermissionsExt},
+    rc::Rc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use common::{
+    blob_with, decompress, file_meta, footer_of, header, pattern, sha256, tar_of, toc_json,
+    with_toc, zstd_manifest, TempDir,
+};
+use stargz_rs::{
+    open_from_bytes, BlobFormat, Chown, CompressionLevel, CountingWriter, DirOptions, DirWatcher,
+    DropXattrs, DuplicatePolicy, EntryAttrs, EntryFilter, EntryMeta, ErrorKind, FooterFormat,
+    ReaderOptions, RedactPaths, StripTimestamps, Symlinks, Writer, MIN_CHUNK_SIZE,
+};
+
+#[test]
+fn same_path_twice_reads_the_last() {
+    let big = pattern(200_000);
+    for policy in [DuplicatePolicy::KeepBoth, DuplicatePolicy::Replace] {
+        let mut blob = Vec::new();
+        blob_with(
+            |w| {
+                w.with_chunk_size(65536)
+                    .unwrap()
+                    .with_duplicate_policy(policy)
+            },
+            |w| {
+                w.add_file("f", &mut &big[..], &file_meta()).unwrap();
+                w.add_file("f", &mut &b"second"[..], &file_meta()).unwrap();
+            },
+            &mut blob,
+        );
+
+        let r = open_from_bytes(blob).unwrap();
+        assert_eq!(r.read_file("f").unwrap(), b"second", "{policy:?}");
+        assert_eq!(r.lookup("f").unwrap().size(), 6);
+        let report = r.verify();
+        assert!(report.failures.is_empty(), "{policy:?}: {report:?}");
+    }
+}
+
+#[test]
+fn same_path_twice_is_an_error_when_asked() {
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_duplicate_policy(DuplicatePolicy::Error),
+        |w| {
+            w.add_file("f", &mut &b"first"[..], &file_meta()).unwrap();
+            assert!(w
+                .add_file("./f", &mut &b"second"[..], &file_meta())
+                .is_err());
+        },
+        &mut blob,
+    );
+    assert_eq!(
+        open_from_bytes(blob).unwrap().read_file("f").unwrap(),
+        b"first"
+    );
+}
+
+#[test]
+fn split_copies_hardlinks_to_earlier_blobs() {
+    let big = pattern(3_000_000);
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Regular, 5);
+    b.append_data(&mut h, "x", &b"hello"[..]).unwrap();
+    let mut h = header(tar::EntryType::Regular, big.len() as u64);
+    b.append_data(&mut h, "big", &big[..]).unwrap();
+    for link in ["b", "c"] {
+        let mut h = header(tar::EntryType::Link, 0);
+        b.append_link(&mut h, link, "x").unwrap();
+    }
+    let input = b.into_inner().unwrap();
+
+    let dir = TempDir::new();
+    let path = |i: usize| dir.path().join(format!("blob.{i}"));
+    let mut w = Writer::new(File::create(path(0)).unwrap())
+        .with_split(1_000_000, |i| File::create(path(i)));
+    w.append_tar(&mut &input[..]).unwrap();
+    w.close().unwrap();
+    assert_eq!(w.blobs().len(), 2);
+
+    let first = ReaderOptions::new()
+        .open(File::open(path(0)).unwrap())
+        .unwrap();
+    assert_eq!(first.read_file("big").unwrap(), big);
+    // The first link is a copy of x, the second a link to the copy
+    let second = ReaderOptions::new()
+        .open(File::open(path(1)).unwrap())
+        .unwrap();
+    assert!(!second.lstat("b").unwrap().is_hardlink());
+    let c = second.lstat("c").unwrap();
+    assert!(c.is_hardlink());
+    assert_eq!(c.entry.link_name(), "b");
+    assert_eq!(c.nlink(), 2);
+    assert_eq!(second.read_file("c").unwrap(), b"hello");
+    assert!(second.verify().is_ok());
+}
+
+#[test]
+fn lossless_split_rejects_hardlinks_to_earlier_blobs() {
+    let big = pattern(100_000);
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Regular, big.len() as u64);
+    b.append_data(&mut h, "a", &big[..]).unwrap();
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "b", "a").unwrap();
+    let input = b.into_inner().unwrap();
+
+    let dir = TempDir::new();
+    let path = |i: usize| dir.path().join(format!("blob.{i}"));
+    let mut w =
+        Writer::new(File::create(path(0)).unwrap()).with_split(1000, |i| File::create(path(i)));
+    let err = w.append_tar_lossless(&mut &input[..]).unwrap_err();
+    assert!(err.to_string().contains("earlier blob"), "{err}");
+}
+
+// A tar with one entry named `name`, a symlink to `target` if there is one
+// and a regular file otherwise. Names in Latin-1 aren't UTF-8.
+fn latin1_tar(name: &[u8], target: Option<&[u8]>) -> Vec<u8> {
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = match target {
+        Some(target) => {
+            let mut h = header(tar::EntryType::Symlink, 0);
+            h.set_link_name(OsStr::from_bytes(target)).unwrap();
+            h
+        }
+        None => header(tar::EntryType::Regular, 4),
+    };
+    h.set_path(OsStr::from_bytes(name)).unwrap();
+    h.set_cksum();
+    let data: &[u8] = if target.is_some() { b"" } else { b"data" };
+    b.append(&h, data).unwrap();
+    b.into_inner().unwrap()
+}
+
+#[test]
+fn non_utf8_paths_are_an_error() {
+    for input in [
+        latin1_tar(b"caf\xe9", None),
+        latin1_tar(b"link", Some(b"caf\xe9")),
+    ] {
+        let mut out = Vec::new();
+        let err = Writer::new(&mut out)
+            .append_tar(&mut &input[..])
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("caf\\xe9"), "{err:#}");
+    }
+}
+
+#[test]
+fn filters_can_drop_non_utf8_paths() {
+    let mut input = latin1_tar(b"caf\xe9", None);
+    input.truncate(input.len() - 1024);
+    input.extend(common::tar_of(&[("kept", b"data")]));
+
+    let filters = [
+        EntryFilter::new().exclude("caf*").unwrap(),
+        EntryFilter::new().filter(|path, _| !path.contains('\u{fffd}')),
+    ];
+    for mut filter in filters {
+        let mut blob = Vec::new();
+        blob_with(
+            |w| w,
+            |w| {
+                w.append_tar_with_filter(&mut &input[..], &mut filter)
+                    .unwrap()
+            },
+            &mut blob,
+        );
+        let r = open_from_bytes(blob).unwrap();
+        assert_eq!(r.read_file("kept").unwrap(), b"data");
+    }
+}
+
+#[test]
+fn append_dir_all_leaves_out_ignored_paths() {
+    let dir = TempDir::new();
+    let files = [
+        ".dockerignore",
+        "a.txt",
+        "b.log",
+        "sub/c.log",
+        "sub/d.txt",
+        "build/out",
+        "build/keep",
+        "tmp/x/file",
+        "tmp/x/y/cache/z",
+    ];
+    for name in files {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, name).unwrap();
+    }
+    std::fs::write(
+        dir.path().join(".dockerignore"),
+        "# not a rule\n\n*.log\n/build/\n!build/keep\ntmp/**/cache\n",
+    )
+    .unwrap();
+
+    let opts = DirOptions::new()
+        .ignore_file(dir.path().join(".dockerignore"))
+        .unwrap()
+        .ignore("./sub/d.txt")
+        .unwrap();
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w,
+        |w| w.append_dir_all_with(dir.path(), &opts).unwrap(),
+        &mut blob,
+    );
+    let r = open_from_bytes(blob).unwrap();
+    let mut names: Vec<&str> = r.entries().iter().map(|e| e.name()).collect();
+    names.sort();
+    // `*.log` stays at the root, and the build directory is left out but
+    // not the file brought back
+    assert_eq!(
+        names,
+        [
+            ".dockerignore",
+            "a.txt",
+            "build/keep",
+            "sub",
+            "sub/c.log",
+            "tmp",
+            "tmp/x",
+            "tmp/x/file",
+            "tmp/x/y"
+        ]
+    );
+    assert_eq!(r.read_file("build/keep").unwrap(), b"build/keep");
+}
+
+#[test]
+fn append_dir_all_symlink_policies() {
+    let outside = TempDir::new();
+    std::fs::write(outside.path().join("secret"), "secret").unwrap();
+    let dir = TempDir::new();
+    std::fs::create_dir(dir.path().join("d")).unwrap();
+    std::fs::write(dir.path().join("d/f"), "f").unwrap();
+    symlink("d/f", dir.path().join("lf")).unwrap();
+    symlink("d", dir.path().join("ld")).unwrap();
+    let append = |symlinks| {
+        let mut blob = Vec::new();
+        {
+            let mut w = Writer::new(&mut blob);
+            w.append_dir_all_with(dir.path(), &DirOptions::new().symlinks(symlinks))?;
+            w.close()?;
+        }
+        open_from_bytes(blob)
+    };
+
+    let r = append(Symlinks::Keep).unwrap();
+    assert_eq!(r.lookup("lf").unwrap().entry_type(), "symlink");
+    assert_eq!(r.lookup("ld").unwrap().link_name(), "d");
+
+    let r = append(Symlinks::Follow).unwrap();
+    assert_eq!(r.read_file("lf").unwrap(), b"f");
+    assert_eq!(r.lookup("ld").unwrap().entry_type(), "dir");
+    assert_eq!(r.read_file("ld/f").unwrap(), b"f");
+
+    let err = append(Symlinks::Forbid).err().unwrap();
+    assert!(format!("{err:#}").contains("symlink"), "{err:#}");
+
+    // Ignored, a symlink isn't followed or refused
+    let opts = DirOptions::new()
+        .symlinks(Symlinks::Forbid)
+        .ignore("l*")
+        .unwrap();
+    Writer::new(Vec::new())
+        .append_dir_all_with(dir.path(), &opts)
+        .unwrap();
+
+    // Never outside of the directory, even through a link that stays in
+    symlink(outside.path().join("secret"), dir.path().join("d/out")).unwrap();
+    symlink("d/out", dir.path().join("lout")).unwrap();
+    let err = append(Symlinks::Follow).err().unwrap();
+    assert!(format!("{err:#}").contains("outside"), "{err:#}");
+    std::fs::remove_file(dir.path().join("d/out")).unwrap();
+    std::fs::remove_file(dir.path().join("lout")).unwrap();
+    assert!(append(Symlinks::Keep).is_ok());
+
+    // Nor around in circles
+    symlink("..", dir.path().join("d/up")).unwrap();
+    let err = append(Symlinks::Follow).err().unwrap();
+    assert!(format!("{err:#}").contains("leads back"), "{err:#}");
+    assert!(append(Symlinks::Keep).is_ok());
+}
+
+#[test]
+fn append_dir_all_writes_hardlinks_once() {
+    let dir = TempDir::new();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    std::fs::write(dir.path().join("a"), "data").unwrap();
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("sub/c")).unwrap();
+    // Same content, another inode
+    std::fs::write(dir.path().join("d"), "data").unwrap();
+    let mut blob = Vec::new();
+    blob_with(|w| w, |w| w.append_dir_all(dir.path()).unwrap(), &mut blob);
+
+    let r = open_from_bytes(blob.clone()).unwrap();
+    assert_eq!(r.lookup("a").unwrap().entry_type(), "reg");
+    assert_eq!(r.lookup("d").unwrap().entry_type(), "reg");
+    for name in ["b", "sub/c"] {
+        let stat = r.lstat(name).unwrap();
+        assert!(stat.is_hardlink(), "{name}");
+        assert_eq!(stat.entry.link_name(), "a");
+        assert_eq!(stat.nlink(), 3);
+        assert_eq!(r.read_file(name).unwrap(), b"data");
+    }
+
+    let tar = decompress(&blob);
+    let mut archive = tar::Archive::new(&tar[..]);
+    let links: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .map(Result::unwrap)
+        .filter(|e| e.header().entry_type() == tar::EntryType::Link)
+        .map(|e| {
+            let path = e.path().unwrap().to_string_lossy().into_owned();
+            let target = e.link_name().unwrap().unwrap();
+            (path, target.to_string_lossy().into_owned())
+        })
+        .collect();
+    assert_eq!(
+        links,
+        [
+            ("b".to_string(), "a".to_string()),
+            ("sub/c".to_string(), "a".to_string())
+        ]
+    );
+}
+
+#[test]
+fn watched_directory_rebuilds_reuse_unchanged_chunks() {
+    const CHUNK: usize = 4096;
+    let dir = TempDir::new();
+    let out = TempDir::new();
+    let output = out.path().join("layer");
+    // Three chunks each, none alike
+    let content = pattern(6 * CHUNK);
+    let (one, two) = content.split_at(3 * CHUNK);
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    std::fs::write(dir.path().join("one"), one).unwrap();
+    std::fs::write(dir.path().join("sub/two"), two).unwrap();
+
+    let mut watcher = DirWatcher::new(dir.path(), &output).chunk_size(CHUNK);
+    assert_eq!(watcher.build().unwrap().reused_chunks, 0);
+    let fresh = std::fs::read(&output).unwrap();
+
+    // All but the last chunk of each file comes from the previous blob,
+    // which ends up the same
+    let stats = watcher.build().unwrap();
+    assert_eq!(stats.reused_chunks, 4);
+    assert!(std::fs::read(&output).unwrap() == fresh);
+
+    // Changing the end of a file only costs its last chunk
+    let mut changed = two.to_vec();
+    changed[3 * CHUNK - 1] ^= 0xff;
+    std::fs::write(dir.path().join("sub/two"), &changed).unwrap();
+    assert_eq!(watcher.build().unwrap().reused_chunks, 4);
+    let r = ReaderOptions::new()
+        .open(File::open(&output).unwrap())
+        .unwrap();
+    assert!(r.read_file("one").unwrap() == one);
+    assert!(r.read_file("sub/two").unwrap() == changed);
+    assert!(r.verify().is_ok());
+
+    // Changing its start, the chunks after it
+    changed[0] ^= 0xff;
+    std::fs::write(dir.path().join("sub/two"), &changed).unwrap();
+    assert_eq!(watcher.build().unwrap().reused_chunks, 3);
+    let r = ReaderOptions::new()
+        .open(File::open(&output).unwrap())
+        .unwrap();
+    assert!(r.read_file("sub/two").unwrap() == changed);
+
+    // A change in a subdirectory ends the wait
+    let path = dir.path().join("sub/new");
+    let writer = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        std::fs::write(path, "new").unwrap();
+    });
+    watcher.wait().unwrap();
+    writer.join().unwrap();
+    watcher.build().unwrap();
+    let r = ReaderOptions::new()
+        .open(File::open(&output).unwrap())
+        .unwrap();
+    assert_eq!(r.read_file("sub/new").unwrap(), b"new");
+
+    // The output can't be in what's watched
+    let err = DirWatcher::new(dir.path(), dir.path().join("layer"))
+        .build()
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("inside"), "{err:#}");
+}
+
+#[test]
+fn close_writes_the_toc_and_footer() {
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w,
+        |w| {
+            w.add_file("f", &mut &b"data"[..], &file_meta()).unwrap();
+            w.close().unwrap();
+        },
+        &mut blob,
+    );
+    let (toc_offset, footer_len) = footer_of(&blob);
+    assert_eq!(footer_len, 51);
+
+    // The TOC is a gzip member of its own, then only the footer follows
+    let mut toc = flate2::bufread::GzDecoder::new(&blob[toc_offset..]);
+    toc.read_to_end(&mut Vec::new()).unwrap();
+    assert_eq!(toc.into_inner().len(), footer_len);
+    let json: serde_json::Value = serde_json::from_slice(&toc_json(&blob)).unwrap();
+    assert_eq!(json["version"], 1);
+    assert!(json["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|e| e["name"] == "f" && e["size"] == 4));
+
+    // An empty gzip member
+    let mut footer = flate2::read::GzDecoder::new(&blob[blob.len() - footer_len..]);
+    assert_eq!(footer.read(&mut [0; 8]).unwrap(), 0);
+
+    // The whole blob decompresses to a tar ending with the TOC
+    let decompressed = decompress(&blob);
+    let names: Vec<_> = tar::Archive::new(&decompressed[..])
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().display().to_string())
+        .collect();
+    assert_eq!(names, ["f", "stargz.index.json"]);
+
+    // Closing again writes nothing more
+    let mut twice = Vec::new();
+    blob_with(
+        |w| w,
+        |w| {
+            w.add_file("f", &mut &b"data"[..], &file_meta()).unwrap();
+            w.close().unwrap();
+        },
+        &mut twice,
+    );
+    assert_eq!(twice, blob);
+
+    let mut empty = Vec::new();
+    blob_with(|w| w, |_| {}, &mut empty);
+    let r = open_from_bytes(empty).unwrap();
+    assert_eq!(r.info().footer, FooterFormat::Estargz);
+    assert_eq!(r.walk().count(), 0);
+    assert_eq!(r.lookup("/").unwrap().entry_type(), "dir");
+}
+
+#[test]
+fn chunk_size_sets_where_members_start() {
+    assert!(Writer::new(Vec::new())
+        .with_chunk_size(MIN_CHUNK_SIZE - 1)
+        .is_err());
+    assert_eq!(Writer::new(Vec::new()).chunk_size(), 4 << 20);
+
+    let size: u64 = (4 << 20) + 100_000;
+    let big = pattern(size as usize);
+    for chunk_size in [None, Some(MIN_CHUNK_SIZE), Some(1 << 20)] {
+        let mut blob = Vec::new();
+        blob_with(
+            |w| {
+                let w = w.with_compression_level(CompressionLevel::Fast).unwrap();
+                match chunk_size {
+                    Some(n) => w.with_chunk_size(n).unwrap(),
+                    None => w,
+                }
+            },
+            |w| w.add_file("big", &mut &big[..], &file_meta()).unwrap(),
+            &mut blob,
+        );
+        let chunk_size = chunk_size.unwrap_or(4 << 20) as u64;
+
+        let r = open_from_bytes(blob).unwrap();
+        let chunks: Vec<_> = r
+            .entries()
+            .iter()
+            .filter(|e| e.name() == "big")
+            .map(|e| (e.chunk_offset(), e.chunk_size()))
+            .collect();
+        assert_eq!(chunks.len() as u64, size.div_ceil(chunk_size));
+        for (i, &(offset, len)) in chunks.iter().enumerate() {
+            assert_eq!(offset, i as u64 * chunk_size, "{chunk_size}");
+            assert_eq!(len, chunk_size.min(size - offset), "{chunk_size}");
+        }
+        // Each chunk in a member of its own
+        let members: HashSet<_> = r
+            .entries()
+            .iter()
+            .filter(|e| e.name() == "big")
+            .map(|e| e.offset())
+            .collect();
+        assert_eq!(members.len(), chunks.len());
+        assert!(r.read_file("big").unwrap() == big);
+    }
+}
+
+// Content that compresses well, unlike `pattern`
+fn text(lines: usize) -> Vec<u8> {
+    (0..lines)
+        .flat_map(|i| format!("{i:08} the quick brown fox {}\n", i % 7).into_bytes())
+        .collect()
+}
+
+// The blob of `files` added at `level`
+fn blob_at(level: CompressionLevel, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_compression_level(level).unwrap(),
+        |w| {
+            for (name, data) in files {
+                w.add_file(name, &mut &data[..], &file_meta()).unwrap();
+            }
+        },
+        &mut blob,
+    );
+    blob
+}
+
+#[test]
+fn compression_levels_trade_size_for_speed() {
+    for level in [0, 10] {
+        let err = Writer::new(Vec::new())
+            .with_compression_level(CompressionLevel::Level(level))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("between 1 and 9"), "{err}");
+    }
+
+    let data = text(20_000);
+    let sizes: Vec<_> = [
+        CompressionLevel::Fast,
+        CompressionLevel::Level(4),
+        CompressionLevel::Default,
+        CompressionLevel::Best,
+    ]
+    .into_iter()
+    .map(|level| {
+        let blob = blob_at(level, &[("f", &data)]);
+        let r = open_from_bytes(blob.clone()).unwrap();
+        assert!(r.read_file("f").unwrap() == data, "{level:?}");
+        assert!(r.verify().is_ok(), "{level:?}");
+        blob.len()
+    })
+    .collect();
+    // Higher levels aren't always smaller, but level 1 is the largest
+    assert!(sizes[1..].iter().all(|&n| n < sizes[0]), "{sizes:?}");
+    // Best is the default
+    assert_eq!(
+        blob_at(CompressionLevel::Best, &[("f", &data)]),
+        blob_at(Default::default(), &[("f", &data)])
+    );
+}
+
+#[test]
+fn stored_members_hold_the_content_as_is() {
+    let data = text(5_000);
+    let blob = blob_at(CompressionLevel::Store, &[("f", &data), ("g", b"small")]);
+    // Stored deflate blocks: the content is in the blob verbatim
+    assert!(blob.len() > data.len());
+    assert!(blob
+        .windows(data.len().min(30_000))
+        .any(|w| w == &data[..w.len()]));
+
+    let r = open_from_bytes(blob.clone()).unwrap();
+    assert!(r.read_file("f").unwrap() == data);
+    assert_eq!(r.read_file("g").unwrap(), b"small");
+    assert!(r.verify().is_ok());
+    assert!(blob.len() > 5 * blob_at(CompressionLevel::Fast, &[("f", &data)]).len());
+}
+
+#[test]
+fn diff_id_is_the_digest_of_the_uncompressed_blob() {
+    let data = text(5_000);
+    let input = tar_of(&[("a", b"aaaa"), ("f", &data)]);
+    for min_chunk_size in [0, 1 << 20] {
+        let mut blob = Vec::new();
+        let mut w = Writer::new(&mut blob)
+            .with_chunk_size(MIN_CHUNK_SIZE)
+            .unwrap()
+            .with_min_chunk_size(min_chunk_size);
+        w.append_tar(&mut &input[..]).unwrap();
+        w.add_file("g", &mut &b"more"[..], &file_meta()).unwrap();
+        assert!(w.diff_id().is_none());
+        w.close().unwrap();
+        let diff_id = w.diff_id().unwrap().to_string();
+        assert_eq!(w.blobs()[0].diff_id, diff_id);
+        drop(w);
+
+        // The TOC entry and the empty footer member included
+        assert_eq!(diff_id, sha256(&decompress(&blob)), "{min_chunk_size}");
+    }
+}
+
+#[test]
+fn toc_digest_is_the_digest_of_the_toc_json() {
+    let mut blob = Vec::new();
+    let mut w = Writer::new(&mut blob);
+    w.add_file("f", &mut &b"data"[..], &file_meta()).unwrap();
+    assert!(w.toc_digest().is_none());
+    w.close().unwrap();
+    let toc_digest = w.toc_digest().unwrap().to_string();
+    assert_eq!(w.blobs()[0].toc_digest, toc_digest);
+    drop(w);
+
+    assert_eq!(toc_digest, sha256(&toc_json(&blob)));
+    // Not of the gzipped TOC member
+    let (toc_offset, footer_len) = footer_of(&blob);
+    assert_ne!(
+        toc_digest,
+        sha256(&blob[toc_offset..blob.len() - footer_len])
+    );
+}
+
+#[test]
+fn chunks_carry_the_digest_of_their_content() {
+    let big = pattern(2 * MIN_CHUNK_SIZE + 100);
+    let mut blob = Vec::new();
+    blob_with(
+        |w| {
+            w.with_chunk_size(MIN_CHUNK_SIZE)
+                .unwrap()
+                .with_min_chunk_size(1000)
+        },
+        |w| {
+            for (name, data) in [("big", &big[..]), ("s1", b"one"), ("s2", b"two")] {
+                w.add_file(name, &mut &data[..], &file_meta()).unwrap();
+            }
+        },
+        &mut blob,
+    );
+
+    let json: serde_json::Value = serde_json::from_slice(&toc_json(&blob)).unwrap();
+    let mut checked = 0;
+    for e in json["entries"].as_array().unwrap() {
+        let data: &[u8] = match e["name"].as_str().unwrap() {
+            "big" => &big,
+            "s1" => b"one",
+            "s2" => b"two",
+            _ => continue,
+        };
+        let offset = e["chunkOffset"].as_u64().unwrap_or(0) as usize;
+        let size = e["chunkSize"].as_u64().unwrap_or(data.len() as u64) as usize;
+        assert_eq!(
+            e["chunkDigest"],
+            sha256(&data[offset..offset + size]).as_str(),
+            "{e}"
+        );
+        checked += 1;
+    }
+    // Three chunks of big, and the two small files sharing a member
+    assert_eq!(checked, 5);
+
+    // What readers check chunks against
+    let corrupt = with_toc(&blob, |toc| {
+        for e in toc["entries"].as_array_mut().unwrap() {
+            if e["type"] == "chunk" && e["chunkOffset"] == MIN_CHUNK_SIZE {
+                e["chunkDigest"] = sha256(b"other").into();
+            }
+        }
+    });
+    let r = ReaderOptions::new()
+        .verify_digests(true)
+        .open_from_bytes(corrupt)
+        .unwrap();
+    assert!(r.read_file("s1").is_ok());
+    let err = r.read_file("big").unwrap_err();
+    assert_eq!(ErrorKind::of(&err), Some(ErrorKind::Corrupt), "{err:#}");
+}
+
+#[test]
+fn regular_files_carry_the_digest_of_their_content() {
+    let big = pattern(2 * MIN_CHUNK_SIZE + 100);
+    let mut b = tar::Builder::new(Vec::new());
+    for (path, data) in [("big", &big[..]), ("small", b"small"), ("empty", b"")] {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, data).unwrap();
+    }
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "hard", "small").unwrap();
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "soft", "small").unwrap();
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_chunk_size(MIN_CHUNK_SIZE).unwrap(),
+        |w| {
+            w.append_tar(&mut &b.into_inner().unwrap()[..]).unwrap();
+            w.add_file("added", &mut &b"added"[..], &file_meta())
+                .unwrap();
+        },
+        &mut blob,
+    );
+
+    let json: serde_json::Value = serde_json::from_slice(&toc_json(&blob)).unwrap();
+    let digests: Vec<_> = json["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|e| e["type"] != "chunk")
+        .map(|e| (e["name"].as_str().unwrap(), e["digest"].as_str()))
+        .collect();
+    let big_digest = sha256(&big);
+    let small_digest = sha256(b"small");
+    let empty_digest = sha256(b"");
+    let added_digest = sha256(b"added");
+    assert_eq!(
+        digests,
+        [
+            ("big", Some(big_digest.as_str())),
+            ("small", Some(small_digest.as_str())),
+            ("empty", Some(empty_digest.as_str())),
+            ("hard", None),
+            ("soft", None),
+            ("added", Some(added_digest.as_str())),
+        ]
+    );
+
+    let r = open_from_bytes(blob.clone()).unwrap();
+    assert_eq!(r.lookup("hard").unwrap().digest(), small_digest);
+    let report = r.verify();
+    assert!(report.is_ok(), "{report:?}");
+    assert_eq!(report.verified, 4);
+
+    // What verify checks whole files against
+    let corrupt = with_toc(&blob, |toc| {
+        for e in toc["entries"].as_array_mut().unwrap() {
+            if e["name"] == "small" {
+                e["digest"] = sha256(b"other").into();
+            }
+        }
+    });
+    let report = open_from_bytes(corrupt).unwrap().verify();
+    assert_eq!(report.failures.len(), 1, "{report:?}");
+}
+
+#[test]
+fn lossless_blobs_decompress_to_the_input_tar() {
+    let big = pattern(3 * MIN_CHUNK_SIZE + 1);
+    let long_name = format!("{}/file", "deep/".repeat(30));
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Directory, 0);
+    h.set_mode(0o755);
+    b.append_data(&mut h, "d", &[][..]).unwrap();
+    // PAX records of no interest to the TOC are kept too
+    b.append_pax_extensions([
+        ("comment", &b"kept as is"[..]),
+        ("SCHILY.xattr.user.k", b"v"),
+    ])
+    .unwrap();
+    let mut h = header(tar::EntryType::Regular, big.len() as u64);
+    h.set_mtime(1234567890);
+    b.append_data(&mut h, "d/big", &big[..]).unwrap();
+    // GNU long names and link names
+    let mut h = header(tar::EntryType::Regular, 3);
+    b.append_data(&mut h, &long_name, &b"odd"[..]).unwrap();
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "long-link", &long_name).unwrap();
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "hard", "d/big").unwrap();
+    let mut h = header(tar::EntryType::Char, 0);
+    h.set_device_major(1).unwrap();
+    h.set_device_minor(3).unwrap();
+    b.append_data(&mut h, "null", &[][..]).unwrap();
+    let input = b.into_inner().unwrap();
+
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_chunk_size(MIN_CHUNK_SIZE).unwrap(),
+        |w| w.append_tar_lossless(&mut &input[..]).unwrap(),
+        &mut blob,
+    );
+
+    // Up to the end-of-archive marker, then the TOC entry
+    let (toc_offset, _) = footer_of(&blob);
+    let data = decompress(&blob[..toc_offset]);
+    let end = input.len() - 1024;
+    assert!(input[end..].iter().all(|&b| b == 0));
+    assert!(data == input[..end], "{} vs {end} bytes", data.len());
+    let decompressed = decompress(&blob);
+    assert!(decompressed[..end] == input[..end]);
+    let toc = tar::Header::from_byte_slice(&decompressed[end..end + 512]);
+    assert_eq!(&*toc.path_bytes(), b"stargz.index.json");
+
+    let r = open_from_bytes(blob).unwrap();
+    assert!(r.read_file("hard").unwrap() == big);
+    assert_eq!(r.lookup("d/big").unwrap().xattrs()["user.k"], b"v");
+    assert_eq!(r.read_file(&long_name).unwrap(), b"odd");
+    assert_eq!(r.lookup("long-link").unwrap().link_name(), long_name);
+    assert_eq!(r.lookup("null").unwrap().entry_type(), "char");
+    assert!(r.verify().is_ok());
+}
+
+// Names of the non-chunk TOC entries of `blob`, in blob order
+fn toc_names(blob: &[u8]) -> Vec<String> {
+    let r = open_from_bytes(blob).unwrap();
+    r.entries()
+        .iter()
+        .filter(|e| e.entry_type() != "chunk")
+        .map(|e| e.name().to_string())
+        .collect()
+}
+
+#[test]
+fn prioritized_files_come_before_the_landmark() {
+    let mut b = tar::Builder::new(Vec::new());
+    for dir in ["usr", "usr/bin", "etc", "bin"] {
+        let mut h = header(tar::EntryType::Directory, 0);
+        b.append_data(&mut h, dir, &[][..]).unwrap();
+    }
+    for (path, data) in [("a", &b"a"[..]), ("usr/bin/sh", b"sh"), ("etc/conf", b"c")] {
+        let mut h = header(tar::EntryType::Regular, data.len() as u64);
+        b.append_data(&mut h, path, data).unwrap();
+    }
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "bin/link", "usr/bin/sh").unwrap();
+    let input = b.into_inner().unwrap();
+    let blob_for = |prioritized: &[&str]| {
+        let mut blob = Vec::new();
+        blob_with(
+            |w| w.with_prioritized_files(prioritized),
+            |w| w.append_tar(&mut &input[..]).unwrap(),
+            &mut blob,
+        );
+        blob
+    };
+
+    // In the order asked, each after its parents and hardlink target
+    let blob = blob_for(&["bin/link", "missing", "etc/conf"]);
+    assert_eq!(
+        toc_names(&blob),
+        [
+            "bin",
+            "usr",
+            "usr/bin",
+            "usr/bin/sh",
+            "bin/link",
+            "etc",
+            "etc/conf",
+            ".prefetch.landmark",
+            "a",
+        ]
+    );
+    let r = open_from_bytes(blob).unwrap();
+    assert_eq!(r.read_file(".prefetch.landmark").unwrap(), [0xf]);
+    assert_eq!(r.read_file("bin/link").unwrap(), b"sh");
+
+    // Only the first append writes a landmark
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_prioritized_files(["a"]),
+        |w| {
+            w.append_tar(&mut &input[..]).unwrap();
+            w.append_tar(&mut &tar_of(&[("a2", b"a")])[..]).unwrap();
+        },
+        &mut blob,
+    );
+    let names = toc_names(&blob);
+    assert_eq!(names[..2], ["a", ".prefetch.landmark"]);
+    assert_eq!(names.iter().filter(|n| n.contains("landmark")).count(), 1);
+
+    // Nothing to prefetch: the other landmark, ahead of everything
+    for prioritized in [&[][..], &["missing"]] {
+        let names = toc_names(&blob_for(prioritized));
+        assert_eq!(names[0], ".no.prefetch.landmark", "{prioritized:?}");
+        assert!(!names.iter().any(|n| n == ".prefetch.landmark"));
+        assert_eq!(
+            names[1..],
+            [
+                "usr",
+                "usr/bin",
+                "etc",
+                "bin",
+                "a",
+                "usr/bin/sh",
+                "etc/conf",
+                "bin/link"
+            ]
+        );
+    }
+}
+
+#[test]
+fn min_chunk_size_packs_small_files() {
+    let small: Vec<(String, Vec<u8>)> = (0..200)
+        .map(|i| (format!("f{i:03}"), text(3 + i % 5)))
+        .collect();
+    let files: Vec<_> = small.iter().map(|(n, d)| (n.as_str(), &d[..])).collect();
+    let input = tar_of(&files);
+
+    let mut unpacked = Vec::new();
+    blob_with(
+        |w| w,
+        |w| w.append_tar(&mut &input[..]).unwrap(),
+        &mut unpacked,
+    );
+    let mut packed = Vec::new();
+    blob_with(
+        |w| w.with_min_chunk_size(16384),
+        |w| w.append_tar(&mut &input[..]).unwrap(),
+        &mut packed,
+    );
+    assert!(
+        packed.len() * 2 < unpacked.len(),
+        "{} vs {}",
+        packed.len(),
+        unpacked.len()
+    );
+
+    // Members fill up, headers included, before the next one starts
+    let r = open_from_bytes(packed).unwrap();
+    let mut members = Vec::new();
+    for (name, data) in &small {
+        let ent = r.lookup(name).unwrap();
+        if members.last() == Some(&ent.offset()) {
+            assert!(ent.inner_offset() > 0, "{name}");
+        } else {
+            members.push(ent.offset());
+        }
+        assert!(r.read_file(name).unwrap() == *data, "{name}");
+    }
+    assert!(members.len() > 1);
+    assert!(members.len() <= input.len() / 16384 + 1, "{members:?}");
+    // Not even the first file shares the first member: offset 0 means no
+    // content in TOCs
+    assert_ne!(members[0], 0);
+    let listed: usize = r.members().unwrap().iter().map(|m| m.entries.len()).sum();
+    assert_eq!(listed, small.len());
+
+    // zstd:chunked has no inner offsets
+    let mut zstd = Vec::new();
+    blob_with(
+        |w| {
+            w.with_format(BlobFormat::ZstdChunked)
+                .unwrap()
+                .with_min_chunk_size(16384)
+        },
+        |w| w.append_tar(&mut &input[..]).unwrap(),
+        &mut zstd,
+    );
+    let manifest: serde_json::Value = serde_json::from_slice(&zstd_manifest(&zstd)).unwrap();
+    let entries = manifest["entries"].as_array().unwrap();
+    assert!(entries.iter().all(|e| e.get("innerOffset").is_none()));
+    let offsets: HashSet<_> = entries.iter().map(|e| e["offset"].as_u64()).collect();
+    assert_eq!(offsets.len(), entries.len());
+}
+
+#[test]
+fn zstd_chunked_blobs_have_frames_a_manifest_and_a_footer() {
+    let big = pattern(2 * MIN_CHUNK_SIZE + 100);
+    let input = tar_of(&[("a", b"aaaa"), ("big", &big)]);
+    let mut blob = Vec::new();
+    let mut w = Writer::new(&mut blob)
+        .with_format(BlobFormat::ZstdChunked)
+        .unwrap()
+        .with_chunk_size(MIN_CHUNK_SIZE)
+        .unwrap();
+    w.append_tar(&mut &input[..]).unwrap();
+    w.close().unwrap();
+    let toc_digest = w.toc_digest().unwrap().to_string();
+    drop(w);
+
+    // A skippable frame holding the manifest offset and lengths, type and
+    // magic
+    let footer = &blob[blob.len() - 72..];
+    assert_eq!(footer[..4], 0x184d_2a50u32.to_le_bytes());
+    assert_eq!(footer[4..8], 64u32.to_le_bytes());
+    let field = |i: usize| u64::from_le_bytes(footer[8 + i * 8..16 + i * 8].try_into().unwrap());
+    assert_eq!(&footer[64..], b"GNUlInUx");
+    let (offset, len) = (field(0) as usize, field(1) as usize);
+    assert_eq!(field(3), 1);
+    // The manifest in a skippable frame of its own, right before
+    assert_eq!(blob[offset - 8..offset - 4], 0x184d_2a50u32.to_le_bytes());
+    assert_eq!(offset + len, blob.len() - 72);
+    assert_eq!(toc_digest, sha256(&blob[offset..offset + len]));
+
+    // It decompresses to a TOC
+    let manifest = zstd_manifest(&blob);
+    assert_eq!(manifest.len() as u64, field(2));
+    let toc: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+    assert_eq!(toc["version"], 1);
+    let entries = toc["entries"].as_array().unwrap();
+
+    // Where each chunk starts, a zstd frame holding just that chunk
+    let mut chunks = 0;
+    for e in entries.iter().filter(|e| e["name"] == "big") {
+        let start = e["chunkOffset"].as_u64().unwrap_or(0) as usize;
+        let end = start + e["chunkSize"].as_u64().unwrap() as usize;
+        let at = e["offset"].as_u64().unwrap() as usize;
+        let mut frame = zstd::stream::read::Decoder::new(&blob[at..])
+            .unwrap()
+            .single_frame();
+        let mut content = Vec::new();
+        frame.read_to_end(&mut content).unwrap();
+        assert!(content == big[start..end], "chunk at {start}");
+        chunks += 1;
+    }
+    assert_eq!(chunks, 3);
+
+    // The frames decompress to the tar, skippable frames to nothing
+    let decompressed = zstd::decode_all(&blob[..]).unwrap();
+    let names: Vec<_> = tar::Archive::new(&decompressed[..])
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().display().to_string())
+        .collect();
+    assert_eq!(names, ["a", "big"]);
+}
+
+#[test]
+fn parallel_compression_writes_the_same_blob() {
+    let big = pattern(7 * MIN_CHUNK_SIZE + 5);
+    let other = pattern(3 * MIN_CHUNK_SIZE);
+    let input = tar_of(&[
+        ("s1", b"one"),
+        ("big", &big),
+        ("s2", b"two"),
+        ("other", &other),
+        ("exact", &big[..MIN_CHUNK_SIZE]),
+    ]);
+    for format in [BlobFormat::Estargz, BlobFormat::ZstdChunked] {
+        for min_chunk_size in [0, 10_000] {
+            let build = |threads: usize| {
+                let mut blob = Vec::new();
+                let mut w = Writer::new(&mut blob)
+                    .with_format(format)
+                    .unwrap()
+                    .with_chunk_size(MIN_CHUNK_SIZE)
+                    .unwrap()
+                    .with_min_chunk_size(min_chunk_size)
+                    .with_threads(threads);
+                w.append_tar(&mut &input[..]).unwrap();
+                w.add_file("added", &mut &big[..], &file_meta()).unwrap();
+                w.close().unwrap();
+                let digests = (
+                    w.diff_id().unwrap().to_string(),
+                    w.toc_digest().unwrap().to_string(),
+                );
+                drop(w);
+                (blob, digests)
+            };
+            let single = build(1);
+            for threads in [2, 3, 8, 0] {
+                let parallel = build(threads);
+                assert!(
+                    parallel == single,
+                    "{format:?}, {min_chunk_size}, {threads} threads"
+                );
+            }
+            if format == BlobFormat::Estargz {
+                let r = open_from_bytes(single.0).unwrap();
+                assert!(r.read_file("added").unwrap() == big);
+                assert!(r.verify().is_ok());
+            }
+        }
+    }
+}
+
+// A pipe: a few bytes per read, no seeking, and where it's at for others to
+// see
+struct Pipe<'a> {
+    data: &'a [u8],
+    pos: Rc<Cell<usize>>,
+}
+
+impl Read for Pipe<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(777).min(self.data.len() - self.pos.get());
+        let at = self.pos.get();
+        buf[..n].copy_from_slice(&self.data[at..at + n]);
+        self.pos.set(at + n);
+        Ok(n)
+    }
+}
+
+// An output noting how much of the input was read when it was written to
+struct Tracked {
+    data: Vec<u8>,
+    input_pos: Rc<Cell<usize>>,
+    // (output length, input position) at each write
+    writes: Vec<(usize, usize)>,
+}
+
+impl Write for Tracked {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        self.writes.push((self.data.len(), self.input_pos.get()));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn append_tar_streams_from_pipes() {
+    let big = pattern(16 * MIN_CHUNK_SIZE + 10);
+    let input = tar_of(&[("big", &big), ("small", b"small")]);
+    let pos = Rc::new(Cell::new(0));
+    let mut out = Tracked {
+        data: Vec::new(),
+        input_pos: pos.clone(),
+        writes: Vec::new(),
+    };
+    let mut w = Writer::new(&mut out)
+        .with_chunk_size(MIN_CHUNK_SIZE)
+        .unwrap();
+    w.append_tar(&mut Pipe {
+        data: &input,
+        pos: pos.clone(),
+    })
+    .unwrap();
+    w.close().unwrap();
+    drop(w);
+    assert_eq!(pos.get(), input.len());
+
+    // Compressed chunks come out while the file is still being read
+    let (written, read) = out
+        .writes
+        .iter()
+        .copied()
+        .find(|&(len, _)| len > 2 * MIN_CHUNK_SIZE)
+        .unwrap();
+    assert!(read < big.len() / 2, "{written} bytes out after {read} in");
+
+    // The same blob as from a slice
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_chunk_size(MIN_CHUNK_SIZE).unwrap(),
+        |w| w.append_tar(&mut &input[..]).unwrap(),
+        &mut blob,
+    );
+    assert!(out.data == blob);
+}
+
+#[test]
+fn external_toc_blobs_have_no_footer() {
+    let big = pattern(2 * MIN_CHUNK_SIZE + 100);
+    let input = tar_of(&[("a", b"aaaa"), ("big", &big)]);
+    let mut blob = Vec::new();
+    let mut w = Writer::new(&mut blob)
+        .with_chunk_size(MIN_CHUNK_SIZE)
+        .unwrap()
+        .with_external_toc();
+    w.append_tar(&mut &input[..]).unwrap();
+    assert!(w.external_toc().is_none());
+    w.close().unwrap();
+    let toc = w.external_toc().unwrap().to_vec();
+    let toc_digest = w.toc_digest().unwrap().to_string();
+    let diff_id = w.diff_id().unwrap().to_string();
+    assert_eq!(w.blobs()[0].external_toc.as_deref(), Some(&toc[..]));
+    drop(w);
+
+    // The data and the end of the tar, nothing after
+    let decompressed = decompress(&blob);
+    assert!(decompressed[..input.len() - 1024] == input[..input.len() - 1024]);
+    assert!(decompressed[input.len() - 1024..].iter().all(|&b| b == 0));
+    assert_eq!(diff_id, sha256(&decompressed));
+    assert!(!blob.windows(6).any(|w| w == b"STARGZ"));
+    assert!(open_from_bytes(blob.clone()).is_err());
+
+    // The TOC member the blob would have ended with, whose JSON the digest
+    // is of
+    let mut json = Vec::new();
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&toc[..]));
+    let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(&*entry.path_bytes(), b"stargz.index.json");
+    entry.read_to_end(&mut json).unwrap();
+    assert_eq!(toc_digest, sha256(&json));
+
+    let dir = TempDir::new();
+    let path = dir.path().join("blob");
+    std::fs::write(&path, &blob).unwrap();
+    let r = ReaderOptions::new()
+        .open_with_external_toc(File::open(&path).unwrap(), &toc[..])
+        .unwrap();
+    assert_eq!(r.info().footer, FooterFormat::External);
+    assert_eq!(r.read_file("a").unwrap(), b"aaaa");
+    assert!(r.read_file("big").unwrap() == big);
+    assert!(r.verify().is_ok());
+}
+
+#[test]
+fn append_tar_keeps_links_devices_and_pax_records() {
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "lnk", "../target").unwrap();
+    let mut h = header(tar::EntryType::Regular, 1);
+    b.append_data(&mut h, "f", &b"f"[..]).unwrap();
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "hard", "f").unwrap();
+    for (path, kind, major, minor) in [
+        ("null", tar::EntryType::Char, 1, 3),
+        ("sda", tar::EntryType::Block, 8, 0),
+    ] {
+        let mut h = header(kind, 0);
+        h.set_device_major(major).unwrap();
+        h.set_device_minor(minor).unwrap();
+        b.append_data(&mut h, path, &[][..]).unwrap();
+    }
+    let mut h = header(tar::EntryType::Fifo, 0);
+    b.append_data(&mut h, "fifo", &[][..]).unwrap();
+    b.append_pax_extensions([
+        ("SCHILY.xattr.security.capability", &b"\x01\x00\x00\x02"[..]),
+        ("SCHILY.xattr.user.note", b"kept"),
+    ])
+    .unwrap();
+    let mut h = header(tar::EntryType::Regular, 3);
+    b.append_data(&mut h, "bin", &b"bin"[..]).unwrap();
+    let input = b.into_inner().unwrap();
+
+    let mut blob = Vec::new();
+    blob_with(|w| w, |w| w.append_tar(&mut &input[..]).unwrap(), &mut blob);
+
+    // In the TOC
+    let r = open_from_bytes(blob.clone()).unwrap();
+    assert_eq!(r.lookup("lnk").unwrap().link_name(), "../target");
+    assert_eq!(r.lstat("hard").unwrap().entry.link_name(), "f");
+    let null = r.lookup("null").unwrap();
+    assert_eq!(
+        (null.entry_type(), null.dev_major(), null.dev_minor()),
+        ("char", 1, 3)
+    );
+    let sda = r.lookup("sda").unwrap();
+    assert_eq!(
+        (sda.entry_type(), sda.dev_major(), sda.dev_minor()),
+        ("block", 8, 0)
+    );
+    assert_eq!(r.lookup("fifo").unwrap().entry_type(), "fifo");
+    let xattrs = r.lookup("bin").unwrap().xattrs();
+    assert_eq!(xattrs["security.capability"], b"\x01\x00\x00\x02");
+    assert_eq!(xattrs["user.note"], b"kept");
+
+    // And in the tar stream of the blob
+    let decompressed = decompress(&blob);
+    let mut archive = tar::Archive::new(&decompressed[..]);
+    let mut seen = Vec::new();
+    for entry in archive.entries().unwrap() {
+        let mut e = entry.unwrap();
+        let h = e.header().clone();
+        let name = e.path().unwrap().display().to_string();
+        seen.push(name.clone());
+        match &*name {
+            "lnk" => assert_eq!(e.link_name().unwrap().unwrap().to_str(), Some("../target")),
+            "hard" => {
+                assert_eq!(h.entry_type(), tar::EntryType::Link);
+                assert_eq!(e.link_name().unwrap().unwrap().to_str(), Some("f"));
+            }
+            "null" => {
+                assert_eq!(h.entry_type(), tar::EntryType::Char);
+                assert_eq!(
+                    (h.device_major().unwrap(), h.device_minor().unwrap()),
+                    (Some(1), Some(3))
+                );
+            }
+            "sda" => {
+                assert_eq!(h.entry_type(), tar::EntryType::Block);
+                assert_eq!(
+                    (h.device_major().unwrap(), h.device_minor().unwrap()),
+                    (Some(8), Some(0))
+                );
+            }
+            "fifo" => assert_eq!(h.entry_type(), tar::EntryType::Fifo),
+            "bin" => {
+                let pax: Vec<_> = e
+                    .pax_extensions()
+                    .unwrap()
+                    .unwrap()
+                    .map(|r| {
+                        let r = r.unwrap();
+                        (r.key().unwrap().to_string(), r.value_bytes().to_vec())
+                    })
+                    .collect();
+                assert!(
+                    pax.contains(&("SCHILY.xattr.user.note".to_string(), b"kept".to_vec())),
+                    "{pax:?}"
+                );
+                assert!(pax
+                    .iter()
+                    .any(|(k, _)| k == "SCHILY.xattr.security.capability"));
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(
+        seen,
+        [
+            "lnk",
+            "f",
+            "hard",
+            "null",
+            "sda",
+            "fifo",
+            "bin",
+            "stargz.index.json"
+        ]
+    );
+}
+
+#[test]
+fn long_names_and_link_targets_are_written_whole() {
+    let dir = "node_modules/some-package/".repeat(8);
+    let file = format!("{dir}lib/index.js");
+    let link = format!("{dir}link");
+    let target = format!("../{}target.js", "x".repeat(150));
+    let hard = format!("{dir}hard");
+    assert!(file.len() > 200 && target.len() > 150);
+
+    // From a tar with GNU long name records, and added directly
+    let mut b = tar::Builder::new(Vec::new());
+    let mut h = header(tar::EntryType::Regular, 2);
+    b.append_data(&mut h, &file, &b"js"[..]).unwrap();
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, &hard, &file).unwrap();
+    let input = b.into_inner().unwrap();
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w,
+        |w| {
+            w.append_tar(&mut &input[..]).unwrap();
+            w.add_symlink(&link, &target, &file_meta()).unwrap();
+            w.add_dir(&format!("{dir}empty"), &file_meta()).unwrap();
+            w.add_file(&format!("{dir}added"), &mut &b"added"[..], &file_meta())
+                .unwrap();
+        },
+        &mut blob,
+    );
+
+    let r = open_from_bytes(blob.clone()).unwrap();
+    assert_eq!(r.read_file(&file).unwrap(), b"js");
+    assert_eq!(r.lstat(&hard).unwrap().entry.link_name(), file);
+    assert_eq!(r.lookup(&link).unwrap().link_name(), target);
+    assert_eq!(r.lookup(format!("{dir}empty")).unwrap().entry_type(), "dir");
+    assert_eq!(r.read_file(&format!("{dir}added")).unwrap(), b"added");
+
+    let decompressed = decompress(&blob);
+    let mut archive = tar::Archive::new(&decompressed[..]);
+    let entries: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .map(|e| {
+            let e = e.unwrap();
+            let link_name = e.link_name().unwrap().map(|l| l.display().to_string());
+            (e.path().unwrap().display().to_string(), link_name)
+        })
+        .collect();
+    assert!(entries.contains(&(file.clone(), None)));
+    assert!(entries.contains(&(hard, Some(file))));
+    assert!(entries.contains(&(link, Some(target))));
+    assert!(entries.contains(&(format!("{dir}added"), None)));
+}
+
+#[test]
+fn reproducible_output_ignores_input_order_and_late_mtimes() {
+    let entries: [(&str, &[u8], u64); 4] = [
+        ("etc/conf", b"conf", 1_500_000_000),
+        ("etc/new", b"new", 1_900_000_000),
+        ("a", b"a", 1_700_000_000),
+        ("z/deep/file", b"deep", 1_600_000_000),
+    ];
+    let tar_in = |order: &[usize]| {
+        let mut b = tar::Builder::new(Vec::new());
+        for &i in order {
+            let (path, data, mtime) = entries[i];
+            let mut h = header(tar::EntryType::Regular, data.len() as u64);
+            h.set_mtime(mtime);
+            b.append_data(&mut h, path, data).unwrap();
+        }
+        b.into_inner().unwrap()
+    };
+    let build = |input: &[u8]| {
+        let mut blob = Vec::new();
+        let mut w = Writer::new(&mut blob).with_reproducible_output(Some(1_650_000_000));
+        w.append_tar(&mut &input[..]).unwrap();
+        w.close().unwrap();
+        let toc_digest = w.toc_digest().unwrap().to_string();
+        drop(w);
+        (blob, toc_digest)
+    };
+
+    let (blob, toc_digest) = build(&tar_in(&[0, 1, 2, 3]));
+    for order in [[0, 1, 2, 3], [3, 2, 1, 0], [2, 0, 3, 1]] {
+        let (again, again_digest) = build(&tar_in(&order));
+        assert!(again == blob, "{order:?}");
+        assert_eq!(again_digest, toc_digest);
+    }
+    assert_eq!(
+        toc_names(&blob),
+        ["a", "etc/conf", "etc/new", "z/deep/file"]
+    );
+    let r = open_from_bytes(blob.clone()).unwrap();
+    let mtime = |name: &str| r.lookup(name).unwrap().mod_time().unwrap().timestamp();
+    assert_eq!(mtime("etc/conf"), 1_500_000_000);
+    assert_eq!(mtime("etc/new"), 1_650_000_000);
+    assert_eq!(mtime("a"), 1_650_000_000);
+
+    // No timestamp or OS in the gzip headers
+    assert_eq!(blob[4..8], [0; 4]);
+    assert_eq!(blob[9], 255);
+}
+
+#[test]
+fn reproducible_output_of_directories() {
+    // The same tree, created in another order at another time
+    let files = [
+        ("b/2", "two"),
+        ("a", "one"),
+        ("b/1", "uno"),
+        ("c/d/e", "deep"),
+    ];
+    let trees = [TempDir::new(), TempDir::new()];
+    for (k, (tree, order)) in trees.iter().zip([[0, 1, 2, 3], [3, 2, 1, 0]]).enumerate() {
+        for i in order {
+            let (path, data) = files[i];
+            let path = tree.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, data).unwrap();
+        }
+        std::fs::hard_link(tree.path().join("a"), tree.path().join("b/link")).unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_700_000_000 + k as u64);
+        for path in ["a", "b/1", "b/2", "c/d/e", "c/d", "c", "b"] {
+            let f = File::open(tree.path().join(path)).unwrap();
+            f.set_modified(mtime).unwrap();
+        }
+    }
+
+    let build = |dir: &TempDir, epoch: Option<u64>| {
+        let mut blob = Vec::new();
+        blob_with(
+            |w| match epoch {
+                Some(_) => w.with_reproducible_output(epoch),
+                None => w,
+            },
+            |w| w.append_dir_all(dir.path()).unwrap(),
+            &mut blob,
+        );
+        blob
+    };
+    let first = build(&trees[0], Some(1_600_000_000));
+    assert!(first == build(&trees[1], Some(1_600_000_000)));
+    assert!(first == build(&trees[0], Some(1_600_000_000)));
+    // Only the mtimes told them apart
+    assert!(build(&trees[0], None) != build(&trees[1], None));
+
+    assert_eq!(
+        toc_names(&first),
+        ["a", "b", "b/1", "b/2", "b/link", "c", "c/d", "c/d/e"]
+    );
+    let r = open_from_bytes(first).unwrap();
+    assert!(r.lstat("b/link").unwrap().is_hardlink());
+    assert!(r
+        .walk()
+        .all(|(_, e)| e.mod_time().unwrap().timestamp() == 1_600_000_000));
+}
+
+#[test]
+fn entries_added_by_hand_carry_their_metadata() {
+    let meta = EntryMeta {
+        mode: 0o4750,
+        uid: 1000,
+        gid: 100,
+        uname: "user".to_string(),
+        gname: "users".to_string(),
+        mtime: 1_650_000_000,
+        xattrs: [("user.k".to_string(), b"v".to_vec())].into(),
+    };
+    let big = pattern(3 * MIN_CHUNK_SIZE + 7);
+    let mut blob = Vec::new();
+    blob_with(
+        |w| {
+            w.with_chunk_size(MIN_CHUNK_SIZE)
+                .unwrap()
+                .with_mtime_clamp(1_600_000_000)
+                .with_reproducible_output(None)
+        },
+        |w| {
+            w.add_dir("./etc/", &meta).unwrap();
+            w.add_file("etc/big", &mut &big[..], &meta).unwrap();
+            w.add_symlink("/etc/link", "big", &meta).unwrap();
+            w.add_file("a", &mut &b"a"[..], &file_meta()).unwrap();
+        },
+        &mut blob,
+    );
+
+    // As added, not sorted
+    assert_eq!(toc_names(&blob), ["etc", "etc/big", "etc/link", "a"]);
+    let r = open_from_bytes(blob.clone()).unwrap();
+    for (name, entry_type, mode) in [
+        ("etc", "dir", 0o4750),
+        ("etc/big", "reg", 0o4750),
+        ("etc/link", "symlink", 0o4750),
+    ] {
+        let e = r.lookup(name).unwrap();
+        assert_eq!(e.entry_type(), entry_type);
+        assert_eq!(e.mode() & 0o7777, mode, "{name}");
+        assert_eq!((e.uid(), e.gid()), (1000, 100), "{name}");
+        assert_eq!((e.uname(), e.gname()), ("user", "users"), "{name}");
+        assert_eq!(e.xattrs()["user.k"], b"v", "{name}");
+        // Clamped like appended entries
+        assert_eq!(e.mod_time().unwrap().timestamp(), 1_600_000_000, "{name}");
+    }
+    assert_eq!(r.lookup("etc/link").unwrap().link_name(), "big");
+    assert!(r.read_file("etc/big").unwrap() == big);
+    let chunks = r.entries().iter().filter(|e| e.name() == "etc/big").count();
+    assert_eq!(chunks, 4);
+    assert!(r.verify().is_ok());
+
+    // The tar headers say the same
+    let decompressed = decompress(&blob);
+    let mut archive = tar::Archive::new(&decompressed[..]);
+    let mut e = archive.entries().unwrap().nth(1).unwrap().unwrap();
+    assert_eq!(e.path().unwrap().to_str(), Some("etc/big"));
+    let h = e.header();
+    assert_eq!(h.mode().unwrap(), 0o4750);
+    assert_eq!(h.uid().unwrap(), 1000);
+    assert_eq!(h.username().unwrap(), Some("user"));
+    assert_eq!(h.mtime().unwrap(), 1_600_000_000);
+    let mut content = Vec::new();
+    e.read_to_end(&mut content).unwrap();
+    assert!(content == big);
+}
+
+#[test]
+fn out_of_range_mtimes_are_errors() {
+    for mtime in [u64::MAX, i64::MAX as u64, 1 << 60] {
+        let meta = EntryMeta {
+            mtime,
+            ..file_meta()
+        };
+        let mut w = Writer::new(Vec::new());
+        assert!(w.add_file("a", &mut &b"a"[..], &meta).is_err(), "{mtime}");
+    }
+}
+
+#[test]
+fn append_dir_all_keeps_what_is_on_disk() {
+    let dir = TempDir::new();
+    for (path, data, mode) in [
+        ("z", "z", 0o600),
+        ("bin/tool", "#!/bin/sh\n", 0o755),
+        ("a/b/c", "c", 0o640),
+    ] {
+        let path = dir.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, data).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+    std::fs::create_dir(dir.path().join("empty")).unwrap();
+    std::fs::set_permissions(
+        dir.path().join("empty"),
+        std::fs::Permissions::from_mode(0o1777),
+    )
+    .unwrap();
+    File::open(dir.path().join("z"))
+        .unwrap()
+        .set_modified(UNIX_EPOCH + Duration::from_secs(1_234_567_890))
+        .unwrap();
+    symlink("../z", dir.path().join("a/lz")).unwrap();
+    let fifo = std::ffi::CString::new(dir.path().join("fifo").as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o620) }, 0);
+    std::fs::set_permissions(
+        dir.path().join("fifo"),
+        std::fs::Permissions::from_mode(0o620),
+    )
+    .unwrap();
+    std::os::unix::net::UnixListener::bind(dir.path().join("sock")).unwrap();
+    // Where the filesystem has user xattrs
+    let tool = std::ffi::CString::new(dir.path().join("bin/tool").as_os_str().as_bytes()).unwrap();
+    let xattrs = unsafe {
+        libc::setxattr(
+            tool.as_ptr(),
+            c"user.origin".as_ptr(),
+            b"test".as_ptr().cast(),
+            4,
+            0,
+        )
+    } == 0;
+
+    let mut blob = Vec::new();
+    blob_with(|w| w, |w| w.append_dir_all(dir.path()).unwrap(), &mut blob);
+
+    // Parents first, siblings by name, sockets left out
+    assert_eq!(
+        toc_names(&blob),
+        ["a", "a/b", "a/b/c", "a/lz", "bin", "bin/tool", "empty", "fifo", "z"]
+    );
+    let r = open_from_bytes(blob).unwrap();
+    let uid = unsafe { libc::getuid() };
+    for (name, entry_type, mode) in [
+        ("z", "reg", 0o600),
+        ("bin/tool", "reg", 0o755),
+        ("a/b/c", "reg", 0o640),
+        ("empty", "dir", 0o1777),
+        ("fifo", "fifo", 0o620),
+    ] {
+        let e = r.lookup(name).unwrap();
+        assert_eq!(e.entry_type(), entry_type, "{name}");
+        assert_eq!(e.mode() & 0o7777, mode, "{name}");
+        assert_eq!(e.uid(), uid, "{name}");
+    }
+    assert_eq!(
+        r.lookup("z").unwrap().mod_time().unwrap().timestamp(),
+        1_234_567_890
+    );
+    assert_eq!(r.lookup("a/lz").unwrap().link_name(), "../z");
+    assert_eq!(r.read_file("bin/tool").unwrap(), b"#!/bin/sh\n");
+    if xattrs {
+        assert_eq!(
+            r.lookup("bin/tool").unwrap().xattrs()["user.origin"],
+            b"test"
+        );
+    }
+}
+
+#[test]
+fn whiteouts_are_empty_files_next_to_what_they_hide() {
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w,
+        |w| {
+            w.add_whiteout("/etc/removed.conf").unwrap();
+            w.add_whiteout("top").unwrap();
+            w.add_dir("var/cache", &file_meta()).unwrap();
+            w.add_opaque_dir("./var/cache/").unwrap();
+            assert!(w.add_whiteout("/").is_err());
+            assert!(w.add_opaque_dir(".").is_err());
+        },
+        &mut blob,
+    );
+
+    assert_eq!(
+        toc_names(&blob),
+        [
+            "etc/.wh.removed.conf",
+            ".wh.top",
+            "var/cache",
+            "var/cache/.wh..wh..opq"
+        ]
+    );
+    let r = open_from_bytes(blob).unwrap();
+    for name in ["etc/.wh.removed.conf", ".wh.top", "var/cache/.wh..wh..opq"] {
+        let e = r.lookup(name).unwrap();
+        assert_eq!(
+            (e.entry_type(), e.size(), e.mode() & 0o7777),
+            ("reg", 0, 0),
+            "{name}"
+        );
+        assert_eq!((e.uid(), e.gid()), (0, 0), "{name}");
+        assert_eq!(e.mod_time().unwrap().timestamp(), 0, "{name}");
+    }
+}
+
+#[test]
+fn several_append_tar_calls_make_one_layer() {
+    let big = pattern(2 * MIN_CHUNK_SIZE + 3);
+    let mut blob = Vec::new();
+    blob_with(
+        |w| w.with_chunk_size(MIN_CHUNK_SIZE).unwrap(),
+        |w| {
+            w.append_tar(&mut &tar_of(&[("a", b"a"), ("big", &big)])[..])
+                .unwrap();
+            w.add_file("added", &mut &b"added"[..], &file_meta())
+                .unwrap();
+            w.append_tar(&mut &tar_of(&[("b", b"b")])[..]).unwrap();
+            w.append_tar(&mut &tar_of(&[])[..]).unwrap();
+            w.append_tar_lossless(&mut &tar_of(&[("c", b"c")])[..])
+                .unwrap();
+        },
+        &mut blob,
+    );
+
+    // No end-of-archive marker until the TOC's, which a tar reader would
+    // stop at
+    let decompressed = decompress(&blob);
+    let names: Vec<_> = tar::Archive::new(&decompressed[..])
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().display().to_string())
+        .collect();
+    assert_eq!(names, ["a", "big", "added", "b", "c", "stargz.index.json"]);
+
+    assert_eq!(toc_names(&blob), ["a", "big", "added", "b", "c"]);
+    let r = open_from_bytes(blob).unwrap();
+    assert!(r.read_file("big").unwrap() == big);
+    assert_eq!(r.read_file("c").unwrap(), b"c");
+    assert!(r.verify().is_ok());
+}
+
+#[test]
+fn hooks_report_what_the_toc_and_members_say() {
+    let big = pattern(2 * MIN_CHUNK_SIZE + 100);
+    let files: [(&str, &[u8]); 5] = [
+        ("big", &big),
+        ("s1", b"one"),
+        ("s2", b"two"),
+        ("empty", b""),
+        ("s3", b"three"),
+    ];
+    let (mut entries, mut chunks, mut members) = (Vec::new(), Vec::new(), Vec::new());
+    let mut blob = Vec::new();
+    let mut w = Writer::new(&mut blob)
+        .with_chunk_size(MIN_CHUNK_SIZE)
+        .unwrap()
+        .with_min_chunk_size(4000)
+        .on_entry(|e| entries.push(e.clone()))
+        .on_chunk(|c| chunks.push(c.clone()))
+        .on_member_close(|m| members.push(*m));
+    w.add_dir("dir", &file_meta()).unwrap();
+    for (name, data) in files {
+        w.add_file(name, &mut &data[..], &file_meta()).unwrap();
+    }
+    w.add_symlink("link", "big", &file_meta()).unwrap();
+    w.close().unwrap();
+    drop(w);
+
+    let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, toc_names(&blob));
+    let data = |name: &str| files.iter().find(|f| f.0 == name).unwrap().1;
+    let decompressed = decompress(&blob);
+    let r = open_from_bytes(blob.clone()).unwrap();
+    for e in &entries {
+        let ent = r.lookup(&e.name).unwrap();
+        assert_eq!(e.entry_type, ent.entry_type(), "{}", e.name);
+        assert_eq!(e.size, ent.size(), "{}", e.name);
+        if e.entry_type != "reg" {
+            assert_eq!(e.digest, None, "{}", e.name);
+            continue;
+        }
+        assert_eq!(e.digest.as_deref(), Some(ent.digest()), "{}", e.name);
+        assert_eq!(e.digest, Some(sha256(data(&e.name))), "{}", e.name);
+        if e.size > 0 {
+            assert_eq!(e.member_offset, ent.offset(), "{}", e.name);
+            let at = e.tar_offset as usize;
+            assert!(&decompressed[at..at + e.size as usize] == data(&e.name));
+        }
+    }
+
+    // One event per TOC entry with content, in TOC order
+    let with_content: Vec<_> = r
+        .entries()
+        .iter()
+        .filter(|e| e.entry_type() == "chunk" || e.entry_type() == "reg" && e.size() > 0)
+        .map(|e| (e.name(), e.chunk_offset()))
+        .collect();
+    let reported: Vec<_> = chunks
+        .iter()
+        .map(|c| (c.name.as_str(), c.chunk_offset))
+        .collect();
+    assert_eq!(reported, with_content);
+    assert_eq!(reported.len(), 6);
+    for c in &chunks {
+        let ent = r
+            .entries()
+            .iter()
+            .find(|e| e.name() == c.name && e.chunk_offset() == c.chunk_offset)
+            .unwrap();
+        assert_eq!(c.member_offset, ent.offset(), "{c:?}");
+        assert_eq!(c.chunk_size, ent.chunk_size(), "{c:?}");
+        assert_eq!(c.inner_offset, ent.inner_offset(), "{c:?}");
+        assert_eq!(c.digest, ent.chunk_digest(), "{c:?}");
+        let content = &data(&c.name)[c.chunk_offset as usize..][..c.chunk_size as usize];
+        assert_eq!(c.digest, sha256(content));
+        let at = c.tar_offset as usize;
+        assert!(&decompressed[at..at + c.chunk_size as usize] == content);
+    }
+    // The small files are packed after the last chunk of big
+    let packed: Vec<_> = chunks[2..]
+        .iter()
+        .map(|c| (c.member_offset, c.inner_offset))
+        .collect();
+    assert!(packed.iter().all(|&(at, _)| at == chunks[2].member_offset));
+    assert!(packed.windows(2).all(|w| w[0].1 < w[1].1), "{packed:?}");
+
+    // Every member of the tar stream, not the TOC's
+    let data_members: Vec<_> = r
+        .members()
+        .unwrap()
+        .iter()
+        .map(|m| (m.offset, m.compressed_size))
+        .collect();
+    let closed: Vec<_> = members
+        .iter()
+        .map(|m| (m.offset, m.compressed_size))
+        .collect();
+    assert_eq!(closed, data_members);
+    let last = closed.last().unwrap();
+    assert_eq!(last.0 + last.1, footer_of(&blob).0 as u64);
+    assert!(members.iter().all(|m| m.blob == 0));
+}
+
+#[test]
+fn transforms_apply_in_order_to_every_entry() {
+    let mut b = tar::Builder::new(Vec::new());
+    b.append_pax_extensions([
+        ("SCHILY.xattr.security.selinux", &b"label"[..]),
+        ("SCHILY.xattr.user.k", b"v"),
+    ])
+    .unwrap();
+    let mut h = header(tar::EntryType::Regular, 1);
+    h.set_uid(1000);
+    h.set_username("alice").unwrap();
+    b.append_data(&mut h, "home/alice/a", &b"a"[..]).unwrap();
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "home/alice/hard", "home/alice/a")
+        .unwrap();
+    let mut h = header(tar::EntryType::Symlink, 0);
+    b.append_link(&mut h, "home/alice/soft", "/home/alice/a")
+        .unwrap();
+    let mut h = header(tar::EntryType::Link, 0);
+    b.append_link(&mut h, "other", "home/alice/a").unwrap();
+    let input = b.into_inner().unwrap();
+    let meta = EntryMeta {
+        uid: 1000,
+        uname: "alice".to_string(),
+        mtime: 1_900_000_000,
+        xattrs: [("security.ima".to_string(), b"sig".to_vec())].into(),
+        ..file_meta()
+    };
+
+    let mut seen = Vec::new();
+    let mut blob = Vec::new();
+    let mut w = Writer::new(&mut blob)
+        .with_mtime_clamp(1_700_000_000)
+        .with_transform(RedactPaths::new("/home/alice/", "home/user"))
+        .with_transform(Chown::new(0, 0).names("root", "root"))
+        // Sees what the transforms before it did
+        .with_transform(|e: &mut EntryAttrs| {
+            seen.push((e.path.clone(), e.uname.clone(), e.entry_type()));
+            e.uname.push('+');
+            Ok(())
+        })
+        .with_transform(DropXattrs::new().prefix("security."))
+        .with_transform(StripTimestamps {
+            mtime: 1_800_000_000,
+        });
+    w.append_tar(&mut &input[..]).unwrap();
+    w.add_file("home/alice/b", &mut &b"b"[..], &meta).unwrap();
+    w.add_dir("home/alice/d", &meta).unwrap();
+    w.add_symlink("home/alice/s", "alice/b", &meta).unwrap();
+    w.close().unwrap();
+    drop(w);
+
+    let paths = [
+        "home/user/a",
+        "home/user/hard",
+        "home/user/soft",
+        "other",
+        "home/user/b",
+        "home/user/d",
+        "home/user/s",
+    ];
+    let expected: Vec<_> = paths
+        .iter()
+        .map(|p| (p.to_string(), "root".to_string()))
+        .collect();
+    let got: Vec<_> = seen
+        .iter()
+        .map(|(p, u, _)| (p.clone(), u.clone()))
+        .collect();
+    assert_eq!(got, expected);
+    assert_eq!(seen[1].2, tar::EntryType::Link);
+    assert_eq!(toc_names(&blob), paths);
+
+    let r = open_from_bytes(blob.clone()).unwrap();
+    for path in paths {
+        let e = r.lstat(path).unwrap().entry;
+        assert_eq!((e.uid(), e.uname(), e.gname()), (0, "root+", "root"));
+        // Clamped after StripTimestamps
+        assert_eq!(e.mod_time().unwrap().timestamp(), 1_700_000_000, "{path}");
+        assert!(!e.xattrs().keys().any(|k| k.starts_with("security.")));
+    }
+    assert_eq!(r.lookup("home/user/a").unwrap().xattrs()["user.k"], b"v");
+    // Hardlinks follow their target, symlinks are left alone
+    assert_eq!(
+        r.lstat("home/user/hard").unwrap().entry.link_name(),
+        "home/user/a"
+    );
+    assert_eq!(r.lstat("other").unwrap().entry.link_name(), "home/user/a");
+    assert_eq!(
+        r.lookup("home/user/soft").unwrap().link_name(),
+        "/home/alice/a"
+    );
+    assert_eq!(r.lookup("home/user/s").unwrap().link_name(), "alice/b");
+    assert_eq!(r.read_file("other").unwrap(), b"a");
+
+    // The tar headers say the same
+    let decompressed = decompress(&blob);
+    let mut archive = tar::Archive::new(&decompressed[..]);
+    for e in archive.entries().unwrap() {
+        let e = e.unwrap();
+        let h = e.header();
+        if e.path().unwrap().to_str() == Some("stargz.index.json") {
+            continue;
+        }
+        assert_eq!(h.mtime().unwrap(), 1_700_000_000);
+        assert_eq!(h.username().unwrap(), Some("root+"));
+        assert!(paths.contains(&e.path().unwrap().to_str().unwrap()));
+    }
+}
+
+#[test]
+fn progress_counts_entries_and_ends_at_the_blob_size() {
+    let big = pattern(2 * MIN_CHUNK_SIZE);
+    let input = tar_of(&[("a", b"a"), ("big", &big), ("c", b"c")]);
+    let mut calls = Vec::new();
+    let mut blob = Vec::new();
+    let mut w = Writer::new(&mut blob)
+        .with_chunk_size(MIN_CHUNK_SIZE)
+        .unwrap()
+        .on_progress(|entries, bytes_in, bytes_out| calls.push((entries, bytes_in, bytes_out)));
+    w.append_tar(&mut &input[..]).unwrap();
+    w.add_dir("d", &file_meta()).unwrap();
+    w.add_file("d/f", &mut &b"f"[..], &file_meta()).unwrap();
+    w.add_symlink("d/l", "f", &file_meta()).unwrap();
+    w.close().unwrap();
+    drop(w);
+
+    let entries: Vec<_> = calls.iter().map(|c| c.0).collect();
+    assert_eq!(entries, [1, 2, 3, 4, 5, 6, 6]);
+    assert!(calls
+        .windows(2)
+        .all(|w| w[0].1 <= w[1].1 && w[0].2 <= w[1].2));
+    // The whole tar was read by the time its last entry was written
+    assert!(calls[2].1 >= 3 * 512 + big.len() as u64, "{calls:?}");
+    assert_eq!(calls.last().unwrap().2, blob.len() as u64);
+}
+
+#[test]
+fn counting_writer_tracks_offsets_across_writes_and_flushes() {
+    let mut w = CountingWriter::with_offset(BufWriter::with_capacity(8, Vec::new()), 100);
+    assert_eq!(
+        (w.position(), w.flushed_position(), w.bytes_written()),
+        (100, 100, 0)
+    );
+
+    // Buffered: written, but not flushed yet
+    w.write_all(b"abcd").unwrap();
+    assert_eq!(
+        (w.position(), w.flushed_position(), w.bytes_written()),
+        (104, 100, 4)
+    );
+    assert!(w.get_ref().is_empty());
+
+    w.flush().unwrap();
+    assert_eq!((w.position(), w.flushed_position()), (104, 104));
+    assert_eq!(w.get_ref(), b"abcd");
+
+    // Past the buffer's capacity: straight through to the inner writer
+    w.write_all(b"ef").unwrap();
+    w.write_all(&[b'g'; 20]).unwrap();
+    assert_eq!(
+        (w.position(), w.flushed_position(), w.bytes_written()),
+        (126, 126, 26)
+    );
+
+    w.write_all(b"h").unwrap();
+    assert_eq!((w.position(), w.flushed_position()), (127, 126));
+    // Flushes what is left
+    let out = w.into_inner().unwrap();
+    assert_eq!(out.len(), 27);
+    assert_eq!(&out[..6], b"abcdef");
+    assert_eq!(out[26], b'h');
+
+    let w = CountingWriter::new(BufWriter::new(Vec::<u8>::new()));
+    assert_eq!((w.position(), w.flushed_position()), (0, 0));
+}